@@ -0,0 +1,48 @@
+use std::{
+    fs::File,
+    io::{stdout, BufReader, BufWriter},
+    path::PathBuf,
+    process::exit,
+};
+
+use SQLDB::{
+    error::Result,
+    sql::{dump, engine::kv::KVEngine},
+    storage::disk::DiskEngine,
+};
+
+const USAGE: &str = "usage:
+    sqldb dump <db-file> [<output-file>]    write a logical dump, to stdout by default
+    sqldb restore <db-file> <input-file>    replay a dump into an empty database";
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Err(err) = run(&args) {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    match args.iter().map(|s| s.as_str()).collect::<Vec<_>>().as_slice() {
+        ["dump", db, rest @ ..] if rest.len() <= 1 => {
+            let engine = KVEngine::new(DiskEngine::new(PathBuf::from(db))?)?;
+            let stats = match rest.first() {
+                Some(path) => dump::dump(&engine, &mut BufWriter::new(File::create(path)?))?,
+                None => dump::dump(&engine, &mut BufWriter::new(stdout().lock()))?,
+            };
+            eprintln!("dumped {} tables, {} rows", stats.tables, stats.rows);
+            Ok(())
+        }
+        ["restore", db, input] => {
+            let engine = KVEngine::new(DiskEngine::new(PathBuf::from(db))?)?;
+            let stats = dump::restore(&engine, BufReader::new(File::open(input)?))?;
+            eprintln!("restored {} tables, {} rows", stats.tables, stats.rows);
+            Ok(())
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    }
+}
@@ -11,6 +11,9 @@ pub enum Error {
     Parse(String),
     Internal(String),
     WriteConflict,
+    Serialization,
+    ReadOnly,
+    LockNotAvailable,
 }
 
 impl From<std::num::ParseIntError> for Error {
@@ -69,6 +72,9 @@ impl Display for Error {
             Error::Parse(err) => write!(f, "parse error {}", err),
             Error::Internal(err) => write!(f, "internal error {}", err),
             Error::WriteConflict => write!(f, "write conflict, try transaction"),
+            Error::Serialization => write!(f, "serialization failure, retry transaction"),
+            Error::ReadOnly => write!(f, "cannot write in a read-only transaction"),
+            Error::LockNotAvailable => write!(f, "key is locked by another transaction"),
         }
     }
 }
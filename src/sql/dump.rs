@@ -0,0 +1,208 @@
+use std::io::{BufRead, Write};
+
+use crate::error::{Error, Result};
+
+use super::{
+    engine::{Engine, Transaction},
+    parser::Parser,
+    plan::Plan,
+};
+
+// rows per INSERT statement in a dump
+const INSERT_BATCH_SIZE: usize = 100;
+
+// Write a logical dump of the whole database
+// every table's CREATE TABLE statement is followed by batched INSERT statements,
+// all read from the snapshot of one transaction
+pub fn dump<E: Engine, W: Write>(engine: &E, w: &mut W) -> Result<DumpStats> {
+    let txn = engine.begin()?;
+    let result = dump_txn(&txn, w);
+    txn.rollback()?;
+    result
+}
+
+// Number of objects written by a dump or replayed by a restore
+#[derive(Debug, Default, PartialEq)]
+pub struct DumpStats {
+    pub tables: usize,
+    pub rows: usize,
+}
+
+fn dump_txn<T: Transaction, W: Write>(txn: &T, w: &mut W) -> Result<DumpStats> {
+    let mut stats = DumpStats::default();
+    writeln!(w, "-- sqldb logical dump")?;
+    for table in txn.scan_tables()? {
+        writeln!(w)?;
+        writeln!(w, "{}", table.to_ddl()?)?;
+        stats.tables += 1;
+
+        let columns = table
+            .columns
+            .iter()
+            .map(|c| c.name.clone())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let rows = txn.scan_table(table.name.clone())?;
+        for batch in rows.chunks(INSERT_BATCH_SIZE) {
+            let mut values = Vec::new();
+            for row in batch {
+                let literals = row
+                    .iter()
+                    .map(|v| v.to_literal())
+                    .collect::<Result<Vec<_>>>()?;
+                values.push(format!("({})", literals.join(", ")));
+            }
+            writeln!(
+                w,
+                "INSERT INTO {} ({}) VALUES\n{};",
+                table.name,
+                columns,
+                values.join(",\n")
+            )?;
+            stats.rows += batch.len();
+        }
+    }
+    w.flush()?;
+    Ok(stats)
+}
+
+// Replay a dump into an empty database
+// the statements are executed in one transaction, so a failed restore leaves nothing behind
+pub fn restore<E: Engine, R: BufRead>(engine: &E, r: R) -> Result<DumpStats> {
+    let mut txn = engine.begin()?;
+    match restore_txn(&mut txn, r) {
+        Ok(stats) => {
+            txn.commit()?;
+            Ok(stats)
+        }
+        Err(err) => {
+            txn.rollback()?;
+            Err(err)
+        }
+    }
+}
+
+fn restore_txn<T: Transaction, R: BufRead>(txn: &mut T, r: R) -> Result<DumpStats> {
+    if !txn.scan_tables()?.is_empty() {
+        return Err(Error::Internal(
+            "restore requires an empty database".to_string(),
+        ));
+    }
+
+    let mut stats = DumpStats::default();
+    for sql in split_statements(r)? {
+        let stmt = Parser::new(&sql).parse()?;
+        match Plan::build(stmt)?.execute(txn)? {
+            super::executor::ResultSet::CreateTable { .. } => stats.tables += 1,
+            super::executor::ResultSet::Insert { count } => stats.rows += count,
+            _ => {}
+        }
+    }
+    Ok(stats)
+}
+
+// Split a script into statements at the semicolons outside string literals,
+// -- comments are dropped
+fn split_statements<R: BufRead>(r: R) -> Result<Vec<String>> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut in_string = false;
+    for line in r.lines() {
+        let line = line?;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' => in_string = !in_string,
+                '-' if !in_string && chars.peek() == Some(&'-') => break,
+                ';' if !in_string => {
+                    current.push(c);
+                    statements.push(std::mem::take(&mut current).trim().to_string());
+                    continue;
+                }
+                _ => {}
+            }
+            current.push(c);
+        }
+        current.push('\n');
+    }
+
+    if !current.trim().is_empty() {
+        return Err(Error::Parse(format!(
+            "unterminated statement: {}",
+            current.trim()
+        )));
+    }
+    Ok(statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dump, restore, DumpStats};
+    use crate::{
+        error::Result,
+        sql::engine::{kv::KVEngine, Engine, Transaction},
+        storage::{disk::DiskEngine, memory::MemoryEngine},
+    };
+
+    fn fill(engine: &impl Engine) -> Result<()> {
+        let mut s = engine.session()?;
+        s.execute("create table t1 (a int not null, b text default 'it''s', c float, d bool default true);")?;
+        s.execute("insert into t1 values (1, 'x', 1.5, false), (-2, 'semi;colon -- not a comment', -0.25, true);")?;
+        s.execute("insert into t1 (a, c) values (3, 2.0), (4, null);")?;
+        s.execute("insert into t1 values (5, '''quoted''', 10000000000.0, null), (6, 'multi\nline', 0.1, false);")?;
+        s.execute("create table empty (id int);")?;
+        s.execute("create table t0 (id int, name varchar not null);")?;
+        for i in 0..250 {
+            s.execute(&format!("insert into t0 values ({}, 'name{}');", i, i))?;
+        }
+        Ok(())
+    }
+
+    // Compare the schema and data of two databases
+    fn assert_same(a: &impl Engine, b: &impl Engine) -> Result<()> {
+        let (ta, tb) = (a.begin()?, b.begin()?);
+        let (tables_a, tables_b) = (ta.scan_tables()?, tb.scan_tables()?);
+        assert_eq!(tables_a, tables_b);
+        for table in tables_a {
+            assert_eq!(
+                ta.scan_table(table.name.clone())?,
+                tb.scan_table(table.name.clone())?
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_restore() -> Result<()> {
+        let source = KVEngine::new(MemoryEngine::new())?;
+        fill(&source)?;
+
+        let mut buf = Vec::new();
+        let stats = dump(&source, &mut buf)?;
+        assert_eq!(stats, DumpStats { tables: 3, rows: 256 });
+
+        let dir = tempfile::tempdir()?;
+        let target = KVEngine::new(DiskEngine::new(dir.path().join("sqldb-log"))?)?;
+        let restored = restore(&target, buf.as_slice())?;
+        assert_eq!(restored, stats);
+        assert_same(&source, &target)?;
+
+        // dumping the restored database gives the same output
+        let mut buf2 = Vec::new();
+        dump(&target, &mut buf2)?;
+        assert_eq!(buf, buf2);
+
+        // a second restore is refused
+        assert!(restore(&target, buf.as_slice()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_failure_rolls_back() -> Result<()> {
+        let engine = KVEngine::new(MemoryEngine::new())?;
+        let script = "CREATE TABLE t (a INTEGER NOT NULL);\nINSERT INTO t (a) VALUES (1);\nINSERT INTO t (a) VALUES (NULL);\n";
+        assert!(restore(&engine, script.as_bytes()).is_err());
+        assert!(engine.begin()?.scan_tables()?.is_empty());
+        Ok(())
+    }
+}
@@ -6,7 +6,11 @@ use crate::{
         schema::Table,
         types::{Row, Value},
     },
-    storage::{self, engine::Engine as StorageEngine},
+    storage::{
+        self,
+        engine::Engine as StorageEngine,
+        mvcc::{LockMode, LockWait, TransactionOptions, Version},
+    },
 };
 
 use super::{Engine, Transaction};
@@ -25,18 +29,98 @@ impl<E: StorageEngine> Clone for KVEngine<E> {
 }
 
 impl<E: StorageEngine> KVEngine<E> {
-    pub fn new(engine: E) -> Self {
-        Self {
-            kv: storage::mvcc::Mvcc::new(engine),
-        }
+    pub fn new(engine: E) -> Result<Self> {
+        Ok(Self {
+            kv: storage::mvcc::Mvcc::new(engine)?,
+        })
     }
+
+    // Record the committed writes for the change stream
+    pub fn capture_changes(mut self, enabled: bool) -> Self {
+        self.kv = self.kv.capture_changes(enabled);
+        self
+    }
+
+    // Stream the committed row changes in commit order, after the transaction
+    // of the version or from the oldest one kept, see Mvcc::changes
+    // Schema changes are left out.
+    pub fn changes(
+        &self,
+        after: Option<Version>,
+    ) -> Result<impl Iterator<Item = Result<CommittedRows>>> {
+        Ok(self.kv.changes(after)?.map(|txn| {
+            let txn = txn?;
+            let mut changes = Vec::new();
+            for change in txn.changes {
+                let table_name = match bincode::deserialize(&change.key)? {
+                    Key::Row(table_name, _) => table_name,
+                    Key::Table(_) => continue,
+                };
+                let decode = |value: Option<Vec<u8>>| -> Result<Option<Row>> {
+                    Ok(match value {
+                        Some(value) => Some(bincode::deserialize(&value)?),
+                        None => None,
+                    })
+                };
+                changes.push(RowChange {
+                    table_name,
+                    before: decode(change.old)?,
+                    after: decode(change.new)?,
+                });
+            }
+            Ok(CommittedRows {
+                version: txn.version,
+                changes,
+            })
+        }))
+    }
+
+    // Drop the changes up to the transaction of the version, see Mvcc::truncate_changes
+    pub fn truncate_changes(&self, through: Version) -> Result<()> {
+        self.kv.truncate_changes(through)
+    }
+}
+
+// A committed change of a row, before is None for a new row
+#[derive(Debug, PartialEq)]
+pub struct RowChange {
+    pub table_name: String,
+    pub before: Option<Row>,
+    pub after: Option<Row>,
+}
+
+// The row changes of a committed transaction
+#[derive(Debug, PartialEq)]
+pub struct CommittedRows {
+    pub version: Version,
+    pub changes: Vec<RowChange>,
 }
 
 impl<E: StorageEngine> Engine for KVEngine<E> {
     type Transaction = KVTransaction<E>;
 
-    fn begin(&self) -> Result<Self::Transaction> {
-        Ok(Self::Transaction::new(self.kv.begin()?))
+    fn begin_with(&self, options: TransactionOptions) -> Result<Self::Transaction> {
+        Ok(Self::Transaction::new(self.kv.begin_with(options)?))
+    }
+
+    fn begin_as_of(&self, version: Version) -> Result<Self::Transaction> {
+        Ok(Self::Transaction::new(self.kv.begin_as_of(version)?))
+    }
+
+    fn current_version(&self) -> Result<Version> {
+        self.kv.current_version()
+    }
+
+    fn vacuum(&self) -> Result<usize> {
+        self.kv.vacuum()
+    }
+
+    fn commit_prepared(&self, id: &str) -> Result<()> {
+        self.kv.commit_prepared(id)
+    }
+
+    fn rollback_prepared(&self, id: &str) -> Result<()> {
+        self.kv.rollback_prepared(id)
     }
 }
 
@@ -53,34 +137,37 @@ impl<E: StorageEngine> KVTransaction<E> {
 
 impl<E: StorageEngine> Transaction for KVTransaction<E> {
     fn commit(&self) -> Result<()> {
-        Ok(())
+        self.txn.commit()
     }
 
     fn rollback(&self) -> Result<()> {
-        Ok(())
+        self.txn.rollback()
+    }
+
+    fn prepare(&self, id: &str) -> Result<()> {
+        self.txn.prepare(id)
+    }
+
+    fn savepoint(&self, name: &str) -> Result<()> {
+        self.txn.savepoint(name)
+    }
+
+    fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        self.txn.rollback_to_savepoint(name)
+    }
+
+    fn release_savepoint(&self, name: &str) -> Result<()> {
+        self.txn.release_savepoint(name)
+    }
+
+    fn begin_statement(&self) -> Result<()> {
+        self.txn.begin_statement()
     }
 
     fn create_row(&mut self, table_name: String, row: Row) -> Result<()> {
         let table = self.must_get_table(table_name.clone())?;
         // check line's validity
-        for (i, col) in table.columns.iter().enumerate() {
-            match row[i].datatype() {
-                None if col.nullable => {}
-                None => {
-                    return Err(Error::Internal(format!(
-                        "column {} cannot be null",
-                        col.name
-                    )))
-                }
-                Some(dt) if dt != col.datatype => {
-                    return Err(Error::Internal(format!(
-                        "column {} type mismatch",
-                        col.name
-                    )))
-                }
-                _ => {}
-            }
-        }
+        table.check_row(&row)?;
 
         // Store data
         // First column as primary key for now
@@ -92,6 +179,17 @@ impl<E: StorageEngine> Transaction for KVTransaction<E> {
         Ok(())
     }
 
+    fn create_rows(&mut self, table_name: String, rows: Vec<Row>) -> Result<()> {
+        let table = self.must_get_table(table_name.clone())?;
+        let mut pairs = Vec::with_capacity(rows.len());
+        for row in rows {
+            table.check_row(&row)?;
+            let id = Key::Row(table_name.clone(), row[0].clone());
+            pairs.push((bincode::serialize(&id)?, bincode::serialize(&row)?));
+        }
+        self.txn.set_batch(pairs)
+    }
+
     fn scan_table(&self, table_name: String) -> Result<Vec<Row>> {
         let prefix = KeyPrefix::Row(table_name.clone());
         let results = self.txn.scan_prefix(bincode::serialize(&prefix)?)?;
@@ -104,6 +202,24 @@ impl<E: StorageEngine> Transaction for KVTransaction<E> {
         Ok(rows)
     }
 
+    fn lock_rows(
+        &self,
+        table_name: String,
+        rows: Vec<Row>,
+        mode: LockMode,
+        wait: LockWait,
+    ) -> Result<Vec<Row>> {
+        let mut locked = Vec::new();
+        for row in rows {
+            let id = Key::Row(table_name.clone(), row[0].clone());
+            // a row changed after the snapshot fails with a write conflict
+            if self.txn.lock(bincode::serialize(&id)?, mode, wait)? {
+                locked.push(row);
+            }
+        }
+        Ok(locked)
+    }
+
     fn create_table(&mut self, table: Table) -> Result<()> {
         // check if table exists
         if self.get_table(table.name.clone())?.is_some() {
@@ -136,6 +252,20 @@ impl<E: StorageEngine> Transaction for KVTransaction<E> {
             .map(|v| bincode::deserialize(&v))
             .transpose()?)
     }
+
+    fn scan_tables(&self) -> Result<Vec<Table>> {
+        let prefix = KeyPrefix::Table;
+        let results = self.txn.scan_prefix(bincode::serialize(&prefix)?)?;
+
+        let mut tables = Vec::new();
+        for result in results {
+            let table: Table = bincode::deserialize(&result.value)?;
+            tables.push(table);
+        }
+        // keys are ordered by the encoded name length first
+        tables.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(tables)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -152,13 +282,26 @@ enum KeyPrefix {
 
 #[cfg(test)]
 mod tests {
-    use crate::{error::Result, sql::engine::Engine, storage::memory::MemoryEngine};
+    use std::time::Duration;
+
+    use crate::{
+        error::{Error, Result},
+        sql::{
+            engine::{Engine, RetryPolicy, Session, Transaction},
+            executor::ResultSet,
+            types::Value,
+        },
+        storage::{
+            disk::{DiskEngine, DiskOptions, SyncPolicy},
+            memory::MemoryEngine,
+        },
+    };
 
-    use super::KVEngine;
+    use super::{CommittedRows, KVEngine, RowChange};
 
     #[test]
     fn test_create_table() -> Result<()> {
-        let kvengine = KVEngine::new(MemoryEngine::new());
+        let kvengine = KVEngine::new(MemoryEngine::new())?;
         let mut s = kvengine.session()?;
 
         s.execute("create table t1 (a int, b text default 'vv', c integer default 100);")?;
@@ -170,4 +313,590 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_commit_rollback() -> Result<()> {
+        let kvengine = KVEngine::new(MemoryEngine::new())?;
+        kvengine
+            .session()?
+            .execute("create table t1 (a int, b text);")?;
+        let row = |a: i64| vec![Value::Integer(a), Value::String("x".to_string())];
+
+        // the rows of a rolled back transaction are discarded
+        let mut txn = kvengine.begin()?;
+        txn.create_row("t1".to_string(), row(1))?;
+        txn.rollback()?;
+        let txn = kvengine.begin()?;
+        assert!(txn.scan_table("t1".to_string())?.is_empty());
+        txn.commit()?;
+
+        // and the rows of a committed one are visible to later transactions
+        let mut txn = kvengine.begin()?;
+        txn.create_row("t1".to_string(), row(2))?;
+        txn.commit()?;
+        let txn = kvengine.begin()?;
+        assert_eq!(txn.scan_table("t1".to_string())?, vec![row(2)]);
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_to() -> Result<()> {
+        let kvengine = KVEngine::new(MemoryEngine::new())?;
+        let mut s = kvengine.session()?;
+
+        s.execute("create table t1 (a int, b text, c float);")?;
+        s.execute("insert into t1 values (1, 'a,b', 1.5), (2, null, 2.0);")?;
+
+        let dir = tempfile::tempdir()?;
+        let csv_path = dir.path().join("t1.csv");
+        let result = s.execute(&format!(
+            "copy (select * from t1) to '{}' with (format csv);",
+            csv_path.display()
+        ))?;
+        assert!(matches!(result, ResultSet::Copy { count: 2 }));
+        assert_eq!(
+            std::fs::read_to_string(&csv_path)?,
+            "a,b,c\r\n1,\"a,b\",1.5\r\n2,,2\r\n"
+        );
+
+        let json_path = dir.path().join("t1.json");
+        s.execute(&format!(
+            "copy (select * from t1) to '{}' with (format json);",
+            json_path.display()
+        ))?;
+        assert_eq!(
+            std::fs::read_to_string(&json_path)?,
+            r#"[{"a":1,"b":"a,b","c":1.5},{"a":2,"b":null,"c":2}]"#
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_from() -> Result<()> {
+        let kvengine = KVEngine::new(MemoryEngine::new())?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t1 (a int not null, b text, c bool default false);")?;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("t1.csv");
+        std::fs::write(&path, "a|b\n1|x\n2|NULL\n3|\"y|z\"\n")?;
+        let result = s.execute(&format!(
+            "copy t1 (a, b) from '{}' with (header, delimiter '|', null 'NULL');",
+            path.display()
+        ))?;
+        assert!(matches!(result, ResultSet::Copy { count: 3 }));
+
+        match s.execute("select * from t1;")? {
+            ResultSet::Scan { rows, .. } => assert_eq!(
+                rows,
+                vec![
+                    vec![
+                        Value::Integer(1),
+                        Value::String("x".to_string()),
+                        Value::Boolean(false)
+                    ],
+                    vec![Value::Integer(2), Value::Null, Value::Boolean(false)],
+                    vec![
+                        Value::Integer(3),
+                        Value::String("y|z".to_string()),
+                        Value::Boolean(false)
+                    ],
+                ]
+            ),
+            _ => unreachable!(),
+        }
+
+        // a bad field fails the whole copy and reports its line
+        std::fs::write(&path, "4,u,true\n5,v,maybe\n")?;
+        let err = s
+            .execute(&format!("copy t1 from '{}';", path.display()))
+            .unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+
+        std::fs::write(&path, "a,b,c\n,u,true\n")?;
+        let err = s
+            .execute(&format!("copy t1 from '{}' with (header);", path.display()))
+            .unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+
+        match s.execute("select * from t1;")? {
+            ResultSet::Scan { rows, .. } => assert_eq!(rows.len(), 3),
+            _ => unreachable!(),
+        }
+
+        // without the header option the first line is data
+        std::fs::write(&path, "4,u,true\n5,v,false\n")?;
+        let result = s.execute(&format!("copy t1 from '{}';", path.display()))?;
+        assert!(matches!(result, ResultSet::Copy { count: 2 }));
+        match s.execute("select * from t1;")? {
+            ResultSet::Scan { rows, .. } => assert_eq!(rows.len(), 5),
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    // the rows of a copy are written in batches, not one by one
+    #[test]
+    fn test_copy_from_batches() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = DiskOptions {
+            sync_policy: SyncPolicy::Always,
+            ..Default::default()
+        };
+        let kvengine = KVEngine::new(DiskEngine::new_with_options(
+            dir.path().join("db"),
+            options,
+        )?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t1 (a int, b text);")?;
+
+        let path = dir.path().join("t1.csv");
+        let data: String = (0..2500).map(|i| format!("{},v{}\n", i, i)).collect();
+        std::fs::write(&path, data)?;
+        let syncs = kvengine.kv.engine()?.sync_count();
+        let result = s.execute(&format!("copy t1 from '{}';", path.display()))?;
+        assert!(matches!(result, ResultSet::Copy { count: 2500 }));
+        // a sync per write, three batches and the commit
+        assert!(kvengine.kv.engine()?.sync_count() - syncs < 10);
+
+        match s.execute("select * from t1;")? {
+            ResultSet::Scan { rows, .. } => assert_eq!(rows.len(), 2500),
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_vacuum() -> Result<()> {
+        let kvengine = KVEngine::new(MemoryEngine::new())?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t1 (a int, b text);")?;
+        s.execute("insert into t1 values (1, 'a'), (2, 'b');")?;
+        // rewriting a row adds a new version, the old one is left for vacuum
+        s.execute("insert into t1 values (1, 'c');")?;
+
+        let result = s.execute("vacuum;")?;
+        assert!(matches!(result, ResultSet::Vacuum { count: 1 }));
+        let result = s.execute("VACUUM;")?;
+        assert!(matches!(result, ResultSet::Vacuum { count: 0 }));
+
+        match s.execute("select * from t1;")? {
+            ResultSet::Scan { rows, .. } => assert_eq!(
+                rows,
+                vec![
+                    vec![Value::Integer(1), Value::String("c".to_string())],
+                    vec![Value::Integer(2), Value::String("b".to_string())],
+                ]
+            ),
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn count(s: &mut Session<KVEngine<MemoryEngine>>, sql: &str) -> Result<usize> {
+        match s.execute(sql)? {
+            ResultSet::Scan { rows, .. } => Ok(rows.len()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_transaction() -> Result<()> {
+        let kvengine = KVEngine::new(MemoryEngine::new())?;
+        let mut s1 = kvengine.session()?;
+        let mut s2 = kvengine.session()?;
+        s1.execute("create table t1 (a int, b text);")?;
+
+        // the writes of an open transaction are invisible to other sessions
+        assert!(matches!(s1.execute("begin;")?, ResultSet::Begin));
+        s1.execute("insert into t1 values (1, 'a');")?;
+        assert_eq!(count(&mut s1, "select * from t1;")?, 1);
+        assert_eq!(count(&mut s2, "select * from t1;")?, 0);
+        assert!(matches!(s1.execute("commit;")?, ResultSet::Commit));
+        assert_eq!(count(&mut s2, "select * from t1;")?, 1);
+
+        s1.execute("begin transaction;")?;
+        s1.execute("insert into t1 values (2, 'b');")?;
+        assert!(matches!(s1.execute("rollback;")?, ResultSet::Rollback));
+        assert_eq!(count(&mut s1, "select * from t1;")?, 1);
+
+        assert!(s1.execute("commit;").is_err());
+        s1.execute("begin;")?;
+        assert!(s1.execute("begin;").is_err());
+        assert!(s1.execute("vacuum;").is_err());
+        // a failed statement ends the transaction
+        assert!(s1.execute("insert into t2 values (1);").is_err());
+        assert!(s1.execute("rollback;").is_err());
+
+        // a snapshot transaction doesn't see later commits, read committed does
+        s1.execute("begin isolation level repeatable read;")?;
+        assert_eq!(count(&mut s1, "select * from t1;")?, 1);
+        s2.execute("insert into t1 values (3, 'c');")?;
+        assert_eq!(count(&mut s1, "select * from t1;")?, 1);
+        s1.execute("commit;")?;
+
+        s1.execute("begin isolation level read committed;")?;
+        assert_eq!(count(&mut s1, "select * from t1;")?, 2);
+        s2.execute("insert into t1 values (4, 'd');")?;
+        assert_eq!(count(&mut s1, "select * from t1;")?, 3);
+        s1.execute("commit;")?;
+
+        // an open transaction is rolled back when its session is dropped
+        s2.execute("begin;")?;
+        s2.execute("insert into t1 values (5, 'e');")?;
+        drop(s2);
+        assert_eq!(count(&mut s1, "select * from t1;")?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only() -> Result<()> {
+        let kvengine = KVEngine::new(MemoryEngine::new())?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t1 (a int);")?;
+
+        s.execute("begin read only;")?;
+        assert_eq!(count(&mut s, "select * from t1;")?, 0);
+        assert!(matches!(
+            s.execute("insert into t1 values (1);"),
+            Err(Error::ReadOnly)
+        ));
+
+        // SET TRANSACTION changes the transaction before its first statement
+        s.execute("begin;")?;
+        assert!(matches!(
+            s.execute("set transaction read only, isolation level serializable;")?,
+            ResultSet::SetTransaction
+        ));
+        assert!(matches!(
+            s.execute("insert into t1 values (1);"),
+            Err(Error::ReadOnly)
+        ));
+        s.execute("begin;")?;
+        s.execute("select * from t1;")?;
+        assert!(s.execute("set transaction read only;").is_err());
+        s.execute("insert into t1 values (1);")?;
+        s.execute("commit;")?;
+
+        // outside of a transaction it sets the session's defaults
+        s.execute("set transaction read only;")?;
+        assert!(matches!(
+            s.execute("insert into t1 values (2);"),
+            Err(Error::ReadOnly)
+        ));
+        s.execute("begin read write;")?;
+        s.execute("insert into t1 values (2);")?;
+        s.execute("commit;")?;
+        s.execute("set transaction read write;")?;
+        s.execute("insert into t1 values (3);")?;
+        assert_eq!(count(&mut s, "select * from t1;")?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_as_of() -> Result<()> {
+        let kvengine = KVEngine::new(MemoryEngine::new())?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t1 (a int, b text);")?;
+        s.execute("insert into t1 values (1, 'a'), (2, 'b');")?;
+        let version = match s.execute("show version;")? {
+            ResultSet::Version { version } => version,
+            _ => unreachable!(),
+        };
+        assert_eq!(version, 2);
+
+        // a bad batch job
+        s.execute("insert into t1 values (1, 'x'), (2, 'x'), (3, 'x');")?;
+        let sql = format!("select * from t1 as of version {};", version);
+        match s.execute(&sql)? {
+            ResultSet::Scan { rows, .. } => assert_eq!(
+                rows,
+                vec![
+                    vec![Value::Integer(1), Value::String("a".to_string())],
+                    vec![Value::Integer(2), Value::String("b".to_string())],
+                ]
+            ),
+            _ => unreachable!(),
+        }
+        assert_eq!(count(&mut s, "select * from t1 as of version 1;")?, 0);
+        assert!(s.execute("select * from t1 as of version 10;").is_err());
+
+        // a past version is read outside of the explicit transaction
+        s.execute("begin;")?;
+        s.execute("insert into t1 values (4, 'x');")?;
+        assert_eq!(count(&mut s, &sql)?, 2);
+        assert_eq!(count(&mut s, "select * from t1;")?, 4);
+        s.execute("commit;")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_savepoint() -> Result<()> {
+        let kvengine = KVEngine::new(MemoryEngine::new())?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t1 (a int, b text);")?;
+        assert!(s.execute("savepoint sp1;").is_err());
+
+        s.execute("begin;")?;
+        s.execute("insert into t1 values (1, 'a');")?;
+        assert!(matches!(s.execute("savepoint sp1;")?, ResultSet::Savepoint));
+        s.execute("insert into t1 values (1, 'b'), (2, 'b');")?;
+        s.execute("savepoint sp2;")?;
+        s.execute("insert into t1 values (3, 'c');")?;
+        assert_eq!(count(&mut s, "select * from t1;")?, 3);
+
+        assert!(matches!(
+            s.execute("rollback to savepoint sp1;")?,
+            ResultSet::RollbackTo
+        ));
+        match s.execute("select * from t1;")? {
+            ResultSet::Scan { rows, .. } => assert_eq!(
+                rows,
+                vec![vec![Value::Integer(1), Value::String("a".to_string())]]
+            ),
+            _ => unreachable!(),
+        }
+        assert!(s.execute("rollback to sp2;").is_err());
+
+        s.execute("insert into t1 values (4, 'd');")?;
+        assert!(matches!(s.execute("release sp1;")?, ResultSet::Release));
+        s.execute("commit;")?;
+        assert_eq!(count(&mut s, "select * from t1;")?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prepared_transaction() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        let rows = |s: &mut Session<KVEngine<DiskEngine>>| -> Result<usize> {
+            match s.execute("select * from t1;")? {
+                ResultSet::Scan { rows, .. } => Ok(rows.len()),
+                _ => unreachable!(),
+            }
+        };
+        {
+            let kvengine = KVEngine::new(DiskEngine::new(path.clone())?)?;
+            let mut s = kvengine.session()?;
+            s.execute("create table t1 (a int, b text);")?;
+            s.execute("insert into t1 values (1, 'a');")?;
+            assert!(s.execute("prepare transaction 'tx1';").is_err());
+
+            s.execute("begin;")?;
+            s.execute("insert into t1 values (1, 'b'), (2, 'b');")?;
+            assert!(matches!(
+                s.execute("prepare transaction 'tx1';")?,
+                ResultSet::PrepareTransaction
+            ));
+            // the session left the transaction
+            assert!(s.execute("commit;").is_err());
+            s.execute("begin;")?;
+            s.execute("insert into t1 values (3, 'c');")?;
+            s.execute("prepare transaction 'tx2';")?;
+            assert!(s.execute("begin;").is_ok());
+            assert!(s.execute("commit prepared 'tx1';").is_err());
+            s.execute("rollback;")?;
+        }
+
+        // the prepared transactions survive the restart and block the writers
+        let kvengine = KVEngine::new(DiskEngine::new(path)?)?;
+        let mut s = kvengine.session()?;
+        assert_eq!(rows(&mut s)?, 1);
+        assert_eq!(
+            s.execute("insert into t1 values (2, 'x');").unwrap_err(),
+            Error::WriteConflict
+        );
+        assert!(matches!(
+            s.execute("COMMIT PREPARED 'tx1';")?,
+            ResultSet::CommitPrepared
+        ));
+        assert!(matches!(
+            s.execute("ROLLBACK PREPARED 'tx2';")?,
+            ResultSet::RollbackPrepared
+        ));
+        assert!(s.execute("commit prepared 'tx1';").is_err());
+        assert_eq!(rows(&mut s)?, 2);
+        s.execute("insert into t1 values (2, 'x'), (3, 'x');")?;
+        assert_eq!(rows(&mut s)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_for_update() -> Result<()> {
+        let kvengine = KVEngine::new(MemoryEngine::new())?;
+        let mut s1 = kvengine.session()?;
+        let mut s2 = kvengine.session()?;
+        s1.execute("create table t1 (a int, b text);")?;
+        s1.execute("insert into t1 values (1, 'a'), (2, 'b'), (3, 'c');")?;
+
+        s1.execute("begin;")?;
+        s1.execute("insert into t1 values (2, 'bb');")?;
+
+        s2.execute("begin;")?;
+        assert!(matches!(
+            s2.execute("select * from t1 for update nowait;"),
+            Err(Error::LockNotAvailable)
+        ));
+        // the failed statement ended the transaction
+        s2.execute("begin;")?;
+        match s2.execute("select * from t1 for update skip locked;")? {
+            ResultSet::Scan { rows, .. } => assert_eq!(
+                rows,
+                vec![
+                    vec![Value::Integer(1), Value::String("a".to_string())],
+                    vec![Value::Integer(3), Value::String("c".to_string())],
+                ]
+            ),
+            _ => unreachable!(),
+        }
+        assert!(matches!(
+            s1.execute("select * from t1 for share nowait;"),
+            Err(Error::LockNotAvailable)
+        ));
+        s2.execute("commit;")?;
+        s1.execute("begin;")?;
+        assert_eq!(count(&mut s1, "select * from t1 for share;")?, 3);
+        s1.execute("commit;")?;
+
+        // the locks went away with the transaction
+        s2.execute("begin;")?;
+        assert_eq!(count(&mut s2, "select * from t1 for update nowait;")?, 3);
+        s2.execute("rollback;")?;
+        s1.execute("insert into t1 values (1, 'aa');")?;
+        assert!(s1
+            .execute("select * from t1 as of version 1 for share;")
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_changes() -> Result<()> {
+        let kvengine = KVEngine::new(MemoryEngine::new())?.capture_changes(true);
+        let mut s = kvengine.session()?;
+        s.execute("create table t1 (a int, b text);")?;
+        s.execute("insert into t1 values (1, 'a'), (2, 'b');")?;
+        s.execute("insert into t1 values (1, 'aa');")?;
+
+        let row = |a: i64, b: &str| vec![Value::Integer(a), Value::String(b.to_string())];
+        let txns = kvengine.changes(None)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            txns,
+            vec![
+                // creating the table changes no row
+                CommittedRows {
+                    version: 1,
+                    changes: vec![],
+                },
+                CommittedRows {
+                    version: 2,
+                    changes: vec![
+                        RowChange {
+                            table_name: "t1".to_string(),
+                            before: None,
+                            after: Some(row(1, "a")),
+                        },
+                        RowChange {
+                            table_name: "t1".to_string(),
+                            before: None,
+                            after: Some(row(2, "b")),
+                        },
+                    ],
+                },
+                CommittedRows {
+                    version: 3,
+                    changes: vec![RowChange {
+                        table_name: "t1".to_string(),
+                        before: Some(row(1, "a")),
+                        after: Some(row(1, "aa")),
+                    }],
+                },
+            ]
+        );
+
+        kvengine.truncate_changes(2)?;
+        let versions = kvengine
+            .changes(None)?
+            .map(|txn| Ok(txn?.version))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(versions, vec![3]);
+        assert!(kvengine.changes(Some(3))?.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_retry() -> Result<()> {
+        let kvengine = KVEngine::new(MemoryEngine::new())?;
+        let mut s = kvengine.session()?;
+        s.execute("create table counter (id int, n int);")?;
+        s.execute("insert into counter values (1, 0);")?;
+
+        // concurrent increments conflict, every one is retried until it commits
+        let policy = RetryPolicy {
+            attempts: 1000,
+            backoff: Duration::from_micros(100),
+            max_backoff: Duration::from_millis(5),
+        };
+        let threads = (0..8)
+            .map(|_| {
+                let kvengine = kvengine.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    let mut s = kvengine.session()?.retry(policy);
+                    for _ in 0..20 {
+                        s.transaction(|s| {
+                            let n = match s.execute("select * from counter;")? {
+                                ResultSet::Scan { rows, .. } => match rows[0][1] {
+                                    Value::Integer(n) => n,
+                                    _ => unreachable!(),
+                                },
+                                _ => unreachable!(),
+                            };
+                            s.execute(&format!("insert into counter values (1, {});", n + 1))
+                        })?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+        match s.execute("select * from counter;")? {
+            ResultSet::Scan { rows, .. } => {
+                assert_eq!(rows, vec![vec![Value::Integer(1), Value::Integer(160)]])
+            }
+            _ => unreachable!(),
+        }
+
+        // the last error is returned as is, other errors aren't retried
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        let mut runs = 0;
+        let result = kvengine.with_retry(policy, |txn| -> Result<()> {
+            runs += 1;
+            txn.create_row(
+                "counter".to_string(),
+                vec![Value::Integer(2), Value::Integer(0)],
+            )?;
+            Err(Error::WriteConflict)
+        });
+        assert_eq!(result, Err(Error::WriteConflict));
+        assert_eq!(runs, 3);
+        let mut runs = 0;
+        let result = kvengine.with_retry(policy, |txn| {
+            runs += 1;
+            txn.must_get_table("missing".to_string())
+        });
+        assert!(result.is_err());
+        assert_eq!(runs, 1);
+        // the failed runs were rolled back
+        assert_eq!(count(&mut s, "select * from counter;")?, 1);
+        Ok(())
+    }
 }
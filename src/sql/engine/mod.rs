@@ -1,34 +1,165 @@
-use crate::error::{Error, Result};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
 
-use super::{executor::ResultSet, parser::Parser, plan::Plan, schema::Table, types::Row};
+use crate::{
+    error::{Error, Result},
+    storage::mvcc::{IsolationLevel, LockMode, LockWait, TransactionOptions, Version},
+};
 
-mod kv;
+use super::{
+    executor::ResultSet,
+    parser::{ast, Parser},
+    plan::Plan,
+    schema::Table,
+    types::Row,
+};
+
+pub mod kv;
+pub mod shard;
 
 // Define SQL's abstract engine layer
-// Currently there is only one KVEngine
+// The KVEngine keeps all data in one Mvcc, the ShardedEngine spreads the rows over several
 pub trait Engine: Clone {
     type Transaction: Transaction;
 
-    fn begin(&self) -> Result<Self::Transaction>;
+    fn begin(&self) -> Result<Self::Transaction> {
+        self.begin_with(TransactionOptions::default())
+    }
+
+    fn begin_with(&self, options: TransactionOptions) -> Result<Self::Transaction>;
+
+    // Begin a read-only transaction which sees the data as of a past version
+    fn begin_as_of(&self, version: Version) -> Result<Self::Transaction>;
+
+    // The newest version given to a transaction
+    fn current_version(&self) -> Result<Version>;
+
+    // Remove the data versions no transaction can read anymore,
+    // returns the number of removed versions
+    fn vacuum(&self) -> Result<usize>;
+
+    // End the transaction prepared as the id, see Transaction::prepare
+    fn commit_prepared(&self, id: &str) -> Result<()>;
+    fn rollback_prepared(&self, id: &str) -> Result<()>;
 
     fn session(&self) -> Result<Session<Self>> {
         Ok(Session {
             engine: self.clone(),
+            txn: None,
+            txn_options: TransactionOptions::default(),
+            txn_started: false,
+            options: TransactionOptions::default(),
+            retry: RetryPolicy::default(),
         })
     }
+
+    // Run the function in a transaction and commit it, a transaction failing
+    // with a conflict is rolled back and run again as the policy allows
+    fn with_retry<T>(
+        &self,
+        policy: RetryPolicy,
+        mut f: impl FnMut(&mut Self::Transaction) -> Result<T>,
+    ) -> Result<T> {
+        policy.run(|| {
+            let mut txn = self.begin()?;
+            match f(&mut txn) {
+                Ok(value) => {
+                    txn.commit()?;
+                    Ok(value)
+                }
+                Err(err) => {
+                    txn.rollback()?;
+                    Err(err)
+                }
+            }
+        })
+    }
+}
+
+// How often and how fast to run a transaction again after a conflict
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    // the number of runs, including the first one
+    pub attempts: u32,
+    // the wait before the first retry, doubled for each following one
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 10,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Run the function until it succeeds, fails with an error other than a
+    // write conflict or serialization failure, or runs out of attempts,
+    // the last error is returned as is
+    pub fn run<T>(&self, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(Error::WriteConflict | Error::Serialization) if attempt < self.attempts => {
+                    std::thread::sleep(Self::jitter(backoff));
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // A random wait between half the backoff and the backoff, so conflicting
+    // transactions don't keep retrying in lockstep
+    fn jitter(backoff: Duration) -> Duration {
+        let random = RandomState::new().build_hasher().finish();
+        let half = backoff / 2;
+        half + half.mul_f64(random as f64 / u64::MAX as f64)
+    }
 }
 
 // Abstract transaction info, including DDL & DML 
-// can be used on regular KV engine or distributed engine later 
+// can be used on regular KV engine or the Raft-replicated engine (storage::raft)
 pub trait Transaction {
     fn commit(&self) -> Result<()>;
     fn rollback(&self) -> Result<()>;
+    // Prepare for a two-phase commit, the transaction is kept across restarts
+    // until the engine commits or rolls it back by the id
+    fn prepare(&self, id: &str) -> Result<()>;
+    // Savepoints, rolling back to one undoes the writes after it
+    fn savepoint(&self, name: &str) -> Result<()>;
+    fn rollback_to_savepoint(&self, name: &str) -> Result<()>;
+    fn release_savepoint(&self, name: &str) -> Result<()>;
+    // Begin a statement, a read committed transaction takes the snapshot
+    // its reads see until the next statement
+    fn begin_statement(&self) -> Result<()>;
     fn create_row(&mut self, table_name: String, row: Row) -> Result<()>;
+    // Create the rows in one write, either all of them or none
+    fn create_rows(&mut self, table_name: String, rows: Vec<Row>) -> Result<()>;
     fn scan_table(&self, table_name: String) -> Result<Vec<Row>>;
+    // Lock the rows until the transaction ends, skipped rows are left out
+    fn lock_rows(
+        &self,
+        table_name: String,
+        rows: Vec<Row>,
+        mode: LockMode,
+        wait: LockWait,
+    ) -> Result<Vec<Row>>;
     // DDL 
     fn create_table(&mut self, table: Table) -> Result<()>;
     // Fetch table
     fn get_table(&self, table_name: String) -> Result<Option<Table>>;
+    // Fetch all tables, ordered by name
+    fn scan_tables(&self) -> Result<Vec<Table>>;
     // report errors if table DNE
     fn must_get_table(&self, table_name: String) -> Result<Table> {
         self.get_table(table_name.clone())?
@@ -40,18 +171,137 @@ pub trait Transaction {
 }
 
 // user end: define session
+// Every statement runs in a transaction of its own, unless BEGIN opened one
 pub struct Session<E: Engine> {
     engine: E,
+    // the transaction opened by BEGIN, its options, and whether a statement ran in it
+    txn: Option<E::Transaction>,
+    txn_options: TransactionOptions,
+    txn_started: bool,
+    // options of the transactions the session begins
+    options: TransactionOptions,
+    retry: RetryPolicy,
 }
 
 impl<E: Engine> Session<E> {
     // execute user end's SQL statement
     pub fn execute(&mut self, sql: &str) -> Result<ResultSet> {
         match Parser::new(sql).parse()? {
+            // vacuum works on the whole engine, outside of a transaction
+            ast::Statement::Vacuum => {
+                if self.txn.is_some() {
+                    return Err(Error::Internal(
+                        "VACUUM cannot run inside a transaction".to_string(),
+                    ));
+                }
+                Ok(ResultSet::Vacuum {
+                    count: self.engine.vacuum()?,
+                })
+            }
+            ast::Statement::Begin {
+                isolation,
+                read_only,
+            } => {
+                if self.txn.is_some() {
+                    return Err(Error::Internal(
+                        "a transaction is already in progress".to_string(),
+                    ));
+                }
+                let options = Self::with_modes(self.options, isolation, read_only);
+                self.begin(options)?;
+                Ok(ResultSet::Begin)
+            }
+            ast::Statement::Commit => {
+                self.take_txn()?.commit()?;
+                Ok(ResultSet::Commit)
+            }
+            ast::Statement::Rollback => {
+                self.take_txn()?.rollback()?;
+                Ok(ResultSet::Rollback)
+            }
+            // the session is no longer in the transaction once it's prepared,
+            // a transaction failing to prepare is rolled back
+            ast::Statement::PrepareTransaction { id } => {
+                let txn = self.take_txn()?;
+                if let Err(err) = txn.prepare(&id) {
+                    txn.rollback()?;
+                    return Err(err);
+                }
+                Ok(ResultSet::PrepareTransaction)
+            }
+            ast::Statement::CommitPrepared { id } => {
+                self.no_txn("COMMIT PREPARED")?;
+                self.engine.commit_prepared(&id)?;
+                Ok(ResultSet::CommitPrepared)
+            }
+            ast::Statement::RollbackPrepared { id } => {
+                self.no_txn("ROLLBACK PREPARED")?;
+                self.engine.rollback_prepared(&id)?;
+                Ok(ResultSet::RollbackPrepared)
+            }
+            ast::Statement::Savepoint { name } => {
+                self.started_txn()?.savepoint(&name)?;
+                Ok(ResultSet::Savepoint)
+            }
+            ast::Statement::RollbackTo { name } => {
+                self.started_txn()?.rollback_to_savepoint(&name)?;
+                Ok(ResultSet::RollbackTo)
+            }
+            ast::Statement::Release { name } => {
+                self.started_txn()?.release_savepoint(&name)?;
+                Ok(ResultSet::Release)
+            }
+            ast::Statement::SetTransaction {
+                isolation,
+                read_only,
+            } => {
+                match self.txn.take() {
+                    // nothing ran in the transaction yet, so it begins again with the options
+                    Some(txn) if !self.txn_started => {
+                        txn.rollback()?;
+                        let options = Self::with_modes(self.txn_options, isolation, read_only);
+                        self.begin(options)?;
+                    }
+                    Some(txn) => {
+                        self.txn = Some(txn);
+                        return Err(Error::Internal(
+                            "SET TRANSACTION must run before any statement of the transaction"
+                                .to_string(),
+                        ));
+                    }
+                    None => self.options = Self::with_modes(self.options, isolation, read_only),
+                }
+                Ok(ResultSet::SetTransaction)
+            }
+            ast::Statement::ShowVersion => Ok(ResultSet::Version {
+                version: self.engine.current_version()?,
+            }),
             stmt => {
-                let mut txn = self.engine.begin()?;
+                // a read of a past version runs in a transaction of its own,
+                // also inside an explicit transaction
+                if let Some(version) = stmt.as_of() {
+                    let mut txn = self.engine.begin_as_of(version)?;
+                    let result = Plan::build(stmt).and_then(|plan| plan.execute(&mut txn));
+                    txn.commit()?;
+                    return result;
+                }
+
+                let plan = Plan::build(stmt)?;
+                // a failed statement rolls back the whole explicit transaction
+                if let Some(txn) = self.txn.as_mut() {
+                    self.txn_started = true;
+                    return match txn.begin_statement().and_then(|_| plan.execute(txn)) {
+                        Ok(result) => Ok(result),
+                        Err(err) => {
+                            self.take_txn()?.rollback()?;
+                            Err(err)
+                        }
+                    };
+                }
+
+                let mut txn = self.engine.begin_with(self.options)?;
                 // build plan，exec SQL statement
-                match Plan::build(stmt).execute(&mut txn) {
+                match txn.begin_statement().and_then(|_| plan.execute(&mut txn)) {
                     Ok(result) => {
                         txn.commit()?;
                         Ok(result)
@@ -64,4 +314,92 @@ impl<E: Engine> Session<E> {
             }
         }
     }
+
+    // The retry policy of Session::transaction
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    // Run the function in an explicit transaction and commit it, the function
+    // runs again in a new transaction after a conflict, as the retry policy allows
+    pub fn transaction<T>(&mut self, mut f: impl FnMut(&mut Self) -> Result<T>) -> Result<T> {
+        if self.txn.is_some() {
+            return Err(Error::Internal(
+                "a transaction is already in progress".to_string(),
+            ));
+        }
+        let policy = self.retry;
+        policy.run(|| {
+            self.begin(self.options)?;
+            match f(self) {
+                Ok(value) => {
+                    self.take_txn()?.commit()?;
+                    Ok(value)
+                }
+                Err(err) => {
+                    // a failed statement already rolled the transaction back
+                    if let Some(txn) = self.txn.take() {
+                        txn.rollback()?;
+                    }
+                    Err(err)
+                }
+            }
+        })
+    }
+
+    fn begin(&mut self, options: TransactionOptions) -> Result<()> {
+        self.txn = Some(self.engine.begin_with(options)?);
+        self.txn_options = options;
+        self.txn_started = false;
+        Ok(())
+    }
+
+    // The explicit transaction, which a statement now runs in
+    fn started_txn(&mut self) -> Result<&E::Transaction> {
+        self.txn_started = true;
+        self.txn
+            .as_ref()
+            .ok_or(Error::Internal("no transaction in progress".to_string()))
+    }
+
+    // Fail if an explicit transaction is open, the statement works outside of them
+    fn no_txn(&self, statement: &str) -> Result<()> {
+        match self.txn {
+            Some(_) => Err(Error::Internal(format!(
+                "{} cannot run inside a transaction",
+                statement
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn take_txn(&mut self) -> Result<E::Transaction> {
+        self.txn
+            .take()
+            .ok_or(Error::Internal("no transaction in progress".to_string()))
+    }
+
+    // The options with the modes of a BEGIN or SET TRANSACTION
+    fn with_modes(
+        options: TransactionOptions,
+        isolation: Option<IsolationLevel>,
+        read_only: Option<bool>,
+    ) -> TransactionOptions {
+        TransactionOptions {
+            isolation: isolation.unwrap_or(options.isolation),
+            read_only: read_only.unwrap_or(options.read_only),
+        }
+    }
+}
+
+// An explicit transaction left open is rolled back
+// A failed rollback leaves the transaction active in the storage, it's
+// rolled back as abandoned when the storage is opened again
+impl<E: Engine> Drop for Session<E> {
+    fn drop(&mut self) {
+        if let Some(txn) = self.txn.take() {
+            let _ = txn.rollback();
+        }
+    }
 }
@@ -0,0 +1,800 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{hash_map::RandomState, BTreeSet, BinaryHeap},
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    error::{Error, Result},
+    sql::{
+        schema::Table,
+        types::{Row, Value},
+    },
+    storage::{
+        engine::Engine as StorageEngine,
+        mvcc::{IsolationLevel, LockMode, LockWait, Mvcc, TransactionOptions, Version},
+    },
+};
+
+use super::{
+    kv::{KVEngine, KVTransaction},
+    Engine, Transaction,
+};
+
+// How the rows are spread over the shards, by their primary key
+#[derive(Debug, Clone, PartialEq)]
+pub enum Partitioning {
+    // by a hash of the key
+    Hash,
+    // by key ranges, the bounds split the keys into one range per shard:
+    // shard i holds the keys from bound i-1 up to but excluding bound i
+    Range(Vec<Value>),
+}
+
+// Spreads the rows of every table over several storage engines, each with an
+// Mvcc of its own, the table definitions are kept on all of them
+// A transaction spans all shards and commits with a two-phase commit once it
+// wrote to more than one, so its writes on the different shards commit
+// together or not at all. The first shard coordinates the commits, see
+// ShardedTransaction::commit. The versions
+// are kept per shard, so there is no read of a past version.
+// The isolation holds per shard only: a transaction takes its snapshot on each
+// shard on its own, and the shards commit one after another, so a concurrent
+// transaction may see the writes of another on some shards but not yet on
+// the others. A serializable transaction would miss cycles across shards, so
+// it's rejected once there is more than one shard.
+pub struct ShardedEngine<E: StorageEngine> {
+    shards: Vec<KVEngine<E>>,
+    partitioning: Arc<Partitioning>,
+}
+
+impl<E: StorageEngine> Clone for ShardedEngine<E> {
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards.clone(),
+            partitioning: self.partitioning.clone(),
+        }
+    }
+}
+
+impl<E: StorageEngine> ShardedEngine<E> {
+    pub fn new(engines: Vec<E>, partitioning: Partitioning) -> Result<Self> {
+        if engines.is_empty() {
+            return Err(Error::Internal(
+                "a sharded engine needs a shard".to_string(),
+            ));
+        }
+        if let Partitioning::Range(bounds) = &partitioning {
+            if bounds.len() + 1 != engines.len() {
+                return Err(Error::Internal(format!(
+                    "{} shards need {} range bounds, got {}",
+                    engines.len(),
+                    engines.len() - 1,
+                    bounds.len()
+                )));
+            }
+            for pair in bounds.windows(2) {
+                if compare(&pair[0], &pair[1])? != Ordering::Less {
+                    return Err(Error::Internal(format!(
+                        "range bounds {} and {} are out of order",
+                        pair[0], pair[1]
+                    )));
+                }
+            }
+        }
+        let engine = Self {
+            shards: engines
+                .into_iter()
+                .map(KVEngine::new)
+                .collect::<Result<_>>()?,
+            partitioning: Arc::new(partitioning),
+        };
+        engine.recover()?;
+        Ok(engine)
+    }
+
+    // End the two-phase commits a crash interrupted: a transaction decided to
+    // commit commits on the shards it's still prepared on, one prepared on
+    // some shards only is rolled back, one prepared on all waits for its end
+    // by id
+    fn recover(&self) -> Result<()> {
+        let decisions = self.coordinator().decisions()?;
+        let mut ids = BTreeSet::new();
+        for shard in &self.shards {
+            ids.extend(shard.kv.prepared()?);
+        }
+        for id in &ids {
+            match decisions.get(id) {
+                Some(true) => {
+                    for shard in self.prepared_on(id)? {
+                        shard.commit_prepared(id)?;
+                    }
+                }
+                Some(false) => {}
+                None => {
+                    for shard in self.prepared_on(id)? {
+                        shard.rollback_prepared(id)?;
+                    }
+                }
+            }
+        }
+        for (id, commit) in decisions {
+            if commit || !ids.contains(&id) {
+                self.coordinator().remove_decision(&id)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The Mvcc keeping the decisions of the two-phase commits
+    fn coordinator(&self) -> &Mvcc<E> {
+        &self.shards[0].kv
+    }
+
+    // The Mvcc of the shard
+    pub fn shard(&self, index: usize) -> Option<&Mvcc<E>> {
+        self.shards.get(index).map(|shard| &shard.kv)
+    }
+}
+
+impl<E: StorageEngine> Engine for ShardedEngine<E> {
+    type Transaction = ShardedTransaction<E>;
+
+    fn begin_with(&self, options: TransactionOptions) -> Result<Self::Transaction> {
+        if options.isolation == IsolationLevel::Serializable && self.shards.len() > 1 {
+            return Err(Error::Internal(
+                "a transaction across shards can't be serializable".to_string(),
+            ));
+        }
+        let mut shards = Vec::new();
+        for shard in &self.shards {
+            match shard.begin_with(options) {
+                Ok(txn) => shards.push(txn),
+                Err(err) => {
+                    for txn in shards {
+                        txn.rollback()?;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(ShardedTransaction {
+            shards,
+            written: Mutex::new(BTreeSet::new()),
+            coordinator: self.coordinator().clone(),
+            partitioning: self.partitioning.clone(),
+            read_only: options.read_only,
+        })
+    }
+
+    fn begin_as_of(&self, _version: Version) -> Result<Self::Transaction> {
+        Err(Error::Internal(
+            "a sharded engine has no versions across shards to read as of".to_string(),
+        ))
+    }
+
+    fn current_version(&self) -> Result<Version> {
+        Err(Error::Internal(
+            "a sharded engine keeps its versions per shard".to_string(),
+        ))
+    }
+
+    fn vacuum(&self) -> Result<usize> {
+        let mut count = 0;
+        for shard in &self.shards {
+            count += shard.vacuum()?;
+        }
+        Ok(count)
+    }
+
+    // On every shard the transaction is prepared on, it only commits once
+    // it was prepared on all
+    fn commit_prepared(&self, id: &str) -> Result<()> {
+        if !self.coordinator().decisions()?.contains_key(id) {
+            return Err(Error::Internal(format!(
+                "transaction {} is not prepared on all shards",
+                id
+            )));
+        }
+        for shard in self.prepared_on(id)? {
+            shard.commit_prepared(id)?;
+        }
+        self.coordinator().remove_decision(id)
+    }
+
+    fn rollback_prepared(&self, id: &str) -> Result<()> {
+        if self.coordinator().decisions()?.get(id) == Some(&true) {
+            return Err(Error::Internal(format!(
+                "transaction {} was decided to commit",
+                id
+            )));
+        }
+        for shard in self.prepared_on(id)? {
+            shard.rollback_prepared(id)?;
+        }
+        self.coordinator().remove_decision(id)
+    }
+}
+
+impl<E: StorageEngine> ShardedEngine<E> {
+    // The shards the transaction of the id is prepared on, a transaction
+    // interrupted in its commit is prepared on some shards only
+    fn prepared_on(&self, id: &str) -> Result<Vec<&KVEngine<E>>> {
+        let mut shards = Vec::new();
+        for shard in &self.shards {
+            if shard.kv.prepared()?.iter().any(|p| p == id) {
+                shards.push(shard);
+            }
+        }
+        if shards.is_empty() {
+            return Err(Error::Internal(format!(
+                "no transaction is prepared as {}",
+                id
+            )));
+        }
+        Ok(shards)
+    }
+}
+
+// A transaction on every shard
+pub struct ShardedTransaction<E: StorageEngine> {
+    shards: Vec<KVTransaction<E>>,
+    // the shards it wrote to
+    written: Mutex<BTreeSet<usize>>,
+    coordinator: Mvcc<E>,
+    partitioning: Arc<Partitioning>,
+    read_only: bool,
+}
+
+impl<E: StorageEngine> ShardedTransaction<E> {
+    // The shard holding the row of the primary key
+    fn shard_of(&self, key: &Value) -> Result<usize> {
+        match self.partitioning.as_ref() {
+            Partitioning::Hash => {
+                Ok((fnv1a(&bincode::serialize(key)?) % self.shards.len() as u64) as usize)
+            }
+            Partitioning::Range(bounds) => {
+                for (i, bound) in bounds.iter().enumerate() {
+                    if compare(key, bound)? == Ordering::Less {
+                        return Ok(i);
+                    }
+                }
+                Ok(bounds.len())
+            }
+        }
+    }
+
+    // Prepare the shards, and record the transaction as prepared on all of
+    // them with the decision, a shard which fails to prepare rolls back all
+    fn prepare_on(&self, shards: &BTreeSet<usize>, id: &str, commit: bool) -> Result<()> {
+        for txn in shards.iter().map(|i| &self.shards[*i]) {
+            if let Err(err) = txn.prepare(id) {
+                self.rollback()?;
+                return Err(err);
+            }
+        }
+        if let Err(err) = self.coordinator.set_decision(id, commit) {
+            self.rollback()?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // Run the function on every shard, the first error is returned once it ran on all
+    fn on_all(&self, f: impl Fn(&KVTransaction<E>) -> Result<()>) -> Result<()> {
+        let mut result = Ok(());
+        for txn in &self.shards {
+            if let Err(err) = f(txn) {
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<E: StorageEngine> Transaction for ShardedTransaction<E> {
+    // Two-phase commit: every shard written to prepares, and only once all of
+    // them did, the decision to commit is recorded on the first shard and all
+    // commit, a shard which fails to prepare rolls back all
+    // The shards are prepared under a random id. A commit interrupted by a
+    // crash is ended when the engine opens again, it commits on the shards
+    // which didn't yet if the decision was recorded, and rolls back otherwise.
+    // With writes on one shard at most, there is nothing to commit together
+    // and every shard commits on its own.
+    fn commit(&self) -> Result<()> {
+        let written = self.written.lock()?.clone();
+        if self.read_only || written.len() <= 1 {
+            return self.on_all(|txn| txn.commit());
+        }
+        let id = format!("shard-{:016x}", RandomState::new().build_hasher().finish());
+        self.prepare_on(&written, &id, true)?;
+        self.on_all(|txn| txn.commit())?;
+        self.coordinator.remove_decision(&id)
+    }
+
+    // The transaction is prepared once it is on all shards, a crash before
+    // rolls it back when the engine opens again
+    fn prepare(&self, id: &str) -> Result<()> {
+        self.prepare_on(&(0..self.shards.len()).collect(), id, false)
+    }
+
+    fn rollback(&self) -> Result<()> {
+        self.on_all(|txn| txn.rollback())
+    }
+
+    fn savepoint(&self, name: &str) -> Result<()> {
+        self.on_all(|txn| txn.savepoint(name))
+    }
+
+    fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        self.on_all(|txn| txn.rollback_to_savepoint(name))
+    }
+
+    fn release_savepoint(&self, name: &str) -> Result<()> {
+        self.on_all(|txn| txn.release_savepoint(name))
+    }
+
+    fn begin_statement(&self) -> Result<()> {
+        self.on_all(|txn| txn.begin_statement())
+    }
+
+    fn create_row(&mut self, table_name: String, row: Row) -> Result<()> {
+        let key = row.first().ok_or(Error::Internal(format!(
+            "row of table {} is empty",
+            table_name
+        )))?;
+        let shard = self.shard_of(key)?;
+        self.written.lock()?.insert(shard);
+        self.shards[shard].create_row(table_name, row)
+    }
+
+    // The rows are written in one batch per shard
+    fn create_rows(&mut self, table_name: String, rows: Vec<Row>) -> Result<()> {
+        let mut shards = vec![Vec::new(); self.shards.len()];
+        for row in rows {
+            let key = row.first().ok_or(Error::Internal(format!(
+                "row of table {} is empty",
+                table_name
+            )))?;
+            shards[self.shard_of(key)?].push(row);
+        }
+        for (i, rows) in shards.into_iter().enumerate() {
+            if !rows.is_empty() {
+                self.written.lock()?.insert(i);
+                self.shards[i].create_rows(table_name.clone(), rows)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The rows of every shard are in key order, they are merged into the
+    // order of a table on one shard
+    fn scan_table(&self, table_name: String) -> Result<Vec<Row>> {
+        let mut shards = Vec::new();
+        for txn in &self.shards {
+            shards.push(txn.scan_table(table_name.clone())?);
+        }
+        MergedRows::new(shards)?.collect()
+    }
+
+    fn lock_rows(
+        &self,
+        table_name: String,
+        rows: Vec<Row>,
+        mode: LockMode,
+        wait: LockWait,
+    ) -> Result<Vec<Row>> {
+        let mut locked = Vec::new();
+        for row in rows {
+            let shard = self.shard_of(&row[0])?;
+            self.written.lock()?.insert(shard);
+            locked.extend(self.shards[shard].lock_rows(
+                table_name.clone(),
+                vec![row],
+                mode,
+                wait,
+            )?);
+        }
+        Ok(locked)
+    }
+
+    fn create_table(&mut self, table: Table) -> Result<()> {
+        self.written.lock()?.extend(0..self.shards.len());
+        for txn in &mut self.shards {
+            txn.create_table(table.clone())?;
+        }
+        Ok(())
+    }
+
+    fn get_table(&self, table_name: String) -> Result<Option<Table>> {
+        self.shards[0].get_table(table_name)
+    }
+
+    fn scan_tables(&self) -> Result<Vec<Table>> {
+        self.shards[0].scan_tables()
+    }
+}
+
+// Merges the ordered rows of the shards by their stored key, the encoded
+// primary key
+struct MergedRows {
+    shards: Vec<std::vec::IntoIter<Row>>,
+    // the next key of every shard which has rows left, with the row
+    heads: BinaryHeap<Reverse<(Vec<u8>, usize)>>,
+    rows: Vec<Option<Row>>,
+}
+
+impl MergedRows {
+    fn new(shards: Vec<Vec<Row>>) -> Result<Self> {
+        let mut merged = Self {
+            rows: vec![None; shards.len()],
+            shards: shards.into_iter().map(|rows| rows.into_iter()).collect(),
+            heads: BinaryHeap::new(),
+        };
+        for shard in 0..merged.shards.len() {
+            merged.advance(shard)?;
+        }
+        Ok(merged)
+    }
+
+    fn advance(&mut self, shard: usize) -> Result<()> {
+        if let Some(row) = self.shards[shard].next() {
+            self.heads
+                .push(Reverse((bincode::serialize(&row[0])?, shard)));
+            self.rows[shard] = Some(row);
+        }
+        Ok(())
+    }
+
+    fn try_next(&mut self) -> Result<Option<Row>> {
+        let Some(Reverse((_, shard))) = self.heads.pop() else {
+            return Ok(None);
+        };
+        let row = self.rows[shard].take();
+        self.advance(shard)?;
+        Ok(row)
+    }
+}
+
+impl Iterator for MergedRows {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
+// Order two keys of the same type, null first and integers and floats by value
+fn compare(a: &Value, b: &Value) -> Result<Ordering> {
+    let ordering = match (a, b) {
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        (Value::Null, _) => Some(Ordering::Less),
+        (_, Value::Null) => Some(Ordering::Greater),
+        (Value::Boolean(a), Value::Boolean(b)) => Some(a.cmp(b)),
+        (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+        (Value::Integer(a), Value::Float(b)) => (*a as f64).partial_cmp(b),
+        (Value::Float(a), Value::Integer(b)) => a.partial_cmp(&(*b as f64)),
+        (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    };
+    ordering.ok_or(Error::Internal(format!("cannot compare {} with {}", a, b)))
+}
+
+// The 64-bit FNV-1a hash, stable across runs unlike the std hashers, so a
+// key stays on its shard after a restart
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::{Error, Result},
+        sql::{
+            engine::{kv::KVEngine, Engine, Transaction},
+            executor::ResultSet,
+            types::{Row, Value},
+        },
+        storage::{
+            disk::DiskEngine,
+            memory::MemoryEngine,
+            mvcc::{IsolationLevel, TransactionOptions},
+        },
+    };
+
+    use std::collections::BTreeSet;
+
+    use super::{Partitioning, ShardedEngine};
+
+    fn select<E: Engine>(engine: &E, sql: &str) -> Result<Vec<Row>> {
+        match engine.session()?.execute(sql)? {
+            ResultSet::Scan { rows, .. } => Ok(rows),
+            _ => unreachable!(),
+        }
+    }
+
+    // The number of rows of the table on each shard
+    fn counts(engine: &ShardedEngine<impl crate::storage::engine::Engine>) -> Result<Vec<usize>> {
+        let mut counts = Vec::new();
+        for i in 0..engine.shards.len() {
+            let txn = engine.shards[i].begin()?;
+            counts.push(txn.scan_table("t1".to_string())?.len());
+            txn.commit()?;
+        }
+        Ok(counts)
+    }
+
+    #[test]
+    fn test_hash() -> Result<()> {
+        let sharded = ShardedEngine::new(
+            vec![
+                MemoryEngine::new(),
+                MemoryEngine::new(),
+                MemoryEngine::new(),
+            ],
+            Partitioning::Hash,
+        )?;
+        let single = KVEngine::new(MemoryEngine::new())?;
+        fn load<E: Engine>(engine: &E) -> Result<()> {
+            let mut s = engine.session()?;
+            s.execute("create table t1 (a int, b text);")?;
+            for i in 0..30 {
+                s.execute(&format!("insert into t1 values ({}, 'v{}');", i, i))?;
+            }
+            s.execute("insert into t1 values (-1, 'n'), (1000, 'm');")?;
+            Ok(())
+        }
+        load(&sharded)?;
+        load(&single)?;
+
+        // every shard got some rows, and a scan sees them in the same order
+        // as a table on one engine
+        let counts = counts(&sharded)?;
+        assert!(counts.iter().all(|n| *n > 0), "{:?}", counts);
+        assert_eq!(counts.iter().sum::<usize>(), 32);
+        let sql = "select * from t1;";
+        assert_eq!(select(&sharded, sql)?, select(&single, sql)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_range() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let open = || -> Result<ShardedEngine<DiskEngine>> {
+            ShardedEngine::new(
+                (0..3)
+                    .map(|i| DiskEngine::new(dir.path().join(format!("shard{}", i))))
+                    .collect::<Result<_>>()?,
+                Partitioning::Range(vec![Value::Integer(10), Value::Integer(20)]),
+            )
+        };
+        let engine = open()?;
+        let mut s = engine.session()?;
+        s.execute("create table t1 (a int, b text);")?;
+        s.execute("insert into t1 values (25, 'c'), (1, 'a'), (10, 'b'), (19, 'b');")?;
+        s.execute("insert into t1 values (-5, 'a'), (100, 'c');")?;
+        assert_eq!(counts(&engine)?, vec![2, 2, 2]);
+        drop(s);
+        drop(engine);
+
+        // the rows stay on their shards after a restart
+        let engine = open()?;
+        assert_eq!(counts(&engine)?, vec![2, 2, 2]);
+        let keys = select(&engine, "select * from t1;")?
+            .into_iter()
+            .map(|row| row[0].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(keys.len(), 6);
+        for key in [-5, 1, 10, 19, 25, 100] {
+            assert!(keys.contains(&Value::Integer(key)));
+        }
+
+        assert!(ShardedEngine::new(
+            vec![MemoryEngine::new(), MemoryEngine::new()],
+            Partitioning::Range(vec![])
+        )
+        .is_err());
+        assert!(ShardedEngine::new(
+            vec![
+                MemoryEngine::new(),
+                MemoryEngine::new(),
+                MemoryEngine::new()
+            ],
+            Partitioning::Range(vec![Value::Integer(2), Value::Integer(1)])
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_two_phase_commit() -> Result<()> {
+        let engine = ShardedEngine::new(
+            vec![MemoryEngine::new(), MemoryEngine::new()],
+            Partitioning::Range(vec![Value::Integer(10)]),
+        )?;
+        let mut s = engine.session()?;
+        s.execute("create table t1 (a int, b text);")?;
+        s.execute("insert into t1 values (1, 'a'), (11, 'b');")?;
+        let row = |a: i64| vec![Value::Integer(a), Value::String("x".to_string())];
+
+        // shard 0 prepares, shard 1 fails as the id is taken there, so
+        // neither commits
+        let other = engine.shard(1).unwrap().begin()?;
+        other.prepare("dup")?;
+        let mut tx = engine.begin()?;
+        tx.create_row("t1".to_string(), row(2))?;
+        tx.create_row("t1".to_string(), row(12))?;
+        assert!(tx.prepare("dup").is_err());
+        assert_eq!(counts(&engine)?, vec![1, 1]);
+        assert_eq!(engine.shard(0).unwrap().prepared()?, Vec::<String>::new());
+        engine.shard(1).unwrap().rollback_prepared("dup")?;
+        assert_eq!(select(&engine, "select * from t1;")?.len(), 2);
+
+        // a transaction on both shards commits on both
+        s.execute("begin;")?;
+        s.execute("insert into t1 values (3, 'c'), (14, 'c');")?;
+        s.execute("commit;")?;
+        assert_eq!(counts(&engine)?, vec![2, 2]);
+
+        // an external coordinator prepares it on all shards, and ends it by id
+        s.execute("begin;")?;
+        s.execute("insert into t1 values (4, 'd'), (15, 'd');")?;
+        s.execute("prepare transaction 'ext';")?;
+        assert_eq!(
+            engine.shard(1).unwrap().prepared()?,
+            vec!["ext".to_string()]
+        );
+        assert_eq!(counts(&engine)?, vec![2, 2]);
+        s.execute("commit prepared 'ext';")?;
+        assert_eq!(counts(&engine)?, vec![3, 3]);
+        assert!(s.execute("rollback prepared 'ext';").is_err());
+        Ok(())
+    }
+
+    // a crash in a commit rolls the transaction back unless the decision to
+    // commit was recorded
+    #[test]
+    fn test_commit_recovery() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let open = || -> Result<ShardedEngine<DiskEngine>> {
+            ShardedEngine::new(
+                (0..2)
+                    .map(|i| DiskEngine::new(dir.path().join(format!("shard{}", i))))
+                    .collect::<Result<_>>()?,
+                Partitioning::Range(vec![Value::Integer(10)]),
+            )
+        };
+        let row = |a: i64| vec![Value::Integer(a), Value::String("x".to_string())];
+        let engine = open()?;
+        engine
+            .session()?
+            .execute("create table t1 (a int, b text);")?;
+
+        // the crash comes between the prepares
+        let mut tx = engine.begin()?;
+        tx.create_row("t1".to_string(), row(1))?;
+        tx.create_row("t1".to_string(), row(11))?;
+        tx.shards[0].prepare("shard-1")?;
+        assert!(engine.commit_prepared("shard-1").is_err());
+        drop((tx, engine));
+        let engine = open()?;
+        assert_eq!(counts(&engine)?, vec![0, 0]);
+        assert_eq!(engine.shard(0).unwrap().prepared()?, Vec::<String>::new());
+
+        // the crash comes after the decision, before the shards commit
+        let mut tx = engine.begin()?;
+        tx.create_row("t1".to_string(), row(2))?;
+        tx.create_row("t1".to_string(), row(12))?;
+        tx.prepare_on(&BTreeSet::from([0, 1]), "shard-2", true)?;
+        tx.shards[0].commit()?;
+        drop((tx, engine));
+        let engine = open()?;
+        assert_eq!(counts(&engine)?, vec![1, 1]);
+        assert_eq!(engine.shard(1).unwrap().prepared()?, Vec::<String>::new());
+        assert!(engine.shard(0).unwrap().decisions()?.is_empty());
+
+        // a transaction prepared on all shards waits for its end by id
+        let mut tx = engine.begin()?;
+        tx.create_row("t1".to_string(), row(3))?;
+        tx.create_row("t1".to_string(), row(13))?;
+        tx.prepare("ext")?;
+        drop((tx, engine));
+        let engine = open()?;
+        assert_eq!(counts(&engine)?, vec![1, 1]);
+        engine.commit_prepared("ext")?;
+        assert_eq!(counts(&engine)?, vec![2, 2]);
+        Ok(())
+    }
+
+    // only a transaction writing to several shards pays for a two-phase
+    // commit, and only on the shards it wrote to
+    #[test]
+    fn test_one_phase_commit() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let engine = ShardedEngine::new(
+            (0..3)
+                .map(|i| DiskEngine::new(dir.path().join(format!("shard{}", i))))
+                .collect::<Result<_>>()?,
+            Partitioning::Range(vec![Value::Integer(10), Value::Integer(20)]),
+        )?;
+        let mut s = engine.session()?;
+        s.execute("create table t1 (a int, b text);")?;
+        let syncs = |engine: &ShardedEngine<DiskEngine>| -> Result<Vec<u64>> {
+            (0..3)
+                .map(|i| Ok(engine.shard(i).unwrap().engine()?.sync_count()))
+                .collect()
+        };
+
+        let before = syncs(&engine)?;
+        s.execute("insert into t1 values (1, 'a');")?;
+        s.execute("select * from t1;")?;
+        assert_eq!(syncs(&engine)?, before);
+
+        // a prepare on both shards, and the decision on the first
+        s.execute("insert into t1 values (2, 'b'), (11, 'c');")?;
+        let after = syncs(&engine)?;
+        let added: Vec<u64> = after.iter().zip(&before).map(|(a, b)| a - b).collect();
+        assert_eq!(added, vec![2, 1, 0]);
+        assert_eq!(counts(&engine)?, vec![2, 1, 0]);
+        Ok(())
+    }
+
+    // a session whose transaction fails to prepare gets the error of the
+    // prepare, the shards prepared before are rolled back
+    #[test]
+    fn test_prepare_failure() -> Result<()> {
+        let engine = ShardedEngine::new(
+            vec![MemoryEngine::new(), MemoryEngine::new()],
+            Partitioning::Range(vec![Value::Integer(10)]),
+        )?;
+        let mut s = engine.session()?;
+        s.execute("create table t1 (a int, b text);")?;
+        let other = engine.shard(1).unwrap().begin()?;
+        other.prepare("dup")?;
+
+        s.execute("begin;")?;
+        s.execute("insert into t1 values (1, 'a'), (11, 'b');")?;
+        assert_eq!(
+            s.execute("prepare transaction 'dup';").err(),
+            Some(Error::Internal(
+                "a transaction is already prepared as dup".to_string()
+            ))
+        );
+        assert_eq!(engine.shard(0).unwrap().prepared()?, Vec::<String>::new());
+        assert_eq!(counts(&engine)?, vec![0, 0]);
+        s.execute("insert into t1 values (2, 'c');")?;
+        assert_eq!(counts(&engine)?, vec![1, 0]);
+        Ok(())
+    }
+
+    // serializable is checked per shard, so it's only taken with one shard
+    #[test]
+    fn test_serializable() -> Result<()> {
+        let serializable = TransactionOptions {
+            isolation: IsolationLevel::Serializable,
+            read_only: false,
+        };
+        let engine = ShardedEngine::new(
+            vec![MemoryEngine::new(), MemoryEngine::new()],
+            Partitioning::Hash,
+        )?;
+        assert!(engine.begin_with(serializable).is_err());
+        let mut s = engine.session()?;
+        s.execute("create table t1 (a int, b text);")?;
+        s.execute("set transaction isolation level serializable;")?;
+        assert!(s.execute("insert into t1 values (1, 'a');").is_err());
+
+        let engine = ShardedEngine::new(vec![MemoryEngine::new()], Partitioning::Hash)?;
+        engine.begin_with(serializable)?.commit()?;
+        Ok(())
+    }
+}
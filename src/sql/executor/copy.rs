@@ -0,0 +1,163 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+};
+
+use crate::{
+    error::{Error, Result},
+    sql::{
+        engine::Transaction,
+        format::{
+            self,
+            csv::{CsvOptions, CsvReader},
+            Format,
+        },
+        plan::Node,
+        schema::Table,
+        types::{Row, Value},
+    },
+};
+
+use super::{mutation::make_row, Executor, ResultSet};
+
+// export query results into a file
+pub struct CopyTo {
+    source: Node,
+    path: String,
+    format: Format,
+    options: CsvOptions,
+}
+
+impl CopyTo {
+    pub fn new(source: Node, path: String, format: Format, options: CsvOptions) -> Box<Self> {
+        Box::new(Self {
+            source,
+            path,
+            format,
+            options,
+        })
+    }
+}
+
+impl<T: Transaction> Executor<T> for CopyTo {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        let result = <dyn Executor<T>>::build(self.source).execute(txn)?;
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        let count = format::write_result(&result, self.format, &self.options, &mut writer)?;
+        Ok(ResultSet::Copy { count })
+    }
+}
+
+// the number of rows COPY FROM writes in one batch
+const BATCH_ROWS: usize = 1000;
+
+// bulk load a csv file into a table
+pub struct CopyFrom {
+    table_name: String,
+    columns: Vec<String>,
+    path: String,
+    options: CsvOptions,
+}
+
+impl CopyFrom {
+    pub fn new(
+        table_name: String,
+        columns: Vec<String>,
+        path: String,
+        options: CsvOptions,
+    ) -> Box<Self> {
+        Box::new(Self {
+            table_name,
+            columns,
+            path,
+            options,
+        })
+    }
+
+    // convert a csv record into a table row
+    // copy tbl from ...      -> the record holds every column in order
+    // copy tbl (c, a) from ... -> missing columns take their default values
+    fn make_row(&self, table: &Table, fields: Vec<Option<String>>) -> Result<Row> {
+        let columns = if self.columns.is_empty() {
+            table.columns.iter().map(|c| c.name.clone()).collect()
+        } else {
+            self.columns.clone()
+        };
+        if fields.len() != columns.len() {
+            return Err(Error::Internal(format!(
+                "expected {} fields, got {}",
+                columns.len(),
+                fields.len()
+            )));
+        }
+
+        let mut values = Vec::new();
+        for (name, field) in columns.iter().zip(fields) {
+            let column = table
+                .columns
+                .iter()
+                .find(|c| &c.name == name)
+                .ok_or(Error::Internal(format!("column {} does not exist", name)))?;
+            values.push(match field {
+                Some(text) => Value::parse_as(&text, &column.datatype).map_err(|e| {
+                    Error::Internal(format!("column {}: {}", column.name, e))
+                })?,
+                None => Value::Null,
+            });
+        }
+
+        let row = make_row(table, &columns, &values)?;
+        table.check_row(&row)?;
+        Ok(row)
+    }
+
+    // Write the rows read from the lines, returns how many there were
+    fn write_batch<T: Transaction>(
+        &self,
+        txn: &mut T,
+        batch: &mut Vec<Row>,
+        lines: (usize, usize),
+    ) -> Result<usize> {
+        let count = batch.len();
+        if count > 0 {
+            txn.create_rows(self.table_name.clone(), std::mem::take(batch))
+                .map_err(|e| {
+                    Error::Internal(format!(
+                        "copy {}, lines {}-{}: {}",
+                        self.table_name, lines.0, lines.1, e
+                    ))
+                })?;
+        }
+        Ok(count)
+    }
+}
+
+impl<T: Transaction> Executor<T> for CopyFrom {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        let table = txn.must_get_table(self.table_name.clone())?;
+        let file = File::open(&self.path)?;
+        let mut reader = CsvReader::new(BufReader::new(file), self.options.clone())?;
+
+        // the rows are written in batches of the transaction, an invalid
+        // record fails with its line, a failed batch with its lines
+        let mut count = 0;
+        let mut batch = Vec::with_capacity(BATCH_ROWS);
+        let mut lines = (0, 0);
+        while let Some((line, fields)) = reader.next_record()? {
+            let row = self.make_row(&table, fields).map_err(|e| {
+                Error::Internal(format!("copy {}, line {}: {}", self.table_name, line, e))
+            })?;
+            if batch.is_empty() {
+                lines.0 = line;
+            }
+            lines.1 = line;
+            batch.push(row);
+            if batch.len() == BATCH_ROWS {
+                count += self.write_batch(txn, &mut batch, lines)?;
+            }
+        }
+        count += self.write_batch(txn, &mut batch, lines)?;
+
+        Ok(ResultSet::Copy { count })
+    }
+}
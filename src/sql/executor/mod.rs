@@ -1,9 +1,11 @@
 use super::{engine::Transaction, plan::Node, types::Row};
 use crate::error::Result;
+use copy::{CopyFrom, CopyTo};
 use mutation::Insert;
 use query::Scan;
 use schema::CreateTable;
 
+mod copy;
 mod mutation;
 mod query;
 mod schema;
@@ -22,7 +24,19 @@ impl<T: Transaction> dyn Executor<T> {
                 columns,
                 values,
             } => Insert::new(table_name, columns, values),
-            Node::Scan { table_name } => Scan::new(table_name),
+            Node::Scan { table_name, lock } => Scan::new(table_name, lock),
+            Node::CopyTo {
+                source,
+                path,
+                format,
+                options,
+            } => CopyTo::new(*source, path, format, options),
+            Node::CopyFrom {
+                table_name,
+                columns,
+                path,
+                options,
+            } => CopyFrom::new(table_name, columns, path, options),
         }
     }
 }
@@ -40,4 +54,23 @@ pub enum ResultSet {
         columns: Vec<String>,
         rows: Vec<Row>,
     },
+    Copy {
+        count: usize,
+    },
+    Vacuum {
+        count: usize,
+    },
+    Begin,
+    Commit,
+    Rollback,
+    PrepareTransaction,
+    CommitPrepared,
+    RollbackPrepared,
+    Savepoint,
+    RollbackTo,
+    Release,
+    SetTransaction,
+    Version {
+        version: u64,
+    },
 }
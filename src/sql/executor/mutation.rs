@@ -57,7 +57,7 @@ fn pad_row(table: &Table, row: &Row) -> Result<Row> {
 // insert into tbl(d, c) values(1, 2);
 //    a          b       c          d
 // default   default     2          1
-fn make_row(table: &Table, columns: &Vec<String>, values: &Row) -> Result<Row> {
+pub(super) fn make_row(table: &Table, columns: &Vec<String>, values: &Row) -> Result<Row> {
     // check if # of cols = value
     if columns.len() != values.len() {
         return Err(Error::Internal(format!("columns and values num mismatch")));
@@ -1,21 +1,29 @@
-use crate::{error::Result, sql::engine::Transaction};
+use crate::{
+    error::Result,
+    sql::engine::Transaction,
+    storage::mvcc::{LockMode, LockWait},
+};
 
 use super::{Executor, ResultSet};
 
 pub struct Scan {
     table_name: String,
+    lock: Option<(LockMode, LockWait)>,
 }
 
 impl Scan {
-    pub fn new(table_name: String) -> Box<Self> {
-        Box::new(Self { table_name })
+    pub fn new(table_name: String, lock: Option<(LockMode, LockWait)>) -> Box<Self> {
+        Box::new(Self { table_name, lock })
     }
 }
 
 impl<T: Transaction> Executor<T> for Scan {
     fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
         let table = txn.must_get_table(self.table_name.clone())?;
-        let rows = txn.scan_table(self.table_name.clone())?;
+        let mut rows = txn.scan_table(self.table_name.clone())?;
+        if let Some((mode, wait)) = self.lock {
+            rows = txn.lock_rows(self.table_name.clone(), rows, mode, wait)?;
+        }
         Ok(ResultSet::Scan {
             columns: table.columns.into_iter().map(|c| c.name.clone()).collect(),
             rows,
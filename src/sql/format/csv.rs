@@ -0,0 +1,345 @@
+use std::io::{BufRead, Write};
+
+use crate::{
+    error::{Error, Result},
+    sql::types::{Row, Value},
+};
+
+// CSV dialect used when exporting or importing data
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvOptions {
+    // whether the first record holds the column names
+    pub header: bool,
+    pub delimiter: char,
+    // the unquoted string representing a NULL value
+    pub null: String,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            header: true,
+            delimiter: ',',
+            null: String::new(),
+        }
+    }
+}
+
+impl CsvOptions {
+    // The defaults of an import, a file is read without a header unless
+    // it's enabled, as in PostgreSQL
+    pub fn import() -> Self {
+        Self {
+            header: false,
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if matches!(self.delimiter, '"' | '\r' | '\n') {
+            return Err(Error::Parse(format!(
+                "invalid csv delimiter {:?}",
+                self.delimiter
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Write rows as RFC 4180 CSV
+// - the first record is a header with the column names (if enabled)
+// - records are terminated by CRLF
+// - NULL is written as the unquoted null string, an equal string value is quoted
+pub fn write_csv<W: Write>(
+    columns: &[String],
+    rows: &[Row],
+    options: &CsvOptions,
+    w: &mut W,
+) -> Result<()> {
+    options.validate()?;
+    let delimiter = options.delimiter.to_string();
+    if options.header {
+        let header = columns
+            .iter()
+            .map(|c| quote_field(c, options))
+            .collect::<Vec<_>>()
+            .join(&delimiter);
+        write!(w, "{}\r\n", header)?;
+    }
+
+    for row in rows {
+        let record = row
+            .iter()
+            .map(|v| format_value(v, options))
+            .collect::<Vec<_>>()
+            .join(&delimiter);
+        write!(w, "{}\r\n", record)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+fn format_value(value: &Value, options: &CsvOptions) -> String {
+    match value {
+        Value::Null => options.null.clone(),
+        Value::String(s) if *s == options.null => format!("\"{}\"", s.replace('"', "\"\"")),
+        v => quote_field(&v.to_string(), options),
+    }
+}
+
+// Fields containing the delimiter, quotes or line breaks are enclosed in
+// double quotes, and a double quote inside is escaped by doubling it
+// a,b   -> "a,b"
+// a"b   -> "a""b"
+fn quote_field(field: &str, options: &CsvOptions) -> String {
+    if field.contains([options.delimiter, '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Reads RFC 4180 CSV records, a NULL field is returned as None
+pub struct CsvReader<R: BufRead> {
+    reader: R,
+    options: CsvOptions,
+    // the number of physical lines read so far
+    line: usize,
+}
+
+#[derive(PartialEq)]
+enum FieldState {
+    Start,
+    Unquoted,
+    Quoted,
+    // a quote seen inside a quoted field, either closing or escaping
+    QuoteInQuoted,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    pub fn new(reader: R, options: CsvOptions) -> Result<Self> {
+        options.validate()?;
+        let mut csv = Self {
+            reader,
+            options,
+            line: 0,
+        };
+        if csv.options.header {
+            csv.next_record()?;
+        }
+        Ok(csv)
+    }
+
+    // Read the next record and the line number it starts at
+    pub fn next_record(&mut self) -> Result<Option<(usize, Vec<Option<String>>)>> {
+        let mut buf = String::new();
+        // skip blank lines between records
+        loop {
+            buf.clear();
+            if self.reader.read_line(&mut buf)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            if !buf.trim_end_matches(['\r', '\n']).is_empty() {
+                break;
+            }
+        }
+
+        let start_line = self.line;
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut state = FieldState::Start;
+        loop {
+            let content = buf.trim_end_matches(['\r', '\n']);
+            let line_ending = &buf[content.len()..];
+            for c in content.chars() {
+                state = match state {
+                    FieldState::Start if c == '"' => FieldState::Quoted,
+                    FieldState::Start | FieldState::Unquoted if c == self.options.delimiter => {
+                        fields.push(self.finish_field(&mut field, state == FieldState::Unquoted));
+                        FieldState::Start
+                    }
+                    FieldState::Start | FieldState::Unquoted => {
+                        field.push(c);
+                        FieldState::Unquoted
+                    }
+                    FieldState::Quoted if c == '"' => FieldState::QuoteInQuoted,
+                    FieldState::Quoted => {
+                        field.push(c);
+                        FieldState::Quoted
+                    }
+                    FieldState::QuoteInQuoted if c == '"' => {
+                        field.push('"');
+                        FieldState::Quoted
+                    }
+                    FieldState::QuoteInQuoted if c == self.options.delimiter => {
+                        fields.push(Some(std::mem::take(&mut field)));
+                        FieldState::Start
+                    }
+                    FieldState::QuoteInQuoted => {
+                        return Err(Error::Parse(format!(
+                            "line {}: unexpected character {:?} after closing quote",
+                            self.line, c
+                        )))
+                    }
+                }
+            }
+
+            if state != FieldState::Quoted {
+                break;
+            }
+            // the line break belongs to the quoted field, continue with the next line
+            field.push_str(line_ending);
+            buf.clear();
+            if self.reader.read_line(&mut buf)? == 0 {
+                return Err(Error::Parse(format!(
+                    "line {}: unterminated quoted field",
+                    start_line
+                )));
+            }
+            self.line += 1;
+        }
+
+        match state {
+            FieldState::QuoteInQuoted => fields.push(Some(field)),
+            _ => fields.push(self.finish_field(&mut field, true)),
+        }
+        Ok(Some((start_line, fields)))
+    }
+
+    fn finish_field(&self, field: &mut String, unquoted: bool) -> Option<String> {
+        let value = std::mem::take(field);
+        if unquoted && value == self.options.null {
+            None
+        } else {
+            Some(value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_csv, CsvOptions, CsvReader};
+    use crate::{error::Result, sql::types::Value};
+
+    #[test]
+    fn test_write_csv() -> Result<()> {
+        let columns = vec!["id".to_string(), "name".to_string(), "score".to_string()];
+        let rows = vec![
+            vec![
+                Value::Integer(1),
+                Value::String("plain".to_string()),
+                Value::Float(1.5),
+            ],
+            vec![
+                Value::Integer(2),
+                Value::String("a,b \"c\"".to_string()),
+                Value::Null,
+            ],
+            vec![
+                Value::Integer(3),
+                Value::String("".to_string()),
+                Value::Boolean(true),
+            ],
+            vec![
+                Value::Integer(4),
+                Value::String("line1\nline2".to_string()),
+                Value::Boolean(false),
+            ],
+        ];
+
+        let mut buf = Vec::new();
+        write_csv(&columns, &rows, &CsvOptions::default(), &mut buf)?;
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "id,name,score\r\n\
+             1,plain,1.5\r\n\
+             2,\"a,b \"\"c\"\"\",\r\n\
+             3,\"\",true\r\n\
+             4,\"line1\nline2\",false\r\n"
+        );
+
+        let options = CsvOptions {
+            header: false,
+            delimiter: '|',
+            null: "NULL".to_string(),
+        };
+        let mut buf = Vec::new();
+        write_csv(&columns, &rows[1..3], &options, &mut buf)?;
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "2|\"a,b \"\"c\"\"\"|NULL\r\n3||true\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_csv() -> Result<()> {
+        let input = "id,name,note\r\n\
+                     1,plain,\r\n\
+                     2,\"a,b \"\"c\"\"\",\"\"\n\
+                     \n\
+                     3,\"multi\nline\",x\n\
+                     4,last,y";
+        let mut reader = CsvReader::new(input.as_bytes(), CsvOptions::default())?;
+
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record()? {
+            records.push(record);
+        }
+        let s = |v: &str| Some(v.to_string());
+        assert_eq!(
+            records,
+            vec![
+                (2, vec![s("1"), s("plain"), None]),
+                (3, vec![s("2"), s("a,b \"c\""), s("")]),
+                (5, vec![s("3"), s("multi\nline"), s("x")]),
+                (7, vec![s("4"), s("last"), s("y")]),
+            ]
+        );
+
+        // without a header the first line is a record
+        let options = CsvOptions::import();
+        let mut reader = CsvReader::new("1,a\n2,b\n".as_bytes(), options.clone())?;
+        assert_eq!(reader.next_record()?, Some((1, vec![s("1"), s("a")])));
+        assert_eq!(reader.next_record()?, Some((2, vec![s("2"), s("b")])));
+        assert_eq!(reader.next_record()?, None);
+
+        let mut reader = CsvReader::new("1,\"open\n2,x\n".as_bytes(), options.clone())?;
+        assert!(reader.next_record().is_err());
+
+        let mut reader = CsvReader::new("1,\"a\"b\n".as_bytes(), options)?;
+        assert!(reader.next_record().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv_round_trip() -> Result<()> {
+        let columns = vec!["a".to_string(), "b".to_string()];
+        let rows = vec![
+            vec![Value::String("x;y".to_string()), Value::Null],
+            vec![Value::String("".to_string()), Value::String("\"q\"".to_string())],
+        ];
+        let options = CsvOptions {
+            header: true,
+            delimiter: ';',
+            null: String::new(),
+        };
+        let mut buf = Vec::new();
+        write_csv(&columns, &rows, &options, &mut buf)?;
+
+        let mut reader = CsvReader::new(buf.as_slice(), options)?;
+        let mut fields = Vec::new();
+        while let Some((_, record)) = reader.next_record()? {
+            fields.push(record);
+        }
+        assert_eq!(
+            fields,
+            vec![
+                vec![Some("x;y".to_string()), None],
+                vec![Some("".to_string()), Some("\"q\"".to_string())],
+            ]
+        );
+        Ok(())
+    }
+}
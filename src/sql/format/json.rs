@@ -0,0 +1,126 @@
+use std::io::Write;
+
+use crate::{
+    error::Result,
+    sql::types::{Row, Value},
+};
+
+// Write rows as a JSON array of objects keyed by column name
+// [{"id":1,"name":"a"},{"id":2,"name":null}]
+pub fn write_json<W: Write>(columns: &[String], rows: &[Row], w: &mut W) -> Result<()> {
+    write!(w, "[")?;
+    for (i, row) in rows.iter().enumerate() {
+        if i > 0 {
+            write!(w, ",")?;
+        }
+        write_object(columns, row, w)?;
+    }
+    write!(w, "]")?;
+    w.flush()?;
+    Ok(())
+}
+
+// Write rows as newline-delimited JSON, one object per line
+// each row is written out as soon as it's formatted
+pub fn write_ndjson<'a, W: Write>(
+    columns: &[String],
+    rows: impl IntoIterator<Item = &'a Row>,
+    w: &mut W,
+) -> Result<()> {
+    for row in rows {
+        write_object(columns, row, w)?;
+        writeln!(w)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+fn write_object<W: Write>(columns: &[String], row: &Row, w: &mut W) -> Result<()> {
+    write!(w, "{{")?;
+    for (i, (col, value)) in columns.iter().zip(row.iter()).enumerate() {
+        if i > 0 {
+            write!(w, ",")?;
+        }
+        write!(w, "{}:{}", quote_string(col), format_value(value))?;
+    }
+    write!(w, "}}")?;
+    Ok(())
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Integer(i) => i.to_string(),
+        // JSON has no representation for NaN and infinity
+        Value::Float(f) if !f.is_finite() => "null".to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::String(s) => quote_string(s),
+    }
+}
+
+fn quote_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_json, write_ndjson};
+    use crate::{error::Result, sql::types::Value};
+
+    #[test]
+    fn test_write_json() -> Result<()> {
+        let columns = vec!["id".to_string(), "name".to_string(), "ok".to_string()];
+        let rows = vec![
+            vec![
+                Value::Integer(1),
+                Value::String("say \"hi\"\n".to_string()),
+                Value::Boolean(true),
+            ],
+            vec![Value::Integer(2), Value::Null, Value::Float(f64::NAN)],
+        ];
+
+        let mut buf = Vec::new();
+        write_json(&columns, &rows, &mut buf)?;
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            r#"[{"id":1,"name":"say \"hi\"\n","ok":true},{"id":2,"name":null,"ok":null}]"#
+        );
+
+        let mut buf = Vec::new();
+        write_json(&columns, &[], &mut buf)?;
+        assert_eq!(String::from_utf8(buf).unwrap(), "[]");
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_ndjson() -> Result<()> {
+        let columns = vec!["id".to_string(), "score".to_string()];
+        let rows = vec![
+            vec![Value::Integer(1), Value::Float(2.5)],
+            vec![Value::Integer(2), Value::String("\u{1}".to_string())],
+        ];
+
+        let mut buf = Vec::new();
+        write_ndjson(&columns, &rows, &mut buf)?;
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "{\"id\":1,\"score\":2.5}\n{\"id\":2,\"score\":\"\\u0001\"}\n"
+        );
+        Ok(())
+    }
+}
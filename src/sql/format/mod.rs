@@ -0,0 +1,54 @@
+use std::io::Write;
+
+use crate::error::{Error, Result};
+
+use super::executor::ResultSet;
+use csv::CsvOptions;
+
+pub mod csv;
+pub mod json;
+
+// Formats a query result can be exported as
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // RFC 4180 CSV with a header row
+    Csv,
+    // A single JSON array of objects
+    Json,
+    // One JSON object per line, for streaming consumers
+    Ndjson,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name.to_lowercase().as_ref() {
+            "csv" => Format::Csv,
+            "json" => Format::Json,
+            "ndjson" => Format::Ndjson,
+            _ => return None,
+        })
+    }
+}
+
+// Serialize a ResultSet::Scan into the writer, returns the number of rows written
+// csv options are ignored by the json formats
+pub fn write_result<W: Write>(
+    result: &ResultSet,
+    format: Format,
+    options: &CsvOptions,
+    w: &mut W,
+) -> Result<usize> {
+    match result {
+        ResultSet::Scan { columns, rows } => {
+            match format {
+                Format::Csv => csv::write_csv(columns, rows, options, w)?,
+                Format::Json => json::write_json(columns, rows, w)?,
+                Format::Ndjson => json::write_ndjson(columns, rows, w)?,
+            }
+            Ok(rows.len())
+        }
+        _ => Err(Error::Internal(
+            "only query results can be exported".to_string(),
+        )),
+    }
+}
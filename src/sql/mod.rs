@@ -1,5 +1,7 @@
+pub mod dump;
 pub mod engine;
 pub mod executor;
+pub mod format;
 pub mod parser;
 pub mod plan;
 pub mod schema;
@@ -1,4 +1,10 @@
-use crate::sql::types::DataType;
+use crate::{
+    sql::{
+        format::{csv::CsvOptions, Format},
+        types::DataType,
+    },
+    storage::mvcc::{IsolationLevel, LockMode, LockWait, Version},
+};
 
 // Define Abstract Syntax Tree
 #[derive(Debug, PartialEq)]
@@ -14,7 +20,71 @@ pub enum Statement {
     },
     Select {
         table_name: String,
+        // read the table as it was at a past version
+        as_of: Option<Version>,
+        // lock the rows read until the transaction ends
+        lock: Option<(LockMode, LockWait)>,
+    },
+    CopyTo {
+        query: Box<Statement>,
+        path: String,
+        format: Format,
+        options: CsvOptions,
+    },
+    CopyFrom {
+        table_name: String,
+        columns: Option<Vec<String>>,
+        path: String,
+        options: CsvOptions,
     },
+    Vacuum,
+    // start an explicit transaction, unset options are taken from the session
+    Begin {
+        isolation: Option<IsolationLevel>,
+        read_only: Option<bool>,
+    },
+    Commit,
+    Rollback,
+    // prepare the current transaction for a two-phase commit, it's ended
+    // later by the id, also from another session
+    PrepareTransaction {
+        id: String,
+    },
+    CommitPrepared {
+        id: String,
+    },
+    RollbackPrepared {
+        id: String,
+    },
+    Savepoint {
+        name: String,
+    },
+    // undo the writes after the savepoint
+    RollbackTo {
+        name: String,
+    },
+    // forget the savepoint, keeping the writes after it
+    Release {
+        name: String,
+    },
+    // the options of the current transaction before its first statement,
+    // otherwise the session's options for the following transactions
+    SetTransaction {
+        isolation: Option<IsolationLevel>,
+        read_only: Option<bool>,
+    },
+    ShowVersion,
+}
+
+impl Statement {
+    // The past version the statement reads, if any
+    pub fn as_of(&self) -> Option<Version> {
+        match self {
+            Statement::Select { as_of, .. } => *as_of,
+            Statement::CopyTo { query, .. } => query.as_of(),
+            _ => None,
+        }
+    }
 }
 
 // define column
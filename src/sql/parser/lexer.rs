@@ -63,6 +63,22 @@ pub enum Keyword {
     Null,
     Primary,
     Key,
+    Copy,
+    To,
+    With,
+    Vacuum,
+    Begin,
+    Commit,
+    Rollback,
+    Set,
+    Transaction,
+    As,
+    Show,
+    Savepoint,
+    Release,
+    For,
+    Update,
+    Prepare,
 }
 
 impl Keyword {
@@ -91,6 +107,22 @@ impl Keyword {
             "NULL" => Keyword::Null,
             "PRIMARY" => Keyword::Primary,
             "KEY" => Keyword::Key,
+            "COPY" => Keyword::Copy,
+            "TO" => Keyword::To,
+            "WITH" => Keyword::With,
+            "VACUUM" => Keyword::Vacuum,
+            "BEGIN" => Keyword::Begin,
+            "COMMIT" => Keyword::Commit,
+            "ROLLBACK" => Keyword::Rollback,
+            "SET" => Keyword::Set,
+            "TRANSACTION" => Keyword::Transaction,
+            "AS" => Keyword::As,
+            "SHOW" => Keyword::Show,
+            "SAVEPOINT" => Keyword::Savepoint,
+            "RELEASE" => Keyword::Release,
+            "FOR" => Keyword::For,
+            "UPDATE" => Keyword::Update,
+            "PREPARE" => Keyword::Prepare,
             _ => return None,
         })
     }
@@ -120,6 +152,22 @@ impl Keyword {
             Keyword::Null => "NULL",
             Keyword::Primary => "PRIMARY",
             Keyword::Key => "KEY",
+            Keyword::Copy => "COPY",
+            Keyword::To => "TO",
+            Keyword::With => "WITH",
+            Keyword::Vacuum => "VACUUM",
+            Keyword::Begin => "BEGIN",
+            Keyword::Commit => "COMMIT",
+            Keyword::Rollback => "ROLLBACK",
+            Keyword::Set => "SET",
+            Keyword::Transaction => "TRANSACTION",
+            Keyword::As => "AS",
+            Keyword::Show => "SHOW",
+            Keyword::Savepoint => "SAVEPOINT",
+            Keyword::Release => "RELEASE",
+            Keyword::For => "FOR",
+            Keyword::Update => "UPDATE",
+            Keyword::Prepare => "PREPARE",
         }
     }
 }
@@ -153,7 +201,41 @@ impl Display for Keyword {
 // values ( expr [, ...] );
 // 3. Select * From
 // -------------------------------------
-// SELECT * FROM table_name;
+// SELECT * FROM table_name [ AS OF VERSION version ]
+// [ FOR { UPDATE | SHARE } [ NOWAIT | SKIP LOCKED ] ];
+// 4. Copy To
+// -------------------------------------
+// COPY ( select_statement ) TO 'file_path'
+// [ WITH ( option [, ...] ) ];
+// 5. Copy From
+// -------------------------------------
+// COPY table_name [ ( column_name [, ...] ) ] FROM 'file_path'
+// [ WITH ( option [, ...] ) ];
+//
+//    where option is:
+//    FORMAT csv | json | ndjson
+//    HEADER [ true | false ]
+//    DELIMITER 'delimiter_character'
+//    NULL 'null_string'
+// 6. Vacuum
+// -------------------------------------
+// VACUUM;
+// 7. Transactions
+// -------------------------------------
+// BEGIN [ TRANSACTION ] [ transaction_mode [, ...] ];
+// SET TRANSACTION transaction_mode [, ...];
+// COMMIT [ TRANSACTION ];
+// ROLLBACK [ TRANSACTION ];
+// SAVEPOINT savepoint_name;
+// ROLLBACK [ TRANSACTION ] TO [ SAVEPOINT ] savepoint_name;
+// RELEASE [ SAVEPOINT ] savepoint_name;
+//
+//    where transaction_mode is:
+//    ISOLATION LEVEL { READ COMMITTED | REPEATABLE READ | SNAPSHOT | SERIALIZABLE }
+//    READ ONLY | READ WRITE
+// 8. Show Version
+// -------------------------------------
+// SHOW VERSION;
 pub struct Lexer<'a> {
     iter: Peekable<Chars<'a>>,
 }
@@ -233,6 +315,8 @@ fn scan_string(&mut self) -> Result<Option<Token>> {
     let mut val = String::new();
     loop {
         match self.iter.next() {
+            // a doubled quote is an escaped quote: 'it''s'
+            Some('\'') if self.next_if(|c| c == '\'').is_some() => val.push('\''),
             Some('\'') => break,
             Some(c) => val.push(c),
             None => return Err(Error::Parse(format!("[Lexer] Unexpected end of string"))),
@@ -429,7 +513,28 @@ fn test_lexer_select() -> Result<()> {
     );
     Ok(())
 }
-}
-
 
+#[test]
+fn test_lexer_escaped_string() -> Result<()> {
+    let tokens = Lexer::new("insert into tbl values ('it''s', '''');")
+        .peekable()
+        .collect::<Result<Vec<_>>>()?;
 
+    assert_eq!(
+        tokens,
+        vec![
+            Token::Keyword(Keyword::Insert),
+            Token::Keyword(Keyword::Into),
+            Token::Ident("tbl".to_string()),
+            Token::Keyword(Keyword::Values),
+            Token::OpenParen,
+            Token::String("it's".to_string()),
+            Token::Comma,
+            Token::String("'".to_string()),
+            Token::CloseParen,
+            Token::Semicolon,
+        ]
+    );
+    Ok(())
+}
+}
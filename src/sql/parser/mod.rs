@@ -3,9 +3,15 @@ use std::iter::Peekable;
 use ast::Column;
 use lexer::{Keyword, Lexer, Token};
 
-use crate::error::{Error, Result};
+use crate::{
+    error::{Error, Result},
+    storage::mvcc::{IsolationLevel, LockMode, LockWait},
+};
 
-use super::types::DataType;
+use super::{
+    format::{csv::CsvOptions, Format},
+    types::DataType,
+};
 
 pub mod ast;
 mod lexer;
@@ -40,6 +46,16 @@ impl<'a> Parser<'a> {
             Some(Token::Keyword(Keyword::Create)) => self.parse_ddl(),
             Some(Token::Keyword(Keyword::Select)) => self.parse_select(),
             Some(Token::Keyword(Keyword::Insert)) => self.parse_insert(),
+            Some(Token::Keyword(Keyword::Copy)) => self.parse_copy(),
+            Some(Token::Keyword(Keyword::Vacuum)) => self.parse_vacuum(),
+            Some(Token::Keyword(Keyword::Show)) => self.parse_show(),
+            Some(Token::Keyword(Keyword::Begin))
+            | Some(Token::Keyword(Keyword::Commit))
+            | Some(Token::Keyword(Keyword::Rollback))
+            | Some(Token::Keyword(Keyword::Savepoint))
+            | Some(Token::Keyword(Keyword::Release))
+            | Some(Token::Keyword(Keyword::Prepare))
+            | Some(Token::Keyword(Keyword::Set)) => self.parse_transaction(),
             Some(t) => Err(Error::Parse(format!("[Parser] Unexpected token {}", t))),
             None => Err(Error::Parse(format!("[Parser] Unexpected end of input"))),
         }
@@ -63,7 +79,47 @@ impl<'a> Parser<'a> {
         self.next_expect(Token::Keyword(Keyword::From))?;
 
         let table_name = self.next_ident()?;
-        Ok(ast::Statement::Select { table_name })
+        // as of version 12, the words after AS aren't keywords
+        let as_of = if self.next_if_token(Token::Keyword(Keyword::As)).is_some() {
+            self.next_expect(Token::Ident("of".to_string()))?;
+            self.next_expect(Token::Ident("version".to_string()))?;
+            match self.next()? {
+                Token::Number(n) => Some(n.parse::<u64>()?),
+                token => return Err(Error::Parse(format!("[Parser] Unexpected token {}", token))),
+            }
+        } else {
+            None
+        };
+        // for update nowait, for share skip locked
+        let lock = if self.next_if_token(Token::Keyword(Keyword::For)).is_some() {
+            let mode = match self.next()? {
+                Token::Keyword(Keyword::Update) => LockMode::Update,
+                Token::Ident(ident) if ident == "share" => LockMode::Share,
+                token => return Err(Error::Parse(format!("[Parser] Unexpected token {}", token))),
+            };
+            let wait = if self.next_if_token(Token::Ident("nowait".to_string())).is_some() {
+                LockWait::NoWait
+            } else if self.next_if_token(Token::Ident("skip".to_string())).is_some() {
+                self.next_expect(Token::Ident("locked".to_string()))?;
+                LockWait::SkipLocked
+            } else {
+                LockWait::Block
+            };
+            Some((mode, wait))
+        } else {
+            None
+        };
+        // past versions are read-only, there is nothing to lock
+        if as_of.is_some() && lock.is_some() {
+            return Err(Error::Parse(
+                "[Parser] AS OF cannot be used with FOR UPDATE or FOR SHARE".to_string(),
+            ));
+        }
+        Ok(ast::Statement::Select {
+            table_name,
+            as_of,
+            lock,
+        })
     }
 
     // Parse Insert command
@@ -75,18 +131,7 @@ impl<'a> Parser<'a> {
 
         // check if to insert to specified column
         let columns = if self.next_if_token(Token::OpenParen).is_some() {
-            let mut cols = Vec::new();
-            loop {
-                cols.push(self.next_ident()?.to_string());
-                match self.next()? {
-                    Token::CloseParen => break,
-                    Token::Comma => {}
-                    token => {
-                        return Err(Error::Parse(format!("[Parser] Unexpected token {}", token)));
-                    }
-                }
-            }
-            Some(cols)
+            Some(self.parse_ident_list()?)
         } else {
             None
         };
@@ -121,6 +166,255 @@ impl<'a> Parser<'a> {
         })
     }
 
+    // Parse Vacuum command
+    fn parse_vacuum(&mut self) -> Result<ast::Statement> {
+        self.next_expect(Token::Keyword(Keyword::Vacuum))?;
+        Ok(ast::Statement::Vacuum)
+    }
+
+    fn parse_show(&mut self) -> Result<ast::Statement> {
+        self.next_expect(Token::Keyword(Keyword::Show))?;
+        self.next_expect(Token::Ident("version".to_string()))?;
+        Ok(ast::Statement::ShowVersion)
+    }
+
+    // Parse transaction control commands
+    fn parse_transaction(&mut self) -> Result<ast::Statement> {
+        let stmt = match self.next()? {
+            Token::Keyword(Keyword::Begin) => {
+                self.next_if_token(Token::Keyword(Keyword::Transaction));
+                let (isolation, read_only) = self.parse_transaction_modes()?;
+                ast::Statement::Begin {
+                    isolation,
+                    read_only,
+                }
+            }
+            Token::Keyword(Keyword::Set) => {
+                self.next_expect(Token::Keyword(Keyword::Transaction))?;
+                let (isolation, read_only) = self.parse_transaction_modes()?;
+                if isolation.is_none() && read_only.is_none() {
+                    return Err(Error::Parse(
+                        "[Parser] Expected a transaction mode".to_string(),
+                    ));
+                }
+                ast::Statement::SetTransaction {
+                    isolation,
+                    read_only,
+                }
+            }
+            // "prepared" isn't a keyword, so it can still name a column
+            Token::Keyword(Keyword::Commit) => {
+                if self.next_if_token(Token::Ident("prepared".to_string())).is_some() {
+                    ast::Statement::CommitPrepared {
+                        id: self.next_string()?,
+                    }
+                } else {
+                    self.next_if_token(Token::Keyword(Keyword::Transaction));
+                    ast::Statement::Commit
+                }
+            }
+            Token::Keyword(Keyword::Prepare) => {
+                self.next_expect(Token::Keyword(Keyword::Transaction))?;
+                ast::Statement::PrepareTransaction {
+                    id: self.next_string()?,
+                }
+            }
+            Token::Keyword(Keyword::Rollback) => {
+                if self.next_if_token(Token::Ident("prepared".to_string())).is_some() {
+                    return Ok(ast::Statement::RollbackPrepared {
+                        id: self.next_string()?,
+                    });
+                }
+                self.next_if_token(Token::Keyword(Keyword::Transaction));
+                if self.next_if_token(Token::Keyword(Keyword::To)).is_some() {
+                    self.next_if_token(Token::Keyword(Keyword::Savepoint));
+                    ast::Statement::RollbackTo {
+                        name: self.next_ident()?,
+                    }
+                } else {
+                    ast::Statement::Rollback
+                }
+            }
+            Token::Keyword(Keyword::Savepoint) => ast::Statement::Savepoint {
+                name: self.next_ident()?,
+            },
+            Token::Keyword(Keyword::Release) => {
+                self.next_if_token(Token::Keyword(Keyword::Savepoint));
+                ast::Statement::Release {
+                    name: self.next_ident()?,
+                }
+            }
+            token => return Err(Error::Parse(format!("[Parser] Unexpected token {}", token))),
+        };
+        Ok(stmt)
+    }
+
+    // Parse transaction modes, the words aren't keywords so they can still name columns
+    // isolation level read committed, read only
+    fn parse_transaction_modes(&mut self) -> Result<(Option<IsolationLevel>, Option<bool>)> {
+        let mut isolation = None;
+        let mut read_only = None;
+        while let Some(Token::Ident(word)) = self.next_if(|t| matches!(t, Token::Ident(_))) {
+            match word.as_str() {
+                "isolation" => {
+                    self.next_expect(Token::Ident("level".to_string()))?;
+                    let level = self.next_ident()?;
+                    isolation = Some(match level.as_str() {
+                        "read" => {
+                            self.next_expect(Token::Ident("committed".to_string()))?;
+                            IsolationLevel::ReadCommitted
+                        }
+                        "repeatable" => {
+                            self.next_expect(Token::Ident("read".to_string()))?;
+                            IsolationLevel::Snapshot
+                        }
+                        "snapshot" => IsolationLevel::Snapshot,
+                        "serializable" => IsolationLevel::Serializable,
+                        level => {
+                            return Err(Error::Parse(format!(
+                                "[Parser] Unknown isolation level {}",
+                                level
+                            )))
+                        }
+                    });
+                }
+                "read" => {
+                    read_only = Some(match self.next_ident()?.as_str() {
+                        "only" => true,
+                        "write" => false,
+                        mode => {
+                            return Err(Error::Parse(format!(
+                                "[Parser] Unknown transaction mode read {}",
+                                mode
+                            )))
+                        }
+                    });
+                }
+                mode => {
+                    return Err(Error::Parse(format!(
+                        "[Parser] Unknown transaction mode {}",
+                        mode
+                    )))
+                }
+            }
+            if self.next_if_token(Token::Comma).is_none() {
+                break;
+            }
+        }
+        Ok((isolation, read_only))
+    }
+
+    // Parse Copy command
+    fn parse_copy(&mut self) -> Result<ast::Statement> {
+        self.next_expect(Token::Keyword(Keyword::Copy))?;
+
+        // copy (select * from tbl) to 'file' with (format csv);
+        if self.next_if_token(Token::OpenParen).is_some() {
+            let query = self.parse_select()?;
+            self.next_expect(Token::CloseParen)?;
+            self.next_expect(Token::Keyword(Keyword::To))?;
+            let path = self.next_string()?;
+            let (format, options) = self.parse_copy_options(CsvOptions::default())?;
+
+            return Ok(ast::Statement::CopyTo {
+                query: Box::new(query),
+                path,
+                format,
+                options,
+            });
+        }
+
+        // copy tbl (a, b) from 'file' with (format csv, header, delimiter ',', null '');
+        let table_name = self.next_ident()?;
+        let columns = if self.next_if_token(Token::OpenParen).is_some() {
+            Some(self.parse_ident_list()?)
+        } else {
+            None
+        };
+        self.next_expect(Token::Keyword(Keyword::From))?;
+        let path = self.next_string()?;
+        let (format, options) = self.parse_copy_options(CsvOptions::import())?;
+        if format != Format::Csv {
+            return Err(Error::Parse(
+                "[Parser] Copy from only supports csv format".to_string(),
+            ));
+        }
+
+        Ok(ast::Statement::CopyFrom {
+            table_name,
+            columns,
+            path,
+            options,
+        })
+    }
+
+    // Parse copy options
+    // with (format csv, header, delimiter ',', null '')
+    // the options not given keep their defaults
+    fn parse_copy_options(&mut self, mut options: CsvOptions) -> Result<(Format, CsvOptions)> {
+        let mut format = Format::Csv;
+        if self.next_if_token(Token::Keyword(Keyword::With)).is_none() {
+            return Ok((format, options));
+        }
+
+        self.next_expect(Token::OpenParen)?;
+        loop {
+            // null is a keyword, other option names are idents
+            let name = match self.next()? {
+                Token::Ident(ident) => ident,
+                Token::Keyword(Keyword::Null) => "null".to_string(),
+                token => return Err(Error::Parse(format!("[Parser] Unexpected token {}", token))),
+            };
+            let value = self.next_if(|t| !matches!(t, Token::Comma | Token::CloseParen));
+            match (name.as_str(), value) {
+                ("format", Some(Token::Ident(f))) => {
+                    format = Format::from_name(&f)
+                        .ok_or(Error::Parse(format!("[Parser] Unknown copy format {}", f)))?
+                }
+                ("header", None) | ("header", Some(Token::Keyword(Keyword::True))) => {
+                    options.header = true
+                }
+                ("header", Some(Token::Keyword(Keyword::False))) => options.header = false,
+                ("delimiter", Some(Token::String(d))) if d.chars().count() == 1 => {
+                    options.delimiter = d.chars().next().unwrap_or(',')
+                }
+                ("null", Some(Token::String(n))) => options.null = n,
+                (name, _) => {
+                    return Err(Error::Parse(format!(
+                        "[Parser] Invalid copy option {}",
+                        name
+                    )))
+                }
+            }
+
+            match self.next()? {
+                Token::CloseParen => break,
+                Token::Comma => {}
+                token => {
+                    return Err(Error::Parse(format!("[Parser] Unexpected token {}", token)));
+                }
+            }
+        }
+        Ok((format, options))
+    }
+
+    // Parse a comma separated list of idents after an open parenthesis
+    // (a, b, c)
+    fn parse_ident_list(&mut self) -> Result<Vec<String>> {
+        let mut idents = Vec::new();
+        loop {
+            idents.push(self.next_ident()?);
+            match self.next()? {
+                Token::CloseParen => break,
+                Token::Comma => {}
+                token => {
+                    return Err(Error::Parse(format!("[Parser] Unexpected token {}", token)));
+                }
+            }
+        }
+        Ok(idents)
+    }
+
     // Parse Create Table command
     fn parse_ddl_create_table(&mut self) -> Result<ast::Statement> {
         // Expected to be Table name
@@ -194,6 +488,19 @@ impl<'a> Parser<'a> {
                     ast::Consts::Float(n.parse()?).into()
                 }
             }
+            // negative number: -1, -1.5
+            Token::Minus => match self.next()? {
+                Token::Number(n) if n.chars().all(|c| c.is_ascii_digit()) => {
+                    ast::Consts::Integer(format!("-{}", n).parse()?).into()
+                }
+                Token::Number(n) => ast::Consts::Float(format!("-{}", n).parse()?).into(),
+                t => {
+                    return Err(Error::Parse(format!(
+                        "[Parser] Unexpected expression token {}",
+                        t
+                    )))
+                }
+            },
             Token::String(s) => ast::Consts::String(s).into(),
             Token::Keyword(Keyword::True) => ast::Consts::Boolean(true).into(),
             Token::Keyword(Keyword::False) => ast::Consts::Boolean(false).into(),
@@ -227,6 +534,16 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn next_string(&mut self) -> Result<String> {
+        match self.next()? {
+            Token::String(s) => Ok(s),
+            token => Err(Error::Parse(format!(
+                "[Parser] Expected string, got token {}",
+                token
+            ))),
+        }
+    }
+
     fn next_expect(&mut self, expect: Token) -> Result<()> {
         let token = self.next()?;
         if token != expect {
@@ -257,7 +574,14 @@ impl<'a> Parser<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{error::Result, sql::parser::ast};
+    use crate::{
+        error::Result,
+        sql::{
+            format::{csv::CsvOptions, Format},
+            parser::ast,
+        },
+        storage::mvcc::{LockMode, LockWait},
+    };
 
     use super::Parser;
 
@@ -342,6 +666,25 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_parser_negative_number() -> Result<()> {
+        let sql = "insert into tbl1 values (-1, -2.5, -9223372036854775808);";
+        let stmt = Parser::new(sql).parse()?;
+        assert_eq!(
+            stmt,
+            ast::Statement::Insert {
+                table_name: "tbl1".to_string(),
+                columns: None,
+                values: vec![vec![
+                    ast::Consts::Integer(-1).into(),
+                    ast::Consts::Float(-2.5).into(),
+                    ast::Consts::Integer(i64::MIN).into(),
+                ]],
+            }
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_parser_select() -> Result<()> {
         let sql = "select * from tbl1;";
@@ -349,9 +692,190 @@ mod tests {
         assert_eq!(
             stmt,
             ast::Statement::Select {
-                table_name: "tbl1".to_string()
+                table_name: "tbl1".to_string(),
+                as_of: None,
+                lock: None,
+            }
+        );
+
+        let sql = "SELECT * FROM tbl1 AS OF VERSION 12;";
+        let stmt = Parser::new(sql).parse()?;
+        assert_eq!(
+            stmt,
+            ast::Statement::Select {
+                table_name: "tbl1".to_string(),
+                as_of: Some(12),
+                lock: None,
+            }
+        );
+        assert!(Parser::new("select * from tbl1 as of version -1;").parse().is_err());
+        assert!(Parser::new("select * from tbl1 as of 12;").parse().is_err());
+
+        for (sql, lock) in [
+            ("select * from tbl1 for update;", (LockMode::Update, LockWait::Block)),
+            ("select * from tbl1 for share nowait;", (LockMode::Share, LockWait::NoWait)),
+            (
+                "SELECT * FROM tbl1 FOR UPDATE SKIP LOCKED;",
+                (LockMode::Update, LockWait::SkipLocked),
+            ),
+        ] {
+            assert_eq!(
+                Parser::new(sql).parse()?,
+                ast::Statement::Select {
+                    table_name: "tbl1".to_string(),
+                    as_of: None,
+                    lock: Some(lock),
+                }
+            );
+        }
+        assert!(Parser::new("select * from tbl1 for delete;").parse().is_err());
+        assert!(Parser::new("select * from tbl1 for update skip;").parse().is_err());
+        assert!(Parser::new("select * from tbl1 as of version 1 for update;").parse().is_err());
+        assert_eq!(Parser::new("show version;").parse()?, ast::Statement::ShowVersion);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_copy_to() -> Result<()> {
+        let sql1 = "copy (select * from tbl1) to '/tmp/tbl1.json' with (format json);";
+        let stmt1 = Parser::new(sql1).parse()?;
+        assert_eq!(
+            stmt1,
+            ast::Statement::CopyTo {
+                query: Box::new(ast::Statement::Select {
+                    table_name: "tbl1".to_string(),
+                    as_of: None,
+                    lock: None,
+                }),
+                path: "/tmp/tbl1.json".to_string(),
+                format: Format::Json,
+                options: CsvOptions::default(),
+            }
+        );
+
+        let sql2 = "COPY (SELECT * FROM tbl1) TO '/tmp/tbl1.csv';";
+        let stmt2 = Parser::new(sql2).parse()?;
+        assert_eq!(
+            stmt2,
+            ast::Statement::CopyTo {
+                query: Box::new(ast::Statement::Select {
+                    table_name: "tbl1".to_string(),
+                    as_of: None,
+                    lock: None,
+                }),
+                path: "/tmp/tbl1.csv".to_string(),
+                format: Format::Csv,
+                options: CsvOptions::default(),
+            }
+        );
+
+        let sql3 = "copy (select * from tbl1) to '/tmp/tbl1' with (format xml);";
+        assert!(Parser::new(sql3).parse().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_copy_from() -> Result<()> {
+        let sql1 = "copy tbl1 (a, b) from '/tmp/tbl1.csv' with (format csv, header false, delimiter '|', null 'NULL');";
+        let stmt1 = Parser::new(sql1).parse()?;
+        assert_eq!(
+            stmt1,
+            ast::Statement::CopyFrom {
+                table_name: "tbl1".to_string(),
+                columns: Some(vec!["a".to_string(), "b".to_string()]),
+                path: "/tmp/tbl1.csv".to_string(),
+                options: CsvOptions {
+                    header: false,
+                    delimiter: '|',
+                    null: "NULL".to_string(),
+                },
+            }
+        );
+
+        let sql2 = "copy tbl1 from '/tmp/tbl1.csv' with (header);";
+        let stmt2 = Parser::new(sql2).parse()?;
+        assert_eq!(
+            stmt2,
+            ast::Statement::CopyFrom {
+                table_name: "tbl1".to_string(),
+                columns: None,
+                path: "/tmp/tbl1.csv".to_string(),
+                options: CsvOptions::default(),
+            }
+        );
+
+        // an import reads no header unless it's enabled
+        let sql3 = "copy tbl1 from '/tmp/tbl1.csv';";
+        assert_eq!(
+            Parser::new(sql3).parse()?,
+            ast::Statement::CopyFrom {
+                table_name: "tbl1".to_string(),
+                columns: None,
+                path: "/tmp/tbl1.csv".to_string(),
+                options: CsvOptions::import(),
+            }
+        );
+
+        let sql3 = "copy tbl1 from '/tmp/tbl1.json' with (format json);";
+        assert!(Parser::new(sql3).parse().is_err());
+        let sql4 = "copy tbl1 from '/tmp/tbl1.csv' with (delimiter '||');";
+        assert!(Parser::new(sql4).parse().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_savepoint() -> Result<()> {
+        assert_eq!(
+            Parser::new("savepoint sp1;").parse()?,
+            ast::Statement::Savepoint {
+                name: "sp1".to_string()
+            }
+        );
+        for sql in ["rollback to sp1;", "ROLLBACK TRANSACTION TO SAVEPOINT sp1;"] {
+            assert_eq!(
+                Parser::new(sql).parse()?,
+                ast::Statement::RollbackTo {
+                    name: "sp1".to_string()
+                }
+            );
+        }
+        for sql in ["release sp1;", "RELEASE SAVEPOINT sp1;"] {
+            assert_eq!(
+                Parser::new(sql).parse()?,
+                ast::Statement::Release {
+                    name: "sp1".to_string()
+                }
+            );
+        }
+        assert_eq!(Parser::new("rollback;").parse()?, ast::Statement::Rollback);
+        assert!(Parser::new("savepoint;").parse().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_prepared() -> Result<()> {
+        assert_eq!(
+            Parser::new("PREPARE TRANSACTION 'tx1';").parse()?,
+            ast::Statement::PrepareTransaction {
+                id: "tx1".to_string()
+            }
+        );
+        assert_eq!(
+            Parser::new("commit prepared 'tx1';").parse()?,
+            ast::Statement::CommitPrepared {
+                id: "tx1".to_string()
+            }
+        );
+        assert_eq!(
+            Parser::new("ROLLBACK PREPARED 'tx1';").parse()?,
+            ast::Statement::RollbackPrepared {
+                id: "tx1".to_string()
             }
         );
+        assert_eq!(Parser::new("commit transaction;").parse()?, ast::Statement::Commit);
+        assert!(Parser::new("prepare transaction tx1;").parse().is_err());
+        assert!(Parser::new("prepare 'tx1';").parse().is_err());
+        assert!(Parser::new("commit prepared;").parse().is_err());
         Ok(())
     }
 }
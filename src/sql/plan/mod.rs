@@ -1,10 +1,14 @@
 use planner::Planner;
 
-use crate::error::Result;
+use crate::{
+    error::Result,
+    storage::mvcc::{LockMode, LockWait},
+};
 
 use super::{
     engine::Transaction,
     executor::{Executor, ResultSet},
+    format::{csv::CsvOptions, Format},
     parser::ast::{self, Expression},
     schema::Table,
 };
@@ -29,6 +33,23 @@ pub enum Node {
     // scan node
     Scan {
         table_name: String,
+        lock: Option<(LockMode, LockWait)>,
+    },
+
+    // export the result of the source node into a file
+    CopyTo {
+        source: Box<Node>,
+        path: String,
+        format: Format,
+        options: CsvOptions,
+    },
+
+    // load rows from a csv file into the table
+    CopyFrom {
+        table_name: String,
+        columns: Vec<String>,
+        path: String,
+        options: CsvOptions,
     },
 }
 
@@ -37,7 +58,7 @@ pub enum Node {
 pub struct Plan(pub Node);
 
 impl Plan {
-    pub fn build(stmt: ast::Statement) -> Self {
+    pub fn build(stmt: ast::Statement) -> Result<Self> {
         Planner::new().build(stmt)
     }
 
@@ -70,7 +91,7 @@ mod tests {
         );
         ";
         let stmt1 = Parser::new(sql1).parse()?;
-        let p1 = Plan::build(stmt1);
+        let p1 = Plan::build(stmt1)?;
 
         let sql2 = "
         create            table tbl1 (
@@ -81,7 +102,7 @@ mod tests {
         );
         ";
         let stmt2 = Parser::new(sql2).parse()?;
-        let p2 = Plan::build(stmt2);
+        let p2 = Plan::build(stmt2)?;
         assert_eq!(p1, p2);
 
         Ok(())
@@ -91,7 +112,7 @@ mod tests {
     fn test_plan_insert() -> Result<()> {
         let sql1 = "insert into tbl1 values (1, 2, 3, 'a', true);";
         let stmt1 = Parser::new(sql1).parse()?;
-        let p1 = Plan::build(stmt1);
+        let p1 = Plan::build(stmt1)?;
         assert_eq!(
             p1,
             Plan(Node::Insert {
@@ -109,7 +130,7 @@ mod tests {
 
         let sql2 = "insert into tbl2 (c1, c2, c3) values (3, 'a', true),(4, 'b', false);";
         let stmt2 = Parser::new(sql2).parse()?;
-        let p2 = Plan::build(stmt2);
+        let p2 = Plan::build(stmt2)?;
         assert_eq!(
             p2,
             Plan(Node::Insert {
@@ -137,11 +158,12 @@ mod tests {
     fn test_plan_select() -> Result<()> {
         let sql = "select * from tbl1;";
         let stmt = Parser::new(sql).parse()?;
-        let p = Plan::build(stmt);
+        let p = Plan::build(stmt)?;
         assert_eq!(
             p,
             Plan(Node::Scan {
                 table_name: "tbl1".to_string(),
+                lock: None,
             })
         );
 
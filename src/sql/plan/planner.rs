@@ -1,7 +1,10 @@
-use crate::sql::{
-    parser::ast,
-    schema::{self, Table},
-    types::Value,
+use crate::{
+    error::{Error, Result},
+    sql::{
+        parser::ast,
+        schema::{self, Table},
+        types::Value,
+    },
 };
 
 use super::{Node, Plan};
@@ -13,12 +16,12 @@ impl Planner {
         Self {}
     }
 
-    pub fn build(&mut self, stmt: ast::Statement) -> Plan {
-        Plan(self.build_statment(stmt))
+    pub fn build(&mut self, stmt: ast::Statement) -> Result<Plan> {
+        Ok(Plan(self.build_statment(stmt)?))
     }
 
-    fn build_statment(&self, stmt: ast::Statement) -> Node {
-        match stmt {
+    fn build_statment(&self, stmt: ast::Statement) -> Result<Node> {
+        Ok(match stmt {
             ast::Statement::CreateTable { name, columns } => Node::CreateTable {
                 schema: Table {
                     name,
@@ -51,7 +54,49 @@ impl Planner {
                 columns: columns.unwrap_or_default(),
                 values,
             },
-            ast::Statement::Select { table_name } => Node::Scan { table_name },
-        }
+            ast::Statement::Select {
+                table_name, lock, ..
+            } => Node::Scan { table_name, lock },
+            ast::Statement::CopyTo {
+                query,
+                path,
+                format,
+                options,
+            } => Node::CopyTo {
+                source: Box::new(self.build_statment(*query)?),
+                path,
+                format,
+                options,
+            },
+            ast::Statement::CopyFrom {
+                table_name,
+                columns,
+                path,
+                options,
+            } => Node::CopyFrom {
+                table_name,
+                columns: columns.unwrap_or_default(),
+                path,
+                options,
+            },
+            // run by the session, outside of the transactions it plans for
+            stmt @ (ast::Statement::Vacuum
+            | ast::Statement::Begin { .. }
+            | ast::Statement::Commit
+            | ast::Statement::Rollback
+            | ast::Statement::PrepareTransaction { .. }
+            | ast::Statement::CommitPrepared { .. }
+            | ast::Statement::RollbackPrepared { .. }
+            | ast::Statement::Savepoint { .. }
+            | ast::Statement::RollbackTo { .. }
+            | ast::Statement::Release { .. }
+            | ast::Statement::SetTransaction { .. }
+            | ast::Statement::ShowVersion) => {
+                return Err(Error::Internal(format!(
+                    "statement {:?} can't be planned, it's run by the session",
+                    stmt
+                )))
+            }
+        })
     }
 }
@@ -1,14 +1,71 @@
 use serde::{Deserialize, Serialize};
 
-use super::types::{DataType, Value};
+use crate::error::{Error, Result};
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+use super::types::{DataType, Row, Value};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Table {
     pub name: String,
     pub columns: Vec<Column>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+impl Table {
+    // Reconstruct the CREATE TABLE statement of the table
+    pub fn to_ddl(&self) -> Result<String> {
+        let mut columns = Vec::new();
+        for col in self.columns.iter() {
+            let mut ddl = format!("    {} {}", col.name, col.datatype);
+            ddl += if col.nullable { " NULL" } else { " NOT NULL" };
+            match &col.default {
+                // nullable columns default to NULL implicitly
+                Some(Value::Null) | None => {}
+                Some(v) => ddl += &format!(" DEFAULT {}", v.to_literal()?),
+            }
+            columns.push(ddl);
+        }
+
+        Ok(format!(
+            "CREATE TABLE {} (\n{}\n);",
+            self.name,
+            columns.join(",\n")
+        ))
+    }
+
+    // check a row's validity against the columns
+    pub fn check_row(&self, row: &Row) -> Result<()> {
+        if row.len() != self.columns.len() {
+            return Err(Error::Internal(format!(
+                "table {} expects {} values, got {}",
+                self.name,
+                self.columns.len(),
+                row.len()
+            )));
+        }
+
+        for (i, col) in self.columns.iter().enumerate() {
+            match row[i].datatype() {
+                None if col.nullable => {}
+                None => {
+                    return Err(Error::Internal(format!(
+                        "column {} cannot be null",
+                        col.name
+                    )))
+                }
+                Some(dt) if dt != col.datatype => {
+                    return Err(Error::Internal(format!(
+                        "column {} type mismatch",
+                        col.name
+                    )))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Column {
     pub name: String,
     pub datatype: DataType,
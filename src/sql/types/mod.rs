@@ -1,8 +1,12 @@
+use std::fmt::Display;
+
 use serde::{Deserialize, Serialize};
 
+use crate::error::{Error, Result};
+
 use super::parser::ast::{Consts, Expression};
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DataType {
     Boolean,
     Integer,
@@ -10,6 +14,17 @@ pub enum DataType {
     String,
 }
 
+impl Display for DataType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DataType::Boolean => "BOOLEAN",
+            DataType::Integer => "INTEGER",
+            DataType::Float => "FLOAT",
+            DataType::String => "STRING",
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Value {
     Null,
@@ -30,6 +45,38 @@ impl Value {
         }
     }
 
+    // Parse a text field, e.g. from a CSV file, into the given type
+    pub fn parse_as(text: &str, datatype: &DataType) -> Result<Self> {
+        Ok(match datatype {
+            DataType::Boolean => match text.trim().to_lowercase().as_ref() {
+                "true" | "t" | "1" => Self::Boolean(true),
+                "false" | "f" | "0" => Self::Boolean(false),
+                _ => return Err(Error::Parse(format!("invalid boolean {:?}", text))),
+            },
+            DataType::Integer => Self::Integer(text.trim().parse()?),
+            DataType::Float => Self::Float(text.trim().parse()?),
+            DataType::String => Self::String(text.to_string()),
+        })
+    }
+
+    // Format as a SQL literal that parses back into the same value
+    // 'it''s', -1, 2.0, TRUE, NULL
+    pub fn to_literal(&self) -> Result<String> {
+        Ok(match self {
+            Self::Null => "NULL".to_string(),
+            Self::Boolean(true) => "TRUE".to_string(),
+            Self::Boolean(false) => "FALSE".to_string(),
+            Self::Integer(i) => i.to_string(),
+            Self::Float(f) if !f.is_finite() => {
+                return Err(Error::Internal(format!("float {} has no sql literal", f)))
+            }
+            // keep the dot, otherwise the literal is parsed as an integer
+            Self::Float(f) if f.fract() == 0.0 => format!("{:.1}", f),
+            Self::Float(f) => f.to_string(),
+            Self::String(s) => format!("'{}'", s.replace('\'', "''")),
+        })
+    }
+
     pub fn datatype(&self) -> Option<DataType> {
         match self {
             Self::Null => None,
@@ -41,4 +88,16 @@ impl Value {
     }
 }
 
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Float(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
+        }
+    }
+}
+
 pub type Row = Vec<Value>;
@@ -0,0 +1,850 @@
+use std::{
+    collections::VecDeque,
+    ops::{Bound, RangeBounds},
+    path::PathBuf,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use crate::error::{Error, Result};
+
+use self::{
+    page::{entries_size, Node, PageId, MAX_ENTRY_SIZE, PAGE_SIZE},
+    pool::BufferPool,
+};
+
+use super::engine::WriteBatch;
+
+mod page;
+mod pool;
+
+// A key and its value
+type Entry = (Vec<u8>, Vec<u8>);
+
+const META_PAGE: PageId = 0;
+// a node other than the root below this size is merged with a sibling,
+// or takes over some of its entries
+const MIN_NODE_SIZE: usize = PAGE_SIZE / 4;
+
+#[derive(Debug, Clone)]
+pub struct BTreeOptions {
+    // pages held by the buffer pool, which bounds the memory use
+    pub pool_pages: usize,
+}
+
+impl Default for BTreeOptions {
+    fn default() -> Self {
+        Self { pool_pages: 1024 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BTreeStats {
+    // pages of the file, including free pages
+    pub pages: u32,
+    pub cached_pages: usize,
+    // pages read from the file since the engine was opened
+    pub page_reads: u64,
+}
+
+// Define B+tree storage engine
+// The tree is stored in fixed-size pages of one file, page 0 holds the root,
+// the page count and the head of the free list.
+// - internal pages route keys to their children, leaves hold the entries
+//   and are linked to both neighbours for scans
+// - a page that overflows is split in two halves, a page that falls below
+//   a quarter of its size is merged with a sibling or refilled from it
+// Pages are read through a buffer pool with LRU eviction. Writes stay in the
+// pool until Engine::sync, an eviction or closing the engine writes them with
+// a checkpoint; a crash rolls the tree back to the last checkpoint, which is
+// always taken between operations, so a write batch is applied all or nothing.
+// A write which fails midway drops the modified pages and goes back to the
+// last checkpoint as well.
+// A key and value must fit into a quarter of a page, MAX_ENTRY_SIZE bytes.
+// Concurrent readers take turns on the buffer pool for each page they read.
+pub struct BTreeEngine {
+    pool: Mutex<BufferPool>,
+    root: PageId,
+    pages: PageId,
+    // first free page, 0 if there is none
+    free: PageId,
+    // a failed write couldn't be rolled back, the pool may hold part of it,
+    // which must never be written
+    failed: bool,
+}
+
+impl BTreeEngine {
+    pub fn new(file_path: PathBuf) -> Result<Self> {
+        Self::new_with_options(file_path, BTreeOptions::default())
+    }
+
+    pub fn new_with_options(file_path: PathBuf, options: BTreeOptions) -> Result<Self> {
+        let mut pool = BufferPool::new(file_path, options.pool_pages)?;
+        if pool.file_pages()? == 0 {
+            // a new file, the meta page and an empty root leaf
+            pool.put(1, Node::empty_leaf());
+            let mut eng = Self {
+                pool: Mutex::new(pool),
+                root: 1,
+                pages: 2,
+                free: 0,
+                failed: false,
+            };
+            eng.save_meta();
+            eng.pool_mut().checkpoint()?;
+            return Ok(eng);
+        }
+
+        match pool.get(META_PAGE)? {
+            Node::Meta { root, pages, free } => Ok(Self {
+                pool: Mutex::new(pool),
+                root,
+                pages,
+                free,
+                failed: false,
+            }),
+            _ => Err(Error::Internal("btree meta page is missing".to_string())),
+        }
+    }
+
+    pub fn stats(&self) -> BTreeStats {
+        let pool = self.pool();
+        BTreeStats {
+            pages: self.pages,
+            cached_pages: pool.cached_pages(),
+            page_reads: pool.reads,
+        }
+    }
+
+    // The buffer pool for a reader, a panic of another reader leaves it consistent
+    // as reads only cache pages, writers hold the engine exclusively
+    fn pool(&self) -> MutexGuard<'_, BufferPool> {
+        self.pool.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn pool_mut(&mut self) -> &mut BufferPool {
+        self.pool.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn check_failed(&self) -> Result<()> {
+        if self.failed {
+            return Err(Error::Internal(
+                "a failed btree write couldn't be rolled back, the engine must be reopened"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Run a write, one which fails is rolled back to the last checkpoint
+    fn write(&mut self, f: impl FnOnce(&mut Self) -> Result<()>) -> Result<()> {
+        self.check_failed()?;
+        if let Err(err) = f(self) {
+            self.failed = true;
+            self.pool_mut().discard();
+            if let Ok(Node::Meta { root, pages, free }) = self.pool_mut().get(META_PAGE) {
+                self.root = root;
+                self.pages = pages;
+                self.free = free;
+                self.failed = false;
+            }
+            return Err(err);
+        }
+        self.pool_mut().evict()
+    }
+
+    fn save_meta(&mut self) {
+        let meta = Node::Meta {
+            root: self.root,
+            pages: self.pages,
+            free: self.free,
+        };
+        self.pool_mut().put(META_PAGE, meta);
+    }
+
+    // Take a page from the free list, or add one to the end of the file
+    fn allocate(&mut self, node: Node) -> Result<PageId> {
+        let id = if self.free != 0 {
+            let id = self.free;
+            match self.pool_mut().get(id)? {
+                Node::Free { next } => self.free = next,
+                _ => return Err(Error::Internal(format!("free page {} is in use", id))),
+            }
+            id
+        } else {
+            self.pages += 1;
+            self.pages - 1
+        };
+        self.pool_mut().put(id, node);
+        self.save_meta();
+        Ok(id)
+    }
+
+    fn release(&mut self, id: PageId) {
+        let free = self.free;
+        self.pool_mut().put(id, Node::Free { next: free });
+        self.free = id;
+        self.save_meta();
+    }
+
+    // The leaf that holds the key, or the first or last leaf
+    fn find_leaf(&self, pool: &mut BufferPool, key: Bound<&[u8]>, last: bool) -> Result<PageId> {
+        let mut id = self.root;
+        loop {
+            match pool.get(id)? {
+                Node::Internal { keys, children } => {
+                    id = match key {
+                        Bound::Included(key) | Bound::Excluded(key) => {
+                            children[child_index(&keys, key)]
+                        }
+                        Bound::Unbounded if last => children[children.len() - 1],
+                        Bound::Unbounded => children[0],
+                    }
+                }
+                Node::Leaf { .. } => return Ok(id),
+                _ => return Err(Error::Internal(format!("page {} is not a tree node", id))),
+            }
+        }
+    }
+
+    fn check_size(key: &[u8], value: &[u8]) -> Result<()> {
+        if key.len() + value.len() > MAX_ENTRY_SIZE {
+            return Err(Error::Internal(format!(
+                "key and value of {} bytes exceed the limit of {} bytes",
+                key.len() + value.len(),
+                MAX_ENTRY_SIZE
+            )));
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        if let Some((sep, right)) = self.insert_into(self.root, key, value)? {
+            // the root split, the tree grows by one level
+            let root = Node::Internal {
+                keys: vec![sep],
+                children: vec![self.root, right],
+            };
+            self.root = self.allocate(root)?;
+            self.save_meta();
+        }
+        Ok(())
+    }
+
+    // Returns the first key and the page of the new right sibling if the node split
+    fn insert_into(
+        &mut self,
+        id: PageId,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<Option<(Vec<u8>, PageId)>> {
+        match self.pool_mut().get(id)? {
+            Node::Leaf {
+                mut entries,
+                prev,
+                next,
+            } => {
+                match entries.binary_search_by(|(k, _)| k.as_slice().cmp(&key)) {
+                    Ok(i) => entries[i].1 = value,
+                    Err(i) => entries.insert(i, (key, value)),
+                }
+                let node = Node::Leaf {
+                    entries,
+                    prev,
+                    next,
+                };
+                if node.size() <= PAGE_SIZE {
+                    self.pool_mut().put(id, node);
+                    return Ok(None);
+                }
+                self.split_leaf(id, node).map(Some)
+            }
+            Node::Internal {
+                mut keys,
+                mut children,
+            } => {
+                let i = child_index(&keys, &key);
+                let (sep, right) = match self.insert_into(children[i], key, value)? {
+                    Some(split) => split,
+                    None => return Ok(None),
+                };
+                keys.insert(i, sep);
+                children.insert(i + 1, right);
+                let node = Node::Internal { keys, children };
+                if node.size() <= PAGE_SIZE {
+                    self.pool_mut().put(id, node);
+                    return Ok(None);
+                }
+                self.split_internal(id, node).map(Some)
+            }
+            _ => Err(Error::Internal(format!("page {} is not a tree node", id))),
+        }
+    }
+
+    fn split_leaf(&mut self, id: PageId, node: Node) -> Result<(Vec<u8>, PageId)> {
+        let (mut entries, prev, next) = match node {
+            Node::Leaf {
+                entries,
+                prev,
+                next,
+            } => (entries, prev, next),
+            _ => return Err(Error::Internal(format!("page {} is not a leaf", id))),
+        };
+        let at = split_point(entries.iter().map(|(k, v)| k.len() + v.len()));
+        let right_entries = entries.split_off(at);
+        let sep = right_entries[0].0.clone();
+        let right = self.allocate(Node::Leaf {
+            entries: right_entries,
+            prev: id,
+            next,
+        })?;
+        self.set_prev(next, right)?;
+        self.pool_mut().put(
+            id,
+            Node::Leaf {
+                entries,
+                prev,
+                next: right,
+            },
+        );
+        Ok((sep, right))
+    }
+
+    fn split_internal(&mut self, id: PageId, node: Node) -> Result<(Vec<u8>, PageId)> {
+        let (mut keys, mut children) = match node {
+            Node::Internal { keys, children } => (keys, children),
+            _ => return Err(Error::Internal(format!("page {} is not internal", id))),
+        };
+        // the middle key moves up into the parent
+        let mid = split_point(keys.iter().map(|k| k.len())).clamp(1, keys.len() - 2);
+        let right_keys = keys.split_off(mid + 1);
+        let right_children = children.split_off(mid + 1);
+        let sep = keys.pop().unwrap_or_default();
+        let right = self.allocate(Node::Internal {
+            keys: right_keys,
+            children: right_children,
+        })?;
+        self.pool_mut().put(id, Node::Internal { keys, children });
+        Ok((sep, right))
+    }
+
+    // Point the prev link of the leaf to a new page
+    fn set_prev(&mut self, id: PageId, prev: PageId) -> Result<()> {
+        if id == 0 {
+            return Ok(());
+        }
+        match self.pool_mut().get(id)? {
+            Node::Leaf { entries, next, .. } => {
+                self.pool_mut().put(
+                    id,
+                    Node::Leaf {
+                        entries,
+                        prev,
+                        next,
+                    },
+                );
+                Ok(())
+            }
+            _ => Err(Error::Internal(format!("page {} is not a leaf", id))),
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.remove_from(self.root, key)?;
+        // the root has a single child left, the tree shrinks by one level
+        let root = self.root;
+        if let Node::Internal { keys, children } = self.pool_mut().get(root)? {
+            if keys.is_empty() {
+                let old = self.root;
+                self.root = children[0];
+                self.release(old);
+            }
+        }
+        Ok(())
+    }
+
+    // Returns whether the node fell below the minimum size
+    fn remove_from(&mut self, id: PageId, key: &[u8]) -> Result<bool> {
+        match self.pool_mut().get(id)? {
+            Node::Leaf {
+                mut entries,
+                prev,
+                next,
+            } => match entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                Ok(i) => {
+                    entries.remove(i);
+                    let node = Node::Leaf {
+                        entries,
+                        prev,
+                        next,
+                    };
+                    let underflow = node.size() < MIN_NODE_SIZE;
+                    self.pool_mut().put(id, node);
+                    Ok(underflow)
+                }
+                Err(_) => Ok(false),
+            },
+            Node::Internal { keys, children } => {
+                let i = child_index(&keys, key);
+                if !self.remove_from(children[i], key)? {
+                    return Ok(false);
+                }
+                self.rebalance(id, keys, children, i)
+            }
+            _ => Err(Error::Internal(format!("page {} is not a tree node", id))),
+        }
+    }
+
+    // Merge the child i that fell below the minimum size with a sibling,
+    // or move entries over from the sibling if both don't fit into one page
+    fn rebalance(
+        &mut self,
+        id: PageId,
+        mut keys: Vec<Vec<u8>>,
+        mut children: Vec<PageId>,
+        i: usize,
+    ) -> Result<bool> {
+        if children.len() < 2 {
+            return Ok(true);
+        }
+        let l = if i > 0 { i - 1 } else { i };
+        let (left_id, right_id) = (children[l], children[l + 1]);
+        match (self.pool_mut().get(left_id)?, self.pool_mut().get(right_id)?) {
+            (
+                Node::Leaf {
+                    entries: mut combined,
+                    prev,
+                    ..
+                },
+                Node::Leaf {
+                    entries: right_entries,
+                    next,
+                    ..
+                },
+            ) => {
+                combined.extend(right_entries);
+                if Node::empty_leaf().size() + entries_size(&combined) <= PAGE_SIZE {
+                    let merged = Node::Leaf {
+                        entries: combined,
+                        prev,
+                        next,
+                    };
+                    self.pool_mut().put(left_id, merged);
+                    self.set_prev(next, left_id)?;
+                    self.release(right_id);
+                    keys.remove(l);
+                    children.remove(l + 1);
+                } else {
+                    let at = split_point(combined.iter().map(|(k, v)| k.len() + v.len()));
+                    let right_entries = combined.split_off(at);
+                    keys[l] = right_entries[0].0.clone();
+                    let left = Node::Leaf {
+                        entries: combined,
+                        prev,
+                        next: right_id,
+                    };
+                    let right = Node::Leaf {
+                        entries: right_entries,
+                        prev: left_id,
+                        next,
+                    };
+                    self.pool_mut().put(left_id, left);
+                    self.pool_mut().put(right_id, right);
+                }
+            }
+            (
+                Node::Internal {
+                    keys: mut combined_keys,
+                    children: mut combined_children,
+                },
+                Node::Internal {
+                    keys: right_keys,
+                    children: right_children,
+                },
+            ) => {
+                // the separator moves down between the keys of both nodes
+                combined_keys.push(keys[l].clone());
+                combined_keys.extend(right_keys);
+                combined_children.extend(right_children);
+                let merged = Node::Internal {
+                    keys: combined_keys,
+                    children: combined_children,
+                };
+                if merged.size() <= PAGE_SIZE {
+                    self.pool_mut().put(left_id, merged);
+                    self.release(right_id);
+                    keys.remove(l);
+                    children.remove(l + 1);
+                } else {
+                    self.split_internal(left_id, merged)
+                        .and_then(|(sep, new_right)| {
+                            // the split allocated a new right page, reuse the old one
+                            let right = self.pool_mut().get(new_right)?;
+                            self.pool_mut().put(right_id, right);
+                            self.release(new_right);
+                            keys[l] = sep;
+                            Ok(())
+                        })?;
+                }
+            }
+            _ => {
+                return Err(Error::Internal(format!(
+                    "pages {} and {} are not siblings",
+                    left_id, right_id
+                )))
+            }
+        }
+
+        let node = Node::Internal { keys, children };
+        let underflow = node.size() < MIN_NODE_SIZE;
+        self.pool_mut().put(id, node);
+        Ok(underflow)
+    }
+
+    fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check_failed()?;
+        let mut pool = self.pool();
+        let id = self.find_leaf(&mut pool, Bound::Included(key), false)?;
+        let node = pool.get(id)?;
+        pool.evict()?;
+        match node {
+            Node::Leaf { entries, .. } => Ok(entries
+                .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+                .ok()
+                .map(|i| entries[i].1.clone())),
+            _ => Err(Error::Internal(format!("page {} is not a leaf", id))),
+        }
+    }
+}
+
+// The child of an internal node that holds the key
+fn child_index(keys: &[Vec<u8>], key: &[u8]) -> usize {
+    keys.partition_point(|k| k.as_slice() <= key)
+}
+
+// Split sizes into two halves of about the same total,
+// returns the index of the first item of the right half
+fn split_point(sizes: impl ExactSizeIterator<Item = usize> + Clone) -> usize {
+    let len = sizes.len();
+    let total = sizes.clone().sum::<usize>();
+    let mut acc = 0;
+    for (i, size) in sizes.enumerate() {
+        acc += size;
+        if acc * 2 >= total {
+            return (i + 1).clamp(1, len - 1);
+        }
+    }
+    len - 1
+}
+
+impl super::engine::Engine for BTreeEngine {
+    type EngineIterator<'a> = BTreeEngineIterator<'a>;
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        Self::check_size(&key, &value)?;
+        self.write(|eng| eng.insert(key, value))
+    }
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.read(&key)
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> Result<()> {
+        self.write(|eng| eng.remove(&key))
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        // check every write first, the pool must not evict in between
+        for (key, value) in batch.ops() {
+            if let Some(value) = value {
+                Self::check_size(key, value)?;
+            }
+        }
+        self.write(|eng| {
+            for (key, value) in batch.into_ops() {
+                match value {
+                    Some(value) => eng.insert(key, value)?,
+                    None => eng.remove(&key)?,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Self::EngineIterator<'_> {
+        BTreeEngineIterator {
+            engine: self,
+            range: (range.start_bound().cloned(), range.end_bound().cloned()),
+            started: false,
+            front_next: 0,
+            back_next: 0,
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+        }
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.check_failed()?;
+        self.pool_mut().checkpoint()
+    }
+}
+
+impl Drop for BTreeEngine {
+    fn drop(&mut self) {
+        if !self.failed {
+            let _ = self.pool_mut().checkpoint();
+        }
+    }
+}
+
+// Walks the leaves of the range along their links, from both ends
+pub struct BTreeEngineIterator<'a> {
+    engine: &'a BTreeEngine,
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    // the leaves of the range are located on the first call
+    started: bool,
+    // leaves not read yet, from front_next to back_next, 0 once both ends met
+    front_next: PageId,
+    back_next: PageId,
+    front: VecDeque<Entry>,
+    back: VecDeque<Entry>,
+}
+
+impl<'a> BTreeEngineIterator<'a> {
+    fn start(&mut self) -> Result<()> {
+        if !self.started {
+            self.engine.check_failed()?;
+            let start = self.range.0.as_ref().map(|k| k.as_slice());
+            let end = self.range.1.as_ref().map(|k| k.as_slice());
+            let mut pool = self.engine.pool();
+            self.front_next = self.engine.find_leaf(&mut pool, start, false)?;
+            self.back_next = self.engine.find_leaf(&mut pool, end, true)?;
+            self.started = true;
+        }
+        Ok(())
+    }
+
+    // Read the leaf, returns its entries in the range and its links
+    fn read(&mut self, id: PageId) -> Result<(VecDeque<Entry>, PageId, PageId)> {
+        let mut pool = self.engine.pool();
+        let node = pool.get(id)?;
+        pool.evict()?;
+        drop(pool);
+        match node {
+            Node::Leaf {
+                entries,
+                prev,
+                next,
+            } => {
+                let entries = entries
+                    .into_iter()
+                    .filter(|(k, _)| self.range.contains(k))
+                    .collect();
+                Ok((entries, prev, next))
+            }
+            _ => Err(Error::Internal(format!("page {} is not a leaf", id))),
+        }
+    }
+
+    fn try_next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.start()?;
+        while self.front.is_empty() && self.front_next != 0 {
+            let id = self.front_next;
+            let (entries, _, next) = self.read(id)?;
+            if id == self.back_next {
+                self.front_next = 0;
+                self.back_next = 0;
+            } else {
+                self.front_next = next;
+            }
+            self.front = entries;
+        }
+        // all leaves are read, the rest is in the back buffer
+        Ok(self.front.pop_front().or_else(|| self.back.pop_front()))
+    }
+
+    fn try_next_back(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.start()?;
+        while self.back.is_empty() && self.back_next != 0 {
+            let id = self.back_next;
+            let (entries, prev, _) = self.read(id)?;
+            if id == self.front_next {
+                self.front_next = 0;
+                self.back_next = 0;
+            } else {
+                self.back_next = prev;
+            }
+            self.back = entries;
+        }
+        Ok(self.back.pop_back().or_else(|| self.front.pop_back()))
+    }
+}
+
+impl<'a> super::engine::EngineIterator for BTreeEngineIterator<'a> {}
+
+impl<'a> Iterator for BTreeEngineIterator<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
+impl<'a> DoubleEndedIterator for BTreeEngineIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.try_next_back().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{page::PAGE_SIZE, BTreeEngine, BTreeOptions, MAX_ENTRY_SIZE};
+    use crate::{
+        error::Result,
+        storage::engine::{Engine, WriteBatch},
+    };
+    use std::{collections::BTreeMap, ops::Bound, path::Path};
+
+    fn small(path: &Path) -> Result<BTreeEngine> {
+        BTreeEngine::new_with_options(path.to_path_buf(), BTreeOptions { pool_pages: 8 })
+    }
+
+    // a deterministic pseudo-random sequence
+    fn next_rand(state: &mut u64) -> u64 {
+        *state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        *state >> 33
+    }
+
+    fn check(eng: &mut BTreeEngine, model: &BTreeMap<Vec<u8>, Vec<u8>>) -> Result<()> {
+        let expect = model
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(eng.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        let mut reversed = eng.scan(..).rev().collect::<Result<Vec<_>>>()?;
+        reversed.reverse();
+        assert_eq!(reversed, expect);
+
+        let from = b"key0100".to_vec();
+        let to = b"key0300".to_vec();
+        let expect = model
+            .range(from.clone()..to.clone())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(eng.scan(from..to).collect::<Result<Vec<_>>>()?, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_btree_engine_random() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-btree");
+        let mut eng = small(&path)?;
+        let mut model = BTreeMap::new();
+        let mut state = 42;
+        for _ in 0..4000 {
+            let key = format!("key{:04}", next_rand(&mut state) % 500).into_bytes();
+            if next_rand(&mut state).is_multiple_of(3) {
+                eng.delete(key.clone())?;
+                model.remove(&key);
+            } else {
+                let value = vec![b'v'; (next_rand(&mut state) % 200) as usize];
+                eng.set(key.clone(), value.clone())?;
+                model.insert(key, value);
+            }
+        }
+        assert!(eng.stats().pages > 10);
+        assert!(eng.stats().cached_pages <= 8);
+        check(&mut eng, &model)?;
+        for (key, value) in &model {
+            assert_eq!(eng.get(key.clone())?, Some(value.clone()));
+        }
+        drop(eng);
+
+        let mut eng = small(&path)?;
+        check(&mut eng, &model)?;
+
+        // pages freed by merges are reused
+        for key in model.keys() {
+            eng.delete(key.clone())?;
+        }
+        let pages = eng.stats().pages;
+        check(&mut eng, &BTreeMap::new())?;
+        let model = model.into_iter().step_by(2).collect::<BTreeMap<_, _>>();
+        for (key, value) in &model {
+            eng.set(key.clone(), value.clone())?;
+        }
+        assert_eq!(eng.stats().pages, pages);
+        check(&mut eng, &model)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_btree_engine_limits() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut eng = small(&dir.path().join("sqldb-btree"))?;
+        eng.set(b"a".to_vec(), vec![0; MAX_ENTRY_SIZE - 1])?;
+        assert!(eng.set(b"b".to_vec(), vec![0; MAX_ENTRY_SIZE]).is_err());
+
+        // a batch with an oversized write is rejected as a whole
+        let mut batch = WriteBatch::new();
+        batch.delete(b"a".to_vec());
+        batch.set(b"c".to_vec(), vec![0; MAX_ENTRY_SIZE]);
+        assert!(eng.write_batch(batch).is_err());
+        assert_eq!(eng.scan(..).count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_btree_engine_incomplete_checkpoint() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-btree");
+        let mut eng = small(&path)?;
+        eng.set(b"a".to_vec(), b"1".to_vec())?;
+        drop(eng);
+
+        // a crash while writing the checkpoint, the file is unchanged
+        let checkpoint = dir.path().join("sqldb-btree.checkpoint");
+        std::fs::write(&checkpoint, b"partial")?;
+        let eng = small(&path)?;
+        assert!(!checkpoint.exists());
+        assert_eq!(eng.get(b"a".to_vec())?, Some(b"1".to_vec()));
+        Ok(())
+    }
+
+    // a batch failing midway, on a corrupt page, leaves nothing behind,
+    // neither in the pool nor in the file
+    #[test]
+    fn test_btree_engine_failed_batch() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-btree");
+        let mut eng = small(&path)?;
+        for i in 0..500 {
+            eng.set(format!("key{:04}", i).into_bytes(), vec![b'v'; 100])?;
+        }
+        let leaf = eng.find_leaf(&mut eng.pool(), Bound::Included(b"key0400"), false)?;
+        drop(eng);
+
+        let mut data = std::fs::read(&path)?;
+        data[leaf as usize * PAGE_SIZE + 10] ^= 0xff;
+        std::fs::write(&path, data)?;
+        let mut eng = small(&path)?;
+        let mut batch = WriteBatch::new();
+        batch.set(b"key0000".to_vec(), b"new".to_vec());
+        batch.delete(b"key0001".to_vec());
+        batch.set(b"key0400".to_vec(), b"new".to_vec());
+        assert!(eng.write_batch(batch).is_err());
+        assert_eq!(eng.get(b"key0000".to_vec())?, Some(vec![b'v'; 100]));
+        assert_eq!(eng.get(b"key0001".to_vec())?, Some(vec![b'v'; 100]));
+
+        // the engine stays usable, and the batch wasn't written on close
+        eng.set(b"key0002".to_vec(), b"new".to_vec())?;
+        drop(eng);
+        let eng = small(&path)?;
+        assert_eq!(eng.get(b"key0000".to_vec())?, Some(vec![b'v'; 100]));
+        assert_eq!(eng.get(b"key0001".to_vec())?, Some(vec![b'v'; 100]));
+        assert_eq!(eng.get(b"key0002".to_vec())?, Some(b"new".to_vec()));
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+use crate::{
+    error::{Error, Result},
+    storage::checksum::crc32,
+};
+
+pub type PageId = u32;
+
+pub const PAGE_SIZE: usize = 4096;
+const PAGE_MAGIC: u32 = 0x42545031;
+// crc(4), page type(1)
+const PAGE_HEADER_SIZE: usize = 5;
+// prev(4), next(4), count(2)
+const LEAF_HEADER_SIZE: usize = PAGE_HEADER_SIZE + 10;
+// count(2), first child(4)
+const INTERNAL_HEADER_SIZE: usize = PAGE_HEADER_SIZE + 6;
+// key len(2), val len(2)
+const LEAF_ENTRY_HEADER_SIZE: usize = 4;
+// key len(2), child(4)
+const INTERNAL_ENTRY_HEADER_SIZE: usize = 6;
+
+// The largest key and value of an entry, so that a split always leaves
+// two halves which fit into a page
+pub const MAX_ENTRY_SIZE: usize = (PAGE_SIZE - LEAF_HEADER_SIZE) / 4 - LEAF_ENTRY_HEADER_SIZE;
+
+// A page of the B+tree file
+// +-------------+-------------+--------------------------------------+
+// | crc(4)        type(1)       content                              |
+// +-------------+-------------+--------------------------------------+
+// crc is the crc32 of the rest of the page
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    // page 0: magic(4), root(4), page count(4), first free page(4)
+    Meta {
+        root: PageId,
+        pages: PageId,
+        free: PageId,
+    },
+    // children[i] holds the keys in [keys[i-1], keys[i])
+    // count(2), first child(4), then key len(2), key, child(4) per key
+    Internal {
+        keys: Vec<Vec<u8>>,
+        children: Vec<PageId>,
+    },
+    // sorted entries, linked to the neighbouring leaves, 0 if there is none
+    // prev(4), next(4), count(2), then key len(2), val len(2), key, val per entry
+    Leaf {
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        prev: PageId,
+        next: PageId,
+    },
+    // a page on the free list, next(4)
+    Free {
+        next: PageId,
+    },
+}
+
+impl Node {
+    pub fn empty_leaf() -> Self {
+        Node::Leaf {
+            entries: Vec::new(),
+            prev: 0,
+            next: 0,
+        }
+    }
+
+    // Encoded size of the node
+    pub fn size(&self) -> usize {
+        match self {
+            Node::Meta { .. } => PAGE_HEADER_SIZE + 16,
+            Node::Internal { keys, .. } => {
+                INTERNAL_HEADER_SIZE
+                    + keys
+                        .iter()
+                        .map(|k| INTERNAL_ENTRY_HEADER_SIZE + k.len())
+                        .sum::<usize>()
+            }
+            Node::Leaf { entries, .. } => LEAF_HEADER_SIZE + entries_size(entries),
+            Node::Free { .. } => PAGE_HEADER_SIZE + 4,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; PAGE_HEADER_SIZE - 4];
+        match self {
+            Node::Meta { root, pages, free } => {
+                buf[0] = 0;
+                buf.extend_from_slice(&PAGE_MAGIC.to_be_bytes());
+                buf.extend_from_slice(&root.to_be_bytes());
+                buf.extend_from_slice(&pages.to_be_bytes());
+                buf.extend_from_slice(&free.to_be_bytes());
+            }
+            Node::Internal { keys, children } => {
+                buf[0] = 1;
+                buf.extend_from_slice(&(keys.len() as u16).to_be_bytes());
+                buf.extend_from_slice(&children[0].to_be_bytes());
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    buf.extend_from_slice(&(key.len() as u16).to_be_bytes());
+                    buf.extend_from_slice(key);
+                    buf.extend_from_slice(&child.to_be_bytes());
+                }
+            }
+            Node::Leaf {
+                entries,
+                prev,
+                next,
+            } => {
+                buf[0] = 2;
+                buf.extend_from_slice(&prev.to_be_bytes());
+                buf.extend_from_slice(&next.to_be_bytes());
+                buf.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+                for (key, value) in entries {
+                    buf.extend_from_slice(&(key.len() as u16).to_be_bytes());
+                    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                    buf.extend_from_slice(key);
+                    buf.extend_from_slice(value);
+                }
+            }
+            Node::Free { next } => {
+                buf[0] = 3;
+                buf.extend_from_slice(&next.to_be_bytes());
+            }
+        }
+        buf.resize(PAGE_SIZE - 4, 0);
+
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        page.extend_from_slice(&crc32(&buf).to_be_bytes());
+        page.extend_from_slice(&buf);
+        page
+    }
+
+    pub fn decode(page: &[u8]) -> Result<Self> {
+        if page.len() != PAGE_SIZE
+            || crc32(&page[4..]) != u32::from_be_bytes(page[0..4].try_into()?)
+        {
+            return Err(Error::Internal("corrupt btree page".to_string()));
+        }
+        let mut reader = Reader {
+            buf: &page[PAGE_HEADER_SIZE..],
+        };
+        let node = match page[4] {
+            0 => {
+                if reader.u32()? != PAGE_MAGIC {
+                    return Err(Error::Internal("not a btree file".to_string()));
+                }
+                Node::Meta {
+                    root: reader.u32()?,
+                    pages: reader.u32()?,
+                    free: reader.u32()?,
+                }
+            }
+            1 => {
+                let count = reader.u16()? as usize;
+                let mut keys = Vec::with_capacity(count);
+                let mut children = vec![reader.u32()?];
+                for _ in 0..count {
+                    let key_size = reader.u16()? as usize;
+                    keys.push(reader.bytes(key_size)?);
+                    children.push(reader.u32()?);
+                }
+                Node::Internal { keys, children }
+            }
+            2 => {
+                let prev = reader.u32()?;
+                let next = reader.u32()?;
+                let count = reader.u16()? as usize;
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key_size = reader.u16()? as usize;
+                    let val_size = reader.u16()? as usize;
+                    let key = reader.bytes(key_size)?;
+                    entries.push((key, reader.bytes(val_size)?));
+                }
+                Node::Leaf {
+                    entries,
+                    prev,
+                    next,
+                }
+            }
+            3 => Node::Free {
+                next: reader.u32()?,
+            },
+            t => return Err(Error::Internal(format!("unknown btree page type {}", t))),
+        };
+        Ok(node)
+    }
+}
+
+pub fn entries_size(entries: &[(Vec<u8>, Vec<u8>)]) -> usize {
+    entries
+        .iter()
+        .map(|(k, v)| LEAF_ENTRY_HEADER_SIZE + k.len() + v.len())
+        .sum()
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<Vec<u8>> {
+        if n > self.buf.len() {
+            return Err(Error::Internal("corrupt btree page".to_string()));
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head.to_vec())
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().map_err(
+            |_| Error::Internal("corrupt btree page".to_string()),
+        )?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().map_err(
+            |_| Error::Internal("corrupt btree page".to_string()),
+        )?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Node, MAX_ENTRY_SIZE, PAGE_SIZE};
+    use crate::error::Result;
+
+    #[test]
+    fn test_page_codec() -> Result<()> {
+        let nodes = vec![
+            Node::Meta {
+                root: 3,
+                pages: 10,
+                free: 7,
+            },
+            Node::Internal {
+                keys: vec![b"b".to_vec(), b"d".to_vec()],
+                children: vec![1, 2, 4],
+            },
+            Node::Leaf {
+                entries: vec![
+                    (b"a".to_vec(), b"1".to_vec()),
+                    (b"b".to_vec(), vec![]),
+                    (vec![7; MAX_ENTRY_SIZE - 1], vec![1]),
+                ],
+                prev: 5,
+                next: 0,
+            },
+            Node::Free { next: 9 },
+            Node::empty_leaf(),
+        ];
+        for node in nodes {
+            let page = node.encode();
+            assert_eq!(page.len(), PAGE_SIZE);
+            assert!(node.size() <= PAGE_SIZE);
+            assert_eq!(Node::decode(&page)?, node);
+        }
+
+        let mut page = Node::Free { next: 9 }.encode();
+        page[100] ^= 1;
+        assert!(Node::decode(&page).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use fs4::FileExt;
+
+use crate::{
+    error::{Error, Result},
+    storage::{
+        checksum::{crc32, Crc32},
+        disk::sync_dir,
+    },
+};
+
+use super::page::{Node, PageId, PAGE_SIZE};
+
+const CHECKPOINT_MAGIC: u32 = 0x43484b31;
+
+struct Frame {
+    node: Node,
+    dirty: bool,
+    // last access, for the LRU eviction
+    tick: u64,
+}
+
+// Caches decoded pages of the B+tree file, holding at most capacity pages
+// between operations
+// Modified pages are only written back by a checkpoint, which first writes
+// the page images into <file>.checkpoint, so a crash while writing the pages
+// in place is repaired by replaying them on open
+pub struct BufferPool {
+    file: File,
+    checkpoint_path: PathBuf,
+    capacity: usize,
+    frames: HashMap<PageId, Frame>,
+    // tick -> page, least recently used first
+    lru: BTreeMap<u64, PageId>,
+    tick: u64,
+    // number of pages read from the file
+    pub reads: u64,
+}
+
+impl BufferPool {
+    pub fn new(file_path: PathBuf, capacity: usize) -> Result<Self> {
+        if let Some(dir) = file_path.parent() {
+            if !dir.as_os_str().is_empty() && !dir.exists() {
+                std::fs::create_dir_all(dir)?;
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&file_path)?;
+
+        // Make sure only one service is using this file
+        file.try_lock_exclusive()?;
+
+        let mut checkpoint_path = file_path.into_os_string();
+        checkpoint_path.push(".checkpoint");
+        let mut pool = Self {
+            file,
+            checkpoint_path: PathBuf::from(checkpoint_path),
+            capacity: capacity.max(1),
+            frames: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            reads: 0,
+        };
+        pool.recover()?;
+        Ok(pool)
+    }
+
+    // Number of pages in the file
+    pub fn file_pages(&self) -> Result<PageId> {
+        Ok((self.file.metadata()?.len() / PAGE_SIZE as u64) as PageId)
+    }
+
+    pub fn cached_pages(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn get(&mut self, id: PageId) -> Result<Node> {
+        if !self.frames.contains_key(&id) {
+            let mut page = vec![0; PAGE_SIZE];
+            self.file
+                .seek(SeekFrom::Start(id as u64 * PAGE_SIZE as u64))?;
+            self.file.read_exact(&mut page)?;
+            self.reads += 1;
+            let node = Node::decode(&page)?;
+            self.frames.insert(
+                id,
+                Frame {
+                    node,
+                    dirty: false,
+                    tick: 0,
+                },
+            );
+        }
+        self.touch(id);
+        match self.frames.get(&id) {
+            Some(frame) => Ok(frame.node.clone()),
+            None => Err(Error::Internal(format!("page {} not cached", id))),
+        }
+    }
+
+    pub fn put(&mut self, id: PageId, node: Node) {
+        match self.frames.get_mut(&id) {
+            Some(frame) => {
+                frame.node = node;
+                frame.dirty = true;
+            }
+            None => {
+                let frame = Frame {
+                    node,
+                    dirty: true,
+                    tick: 0,
+                };
+                self.frames.insert(id, frame);
+            }
+        }
+        self.touch(id);
+    }
+
+    fn touch(&mut self, id: PageId) {
+        self.tick += 1;
+        if let Some(frame) = self.frames.get_mut(&id) {
+            self.lru.remove(&frame.tick);
+            frame.tick = self.tick;
+            self.lru.insert(self.tick, id);
+        }
+    }
+
+    // Evict least recently used pages down to the capacity
+    // Must only be called while the tree is consistent, since evicting
+    // a modified page writes all modified pages
+    pub fn evict(&mut self) -> Result<()> {
+        while self.frames.len() > self.capacity {
+            let (tick, id) = match self.lru.iter().next() {
+                Some((tick, id)) => (*tick, *id),
+                None => break,
+            };
+            if self.frames.get(&id).is_some_and(|f| f.dirty) {
+                self.checkpoint()?;
+            }
+            self.lru.remove(&tick);
+            self.frames.remove(&id);
+        }
+        Ok(())
+    }
+
+    // Drop the modified pages, the pages are read again as of the last
+    // checkpoint
+    pub fn discard(&mut self) {
+        let dirty = self
+            .frames
+            .iter()
+            .filter(|(_, frame)| frame.dirty)
+            .map(|(id, frame)| (*id, frame.tick))
+            .collect::<Vec<_>>();
+        for (id, tick) in dirty {
+            self.lru.remove(&tick);
+            self.frames.remove(&id);
+        }
+    }
+
+    // Write all modified pages into the file
+    // +-------------+-------------+------------------------------------+
+    // | page id(4)    page(4096)    ...     magic(4), count(4), crc(4) |
+    // +-------------+-------------+------------------------------------+
+    pub fn checkpoint(&mut self) -> Result<()> {
+        let mut dirty = self
+            .frames
+            .iter()
+            .filter(|(_, frame)| frame.dirty)
+            .map(|(id, frame)| (*id, frame.node.encode()))
+            .collect::<Vec<_>>();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        dirty.sort_by_key(|(id, _)| *id);
+
+        // 1. the page images, synced before any page is overwritten
+        let mut crc = Crc32::new();
+        let mut writer = BufWriter::new(File::create(&self.checkpoint_path)?);
+        for (id, page) in &dirty {
+            crc.update(&id.to_be_bytes());
+            crc.update(page);
+            writer.write_all(&id.to_be_bytes())?;
+            writer.write_all(page)?;
+        }
+        writer.write_all(&CHECKPOINT_MAGIC.to_be_bytes())?;
+        writer.write_all(&(dirty.len() as u32).to_be_bytes())?;
+        writer.write_all(&crc.finish().to_be_bytes())?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        sync_dir(&self.checkpoint_path)?;
+
+        // 2. the pages in place
+        self.write_pages(&dirty)?;
+
+        // 3. the images aren't needed anymore
+        std::fs::remove_file(&self.checkpoint_path)?;
+        sync_dir(&self.checkpoint_path)?;
+        for frame in self.frames.values_mut() {
+            frame.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn write_pages(&mut self, pages: &[(PageId, Vec<u8>)]) -> Result<()> {
+        for (id, page) in pages {
+            self.file
+                .seek(SeekFrom::Start(*id as u64 * PAGE_SIZE as u64))?;
+            self.file.write_all(page)?;
+        }
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    // Replay a complete checkpoint left by a crash, an incomplete one
+    // never touched the file and is dropped
+    fn recover(&mut self) -> Result<()> {
+        if !self.checkpoint_path.exists() {
+            return Ok(());
+        }
+        let data = std::fs::read(&self.checkpoint_path)?;
+        let record = 4 + PAGE_SIZE;
+        let mut pages = Vec::new();
+        if data.len() >= 12 && (data.len() - 12) % record == 0 {
+            let (body, trailer) = data.split_at(data.len() - 12);
+            let magic = u32::from_be_bytes(trailer[0..4].try_into()?);
+            let count = u32::from_be_bytes(trailer[4..8].try_into()?) as usize;
+            let crc = u32::from_be_bytes(trailer[8..12].try_into()?);
+            if magic == CHECKPOINT_MAGIC && count * record == body.len() && crc32(body) == crc {
+                for chunk in body.chunks(record) {
+                    let id = u32::from_be_bytes(chunk[0..4].try_into()?);
+                    pages.push((id, chunk[4..].to_vec()));
+                }
+            }
+        }
+        if !pages.is_empty() {
+            self.write_pages(&pages)?;
+        }
+        std::fs::remove_file(&self.checkpoint_path)?;
+        sync_dir(&self.checkpoint_path)?;
+        Ok(())
+    }
+}
@@ -1,30 +1,305 @@
 use std::{
     collections::{btree_map, BTreeMap},
+    ffi::OsString,
     fs::{File, OpenOptions},
     io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+    os::unix::fs::FileExt as _,
+    path::{Path, PathBuf},
+    thread::JoinHandle,
+    time::{Duration, Instant},
     vec,
 };
 
 use fs4::FileExt;
 
-use crate::error::Result;
+use crate::error::{Error, Result};
 
-pub type KeyDir = BTreeMap<Vec<u8>, (u64, u32)>;
-const LOG_HEADER_SIZE: u32 = 8;
+use super::{
+    checksum::{crc32, Crc32},
+    engine::WriteBatch,
+};
+
+// key -> (segment, value offset, value size)
+pub type KeyDir = BTreeMap<Vec<u8>, (u64, u64, u32)>;
+// Every log file starts with the magic and the version of its format
+// +-------------+-------------+
+// | magic(4)      version(4)  |
+// +-------------+-------------+
+const LOG_MAGIC: [u8; 4] = *b"SQLG";
+const LOG_VERSION: u32 = 1;
+const FILE_HEADER_SIZE: u64 = 8;
+const LOG_HEADER_SIZE: u32 = 12;
+// key len of a write batch record
+const BATCH_MARKER: u32 = u32::MAX;
+// key len and val len of an entry inside a write batch
+const BATCH_ENTRY_HEADER_SIZE: u64 = 8;
+// key len, val len and val offset in front of each key of a hint file
+const HINT_HEADER_SIZE: usize = 20;
+
+// When written entries are synced from the OS page cache to the disk
+// A write that is not synced yet can be lost on power failure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    // sync after every write, slowest but nothing is ever lost
+    Always,
+    // sync after every n writes, at most n-1 writes are lost
+    EveryN(u64),
+    // sync on the first write after the interval has passed since the last sync
+    Interval(Duration),
+    // only sync on an explicit Engine::sync, e.g. at transaction commit
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiskOptions {
+    pub sync_policy: SyncPolicy,
+    // the active segment is sealed once it reaches this size
+    pub segment_size: u64,
+    // compact once this fraction of the log is garbage
+    pub compact_ratio: Option<f64>,
+    // compact once the garbage reaches this many bytes
+    pub compact_garbage: Option<u64>,
+    // never compact automatically while the log is smaller than this
+    pub compact_min_size: u64,
+    // write the compacted log on a background thread while the engine keeps serving
+    // the merged segment is installed by the next write or sync once it's done,
+    // or when the engine is dropped; reads take &self and keep using the old
+    // segments until then
+    pub compact_in_background: bool,
+}
+
+impl Default for DiskOptions {
+    fn default() -> Self {
+        Self {
+            sync_policy: SyncPolicy::Never,
+            segment_size: 64 * 1024 * 1024,
+            compact_ratio: Some(0.5),
+            compact_garbage: None,
+            compact_min_size: 4 * 1024 * 1024,
+            compact_in_background: true,
+        }
+    }
+}
+
+// Size and garbage of the log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiskStats {
+    // bytes of the log entries, including overwritten and deleted ones
+    pub log_size: u64,
+    // bytes the live keys would take in a compacted log
+    pub live_size: u64,
+    // number of compactions done so far
+    pub compactions: u64,
+    // the torn tail cut off the log when the engine was opened
+    pub truncated: Option<TornTail>,
+}
+
+// The end of a segment dropped by the startup scan, left by a crash in the
+// middle of a write
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TornTail {
+    pub segment: u64,
+    // where the segment was cut, and the number of bytes dropped after it
+    pub offset: u64,
+    pub dropped: u64,
+}
+
+impl DiskStats {
+    pub fn garbage(&self) -> u64 {
+        self.log_size.saturating_sub(self.live_size)
+    }
+}
 
 // Define disk storage engine
+// The log is split into segments, numbered in the order they were written:
+// - the active segment is the file at the log path, new writes are appended to it
+// - sealed segments <log>.<seq> are immutable, the active segment is renamed
+//   into one once it reaches DiskOptions::segment_size
+// - compaction merges all sealed segments into one, along with a hint file
+//   <log>.<seq>.hint listing the keydir entries of the merged segment,
+//   so opening the engine reads the hints instead of scanning the data
 pub struct DiskEngine {
     keydir: KeyDir,
+    // the active segment
     log: Log,
+    active: u64,
+    // all segments including the active one, for reading values
+    segments: BTreeMap<u64, Segment>,
+    options: DiskOptions,
+    // writes since the last sync
+    unsynced: u64,
+    last_sync: Instant,
+    // number of syncs done so far
+    syncs: u64,
+    stats: DiskStats,
+    compaction: Option<Compaction>,
+}
+
+// A compaction merging the sealed segments up to the target on its own thread
+// The merged segment and its hint are written into <log>.compact and <log>.compact.hint.
+// Once they are durable, the target is recorded in <log>.compact.target, then they are
+// moved over the target segment, the older segments are deleted and the record removed.
+// The merged segment holds no tombstones, so replaying an older segment before it
+// could bring back deleted keys:
+// - a crash before the rename leaves the segments untouched and stale compact files,
+//   which are removed on the next open
+// - a crash after the rename leaves the record without the compact file, the next
+//   open deletes the older segments named by it before reading any segment
+struct Compaction {
+    target: u64,
+    handle: JoinHandle<Result<(Log, KeyDir)>>,
+}
+
+// A data file of the log, read through its own handle
+struct Segment {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl Segment {
+    // A positioned read, which doesn't move the shared file offset,
+    // so concurrent readers can use the same file
+    fn read_value(&self, offset: u64, val_size: u32) -> Result<Vec<u8>> {
+        let mut buf = vec![0; val_size as usize];
+        self.file.read_exact_at(&mut buf, offset)?;
+        Ok(buf)
+    }
+}
+
+// Size of a key in a compacted log
+fn entry_size(key: &[u8], val_size: u32) -> u64 {
+    LOG_HEADER_SIZE as u64 + key.len() as u64 + val_size as u64
 }
 
 impl DiskEngine {
     pub fn new(file_path: PathBuf) -> Result<Self> {
+        Self::new_with_options(file_path, DiskOptions::default())
+    }
+
+    pub fn new_with_options(file_path: PathBuf, options: DiskOptions) -> Result<Self> {
         let mut log = Log::new(file_path)?;
-        // Restore keydir from log
-        let keydir = log.build_keydir()?;
-        Ok(Self { keydir, log })
+        recover_compaction(&log.file_path)?;
+
+        // Restore keydir from the sealed segments, then the active one
+        let mut keydir = KeyDir::new();
+        let mut segments = BTreeMap::new();
+        for (seq, path) in list_segments(&log.file_path)? {
+            let file = File::open(&path)?;
+            check_file_header(&file, &path)?;
+            let size = file.metadata()?.len();
+            if !load_hint(&path, seq, size, &mut keydir)? {
+                // a sealed segment was synced completely, so it can't be torn,
+                // a bad entry in it is corruption which mustn't be skipped
+                let end = scan_segment(&file, seq, size, &mut keydir)?;
+                if end < size {
+                    return Err(Error::Internal(format!(
+                        "corrupt entry at offset {} of segment {}",
+                        end,
+                        path.display()
+                    )));
+                }
+            }
+            segments.insert(seq, Segment { path, file, size });
+        }
+        let active = segments.keys().last().map_or(1, |seq| seq + 1);
+        let truncated = log.build_keydir(active, &mut keydir)?;
+        segments.insert(active, log.open_segment()?);
+
+        let stats = DiskStats {
+            log_size: entries_size(&segments),
+            live_size: keydir.iter().map(|(k, (_, _, v))| entry_size(k, *v)).sum(),
+            compactions: 0,
+            truncated,
+        };
+        Ok(Self {
+            keydir,
+            log,
+            active,
+            segments,
+            options,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            syncs: 0,
+            stats,
+            compaction: None,
+        })
+    }
+
+    pub fn stats(&self) -> DiskStats {
+        self.stats
+    }
+
+    // The number of syncs to disk since the engine was opened
+    pub fn sync_count(&self) -> u64 {
+        self.syncs
+    }
+
+    // The sequence numbers of the segments, the last one is the active segment
+    pub fn segments(&self) -> Vec<u64> {
+        self.segments.keys().copied().collect()
+    }
+
+    // Sync a write according to the sync policy
+    fn sync_written(&mut self) -> Result<()> {
+        self.unsynced += 1;
+        let due = match self.options.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryN(n) => self.unsynced >= n,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if due {
+            self.sync_log()?;
+        }
+        Ok(())
+    }
+
+    fn sync_log(&mut self) -> Result<()> {
+        self.log.file.sync_data()?;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        self.syncs += 1;
+        Ok(())
+    }
+
+    // Account for a record appended to the active segment, ending at the offset
+    fn appended(&mut self, end: u64) -> Result<()> {
+        let segment = self.segment_mut(self.active)?;
+        let grown = end - segment.size;
+        segment.size = end;
+        self.stats.log_size += grown;
+
+        self.sync_written()?;
+        if end >= self.options.segment_size {
+            self.rotate()?;
+        }
+        self.maybe_compact()
+    }
+
+    // Seal the active segment and start a new one
+    fn rotate(&mut self) -> Result<()> {
+        if self.segment_mut(self.active)?.size <= FILE_HEADER_SIZE {
+            return Ok(());
+        }
+        // a sealed segment is always complete on disk
+        self.sync_log()?;
+        let sealed_path = segment_path(&self.log.file_path, self.active);
+        std::fs::rename(&self.log.file_path, &sealed_path)?;
+        self.segment_mut(self.active)?.path = sealed_path;
+
+        self.log = Log::new(self.log.file_path.clone())?;
+        sync_dir(&self.log.file_path)?;
+        self.active += 1;
+        let segment = self.log.open_segment()?;
+        self.segments.insert(self.active, segment);
+        Ok(())
+    }
+
+    fn segment_mut(&mut self, seq: u64) -> Result<&mut Segment> {
+        self.segments
+            .get_mut(&seq)
+            .ok_or_else(|| Error::Internal(format!("segment {} not found", seq)))
     }
 
     pub fn new_compact(file_path: PathBuf) -> Result<Self> {
@@ -33,35 +308,560 @@ impl DiskEngine {
         Ok(eng)
     }
 
-    fn compact(&mut self) -> Result<()> {
-        // Open a new temporary log file 
-        let mut new_path = self.log.file_path.clone();
-        new_path.set_extension("compact");
+    // Compact the log and wait for it to finish
+    pub fn compact(&mut self) -> Result<()> {
+        self.finish_compaction()?;
+        self.start_compaction()?;
+        self.finish_compaction()
+    }
+
+    // Wait for a background compaction to finish and install the merged segment
+    pub fn finish_compaction(&mut self) -> Result<()> {
+        let compaction = match self.compaction.take() {
+            Some(compaction) => compaction,
+            None => return Ok(()),
+        };
+        let target = compaction.target;
+        let (merged_log, merged) = compaction
+            .handle
+            .join()
+            .map_err(|_| Error::Internal("compaction thread panicked".to_string()))??;
 
-        let mut new_log = Log::new(new_path)?;
-        let mut new_keydir = KeyDir::new();
+        // Keys still located in the merged segments move to the merged one,
+        // they are unchanged since the compaction started
+        let mut moved = Vec::new();
+        for (key, (seq, _, _)) in self.keydir.iter() {
+            if *seq > target {
+                continue;
+            }
+            match merged.get(key) {
+                Some(pos) => moved.push((key.clone(), *pos)),
+                None => {
+                    return Err(Error::Internal(format!(
+                        "key {:?} is missing from the merged segment",
+                        key
+                    )))
+                }
+            }
+        }
+
+        // Record the target once the merged files are durable, from here on
+        // the older segments are obsolete
+        sync_dir(&merged_log.file_path)?;
+        let record_path = path_with_suffix(&self.log.file_path, ".compact.target");
+        let mut record = File::create(&record_path)?;
+        write!(record, "{}", target)?;
+        record.sync_all()?;
+        sync_dir(&record_path)?;
 
-        // Re-write data to the temp file 
-        for (key, (offset, val_size)) in self.keydir.iter() {
-            // Read value
-            let value = self.log.read_value(*offset, *val_size)?;
-            let (new_offset, new_size) = new_log.write_entry(key, Some(&value))?;
+        // Move the merged segment over the target, a stale hint of the target
+        // must never be paired with the new data
+        let target_path = segment_path(&self.log.file_path, target);
+        let hint_path = path_with_suffix(&target_path, ".hint");
+        if hint_path.exists() {
+            std::fs::remove_file(&hint_path)?;
+        }
+        std::fs::rename(&merged_log.file_path, &target_path)?;
+        std::fs::rename(path_with_suffix(&merged_log.file_path, ".hint"), &hint_path)?;
+        sync_dir(&target_path)?;
+
+        // Delete the older segments in order
+        let older = self
+            .segments
+            .range(..target)
+            .map(|(seq, _)| *seq)
+            .collect::<Vec<_>>();
+        for seq in older {
+            if let Some(segment) = self.segments.remove(&seq) {
+                let hint_path = path_with_suffix(&segment.path, ".hint");
+                if hint_path.exists() {
+                    std::fs::remove_file(&hint_path)?;
+                }
+                std::fs::remove_file(&segment.path)?;
+            }
+        }
+        sync_dir(&target_path)?;
+        std::fs::remove_file(&record_path)?;
+        sync_dir(&record_path)?;
+
+        let segment = Segment {
+            path: target_path,
+            size: merged_log.file.metadata()?.len(),
+            file: merged_log.file,
+        };
+        self.segments.insert(target, segment);
+        for (key, pos) in moved {
+            self.keydir.insert(key, pos);
+        }
+        self.stats.log_size = entries_size(&self.segments);
+        self.stats.compactions += 1;
+        Ok(())
+    }
+
+    fn start_compaction(&mut self) -> Result<()> {
+        // Seal the active segment, so the garbage in it is merged too
+        self.rotate()?;
+        let target = match self.segments.range(..self.active).last() {
+            Some((seq, _)) => *seq,
+            None => return Ok(()),
+        };
+
+        let snapshot = DiskSnapshot {
+            keydir: self
+                .keydir
+                .iter()
+                .filter(|(_, (seq, _, _))| *seq <= target)
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+            files: self.open_segments(..=target)?,
+        };
+        let compact_path = path_with_suffix(&self.log.file_path, ".compact");
+        let handle = std::thread::spawn(move || {
+            let mut log = Log::create(compact_path)?;
+            let keydir = snapshot.write_compacted(&mut log, target)?;
+            log.file.sync_all()?;
+            write_hint(&path_with_suffix(&log.file_path, ".hint"), &keydir)?;
+            Ok((log, keydir))
+        });
+        self.compaction = Some(Compaction { target, handle });
+        Ok(())
+    }
+
+    // Install a background compaction if it's done, returns whether one is still running
+    fn install_finished(&mut self) -> Result<bool> {
+        match &self.compaction {
+            Some(compaction) if compaction.handle.is_finished() => {
+                self.finish_compaction()?;
+                Ok(false)
+            }
+            Some(_) => Ok(true),
+            None => Ok(false),
+        }
+    }
 
-            new_keydir.insert(
+    // Install a finished background compaction, or start one once the log
+    // has crossed a garbage threshold
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.install_finished()? {
+            return Ok(());
+        }
+
+        let stats = self.stats;
+        if stats.log_size < self.options.compact_min_size {
+            return Ok(());
+        }
+        let by_ratio = self
+            .options
+            .compact_ratio
+            .is_some_and(|ratio| stats.garbage() as f64 >= ratio * stats.log_size as f64);
+        let by_size = self
+            .options
+            .compact_garbage
+            .is_some_and(|size| stats.garbage() >= size);
+        if by_ratio || by_size {
+            self.start_compaction()?;
+            if !self.options.compact_in_background {
+                self.finish_compaction()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn index_insert(&mut self, key: Vec<u8>, offset: u64, val_size: u32) {
+        self.stats.live_size += entry_size(&key, val_size);
+        let pos = (self.active, offset, val_size);
+        if let Some((_, _, old_size)) = self.keydir.insert(key.clone(), pos) {
+            self.stats.live_size -= entry_size(&key, old_size);
+        }
+    }
+
+    fn index_remove(&mut self, key: &[u8]) {
+        if let Some((_, _, old_size)) = self.keydir.remove(key) {
+            self.stats.live_size -= entry_size(key, old_size);
+        }
+    }
+
+    // Open separate read handles of the segments
+    fn open_segments(&self, range: impl std::ops::RangeBounds<u64>) -> Result<BTreeMap<u64, File>> {
+        let mut files = BTreeMap::new();
+        for (seq, segment) in self.segments.range(range) {
+            files.insert(*seq, File::open(&segment.path)?);
+        }
+        Ok(files)
+    }
+
+    // Take a point-in-time view of the engine for a backup
+    pub fn snapshot(&self) -> Result<DiskSnapshot> {
+        Ok(DiskSnapshot {
+            keydir: self.keydir.clone(),
+            files: self.open_segments(..)?,
+        })
+    }
+
+    // Write a compacted copy of the current data into the target file
+    pub fn backup(&self, target: PathBuf) -> Result<BackupManifest> {
+        self.snapshot()?.write_backup(target)
+    }
+
+    // Check a backup file against its manifest
+    // a verified backup can be opened directly with DiskEngine::new
+    pub fn verify_backup(backup: PathBuf) -> Result<BackupManifest> {
+        let manifest = BackupManifest::read(&backup)?;
+        let (size, checksum) = file_checksum(&backup)?;
+        if size != manifest.size || checksum != manifest.checksum {
+            return Err(Error::Internal(format!(
+                "backup {} does not match its manifest",
+                backup.display()
+            )));
+        }
+
+        // the backup is compacted, every entry is a live key
+        let mut log = Log::new(backup)?;
+        let mut keydir = KeyDir::new();
+        log.build_keydir(1, &mut keydir)?;
+        let entries = keydir.len() as u64;
+        if entries != manifest.entries {
+            return Err(Error::Internal(format!(
+                "backup has {} entries, manifest expects {}",
+                entries, manifest.entries
+            )));
+        }
+        Ok(manifest)
+    }
+}
+
+// A point-in-time view of a DiskEngine
+// The keydir is copied and values are read through separate file handles,
+// so a backup can be written while the engine keeps serving writes:
+// - segments are append-only, so new writes never touch the snapshot offsets
+// - a compaction replaces or deletes segment files, the handles keep the old ones
+pub struct DiskSnapshot {
+    keydir: KeyDir,
+    files: BTreeMap<u64, File>,
+}
+
+impl DiskSnapshot {
+    // Write the snapshot into the target file, followed by the manifest
+    // The data goes into a temporary file first, which holds the file lock
+    // and is renamed into place once complete and synced
+    pub fn write_backup(&self, target: PathBuf) -> Result<BackupManifest> {
+        if target.exists() {
+            return Err(Error::Internal(format!(
+                "backup target {} already exists",
+                target.display()
+            )));
+        }
+
+        let tmp_path = path_with_suffix(&target, ".tmp");
+        let mut log = Log::create(tmp_path.clone())?;
+        self.write_compacted(&mut log, 1)?;
+        log.file.sync_all()?;
+
+        let (size, checksum) = file_checksum(&tmp_path)?;
+        let manifest = BackupManifest {
+            entries: self.keydir.len() as u64,
+            size,
+            checksum,
+        };
+        std::fs::rename(&tmp_path, &target)?;
+        sync_dir(&target)?;
+        manifest.write(&target)?;
+        Ok(manifest)
+    }
+
+    // Write every key once into the empty log as the segment seq,
+    // returns the keydir of the new segment
+    fn write_compacted(&self, log: &mut Log, seq: u64) -> Result<KeyDir> {
+        let mut keydir = KeyDir::new();
+        for (key, (old_seq, offset, val_size)) in self.keydir.iter() {
+            let value = self.read_value(*old_seq, *offset, *val_size)?;
+            let (new_offset, new_size) = log.write_entry(key, Some(&value))?;
+            keydir.insert(
                 key.clone(),
-                (new_offset + new_size as u64 - *val_size as u64, *val_size),
+                (
+                    seq,
+                    new_offset + new_size as u64 - *val_size as u64,
+                    *val_size,
+                ),
             );
         }
+        Ok(keydir)
+    }
 
-        // Convert the temp file into official
-        std::fs::rename(&new_log.file_path, &self.log.file_path)?;
+    fn read_value(&self, seq: u64, offset: u64, val_size: u32) -> Result<Vec<u8>> {
+        let file = self
+            .files
+            .get(&seq)
+            .ok_or_else(|| Error::Internal(format!("segment {} not found", seq)))?;
+        let mut buf = vec![0; val_size as usize];
+        file.read_exact_at(&mut buf, offset)?;
+        Ok(buf)
+    }
+}
 
-        new_log.file_path = self.log.file_path.clone();
-        self.keydir = new_keydir;
-        self.log = new_log;
+// Summary of a backup, stored next to it in <backup>.manifest
+// entries 3
+// size 57
+// crc32 8d2b0a31
+#[derive(Debug, PartialEq)]
+pub struct BackupManifest {
+    pub entries: u64,
+    pub size: u64,
+    pub checksum: u32,
+}
 
+impl BackupManifest {
+    fn write(&self, backup: &Path) -> Result<()> {
+        let path = path_with_suffix(backup, ".manifest");
+        let tmp_path = path_with_suffix(&path, ".tmp");
+        let mut file = File::create(&tmp_path)?;
+        write!(
+            file,
+            "entries {}\nsize {}\ncrc32 {:08x}\n",
+            self.entries, self.size, self.checksum
+        )?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, &path)?;
+        sync_dir(&path)?;
         Ok(())
     }
+
+    fn read(backup: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path_with_suffix(backup, ".manifest"))?;
+        let mut manifest = Self {
+            entries: 0,
+            size: 0,
+            checksum: 0,
+        };
+        for line in content.lines() {
+            match line.split_once(' ') {
+                Some(("entries", v)) => manifest.entries = v.parse()?,
+                Some(("size", v)) => manifest.size = v.parse()?,
+                Some(("crc32", v)) => manifest.checksum = u32::from_str_radix(v, 16)?,
+                _ => return Err(Error::Internal(format!("invalid manifest line {}", line))),
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+// db.log -> db.log.manifest
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut p = OsString::from(path.as_os_str());
+    p.push(suffix);
+    PathBuf::from(p)
+}
+
+// db.log -> db.log.00000003
+fn segment_path(log_path: &Path, seq: u64) -> PathBuf {
+    path_with_suffix(log_path, &format!(".{:08}", seq))
+}
+
+// Find the sealed segments of the log, ordered by their sequence number
+fn list_segments(log_path: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let dir = match log_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = match log_path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => format!("{}.", name),
+        None => return Ok(Vec::new()),
+    };
+
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let seq = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .filter(|seq| !seq.is_empty() && seq.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|seq| seq.parse::<u64>().ok());
+        if let Some(seq) = seq {
+            segments.push((seq, segment_path(log_path, seq)));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+// Finish or discard a compaction interrupted by a crash, see Compaction
+fn recover_compaction(log_path: &Path) -> Result<()> {
+    let compact_path = path_with_suffix(log_path, ".compact");
+    let record_path = path_with_suffix(log_path, ".compact.target");
+    // the record is synced before the rename, an unreadable one means the
+    // rename never happened
+    let target = match std::fs::read_to_string(&record_path) {
+        Ok(target) => target.parse::<u64>().ok(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    if let Some(target) = target.filter(|_| !compact_path.exists()) {
+        for (seq, path) in list_segments(log_path)? {
+            if seq >= target {
+                break;
+            }
+            let hint_path = path_with_suffix(&path, ".hint");
+            if hint_path.exists() {
+                std::fs::remove_file(&hint_path)?;
+            }
+            std::fs::remove_file(&path)?;
+        }
+        sync_dir(log_path)?;
+    }
+
+    for path in [
+        compact_path.clone(),
+        path_with_suffix(&compact_path, ".hint"),
+        record_path,
+    ] {
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+// Size of the entries of the segments, without their file headers
+fn entries_size(segments: &BTreeMap<u64, Segment>) -> u64 {
+    segments
+        .values()
+        .map(|s| s.size.saturating_sub(FILE_HEADER_SIZE))
+        .sum()
+}
+
+fn file_header() -> [u8; FILE_HEADER_SIZE as usize] {
+    let mut header = [0; FILE_HEADER_SIZE as usize];
+    header[0..4].copy_from_slice(&LOG_MAGIC);
+    header[4..8].copy_from_slice(&LOG_VERSION.to_be_bytes());
+    header
+}
+
+// Refuse a file which is not a log of this format, e.g. one written by an
+// older version, rather than treating its entries as corrupt and dropping them
+fn check_file_header(file: &File, path: &Path) -> Result<()> {
+    let mut header = [0; FILE_HEADER_SIZE as usize];
+    if file.read_exact_at(&mut header, 0).is_err() || header[0..4] != LOG_MAGIC {
+        return Err(Error::Internal(format!(
+            "{} is not a log file of this format",
+            path.display()
+        )));
+    }
+    let version = u32::from_be_bytes(header[4..8].try_into()?);
+    if version != LOG_VERSION {
+        return Err(Error::Internal(format!(
+            "{} has log format version {}, expected {}",
+            path.display(),
+            version,
+            LOG_VERSION
+        )));
+    }
+    Ok(())
+}
+
+// Make renames and deletes in the directory of the file durable
+pub(crate) fn sync_dir(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        if !dir.as_os_str().is_empty() {
+            File::open(dir)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+// Scan the records of a segment into the keydir,
+// returns the offset of the first torn or corrupt record, or the size
+fn scan_segment(file: &File, seq: u64, size: u64, keydir: &mut KeyDir) -> Result<u64> {
+    let mut buf_reader = BufReader::new(file);
+    let mut offset = FILE_HEADER_SIZE;
+    while offset < size {
+        let (entries, next_offset) = match Log::read_record(&mut buf_reader, offset, size)? {
+            Some(record) => record,
+            None => break,
+        };
+        for (key, val_size, val_offset) in entries {
+            if val_size == -1 {
+                keydir.remove(&key);
+            } else {
+                keydir.insert(key, (seq, val_offset, val_size as u32));
+            }
+        }
+        offset = next_offset;
+    }
+    Ok(offset)
+}
+
+// +-------------+-------------+-------------+----------------+----------------+
+// | crc(4)        key len(4)    val len(4)     val offset(8)     key          |
+// +-------------+-------------+-------------+----------------+----------------+
+// one entry per key of a merged segment, crc is the crc32 of everything after it
+fn write_hint(path: &Path, keydir: &KeyDir) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for (key, (_, offset, val_size)) in keydir.iter() {
+        let mut entry = Vec::with_capacity(HINT_HEADER_SIZE + key.len());
+        entry.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        entry.extend_from_slice(&val_size.to_be_bytes());
+        entry.extend_from_slice(&offset.to_be_bytes());
+        entry.extend_from_slice(key);
+        writer.write_all(&crc32(&entry).to_be_bytes())?;
+        writer.write_all(&entry)?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    Ok(())
+}
+
+// Load the hint file of a segment into the keydir
+// returns false without touching the keydir if the hint is missing or corrupt,
+// the segment has to be scanned then
+fn load_hint(segment: &Path, seq: u64, size: u64, keydir: &mut KeyDir) -> Result<bool> {
+    let path = path_with_suffix(segment, ".hint");
+    if !path.exists() {
+        return Ok(false);
+    }
+    let data = std::fs::read(&path)?;
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        if pos + HINT_HEADER_SIZE > data.len() {
+            return Ok(false);
+        }
+        let crc = u32::from_be_bytes(data[pos..pos + 4].try_into()?);
+        let key_size = u32::from_be_bytes(data[pos + 4..pos + 8].try_into()?) as usize;
+        let val_size = u32::from_be_bytes(data[pos + 8..pos + 12].try_into()?);
+        let offset = u64::from_be_bytes(data[pos + 12..pos + 20].try_into()?);
+        let end = pos + HINT_HEADER_SIZE + key_size;
+        if end > data.len()
+            || crc32(&data[pos + 4..end]) != crc
+            || offset.checked_add(val_size as u64).is_none_or(|e| e > size)
+        {
+            return Ok(false);
+        }
+        entries.push((data[pos + HINT_HEADER_SIZE..end].to_vec(), offset, val_size));
+        pos = end;
+    }
+
+    for (key, offset, val_size) in entries {
+        keydir.insert(key, (seq, offset, val_size));
+    }
+    Ok(true)
+}
+
+fn file_checksum(path: &Path) -> Result<(u64, u32)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut crc = Crc32::new();
+    let mut size = 0;
+    let mut buf = [0; 8192];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        crc.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((size, crc.finish()))
 }
 
 impl super::engine::Engine for DiskEngine {
@@ -75,15 +875,19 @@ impl super::engine::Engine for DiskEngine {
         //                   130
         // val size = 20
         let val_size = value.len() as u32;
-        self.keydir
-            .insert(key, (offset + size as u64 - val_size as u64, val_size));
-        Ok(())
+        self.index_insert(key, offset + size as u64 - val_size as u64, val_size);
+        self.appended(offset + size as u64)
     }
 
-    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         match self.keydir.get(&key) {
-            Some((offset, val_size)) => {
-                let val = self.log.read_value(*offset, *val_size)?;
+            Some((seq, offset, val_size)) => {
+                let (seq, offset, val_size) = (*seq, *offset, *val_size);
+                let val = self
+                    .segments
+                    .get(&seq)
+                    .ok_or_else(|| Error::Internal(format!("segment {} not found", seq)))?
+                    .read_value(offset, val_size)?;
                 Ok(Some(val))
             }
             None => Ok(None),
@@ -91,28 +895,74 @@ impl super::engine::Engine for DiskEngine {
     }
 
     fn delete(&mut self, key: Vec<u8>) -> Result<()> {
-        self.log.write_entry(&key, None)?;
-        self.keydir.remove(&key);
-        Ok(())
+        let (offset, size) = self.log.write_entry(&key, None)?;
+        self.index_remove(&key);
+        self.appended(offset + size as u64)
     }
 
-    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::EngineIterator<'_> {
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        // The whole batch is one log record, then update memory indexing
+        // with the value offsets inside the record
+        let (offset, size) = self.log.write_batch(&batch)?;
+        let mut pos = offset + LOG_HEADER_SIZE as u64;
+        for (key, value) in batch.into_ops() {
+            pos += BATCH_ENTRY_HEADER_SIZE + key.len() as u64;
+            match value {
+                Some(value) => {
+                    self.index_insert(key, pos, value.len() as u32);
+                    pos += value.len() as u64;
+                }
+                None => self.index_remove(&key),
+            }
+        }
+        self.appended(offset + size)
+    }
+
+    fn scan(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::EngineIterator<'_> {
         DiskEngineIterator {
             inner: self.keydir.range(range),
-            log: &mut self.log,
+            segments: &self.segments,
+        }
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced > 0 {
+            self.sync_log()?;
+        }
+        self.install_finished()?;
+        Ok(())
+    }
+}
+
+impl Drop for DiskEngine {
+    fn drop(&mut self) {
+        // a running compaction is waited for and installed, a failed one
+        // leaves its files behind, which the next open removes
+        let _ = self.finish_compaction();
+
+        // writes pending under a periodic policy are synced on close
+        if self.options.sync_policy != SyncPolicy::Never {
+            let _ = super::engine::Engine::sync(self);
         }
     }
 }
 
 pub struct DiskEngineIterator<'a> {
-    inner: btree_map::Range<'a, Vec<u8>, (u64, u32)>,
-    log: &'a mut Log,
+    inner: btree_map::Range<'a, Vec<u8>, (u64, u64, u32)>,
+    segments: &'a BTreeMap<u64, Segment>,
 }
 
 impl<'a> DiskEngineIterator<'a> {
-    fn map(&mut self, item: (&Vec<u8>, &(u64, u32))) -> <Self as Iterator>::Item {
-        let (k, (offset, val_size)) = item;
-        let value = self.log.read_value(*offset, *val_size)?;
+    fn map(&mut self, item: (&Vec<u8>, &(u64, u64, u32))) -> <Self as Iterator>::Item {
+        let (k, (seq, offset, val_size)) = item;
+        let segment = self
+            .segments
+            .get(seq)
+            .ok_or_else(|| Error::Internal(format!("segment {} not found", seq)))?;
+        let value = segment.read_value(*offset, *val_size)?;
         Ok((k.clone(), value))
     }
 }
@@ -139,7 +989,38 @@ struct Log {
 }
 
 impl Log {
+    // Open the log file, a new file gets the file header
     fn new(file_path: PathBuf) -> Result<Self> {
+        let log = Self::open(file_path)?;
+        let size = log.file.metadata()?.len();
+        if size >= FILE_HEADER_SIZE {
+            check_file_header(&log.file, &log.file_path)?;
+            return Ok(log);
+        }
+
+        // a crash while creating the file may have left part of the header
+        let mut existing = vec![0; size as usize];
+        log.file.read_exact_at(&mut existing, 0)?;
+        if !file_header().starts_with(&existing) {
+            return Err(Error::Internal(format!(
+                "{} is not a log file of this format",
+                log.file_path.display()
+            )));
+        }
+        log.file.write_all_at(&file_header(), 0)?;
+        log.file.sync_all()?;
+        Ok(log)
+    }
+
+    // Create an empty log file, replacing the file if it exists
+    fn create(file_path: PathBuf) -> Result<Self> {
+        let log = Self::open(file_path)?;
+        log.file.set_len(0)?;
+        log.file.write_all_at(&file_header(), 0)?;
+        Ok(log)
+    }
+
+    fn open(file_path: PathBuf) -> Result<Self> {
         // If dir DNE, create one
         if let Some(dir) = file_path.parent() {
             if !dir.exists() {
@@ -155,47 +1036,44 @@ impl Log {
             .open(&file_path)?;
 
         // Add file lock
-        // Make sure only one service is using this file 
+        // Make sure only one service is using this file
         file.try_lock_exclusive()?;
 
         Ok(Self { file, file_path })
     }
 
-    // Iterate the datafile, create memory indexing
-    fn build_keydir(&mut self) -> Result<KeyDir> {
-        let mut keydir = KeyDir::new();
+    // Iterate the datafile as the segment seq, fill in memory indexing
+    // A crash in the middle of a write leaves a torn entry at the end of the log,
+    // the scan stops at the first torn or corrupt entry and truncates the tail,
+    // which is returned
+    fn build_keydir(&mut self, seq: u64, keydir: &mut KeyDir) -> Result<Option<TornTail>> {
         let file_size = self.file.metadata()?.len();
-        let mut buf_reader = BufReader::new(&self.file);
-
-        let mut offset = 0;
-        loop {
-            if offset >= file_size {
-                break;
-            }
-
-            let (key, val_size) = Self::read_entry(&mut buf_reader, offset)?;
-            let key_size = key.len() as u32;
-            if val_size == -1 {
-                keydir.remove(&key);
-                offset += key_size as u64 + LOG_HEADER_SIZE as u64;
-            } else {
-                keydir.insert(
-                    key,
-                    (
-                        offset + LOG_HEADER_SIZE as u64 + key_size as u64,
-                        val_size as u32,
-                    ),
-                );
-                offset += key_size as u64 + val_size as u64 + LOG_HEADER_SIZE as u64;
-            }
+        let offset = scan_segment(&self.file, seq, file_size, keydir)?;
+        if offset == file_size {
+            return Ok(None);
         }
+        self.file.set_len(offset)?;
+        self.file.sync_all()?;
+        Ok(Some(TornTail {
+            segment: seq,
+            offset,
+            dropped: file_size - offset,
+        }))
+    }
 
-        Ok(keydir)
+    // A read handle of the log file
+    fn open_segment(&self) -> Result<Segment> {
+        Ok(Segment {
+            path: self.file_path.clone(),
+            file: File::open(&self.file_path)?,
+            size: self.file.metadata()?.len(),
+        })
     }
 
-    // +-------------+-------------+----------------+----------------+
-    // | key len(4)    val len(4)     key(varint)       val(varint)  |
-    // +-------------+-------------+----------------+----------------+
+    // +-------------+-------------+-------------+----------------+----------------+
+    // | crc(4)        key len(4)    val len(4)     key(varint)       val(varint)  |
+    // +-------------+-------------+-------------+----------------+----------------+
+    // crc is the crc32 of everything after it, val len is -1 for a deleted key
     fn write_entry(&mut self, key: &Vec<u8>, value: Option<&Vec<u8>>) -> Result<(u64, u32)> {
         // Move the offset to the end
         let offset = self.file.seek(SeekFrom::End(0))?;
@@ -203,8 +1081,17 @@ impl Log {
         let val_size = value.map_or(0, |v| v.len() as u32);
         let total_size = key_size + val_size + LOG_HEADER_SIZE;
 
-        // Write in key size, value size, key & value
+        let mut crc = Crc32::new();
+        crc.update(&key_size.to_be_bytes());
+        crc.update(&value.map_or(-1, |v| v.len() as i32).to_be_bytes());
+        crc.update(key);
+        if let Some(v) = value {
+            crc.update(v);
+        }
+
+        // Write in crc, key size, value size, key & value
         let mut writer = BufWriter::with_capacity(total_size as usize, &self.file);
+        writer.write_all(&crc.finish().to_be_bytes())?;
         writer.write_all(&key_size.to_be_bytes())?;
         writer.write_all(&value.map_or(-1, |v| v.len() as i32).to_be_bytes())?;
         writer.write_all(&key)?;
@@ -216,40 +1103,123 @@ impl Log {
         Ok((offset, total_size))
     }
 
-    fn read_value(&mut self, offset: u64, val_size: u32) -> Result<Vec<u8>> {
-        self.file.seek(SeekFrom::Start(offset))?;
-        let mut buf = vec![0; val_size as usize];
-        self.file.read_exact(&mut buf)?;
-        Ok(buf)
+    // A write batch is a single record holding all its entries
+    // +-------------+-------------+-------------+---------------------------------+
+    // | crc(4)        0xffffffff    body len(4)    entries                         |
+    // +-------------+-------------+-------------+---------------------------------+
+    // each entry is key len(4), val len(4), key, val without a crc of its own,
+    // so on recovery the batch is applied entirely or not at all
+    fn write_batch(&mut self, batch: &WriteBatch) -> Result<(u64, u64)> {
+        let mut body = Vec::new();
+        for (key, value) in batch.ops() {
+            body.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            body.extend_from_slice(&value.as_ref().map_or(-1, |v| v.len() as i32).to_be_bytes());
+            body.extend_from_slice(key);
+            if let Some(v) = value {
+                body.extend_from_slice(v);
+            }
+        }
+        let body_size = i32::try_from(body.len()).map_err(|_| {
+            Error::Internal(format!("write batch of {} bytes is too large", body.len()))
+        })?;
+
+        let mut header = [0; LOG_HEADER_SIZE as usize];
+        header[4..8].copy_from_slice(&BATCH_MARKER.to_be_bytes());
+        header[8..12].copy_from_slice(&body_size.to_be_bytes());
+        let mut crc = Crc32::new();
+        crc.update(&header[4..]);
+        crc.update(&body);
+        header[0..4].copy_from_slice(&crc.finish().to_be_bytes());
+
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        let mut writer = BufWriter::with_capacity(header.len() + body.len(), &self.file);
+        writer.write_all(&header)?;
+        writer.write_all(&body)?;
+        writer.flush()?;
+
+        Ok((offset, (header.len() + body.len()) as u64))
     }
 
-    fn read_entry(buf_reader: &mut BufReader<&File>, offset: u64) -> Result<(Vec<u8>, i32)> {
+    // Read the record at the offset and verify its checksum
+    // returns the entries as (key, val size, val offset) and the offset of the next record,
+    // or None if the record is cut off by the end of the file or corrupt
+    #[allow(clippy::type_complexity)]
+    fn read_record(
+        buf_reader: &mut BufReader<&File>,
+        offset: u64,
+        file_size: u64,
+    ) -> Result<Option<(Vec<(Vec<u8>, i32, u64)>, u64)>> {
+        if offset + LOG_HEADER_SIZE as u64 > file_size {
+            return Ok(None);
+        }
         buf_reader.seek(SeekFrom::Start(offset))?;
-        let mut len_buf = [0; 4];
+        let mut header = [0; LOG_HEADER_SIZE as usize];
+        buf_reader.read_exact(&mut header)?;
+        let crc = u32::from_be_bytes(header[0..4].try_into()?);
+        let key_size = u32::from_be_bytes(header[4..8].try_into()?);
+        let val_size = i32::from_be_bytes(header[8..12].try_into()?);
 
-        // read key size
-        buf_reader.read_exact(&mut len_buf)?;
-        let key_size = u32::from_be_bytes(len_buf);
+        // a corrupt header may hold any size, check it before allocating
+        let body_size = if key_size == BATCH_MARKER {
+            val_size as u64
+        } else {
+            key_size as u64 + val_size.max(0) as u64
+        };
+        if val_size < -1 || offset + LOG_HEADER_SIZE as u64 + body_size > file_size {
+            return Ok(None);
+        }
 
-        // read value size
-        buf_reader.read_exact(&mut len_buf)?;
-        let val_size = i32::from_be_bytes(len_buf);
+        // read key & value
+        let mut body = vec![0; body_size as usize];
+        buf_reader.read_exact(&mut body)?;
+        if crc32(&[&header[4..], &body[..]].concat()) != crc {
+            return Ok(None);
+        }
 
-        // read key
-        let mut key = vec![0; key_size as usize];
-        buf_reader.read_exact(&mut key)?;
+        let body_offset = offset + LOG_HEADER_SIZE as u64;
+        let next_offset = body_offset + body_size;
+        if key_size != BATCH_MARKER {
+            let val_offset = body_offset + key_size as u64;
+            body.truncate(key_size as usize);
+            return Ok(Some((vec![(body, val_size, val_offset)], next_offset)));
+        }
 
-        Ok((key, val_size))
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < body_size {
+            if pos + BATCH_ENTRY_HEADER_SIZE > body_size {
+                return Ok(None);
+            }
+            let at = pos as usize;
+            let key_size = u32::from_be_bytes(body[at..at + 4].try_into()?) as u64;
+            let val_size = i32::from_be_bytes(body[at + 4..at + 8].try_into()?);
+            let key_start = pos + BATCH_ENTRY_HEADER_SIZE;
+            let entry_end = key_start + key_size + val_size.max(0) as u64;
+            if val_size < -1 || entry_end > body_size {
+                return Ok(None);
+            }
+            let key = body[key_start as usize..(key_start + key_size) as usize].to_vec();
+            entries.push((key, val_size, body_offset + key_start + key_size));
+            pos = entry_end;
+        }
+        Ok(Some((entries, next_offset)))
     }
 }
 
 #[cfg(test)]
 mod tets {
+    use super::{DiskOptions, SyncPolicy, TornTail, FILE_HEADER_SIZE};
     use crate::{
         error::Result,
-        storage::{disk::DiskEngine, engine::Engine},
+        storage::{
+            disk::DiskEngine,
+            engine::{Engine, WriteBatch},
+        },
+    };
+    use std::{
+        path::{Path, PathBuf},
+        time::{Duration, Instant},
     };
-    use std::path::PathBuf;
 
     #[test]
     fn test_disk_engine_compact() -> Result<()> {
@@ -280,7 +1250,7 @@ mod tets {
         );
         drop(eng);
 
-        let mut eng2 = DiskEngine::new_compact(PathBuf::from("/tmp/sqldb/sqldb-log"))?;
+        let eng2 = DiskEngine::new_compact(PathBuf::from("/tmp/sqldb/sqldb-log"))?;
         let iter2 = eng2.scan(..);
         let v2 = iter2.collect::<Result<Vec<_>>>()?;
         assert_eq!(
@@ -297,5 +1267,630 @@ mod tets {
 
         Ok(())
     }
-}
 
+    #[test]
+    fn test_disk_engine_backup() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut eng = DiskEngine::new(dir.path().join("sqldb-log"))?;
+        eng.set(b"aa".to_vec(), b"value1".to_vec())?;
+        eng.set(b"aa".to_vec(), b"value2".to_vec())?;
+        eng.set(b"bb".to_vec(), b"value3".to_vec())?;
+        eng.set(b"cc".to_vec(), b"value4".to_vec())?;
+        eng.delete(b"cc".to_vec())?;
+
+        // writes after the snapshot are not part of the backup
+        let snapshot = eng.snapshot()?;
+        eng.set(b"dd".to_vec(), b"value5".to_vec())?;
+        eng.delete(b"aa".to_vec())?;
+
+        let backup = dir.path().join("backup").join("sqldb-log");
+        std::fs::create_dir_all(backup.parent().unwrap())?;
+        let manifest = snapshot.write_backup(backup.clone())?;
+        assert_eq!(manifest.entries, 2);
+        assert!(snapshot.write_backup(backup.clone()).is_err());
+        drop(snapshot);
+
+        assert_eq!(DiskEngine::verify_backup(backup.clone())?, manifest);
+
+        let restored = DiskEngine::new(backup.clone())?;
+        let v = restored.scan(..).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            v,
+            vec![
+                (b"aa".to_vec(), b"value2".to_vec()),
+                (b"bb".to_vec(), b"value3".to_vec()),
+            ]
+        );
+        drop(restored);
+
+        // the engine itself is untouched
+        let v = eng.scan(..).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            v,
+            vec![
+                (b"bb".to_vec(), b"value3".to_vec()),
+                (b"dd".to_vec(), b"value5".to_vec()),
+            ]
+        );
+
+        // a damaged backup is detected
+        let mut data = std::fs::read(&backup)?;
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&backup, data)?;
+        assert!(DiskEngine::verify_backup(backup).is_err());
+        Ok(())
+    }
+
+    // Write a few entries, returns the file size after each of them
+    fn write_entries(path: PathBuf) -> Result<Vec<u64>> {
+        let mut eng = DiskEngine::new(path.clone())?;
+        let mut sizes = Vec::new();
+        eng.set(b"key1".to_vec(), b"value1".to_vec())?;
+        sizes.push(std::fs::metadata(&path)?.len());
+        eng.set(b"key2".to_vec(), b"value2".to_vec())?;
+        sizes.push(std::fs::metadata(&path)?.len());
+        eng.delete(b"key1".to_vec())?;
+        sizes.push(std::fs::metadata(&path)?.len());
+        eng.set(b"key3".to_vec(), b"value3".to_vec())?;
+        sizes.push(std::fs::metadata(&path)?.len());
+        Ok(sizes)
+    }
+
+    // total size of the entries in the data files of the dir, without hints
+    fn segments_size(dir: &Path) -> Result<u64> {
+        let mut size = 0;
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if !entry.file_name().to_string_lossy().ends_with(".hint") {
+                size += entry.metadata()?.len() - FILE_HEADER_SIZE;
+            }
+        }
+        Ok(size)
+    }
+
+    fn scan_all(eng: &mut DiskEngine) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        eng.scan(..).collect::<Result<Vec<_>>>()
+    }
+
+    #[test]
+    fn test_disk_engine_torn_write() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        let sizes = write_entries(path.clone())?;
+        let full = std::fs::read(&path)?;
+
+        // cut the last entry at every possible point
+        for len in sizes[2]..sizes[3] {
+            std::fs::write(&path, &full[..len as usize])?;
+            let mut eng = DiskEngine::new(path.clone())?;
+            assert_eq!(
+                scan_all(&mut eng)?,
+                vec![(b"key2".to_vec(), b"value2".to_vec())]
+            );
+            // the truncation is reported
+            assert_eq!(
+                eng.stats().truncated,
+                (len > sizes[2]).then_some(TornTail {
+                    segment: 1,
+                    offset: sizes[2],
+                    dropped: len - sizes[2],
+                })
+            );
+            drop(eng);
+            assert_eq!(std::fs::metadata(&path)?.len(), sizes[2]);
+        }
+
+        // the log stays usable after the recovery
+        let mut eng = DiskEngine::new(path.clone())?;
+        assert_eq!(eng.stats().truncated, None);
+        eng.set(b"key4".to_vec(), b"value4".to_vec())?;
+        drop(eng);
+        let mut eng = DiskEngine::new(path.clone())?;
+        assert_eq!(
+            scan_all(&mut eng)?,
+            vec![
+                (b"key2".to_vec(), b"value2".to_vec()),
+                (b"key4".to_vec(), b"value4".to_vec()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_engine_corrupt_entry() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        let sizes = write_entries(path.clone())?;
+        let full = std::fs::read(&path)?;
+
+        // flip a bit in every byte of the second entry,
+        // the scan keeps the first entry and drops everything after it
+        for i in sizes[0]..sizes[1] {
+            let mut data = full.clone();
+            data[i as usize] ^= 0x10;
+            std::fs::write(&path, &data)?;
+
+            let mut eng = DiskEngine::new(path.clone())?;
+            assert_eq!(
+                scan_all(&mut eng)?,
+                vec![(b"key1".to_vec(), b"value1".to_vec())]
+            );
+            drop(eng);
+            assert_eq!(std::fs::metadata(&path)?.len(), sizes[0]);
+        }
+
+        // a corrupt size must not cause a huge allocation
+        let mut data = full.clone();
+        let at = FILE_HEADER_SIZE as usize;
+        data[at + 4..at + 8].copy_from_slice(&u32::MAX.to_be_bytes());
+        std::fs::write(&path, &data)?;
+        let mut eng = DiskEngine::new(path.clone())?;
+        assert_eq!(scan_all(&mut eng)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_engine_file_header() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        write_entries(path.clone())?;
+        let full = std::fs::read(&path)?;
+        assert_eq!(&full[..4], b"SQLG");
+
+        // a log without the header, e.g. of an older version, is refused
+        // and left as it is instead of being truncated
+        let old = full[FILE_HEADER_SIZE as usize..].to_vec();
+        std::fs::write(&path, &old)?;
+        assert!(DiskEngine::new(path.clone()).is_err());
+        assert_eq!(std::fs::read(&path)?, old);
+
+        // as is a log of another format version
+        let mut newer = full.clone();
+        newer[4..8].copy_from_slice(&2u32.to_be_bytes());
+        std::fs::write(&path, &newer)?;
+        assert!(DiskEngine::new(path.clone()).is_err());
+        assert_eq!(std::fs::read(&path)?, newer);
+
+        // a header torn while creating the log is completed
+        std::fs::write(&path, &full[..3])?;
+        let mut eng = DiskEngine::new(path.clone())?;
+        assert_eq!(scan_all(&mut eng)?, vec![]);
+        eng.set(b"key1".to_vec(), b"value1".to_vec())?;
+        drop(eng);
+        let mut eng = DiskEngine::new(path.clone())?;
+        assert_eq!(
+            scan_all(&mut eng)?,
+            vec![(b"key1".to_vec(), b"value1".to_vec())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_engine_torn_batch() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        let mut eng = DiskEngine::new(path.clone())?;
+        eng.set(b"key1".to_vec(), b"value1".to_vec())?;
+        let before = std::fs::metadata(&path)?.len();
+
+        let mut batch = WriteBatch::new();
+        batch.delete(b"key1".to_vec());
+        batch.set(b"key2".to_vec(), b"value2".to_vec());
+        batch.set(b"key3".to_vec(), b"value3".to_vec());
+        eng.write_batch(batch)?;
+        drop(eng);
+        let full = std::fs::read(&path)?;
+
+        // the batch is applied entirely after a restart
+        let mut eng = DiskEngine::new(path.clone())?;
+        assert_eq!(
+            scan_all(&mut eng)?,
+            vec![
+                (b"key2".to_vec(), b"value2".to_vec()),
+                (b"key3".to_vec(), b"value3".to_vec()),
+            ]
+        );
+        drop(eng);
+
+        // or not at all when the record is torn
+        for len in before..full.len() as u64 {
+            std::fs::write(&path, &full[..len as usize])?;
+            let mut eng = DiskEngine::new(path.clone())?;
+            assert_eq!(
+                scan_all(&mut eng)?,
+                vec![(b"key1".to_vec(), b"value1".to_vec())]
+            );
+            drop(eng);
+            assert_eq!(std::fs::metadata(&path)?.len(), before);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_engine_auto_compact() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        let options = DiskOptions {
+            compact_ratio: Some(0.5),
+            compact_min_size: 1024,
+            compact_in_background: false,
+            ..Default::default()
+        };
+        let mut eng = DiskEngine::new_with_options(path.clone(), options.clone())?;
+        for i in 0..100u32 {
+            eng.set(format!("key{}", i % 10).into_bytes(), vec![i as u8; 20])?;
+        }
+        let stats = eng.stats();
+        assert!(stats.compactions > 0);
+        assert!(stats.log_size < 1024 * 2);
+        assert_eq!(stats.log_size, segments_size(dir.path())?);
+
+        let expect = scan_all(&mut eng)?;
+        assert_eq!(expect.len(), 10);
+        assert_eq!(expect[9], (b"key9".to_vec(), vec![99; 20]));
+        drop(eng);
+
+        let mut eng = DiskEngine::new_with_options(path.clone(), options)?;
+        assert_eq!(scan_all(&mut eng)?, expect);
+
+        // a garbage size threshold
+        let options = DiskOptions {
+            compact_ratio: None,
+            compact_garbage: Some(500),
+            compact_min_size: 0,
+            compact_in_background: false,
+            ..Default::default()
+        };
+        let mut eng = DiskEngine::new_with_options(dir.path().join("sqldb-log2"), options)?;
+        for _ in 0..20 {
+            eng.set(b"key".to_vec(), vec![0; 20])?;
+        }
+        assert_eq!(eng.stats().compactions, 1);
+        assert!(eng.stats().garbage() < 500);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_engine_background_compact() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        let options = DiskOptions {
+            compact_ratio: Some(0.5),
+            compact_min_size: 1024,
+            compact_in_background: true,
+            ..Default::default()
+        };
+        let mut eng = DiskEngine::new_with_options(path.clone(), options.clone())?;
+        for i in 0..2000u32 {
+            let key = format!("key{}", i % 50).into_bytes();
+            if i % 7 == 0 {
+                eng.delete(key)?;
+            } else {
+                let mut batch = WriteBatch::new();
+                batch.set(key, i.to_be_bytes().to_vec());
+                batch.set(b"last".to_vec(), i.to_be_bytes().to_vec());
+                eng.write_batch(batch)?;
+            }
+        }
+        eng.finish_compaction()?;
+        assert!(eng.stats().compactions > 0);
+        assert_eq!(eng.stats().log_size, segments_size(dir.path())?);
+
+        let expect = scan_all(&mut eng)?;
+        assert_eq!(
+            expect.last(),
+            Some(&(b"last".to_vec(), 1999u32.to_be_bytes().to_vec()))
+        );
+        drop(eng);
+
+        let mut eng = DiskEngine::new_with_options(path.clone(), options)?;
+        assert_eq!(scan_all(&mut eng)?, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_engine_install_compaction() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        let mut eng = DiskEngine::new(path.clone())?;
+        for i in 0..20u32 {
+            eng.set(
+                format!("key{}", i % 4).into_bytes(),
+                i.to_be_bytes().to_vec(),
+            )?;
+        }
+        let expect = scan_all(&mut eng)?;
+
+        // a finished compaction is installed by a sync without further writes
+        eng.start_compaction()?;
+        while !eng.compaction.as_ref().unwrap().handle.is_finished() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(scan_all(&mut eng)?, expect);
+        assert_eq!(eng.stats().compactions, 0);
+        eng.sync()?;
+        assert_eq!(eng.stats().compactions, 1);
+        assert_eq!(eng.stats().garbage(), 0);
+
+        // and a running one when the engine is dropped
+        eng.set(b"key0".to_vec(), b"value".to_vec())?;
+        eng.start_compaction()?;
+        drop(eng);
+        assert!(!dir.path().join("sqldb-log.compact").exists());
+        assert!(!dir.path().join("sqldb-log.00000001").exists());
+        assert!(dir.path().join("sqldb-log.00000002.hint").exists());
+
+        let mut eng = DiskEngine::new(path)?;
+        assert_eq!(eng.segments(), vec![2, 3]);
+        assert_eq!(eng.stats().garbage(), 0);
+        assert_eq!(
+            scan_all(&mut eng)?[0],
+            (b"key0".to_vec(), b"value".to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_engine_stale_compact_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        write_entries(path.clone())?;
+
+        // a crash before the rename leaves a partial compact file behind
+        let compact_path = dir.path().join("sqldb-log.compact");
+        std::fs::write(&compact_path, b"partial")?;
+        let mut eng = DiskEngine::new(path.clone())?;
+        assert!(!compact_path.exists());
+        assert_eq!(
+            scan_all(&mut eng)?,
+            vec![
+                (b"key2".to_vec(), b"value2".to_vec()),
+                (b"key3".to_vec(), b"value3".to_vec()),
+            ]
+        );
+
+        eng.compact()?;
+        assert_eq!(eng.stats().garbage(), 0);
+        drop(eng);
+        let mut eng = DiskEngine::new(path)?;
+        assert_eq!(scan_all(&mut eng)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_engine_crash_after_compact_rename() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        // every write seals its own segment
+        let options = DiskOptions {
+            segment_size: 1,
+            compact_ratio: None,
+            ..Default::default()
+        };
+        let mut eng = DiskEngine::new_with_options(path.clone(), options.clone())?;
+        eng.set(b"key1".to_vec(), b"value1".to_vec())?;
+        eng.set(b"key2".to_vec(), b"value2".to_vec())?;
+        eng.delete(b"key1".to_vec())?;
+        assert_eq!(eng.segments(), vec![1, 2, 3, 4]);
+        let first = dir.path().join("sqldb-log.00000001");
+        let first_data = std::fs::read(&first)?;
+
+        // the merged segment 3 has no tombstone of key1
+        eng.compact()?;
+        drop(eng);
+        assert!(!first.exists());
+        assert!(!dir.path().join("sqldb-log.compact.target").exists());
+
+        // a crash after the rename, before segment 1 was deleted
+        std::fs::write(&first, &first_data)?;
+        std::fs::write(dir.path().join("sqldb-log.compact.target"), "3")?;
+        let mut eng = DiskEngine::new_with_options(path.clone(), options.clone())?;
+        assert!(!first.exists());
+        assert!(!dir.path().join("sqldb-log.compact.target").exists());
+        assert_eq!(eng.segments(), vec![3, 4]);
+        assert_eq!(
+            scan_all(&mut eng)?,
+            vec![(b"key2".to_vec(), b"value2".to_vec())]
+        );
+        drop(eng);
+
+        // a crash before the rename keeps all segments, the record is discarded
+        // along with the compact file
+        std::fs::write(dir.path().join("sqldb-log.compact"), b"partial")?;
+        std::fs::write(dir.path().join("sqldb-log.compact.target"), "4")?;
+        let mut eng = DiskEngine::new_with_options(path, options)?;
+        assert_eq!(eng.segments(), vec![3, 4]);
+        assert!(!dir.path().join("sqldb-log.compact").exists());
+        assert!(!dir.path().join("sqldb-log.compact.target").exists());
+        assert_eq!(
+            scan_all(&mut eng)?,
+            vec![(b"key2".to_vec(), b"value2".to_vec())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_engine_segments() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        let options = DiskOptions {
+            segment_size: 256,
+            compact_ratio: None,
+            ..Default::default()
+        };
+        let mut eng = DiskEngine::new_with_options(path.clone(), options.clone())?;
+        for i in 0..100u32 {
+            eng.set(
+                format!("key{}", i % 30).into_bytes(),
+                i.to_be_bytes().to_vec(),
+            )?;
+        }
+        eng.delete(b"key0".to_vec())?;
+        let segments = eng.segments();
+        assert!(segments.len() > 5);
+        assert!(dir.path().join("sqldb-log.00000001").exists());
+        assert_eq!(eng.stats().log_size, segments_size(dir.path())?);
+
+        let expect = scan_all(&mut eng)?;
+        assert_eq!(expect.len(), 29);
+        drop(eng);
+
+        let mut eng = DiskEngine::new_with_options(path.clone(), options)?;
+        assert_eq!(eng.segments(), segments);
+        assert_eq!(scan_all(&mut eng)?, expect);
+        assert_eq!(
+            eng.get(b"key29".to_vec())?,
+            Some(89u32.to_be_bytes().to_vec())
+        );
+
+        // compaction merges the sealed segments into the last one
+        eng.compact()?;
+        let merged = *segments.last().unwrap();
+        assert_eq!(eng.segments(), vec![merged, merged + 1]);
+        assert!(dir
+            .path()
+            .join(format!("sqldb-log.{:08}.hint", merged))
+            .exists());
+        assert!(!dir.path().join("sqldb-log.00000001").exists());
+        assert_eq!(scan_all(&mut eng)?, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_engine_hint() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        let mut eng = DiskEngine::new(path.clone())?;
+        eng.set(b"key1".to_vec(), b"value1".to_vec())?;
+        eng.set(b"key2".to_vec(), b"value2".to_vec())?;
+        eng.set(b"key1".to_vec(), b"value3".to_vec())?;
+        eng.compact()?;
+        eng.set(b"key3".to_vec(), b"value4".to_vec())?;
+        drop(eng);
+        let expect = vec![
+            (b"key1".to_vec(), b"value3".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+            (b"key3".to_vec(), b"value4".to_vec()),
+        ];
+
+        // the merged segment is loaded from its hint without reading the entries,
+        // a broken entry crc goes unnoticed
+        let segment = dir.path().join("sqldb-log.00000001");
+        let hint = dir.path().join("sqldb-log.00000001.hint");
+        let data = std::fs::read(&segment)?;
+        let mut broken = data.clone();
+        broken[FILE_HEADER_SIZE as usize] ^= 0xff;
+        std::fs::write(&segment, &broken)?;
+        let mut eng = DiskEngine::new(path.clone())?;
+        assert_eq!(scan_all(&mut eng)?, expect);
+        drop(eng);
+
+        // a corrupt hint falls back to scanning the segment
+        std::fs::write(&segment, &data)?;
+        let mut hint_data = std::fs::read(&hint)?;
+        let last = hint_data.len() - 1;
+        hint_data[last] ^= 0xff;
+        std::fs::write(&hint, &hint_data)?;
+        let mut eng = DiskEngine::new(path.clone())?;
+        assert_eq!(scan_all(&mut eng)?, expect);
+        drop(eng);
+
+        // as does a missing one
+        std::fs::remove_file(&hint)?;
+        let mut eng = DiskEngine::new(path)?;
+        assert_eq!(scan_all(&mut eng)?, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_engine_sync_policy() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let open = |name: &str, sync_policy| {
+            DiskEngine::new_with_options(
+                dir.path().join(name),
+                DiskOptions {
+                    sync_policy,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let mut eng = open("always", SyncPolicy::Always)?;
+        for i in 0..10u8 {
+            eng.set(vec![i], vec![i])?;
+        }
+        eng.delete(vec![0])?;
+        assert_eq!(eng.sync_count(), 11);
+
+        let mut eng = open("every", SyncPolicy::EveryN(4))?;
+        for i in 0..10u8 {
+            eng.set(vec![i], vec![i])?;
+        }
+        assert_eq!(eng.sync_count(), 2);
+        // an explicit sync flushes the remaining writes, and is a no-op afterwards
+        eng.sync()?;
+        eng.sync()?;
+        assert_eq!(eng.sync_count(), 3);
+
+        let mut eng = open("interval", SyncPolicy::Interval(Duration::from_secs(3600)))?;
+        for i in 0..10u8 {
+            eng.set(vec![i], vec![i])?;
+        }
+        assert_eq!(eng.sync_count(), 0);
+
+        let mut eng = open("zero-interval", SyncPolicy::Interval(Duration::ZERO))?;
+        for i in 0..10u8 {
+            eng.set(vec![i], vec![i])?;
+        }
+        assert_eq!(eng.sync_count(), 10);
+
+        let mut eng = open("never", SyncPolicy::Never)?;
+        for i in 0..10u8 {
+            eng.set(vec![i], vec![i])?;
+        }
+        assert_eq!(eng.sync_count(), 0);
+        eng.sync()?;
+        assert_eq!(eng.sync_count(), 1);
+        Ok(())
+    }
+
+    // Shows the cost of each sync policy, run with
+    // cargo test --release bench_disk_engine_sync_policy -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_disk_engine_sync_policy() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let policies = [
+            SyncPolicy::Always,
+            SyncPolicy::EveryN(100),
+            SyncPolicy::Interval(Duration::from_millis(10)),
+            SyncPolicy::Never,
+        ];
+        for (i, sync_policy) in policies.into_iter().enumerate() {
+            let path = dir.path().join(format!("bench-{}", i));
+            let mut eng = DiskEngine::new_with_options(
+                path,
+                DiskOptions {
+                    sync_policy,
+                    ..Default::default()
+                },
+            )?;
+            let n = 2000;
+            let start = Instant::now();
+            for j in 0..n {
+                eng.set(format!("key{}", j).into_bytes(), vec![0; 100])?;
+            }
+            let elapsed = start.elapsed();
+            println!(
+                "{:?}: {} writes in {:?}, {:.0} writes/s, {} syncs",
+                sync_policy,
+                n,
+                elapsed,
+                n as f64 / elapsed.as_secs_f64(),
+                eng.sync_count()
+            );
+        }
+        Ok(())
+    }
+}
@@ -4,6 +4,8 @@ use crate::error::Result;
 
 // Defines an abstract storage engine interface for plugging in different storage engines
 // Currently supports in-memory and simple disk-based KV storage.
+// Reads take a shared reference, so readers can run concurrently,
+// while writes need exclusive access.
 pub trait Engine {
     type EngineIterator<'a>: EngineIterator
     where
@@ -13,24 +15,51 @@ pub trait Engine {
     fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
 
     // Fetch key's data
-    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
 
     // Remove key's data; ignore if key DNE
     fn delete(&mut self, key: Vec<u8>) -> Result<()>;
 
     // Scanner
-    fn scan(&mut self, range: impl RangeBounds<Vec<u8>>) -> Self::EngineIterator<'_>;
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Self::EngineIterator<'_>;
+
+    // Apply all writes of the batch as one unit
+    // engines that persist data must apply either all of them or none after a crash,
+    // the default applies them one by one and is only fit for in-memory engines
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        for (key, value) in batch.into_ops() {
+            match value {
+                Some(value) => self.set(key, value)?,
+                None => self.delete(key)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Make all previous writes durable; a no-op for engines without persistence
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
 
     // Scan the prefic
-    fn scan_prefix(&mut self, prefix: Vec<u8>) -> Self::EngineIterator<'_> {
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Self::EngineIterator<'_> {
         // start: aaaa
         // end:   aaab
         let start = Bound::Included(prefix.clone());
+        // trailing 255 bytes can't be increased, drop them and carry over
+        // start: aa 255
+        // end:   ab
         let mut bound_prefix = prefix.clone();
-        if let Some(last) = bound_prefix.iter_mut().last() {
-            *last += 1;
+        while bound_prefix.last() == Some(&255) {
+            bound_prefix.pop();
+        }
+        let end = match bound_prefix.iter_mut().last() {
+            Some(last) => {
+                *last += 1;
+                Bound::Excluded(bound_prefix)
+            }
+            None => Bound::Unbounded,
         };
-        let end = Bound::Excluded(bound_prefix);
 
         self.scan((start, end))
     }
@@ -38,12 +67,54 @@ pub trait Engine {
 
 pub trait EngineIterator: DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> {}
 
+// A group of writes applied atomically by Engine::write_batch
+// a None value deletes the key
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WriteBatch {
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push((key, Some(value)));
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.ops.push((key, None));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[(Vec<u8>, Option<Vec<u8>>)] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.ops
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Engine;
+    use super::{Engine, WriteBatch};
     use crate::{
         error::Result,
-        storage::{disk::DiskEngine, memory::MemoryEngine},
+        storage::{
+            btree::{BTreeEngine, BTreeOptions},
+            disk::DiskEngine,
+            lsm::{LsmEngine, LsmOptions},
+            memory::MemoryEngine,
+        },
     };
     use std::{ops::Bound, path::PathBuf};
 
@@ -128,11 +199,77 @@ mod tests {
         Ok(())
     }
 
+    // Test scan_prefix with prefixes ending in 255 bytes, which carry over
+    // into the byte before them for the end bound
+    #[test]
+    fn test_scan_prefix_carry() -> Result<()> {
+        let mut eng = MemoryEngine::new();
+        eng.set(vec![b'f'], b"value1".to_vec())?;
+        eng.set(vec![b'f', 255], b"value2".to_vec())?;
+        eng.set(vec![b'f', 255, 1], b"value3".to_vec())?;
+        eng.set(vec![b'f', 255, 255], b"value4".to_vec())?;
+        eng.set(vec![b'g'], b"value5".to_vec())?;
+        eng.set(vec![255, 255], b"value6".to_vec())?;
+        eng.set(vec![255, 255, 0], b"value7".to_vec())?;
+        let keys = |eng: &MemoryEngine, prefix: Vec<u8>| {
+            eng.scan_prefix(prefix)
+                .map(|r| r.map(|(k, _)| k))
+                .collect::<Result<Vec<_>>>()
+        };
+
+        // f 255 -> end bound g
+        assert_eq!(
+            keys(&eng, vec![b'f', 255])?,
+            vec![vec![b'f', 255], vec![b'f', 255, 1], vec![b'f', 255, 255]]
+        );
+        // f 255 255 -> end bound g
+        assert_eq!(
+            keys(&eng, vec![b'f', 255, 255])?,
+            vec![vec![b'f', 255, 255]]
+        );
+        // a prefix of only 255 bytes has no end bound
+        assert_eq!(
+            keys(&eng, vec![255, 255])?,
+            vec![vec![255, 255], vec![255, 255, 0]]
+        );
+        // neither has the empty prefix
+        assert_eq!(keys(&eng, vec![])?.len(), 7);
+        Ok(())
+    }
+
+    // Test write batch
+    fn test_write_batch(mut eng: impl Engine) -> Result<()> {
+        eng.set(b"aa".to_vec(), b"value1".to_vec())?;
+        eng.set(b"bb".to_vec(), b"value2".to_vec())?;
+
+        let mut batch = WriteBatch::new();
+        batch.set(b"cc".to_vec(), b"value3".to_vec());
+        batch.delete(b"aa".to_vec());
+        batch.set(b"bb".to_vec(), b"value4".to_vec());
+        batch.set(b"dd".to_vec(), vec![]);
+        batch.delete(b"dd".to_vec());
+        eng.write_batch(batch)?;
+
+        let items = eng.scan(..).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            items,
+            vec![
+                (b"bb".to_vec(), b"value4".to_vec()),
+                (b"cc".to_vec(), b"value3".to_vec()),
+            ]
+        );
+
+        eng.write_batch(WriteBatch::new())?;
+        assert_eq!(eng.scan(..).count(), 2);
+        Ok(())
+    }
+
     #[test]
     fn test_memory() -> Result<()> {
         test_point_opt(MemoryEngine::new())?;
         test_scan(MemoryEngine::new())?;
         test_scan_prefix(MemoryEngine::new())?;
+        test_write_batch(MemoryEngine::new())?;
         Ok(())
     }
 
@@ -146,6 +283,61 @@ mod tests {
 
         test_scan_prefix(DiskEngine::new(PathBuf::from("/tmp/sqldb3/db.log"))?)?;
         std::fs::remove_dir_all(PathBuf::from("/tmp/sqldb3"))?;
+
+        test_write_batch(DiskEngine::new(PathBuf::from("/tmp/sqldb4/db.log"))?)?;
+        std::fs::remove_dir_all(PathBuf::from("/tmp/sqldb4"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_lsm() -> Result<()> {
+        // a small memtable, so the keys spread over several tables
+        let options = LsmOptions {
+            memtable_size: 16,
+            block_size: 32,
+            tier_size: 2,
+        };
+        let dir = tempfile::tempdir()?;
+        test_point_opt(LsmEngine::new_with_options(
+            dir.path().join("lsm1"),
+            options.clone(),
+        )?)?;
+        test_scan(LsmEngine::new_with_options(
+            dir.path().join("lsm2"),
+            options.clone(),
+        )?)?;
+        test_scan_prefix(LsmEngine::new_with_options(
+            dir.path().join("lsm3"),
+            options.clone(),
+        )?)?;
+        test_write_batch(LsmEngine::new_with_options(
+            dir.path().join("lsm4"),
+            options,
+        )?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_btree() -> Result<()> {
+        // a small buffer pool, so pages are evicted and read back
+        let options = BTreeOptions { pool_pages: 8 };
+        let dir = tempfile::tempdir()?;
+        test_point_opt(BTreeEngine::new_with_options(
+            dir.path().join("btree1"),
+            options.clone(),
+        )?)?;
+        test_scan(BTreeEngine::new_with_options(
+            dir.path().join("btree2"),
+            options.clone(),
+        )?)?;
+        test_scan_prefix(BTreeEngine::new_with_options(
+            dir.path().join("btree3"),
+            options.clone(),
+        )?)?;
+        test_write_batch(BTreeEngine::new_with_options(
+            dir.path().join("btree4"),
+            options,
+        )?)?;
         Ok(())
     }
 }
@@ -0,0 +1,98 @@
+use crate::error::{Error, Result};
+
+// Bits per key, gives about 1% false positives with 7 hashes
+const BITS_PER_KEY: usize = 10;
+const HASHES: u32 = 7;
+
+// A bloom filter over the keys of a table
+// A get for a key that's not in the table can skip reading its blocks
+// if any of the key's bits is not set
+pub struct Bloom {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl Bloom {
+    pub fn new(keys: usize) -> Self {
+        let bytes = (keys * BITS_PER_KEY).div_ceil(8).max(8);
+        Self {
+            bits: vec![0; bytes],
+            hashes: HASHES,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in self.positions(key) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    // false means the key is definitely not in the set
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.positions(key)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // double hashing, h1 + i * h2 for the i-th hash
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let hash = fnv1a(key);
+        let (h1, h2) = (hash as u32, (hash >> 32) as u32);
+        let nbits = self.bits.len() as u64 * 8;
+        (0..self.hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) as u64 % nbits) as usize)
+    }
+
+    // +-------------+-------------+
+    // | hashes(4)     bits        |
+    // +-------------+-------------+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + self.bits.len());
+        buf.extend_from_slice(&self.hashes.to_be_bytes());
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 4 + 8 {
+            return Err(Error::Internal("bloom filter is too short".to_string()));
+        }
+        Ok(Self {
+            hashes: u32::from_be_bytes(buf[0..4].try_into()?),
+            bits: buf[4..].to_vec(),
+        })
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bloom;
+    use crate::error::Result;
+
+    #[test]
+    fn test_bloom() -> Result<()> {
+        let mut bloom = Bloom::new(1000);
+        for i in 0..1000 {
+            bloom.insert(format!("key{}", i).as_bytes());
+        }
+        let bloom = Bloom::decode(&bloom.encode())?;
+
+        // no false negatives
+        for i in 0..1000 {
+            assert!(bloom.may_contain(format!("key{}", i).as_bytes()));
+        }
+        // few false positives
+        let positives = (1000..11000)
+            .filter(|i| bloom.may_contain(format!("key{}", i).as_bytes()))
+            .count();
+        assert!(positives < 300, "{} false positives", positives);
+        Ok(())
+    }
+}
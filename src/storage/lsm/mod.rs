@@ -0,0 +1,674 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+};
+
+use crate::error::{Error, Result};
+
+use self::{
+    sstable::{Table, TableWriter},
+    wal::Wal,
+};
+
+use super::engine::WriteBatch;
+
+mod bloom;
+mod sstable;
+mod wal;
+
+// A key and its value, None if the key is deleted
+type Entry = (Vec<u8>, Option<Vec<u8>>);
+
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    // the memtable is flushed into a table once its writes reach this size
+    pub memtable_size: usize,
+    // target size of the data blocks of a table
+    pub block_size: usize,
+    // a level is merged into one table of the next level once it holds this many tables
+    pub tier_size: usize,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        Self {
+            memtable_size: 4 * 1024 * 1024,
+            block_size: 4096,
+            tier_size: 4,
+        }
+    }
+}
+
+// Define LSM-tree storage engine
+// Only the memtable, the table indexes and the bloom filters are held in memory,
+// so the data can be much larger than the memory:
+// - writes go to the write-ahead log, then into the memtable
+// - a full memtable is flushed into a sorted table in level 0
+// - tiered compaction: once a level holds tier_size tables, they are merged
+//   into a single table in the next level
+// Every level only holds data older than the levels above it, so the table
+// sequence numbers order all tables from oldest to newest.
+// The MANIFEST lists the live tables as "<level> <seq>" lines, a table is
+// written and synced before it's added to the manifest and deleted after it's removed.
+pub struct LsmEngine {
+    dir: PathBuf,
+    options: LsmOptions,
+    memtable: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // bytes written into the memtable since the last flush
+    memtable_size: usize,
+    wal: Wal,
+    // tables of each level, newest last
+    levels: Vec<Vec<Table>>,
+    next_seq: u64,
+}
+
+impl LsmEngine {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        Self::new_with_options(dir, LsmOptions::default())
+    }
+
+    pub fn new_with_options(dir: PathBuf, options: LsmOptions) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut wal = Wal::new(dir.join("wal"))?;
+
+        // Open the tables in the manifest, files of an interrupted flush
+        // or compaction are removed
+        let manifest = read_manifest(&dir)?;
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let stale = match table_seq(&path) {
+                Some(seq) => !manifest.iter().any(|(_, s)| *s == seq),
+                None => path.file_name().is_some_and(|name| name == "MANIFEST.tmp"),
+            };
+            if stale {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        let mut levels: Vec<Vec<Table>> = Vec::new();
+        let mut next_seq = 1;
+        for (level, seq) in manifest {
+            while levels.len() <= level {
+                levels.push(Vec::new());
+            }
+            levels[level].push(Table::open(table_path(&dir, seq), seq)?);
+            next_seq = next_seq.max(seq + 1);
+        }
+        for tables in levels.iter_mut() {
+            tables.sort_by_key(|t| t.seq);
+        }
+
+        // Restore the memtable from the log
+        let mut memtable = BTreeMap::new();
+        let mut memtable_size = 0;
+        for (key, value) in wal.replay()? {
+            memtable_size += key.len() + value.as_ref().map_or(0, |v| v.len());
+            memtable.insert(key, value);
+        }
+
+        Ok(Self {
+            dir,
+            options,
+            memtable,
+            memtable_size,
+            wal,
+            levels,
+            next_seq,
+        })
+    }
+
+    // The number of tables in each level
+    pub fn levels(&self) -> Vec<usize> {
+        self.levels.iter().map(|tables| tables.len()).collect()
+    }
+
+    // All tables, newest first
+    fn tables(&self) -> Vec<&Table> {
+        let mut tables = self.levels.iter().flatten().collect::<Vec<_>>();
+        tables.sort_by_key(|t| std::cmp::Reverse(t.seq));
+        tables
+    }
+
+    fn write(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        self.memtable_size += key.len() + value.as_ref().map_or(0, |v| v.len());
+        self.memtable.insert(key, value);
+    }
+
+    fn maybe_flush(&mut self) -> Result<()> {
+        if self.memtable_size >= self.options.memtable_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Write the memtable into a new table in level 0 and empty the log
+    pub fn flush(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        // without older tables a deleted key needs no tombstone
+        let bottom = self.levels.iter().all(|tables| tables.is_empty());
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let mut writer = TableWriter::new(
+            table_path(&self.dir, seq),
+            self.options.block_size,
+            self.memtable.len(),
+        )?;
+        for (key, value) in self.memtable.iter() {
+            if value.is_some() || !bottom {
+                writer.add(key, value.as_deref())?;
+            }
+        }
+        let table = writer.finish(seq)?;
+
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+        if table.entries > 0 {
+            self.levels[0].push(table);
+        } else {
+            std::fs::remove_file(&table.path)?;
+        }
+        self.write_manifest()?;
+        self.wal.reset()?;
+        self.memtable.clear();
+        self.memtable_size = 0;
+        self.compact()
+    }
+
+    // Merge every level that holds tier_size tables into the next level
+    fn compact(&mut self) -> Result<()> {
+        let mut level = 0;
+        while level < self.levels.len() {
+            if self.levels[level].len() < self.options.tier_size.max(2) {
+                level += 1;
+                continue;
+            }
+
+            // tombstones are only needed while older tables are left below
+            let bottom = self.levels[level + 1..]
+                .iter()
+                .all(|tables| tables.is_empty());
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            let tables = &self.levels[level];
+            let keys = tables.iter().map(|t| t.entries as usize).sum();
+            let mut writer =
+                TableWriter::new(table_path(&self.dir, seq), self.options.block_size, keys)?;
+            let sources = tables
+                .iter()
+                .rev()
+                .map(|t| Source::new(t.scan((Bound::Unbounded, Bound::Unbounded))))
+                .collect();
+            for entry in MergeIterator::new(sources, !bottom) {
+                let (key, value) = entry?;
+                writer.add(&key, value.as_deref())?;
+            }
+            let table = writer.finish(seq)?;
+
+            let merged = std::mem::take(&mut self.levels[level]);
+            if self.levels.len() == level + 1 {
+                self.levels.push(Vec::new());
+            }
+            if table.entries > 0 {
+                self.levels[level + 1].push(table);
+            } else {
+                std::fs::remove_file(&table.path)?;
+            }
+            self.write_manifest()?;
+            for table in merged {
+                std::fs::remove_file(&table.path)?;
+            }
+            level += 1;
+        }
+        Ok(())
+    }
+
+    fn write_manifest(&self) -> Result<()> {
+        let tmp_path = self.dir.join("MANIFEST.tmp");
+        let mut file = File::create(&tmp_path)?;
+        for (level, tables) in self.levels.iter().enumerate() {
+            for table in tables {
+                writeln!(file, "{} {}", level, table.seq)?;
+            }
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, self.dir.join("MANIFEST"))?;
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+impl super::engine::Engine for LsmEngine {
+    type EngineIterator<'a> = LsmEngineIterator<'a>;
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.wal.append(&[(&key, Some(&value))])?;
+        self.write(key, Some(value));
+        self.maybe_flush()
+    }
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.memtable.get(&key) {
+            return Ok(value.clone());
+        }
+        for table in self.tables() {
+            if let Some(value) = table.get(&key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> Result<()> {
+        self.wal.append(&[(&key, None)])?;
+        self.write(key, None);
+        self.maybe_flush()
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let entries = batch
+            .ops()
+            .iter()
+            .map(|(k, v)| (k.as_slice(), v.as_deref()))
+            .collect::<Vec<_>>();
+        self.wal.append(&entries)?;
+        for (key, value) in batch.into_ops() {
+            self.write(key, value);
+        }
+        self.maybe_flush()
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Self::EngineIterator<'_> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut sources = vec![Source::new(
+            self.memtable
+                .range(range.clone())
+                .map(|(k, v)| Ok((k.clone(), v.clone()))),
+        )];
+        for table in self.tables() {
+            sources.push(Source::new(table.scan(range.clone())));
+        }
+        LsmEngineIterator {
+            inner: MergeIterator::new(sources, false),
+        }
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.wal.sync()
+    }
+}
+
+pub struct LsmEngineIterator<'a> {
+    inner: MergeIterator<'a>,
+}
+
+impl<'a> LsmEngineIterator<'a> {
+    fn map(item: Result<Entry>) -> <Self as Iterator>::Item {
+        match item? {
+            (key, Some(value)) => Ok((key, value)),
+            (key, None) => Err(Error::Internal(format!("unexpected tombstone {:?}", key))),
+        }
+    }
+}
+
+impl<'a> super::engine::EngineIterator for LsmEngineIterator<'a> {}
+
+impl<'a> Iterator for LsmEngineIterator<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(Self::map)
+    }
+}
+
+impl<'a> DoubleEndedIterator for LsmEngineIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(Self::map)
+    }
+}
+
+// A sorted source of a merge with one entry looked ahead at each end
+// An entry is either in the iterator or in one of the slots, so both ends
+// of a source can be consumed until they meet
+struct Source<'a> {
+    iter: Box<dyn DoubleEndedIterator<Item = Result<Entry>> + 'a>,
+    front: Option<Result<Entry>>,
+    back: Option<Result<Entry>>,
+}
+
+impl<'a> Source<'a> {
+    fn new(iter: impl DoubleEndedIterator<Item = Result<Entry>> + 'a) -> Self {
+        Self {
+            iter: Box::new(iter),
+            front: None,
+            back: None,
+        }
+    }
+
+    fn peek_front(&mut self) -> Option<&Result<Entry>> {
+        if self.front.is_none() {
+            self.front = self.iter.next().or_else(|| self.back.take());
+        }
+        self.front.as_ref()
+    }
+
+    fn peek_back(&mut self) -> Option<&Result<Entry>> {
+        if self.back.is_none() {
+            self.back = self.iter.next_back().or_else(|| self.front.take());
+        }
+        self.back.as_ref()
+    }
+}
+
+// Merges sorted sources ordered from newest to oldest,
+// the newest entry of a key hides the older ones
+struct MergeIterator<'a> {
+    sources: Vec<Source<'a>>,
+    keep_tombstones: bool,
+    // the last keys taken from each end, the ends must not cross
+    front_key: Option<Vec<u8>>,
+    back_key: Option<Vec<u8>>,
+}
+
+impl<'a> MergeIterator<'a> {
+    fn new(sources: Vec<Source<'a>>, keep_tombstones: bool) -> Self {
+        Self {
+            sources,
+            keep_tombstones,
+            front_key: None,
+            back_key: None,
+        }
+    }
+
+    // Take the smallest (or largest from the back) key of all sources
+    fn take(&mut self, from_back: bool) -> Option<Result<Entry>> {
+        let mut found: Option<(usize, Vec<u8>)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            let peeked = if from_back {
+                source.peek_back()
+            } else {
+                source.peek_front()
+            };
+            let key = match peeked {
+                Some(Ok((key, _))) => key,
+                Some(Err(_)) => {
+                    let slot = if from_back {
+                        &mut source.back
+                    } else {
+                        &mut source.front
+                    };
+                    return slot.take();
+                }
+                None => continue,
+            };
+            // on equal keys the first, newest, source wins
+            let better = match &found {
+                Some((_, k)) if from_back => key > k,
+                Some((_, k)) => key < k,
+                None => true,
+            };
+            if better {
+                found = Some((i, key.clone()));
+            }
+        }
+        let (i, key) = found?;
+
+        let crossed = if from_back {
+            self.front_key.as_ref().is_some_and(|k| &key <= k)
+        } else {
+            self.back_key.as_ref().is_some_and(|k| &key >= k)
+        };
+        if crossed {
+            return None;
+        }
+
+        // drop the older entries of the key
+        let mut entry = None;
+        for (j, source) in self.sources.iter_mut().enumerate() {
+            let slot = if from_back {
+                &mut source.back
+            } else {
+                &mut source.front
+            };
+            if matches!(slot, Some(Ok((k, _))) if *k == key) {
+                let taken = slot.take();
+                if j == i {
+                    entry = taken;
+                }
+            }
+        }
+        if from_back {
+            self.back_key = Some(key);
+        } else {
+            self.front_key = Some(key);
+        }
+        entry
+    }
+
+    fn next_entry(&mut self, from_back: bool) -> Option<Result<Entry>> {
+        loop {
+            match self.take(from_back)? {
+                Ok((_, None)) if !self.keep_tombstones => continue,
+                entry => return Some(entry),
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for MergeIterator<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry(false)
+    }
+}
+
+impl<'a> DoubleEndedIterator for MergeIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_entry(true)
+    }
+}
+
+// +-------------+-------------+----------------+----------------+
+// | key len(4)    val len(4)     key              val          |
+// +-------------+-------------+----------------+----------------+
+// val len is -1 for a deleted key
+fn encode_entry(buf: &mut Vec<u8>, key: &[u8], value: Option<&[u8]>) {
+    buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&value.map_or(-1, |v| v.len() as i32).to_be_bytes());
+    buf.extend_from_slice(key);
+    if let Some(v) = value {
+        buf.extend_from_slice(v);
+    }
+}
+
+fn decode_entries(buf: &[u8]) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        if pos + 8 > buf.len() {
+            return Err(Error::Internal("truncated entry".to_string()));
+        }
+        let key_size = u32::from_be_bytes(buf[pos..pos + 4].try_into()?) as usize;
+        let val_size = i32::from_be_bytes(buf[pos + 4..pos + 8].try_into()?);
+        let key_start = pos + 8;
+        let val_start = key_start + key_size;
+        let end = val_start + val_size.max(0) as usize;
+        if val_size < -1 || end > buf.len() {
+            return Err(Error::Internal("truncated entry".to_string()));
+        }
+        let value = (val_size >= 0).then(|| buf[val_start..end].to_vec());
+        entries.push((buf[key_start..val_start].to_vec(), value));
+        pos = end;
+    }
+    Ok(entries)
+}
+
+fn table_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{:08}.sst", seq))
+}
+
+// 00000003.sst -> 3
+fn table_seq(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    let seq = name.strip_suffix(".sst")?;
+    if seq.is_empty() || !seq.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    seq.parse().ok()
+}
+
+fn read_manifest(dir: &Path) -> Result<Vec<(usize, u64)>> {
+    let path = dir.join("MANIFEST");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut tables = Vec::new();
+    for line in std::fs::read_to_string(path)?.lines() {
+        match line.split_once(' ') {
+            Some((level, seq)) => tables.push((level.parse()?, seq.parse()?)),
+            None => return Err(Error::Internal(format!("invalid manifest line {}", line))),
+        }
+    }
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LsmEngine, LsmOptions};
+    use crate::{
+        error::Result,
+        storage::engine::{Engine, WriteBatch},
+    };
+    use std::path::Path;
+
+    fn small(dir: &Path) -> Result<LsmEngine> {
+        LsmEngine::new_with_options(
+            dir.to_path_buf(),
+            LsmOptions {
+                memtable_size: 256,
+                block_size: 64,
+                tier_size: 3,
+            },
+        )
+    }
+
+    fn scan_all(eng: &mut LsmEngine) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        eng.scan(..).collect::<Result<Vec<_>>>()
+    }
+
+    #[test]
+    fn test_lsm_engine_flush_compact() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut eng = small(dir.path())?;
+        let mut expect = std::collections::BTreeMap::new();
+        for i in 0..500u32 {
+            let key = format!("key{:03}", i % 97).into_bytes();
+            if i % 5 == 0 {
+                eng.delete(key.clone())?;
+                expect.remove(&key);
+            } else {
+                eng.set(key.clone(), i.to_be_bytes().to_vec())?;
+                expect.insert(key, i.to_be_bytes().to_vec());
+            }
+        }
+        let levels = eng.levels();
+        assert!(levels.len() > 1);
+        assert!(levels.iter().all(|n| *n < 3));
+
+        let expect = expect.into_iter().collect::<Vec<_>>();
+        assert_eq!(scan_all(&mut eng)?, expect);
+        let mut reversed = eng.scan(..).rev().collect::<Result<Vec<_>>>()?;
+        reversed.reverse();
+        assert_eq!(reversed, expect);
+        for (key, value) in &expect {
+            assert_eq!(eng.get(key.clone())?, Some(value.clone()));
+        }
+        assert_eq!(eng.get(b"key000".to_vec())?, None);
+        drop(eng);
+
+        // tables and the unflushed memtable survive a restart
+        let mut eng = small(dir.path())?;
+        assert_eq!(eng.levels(), levels);
+        assert_eq!(scan_all(&mut eng)?, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lsm_engine_tombstones() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut eng = small(dir.path())?;
+        eng.set(b"a".to_vec(), b"1".to_vec())?;
+        eng.set(b"b".to_vec(), b"2".to_vec())?;
+        eng.set(b"c".to_vec(), b"3".to_vec())?;
+        eng.flush()?;
+
+        // a deleted key is hidden by a tombstone in a newer table
+        let mut batch = WriteBatch::new();
+        batch.delete(b"b".to_vec());
+        batch.set(b"d".to_vec(), b"4".to_vec());
+        eng.write_batch(batch)?;
+        eng.flush()?;
+        assert_eq!(eng.levels(), vec![2]);
+        assert_eq!(eng.get(b"b".to_vec())?, None);
+
+        // and by the memtable
+        eng.delete(b"c".to_vec())?;
+        assert_eq!(eng.get(b"c".to_vec())?, None);
+        assert_eq!(
+            scan_all(&mut eng)?,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"d".to_vec(), b"4".to_vec()),
+            ]
+        );
+        let mut iter = eng.scan(..);
+        assert_eq!(
+            iter.next_back().transpose()?,
+            Some((b"d".to_vec(), b"4".to_vec()))
+        );
+        assert_eq!(
+            iter.next().transpose()?,
+            Some((b"a".to_vec(), b"1".to_vec()))
+        );
+        assert_eq!(iter.next().transpose()?, None);
+        assert_eq!(iter.next_back().transpose()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lsm_engine_stale_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut eng = small(dir.path())?;
+        eng.set(b"a".to_vec(), b"1".to_vec())?;
+        eng.flush()?;
+        eng.set(b"b".to_vec(), b"2".to_vec())?;
+        drop(eng);
+
+        // a table of an interrupted flush is not in the manifest
+        std::fs::write(dir.path().join("00000009.sst"), b"partial")?;
+        // a torn write at the end of the log
+        let wal = dir.path().join("wal");
+        let mut data = std::fs::read(&wal)?;
+        data.extend_from_slice(&[1, 2, 3]);
+        std::fs::write(&wal, data)?;
+
+        let mut eng = small(dir.path())?;
+        assert!(!dir.path().join("00000009.sst").exists());
+        assert_eq!(
+            scan_all(&mut eng)?,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,386 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    ops::{Bound, RangeBounds},
+    os::unix::fs::FileExt,
+    path::PathBuf,
+};
+
+use crate::{
+    error::{Error, Result},
+    storage::checksum::crc32,
+};
+
+use super::{bloom::Bloom, decode_entries, encode_entry, Entry};
+
+const FOOTER_SIZE: usize = 32;
+const TABLE_MAGIC: u32 = 0x4c534d31;
+
+// A sorted, immutable table of entries
+// +----------------+----------------+----------------+----------------+
+// | data blocks      index            bloom filter     footer(32)     |
+// +----------------+----------------+----------------+----------------+
+// - a data block holds sorted entries followed by their crc(4)
+// - the index holds one entry per block: key len(4), last key, offset(8), size(4)
+// - the footer holds index offset(8), index size(4), bloom size(4), entries(8),
+//   crc(4) of the index and bloom filter, magic(4)
+// Only the index and the bloom filter are kept in memory, blocks are read on demand
+pub struct Table {
+    pub seq: u64,
+    pub path: PathBuf,
+    file: File,
+    // (last key, offset, size) of each block
+    index: Vec<(Vec<u8>, u64, u32)>,
+    bloom: Bloom,
+    pub entries: u64,
+}
+
+impl Table {
+    pub fn open(path: PathBuf, seq: u64) -> Result<Self> {
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_SIZE as u64 {
+            return Err(Error::Internal(format!(
+                "table {} is too short",
+                path.display()
+            )));
+        }
+        let mut footer = [0; FOOTER_SIZE];
+        file.seek(SeekFrom::Start(size - FOOTER_SIZE as u64))?;
+        file.read_exact(&mut footer)?;
+        let index_offset = u64::from_be_bytes(footer[0..8].try_into()?);
+        let index_size = u32::from_be_bytes(footer[8..12].try_into()?) as u64;
+        let bloom_size = u32::from_be_bytes(footer[12..16].try_into()?) as u64;
+        let entries = u64::from_be_bytes(footer[16..24].try_into()?);
+        let crc = u32::from_be_bytes(footer[24..28].try_into()?);
+        let magic = u32::from_be_bytes(footer[28..32].try_into()?);
+        if magic != TABLE_MAGIC
+            || index_offset + index_size + bloom_size + FOOTER_SIZE as u64 != size
+        {
+            return Err(Error::Internal(format!(
+                "table {} is corrupt",
+                path.display()
+            )));
+        }
+
+        let mut meta = vec![0; (index_size + bloom_size) as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut meta)?;
+        if crc32(&meta) != crc {
+            return Err(Error::Internal(format!(
+                "table {} is corrupt",
+                path.display()
+            )));
+        }
+        let (index_data, bloom_data) = meta.split_at(index_size as usize);
+
+        let mut index = Vec::new();
+        let mut pos = 0;
+        while pos < index_data.len() {
+            let key_size = u32::from_be_bytes(index_data[pos..pos + 4].try_into()?) as usize;
+            pos += 4;
+            let key = index_data[pos..pos + key_size].to_vec();
+            pos += key_size;
+            let offset = u64::from_be_bytes(index_data[pos..pos + 8].try_into()?);
+            let block_size = u32::from_be_bytes(index_data[pos + 8..pos + 12].try_into()?);
+            pos += 12;
+            index.push((key, offset, block_size));
+        }
+
+        Ok(Self {
+            seq,
+            path,
+            file,
+            index,
+            bloom: Bloom::decode(bloom_data)?,
+            entries,
+        })
+    }
+
+    // Some(None) if the key is deleted in this table
+    pub fn get(&self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        // the first block whose last key is not less than the key
+        let block = self
+            .index
+            .partition_point(|(last, _, _)| last.as_slice() < key);
+        if block == self.index.len() {
+            return Ok(None);
+        }
+        Ok(self
+            .read_block(block)?
+            .into_iter()
+            .find(|(k, _)| k.as_slice() == key)
+            .map(|(_, v)| v))
+    }
+
+    pub fn scan(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> TableIterator<'_> {
+        // blocks whose keys may fall into the range
+        let start = match &range.0 {
+            Bound::Included(k) | Bound::Excluded(k) => {
+                self.index.partition_point(|(last, _, _)| last < k)
+            }
+            Bound::Unbounded => 0,
+        };
+        let end = match &range.1 {
+            Bound::Included(k) | Bound::Excluded(k) => {
+                (self.index.partition_point(|(last, _, _)| last < k) + 1).min(self.index.len())
+            }
+            Bound::Unbounded => self.index.len(),
+        };
+        TableIterator {
+            table: self,
+            range,
+            next_block: start,
+            end_block: end.max(start),
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+        }
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<Entry>> {
+        let (_, offset, size) = &self.index[block];
+        // a positioned read, tables are shared by concurrent readers
+        let mut buf = vec![0; *size as usize];
+        self.file.read_exact_at(&mut buf, *offset)?;
+        if buf.len() < 4 {
+            return Err(Error::Internal(format!(
+                "table {} is corrupt",
+                self.path.display()
+            )));
+        }
+        let (data, crc) = buf.split_at(buf.len() - 4);
+        if crc32(data) != u32::from_be_bytes(crc.try_into()?) {
+            return Err(Error::Internal(format!(
+                "block {} of table {} is corrupt",
+                block,
+                self.path.display()
+            )));
+        }
+        decode_entries(data)
+    }
+}
+
+// Iterates the entries of a table in a range, reading blocks from both ends as needed
+pub struct TableIterator<'a> {
+    table: &'a Table,
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    // blocks not read yet
+    next_block: usize,
+    end_block: usize,
+    front: VecDeque<Entry>,
+    back: VecDeque<Entry>,
+}
+
+impl<'a> TableIterator<'a> {
+    fn read(&self, block: usize) -> Result<VecDeque<Entry>> {
+        Ok(self
+            .table
+            .read_block(block)?
+            .into_iter()
+            .filter(|(k, _)| self.range.contains(k))
+            .collect())
+    }
+}
+
+impl<'a> Iterator for TableIterator<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.front.is_empty() && self.next_block < self.end_block {
+            match self.read(self.next_block) {
+                Ok(entries) => self.front = entries,
+                Err(err) => return Some(Err(err)),
+            }
+            self.next_block += 1;
+        }
+        // all blocks are read, the rest is in the back buffer
+        self.front
+            .pop_front()
+            .or_else(|| self.back.pop_front())
+            .map(Ok)
+    }
+}
+
+impl<'a> DoubleEndedIterator for TableIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while self.back.is_empty() && self.next_block < self.end_block {
+            match self.read(self.end_block - 1) {
+                Ok(entries) => self.back = entries,
+                Err(err) => return Some(Err(err)),
+            }
+            self.end_block -= 1;
+        }
+        self.back
+            .pop_back()
+            .or_else(|| self.front.pop_back())
+            .map(Ok)
+    }
+}
+
+// Writes sorted entries into a new table file
+pub struct TableWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    block_size: usize,
+    block: Vec<u8>,
+    last_key: Vec<u8>,
+    offset: u64,
+    index: Vec<(Vec<u8>, u64, u32)>,
+    bloom: Bloom,
+    entries: u64,
+}
+
+impl TableWriter {
+    // keys is the expected number of keys, used to size the bloom filter
+    pub fn new(path: PathBuf, block_size: usize, keys: usize) -> Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            block_size,
+            block: Vec::new(),
+            last_key: Vec::new(),
+            offset: 0,
+            index: Vec::new(),
+            bloom: Bloom::new(keys),
+            entries: 0,
+        })
+    }
+
+    // Keys must be added in ascending order
+    pub fn add(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        if self.entries > 0 && key <= self.last_key.as_slice() {
+            return Err(Error::Internal(format!(
+                "table keys out of order: {:?} after {:?}",
+                key, self.last_key
+            )));
+        }
+        encode_entry(&mut self.block, key, value);
+        self.bloom.insert(key);
+        self.last_key = key.to_vec();
+        self.entries += 1;
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let crc = crc32(&self.block);
+        self.block.extend_from_slice(&crc.to_be_bytes());
+        self.writer.write_all(&self.block)?;
+        self.index
+            .push((self.last_key.clone(), self.offset, self.block.len() as u32));
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    // Write the index, bloom filter and footer, sync the file and open it
+    pub fn finish(mut self, seq: u64) -> Result<Table> {
+        self.finish_block()?;
+        let mut meta = Vec::new();
+        for (key, offset, size) in &self.index {
+            meta.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            meta.extend_from_slice(key);
+            meta.extend_from_slice(&offset.to_be_bytes());
+            meta.extend_from_slice(&size.to_be_bytes());
+        }
+        let index_size = meta.len() as u32;
+        let bloom = self.bloom.encode();
+        meta.extend_from_slice(&bloom);
+
+        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+        footer.extend_from_slice(&self.offset.to_be_bytes());
+        footer.extend_from_slice(&index_size.to_be_bytes());
+        footer.extend_from_slice(&(bloom.len() as u32).to_be_bytes());
+        footer.extend_from_slice(&self.entries.to_be_bytes());
+        footer.extend_from_slice(&crc32(&meta).to_be_bytes());
+        footer.extend_from_slice(&TABLE_MAGIC.to_be_bytes());
+
+        self.writer.write_all(&meta)?;
+        self.writer.write_all(&footer)?;
+        self.writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Table::open(self.path, seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Table, TableWriter};
+    use crate::error::Result;
+    use std::ops::Bound;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_table() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("1.sst");
+        let mut writer = TableWriter::new(path.clone(), 128, 1000)?;
+        for i in 0..1000 {
+            let value = if i % 10 == 0 {
+                None
+            } else {
+                Some(i.to_string().into_bytes())
+            };
+            writer.add(&key(i), value.as_deref())?;
+        }
+        assert!(writer.add(&key(5), None).is_err());
+        let table = writer.finish(1)?;
+        assert_eq!(table.entries, 1000);
+        assert!(table.index.len() > 10);
+
+        // reopen from disk
+        let table = Table::open(path, 1)?;
+        assert_eq!(table.get(&key(1))?, Some(Some(b"1".to_vec())));
+        assert_eq!(table.get(&key(999))?, Some(Some(b"999".to_vec())));
+        assert_eq!(table.get(&key(10))?, Some(None));
+        assert_eq!(table.get(&key(1000))?, None);
+        assert_eq!(table.get(b"a")?, None);
+
+        // scan from both ends, across blocks
+        let range = (Bound::Included(key(95)), Bound::Excluded(key(305)));
+        let keys = table
+            .scan(range.clone())
+            .map(|r| r.map(|(k, _)| k))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, (95..305).map(key).collect::<Vec<_>>());
+        let mut iter = table.scan(range);
+        let mut front = Vec::new();
+        let mut back = Vec::new();
+        while let Some((k, _)) = iter.next().transpose()? {
+            front.push(k);
+            match iter.next_back().transpose()? {
+                Some((k, _)) => back.push(k),
+                None => break,
+            }
+        }
+        back.reverse();
+        front.extend(back);
+        assert_eq!(front, (95..305).map(key).collect::<Vec<_>>());
+
+        assert_eq!(
+            table.scan((Bound::Unbounded, Bound::Unbounded)).count(),
+            1000
+        );
+        assert_eq!(
+            table
+                .scan((Bound::Excluded(key(999)), Bound::Unbounded))
+                .count(),
+            0
+        );
+        Ok(())
+    }
+}
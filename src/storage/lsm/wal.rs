@@ -0,0 +1,101 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use fs4::FileExt;
+
+use crate::{
+    error::Result,
+    storage::checksum::{crc32, Crc32},
+};
+
+use super::{decode_entries, encode_entry, Entry};
+
+const RECORD_HEADER_SIZE: usize = 8;
+
+// Write-ahead log of the memtable
+// Every write is appended here before it goes into the memtable,
+// the log is replayed on open and emptied once the memtable is flushed to a table
+pub struct Wal {
+    file: File,
+}
+
+impl Wal {
+    pub fn new(file_path: PathBuf) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&file_path)?;
+
+        // Make sure only one service is using this directory
+        file.try_lock_exclusive()?;
+
+        Ok(Self { file })
+    }
+
+    // +-------------+-------------+-----------------------------+
+    // | crc(4)        len(4)        entries                     |
+    // +-------------+-------------+-----------------------------+
+    // one record per write, a write batch is a single record
+    pub fn append(&mut self, entries: &[(&[u8], Option<&[u8]>)]) -> Result<()> {
+        let mut body = Vec::new();
+        for (key, value) in entries {
+            encode_entry(&mut body, key, *value);
+        }
+        let len = (body.len() as u32).to_be_bytes();
+        let mut crc = Crc32::new();
+        crc.update(&len);
+        crc.update(&body);
+
+        self.file.seek(SeekFrom::End(0))?;
+        let mut writer = BufWriter::with_capacity(RECORD_HEADER_SIZE + body.len(), &self.file);
+        writer.write_all(&crc.finish().to_be_bytes())?;
+        writer.write_all(&len)?;
+        writer.write_all(&body)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Read back all writes in order
+    // the replay stops at the first torn or corrupt record and truncates the tail
+    pub fn replay(&mut self) -> Result<Vec<Entry>> {
+        let mut data = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut data)?;
+
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos + RECORD_HEADER_SIZE <= data.len() {
+            let crc = u32::from_be_bytes(data[pos..pos + 4].try_into()?);
+            let len = u32::from_be_bytes(data[pos + 4..pos + 8].try_into()?) as usize;
+            let end = pos + RECORD_HEADER_SIZE + len;
+            if end > data.len() || crc32(&data[pos + 4..end]) != crc {
+                break;
+            }
+            entries.extend(decode_entries(&data[pos + RECORD_HEADER_SIZE..end])?);
+            pos = end;
+        }
+
+        if pos < data.len() {
+            self.file.set_len(pos as u64)?;
+            self.file.sync_all()?;
+        }
+        Ok(entries)
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // Empty the log once its writes are stored in a table
+    pub fn reset(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
@@ -23,7 +23,7 @@ impl super::engine::Engine for MemoryEngine {
         Ok(())
     }
 
-    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         let value = self.data.get(&key).cloned();
         Ok(value)
     }
@@ -33,7 +33,7 @@ impl super::engine::Engine for MemoryEngine {
         Ok(())
     }
 
-    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::EngineIterator<'_> {
+    fn scan(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::EngineIterator<'_> {
         MemoryEngineIterator {
             inner: self.data.range(range),
         }
@@ -1,5 +1,11 @@
+pub mod btree;
+pub mod checksum;
 pub mod disk;
 pub mod engine;
 pub mod keycode;
+pub mod lsm;
 pub mod memory;
 pub mod mvcc;
+pub mod raft;
+pub mod replication;
+pub mod ssi;
@@ -1,6 +1,8 @@
 use std::{
-    collections::{BTreeMap, HashSet},
-    sync::{Arc, Mutex, MutexGuard},
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock, RwLockWriteGuard},
+    time::{Duration, Instant},
     u64,
 };
 
@@ -9,159 +11,286 @@ use serde::{Deserialize, Serialize};
 use crate::error::{Error, Result};
 
 use super::{
-    engine::Engine,
+    engine::{Engine, WriteBatch},
     keycode::{deserialize_key, serialize_key},
+    ssi::SsiTracker,
 };
 
 pub type Version = u64;
 
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IsolationLevel {
+    // every statement sees the data committed before it began, see
+    // MvccTransaction::begin_statement; a write conflicts with uncommitted
+    // writes of the key, and with versions of transactions which began later,
+    // as the transaction's own version would be ordered before them
+    ReadCommitted,
+    // also known as repeatable read: reads see the snapshot taken at begin,
+    // concurrent writes of a key conflict
+    #[default]
+    Snapshot,
+    // snapshot isolation, and a transaction whose reads and writes could
+    // form a cycle with concurrent ones fails with a serialization error
+    Serializable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TransactionOptions {
+    pub isolation: IsolationLevel,
+    // a read-only transaction stores nothing and fails on any write
+    pub read_only: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LockMode {
+    // FOR SHARE: other transactions may lock the key for share as well,
+    // but can't write it
+    Share,
+    // FOR UPDATE: no other transaction may lock or write the key
+    Update,
+}
+
+// What to do when a key is locked by another transaction
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LockWait {
+    // wait for the other transactions to finish, up to the lock timeout
+    #[default]
+    Block,
+    // fail with Error::LockNotAvailable at once
+    NoWait,
+    // leave the key unlocked
+    SkipLocked,
+}
+
+// How long a lock or a write waits for the locks of other transactions
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The oldest version invisible to each active read-only transaction, with the
+// number of transactions, they aren't stored as active but hold back the vacuum
+type Snapshots = BTreeMap<Version, usize>;
+
 pub struct Mvcc<E: Engine> {
-    engine: Arc<Mutex<E>>,
+    engine: Arc<RwLock<E>>,
+    // sync the storage engine when a transaction commits
+    sync_on_commit: bool,
+    isolation: IsolationLevel,
+    // reads and writes of the serializable transactions
+    ssi: Arc<Mutex<SsiTracker>>,
+    snapshots: Arc<Mutex<Snapshots>>,
+    lock_timeout: Duration,
+    // keep a commit record of every transaction which wrote, for the change stream
+    capture_changes: bool,
+    // the last failure of the periodic vacuum, see vacuum_every
+    vacuum_error: Arc<Mutex<Option<Error>>>,
 }
 
 impl<E: Engine> Clone for Mvcc<E> {
     fn clone(&self) -> Self {
         Self {
             engine: self.engine.clone(),
+            sync_on_commit: self.sync_on_commit,
+            isolation: self.isolation,
+            ssi: self.ssi.clone(),
+            snapshots: self.snapshots.clone(),
+            lock_timeout: self.lock_timeout,
+            capture_changes: self.capture_changes,
+            vacuum_error: self.vacuum_error.clone(),
         }
     }
 }
 
 impl<E: Engine> Mvcc<E> {
-    pub fn new(eng: E) -> Self {
-        Self {
-            engine: Arc::new(Mutex::new(eng)),
+    // Transactions still active in the engine were abandoned by a crash,
+    // they are rolled back before any new one begins, but the prepared ones,
+    // which wait for commit_prepared or rollback_prepared
+    pub fn new(mut eng: E) -> Result<Self> {
+        let mut abandoned = MvccTransaction::scan_active(&eng)?;
+        for version in MvccTransaction::scan_prepared(&eng)?.values() {
+            abandoned.remove(version);
+        }
+        for version in &abandoned {
+            let batch = MvccTransaction::rollback_batch(&eng, *version)?;
+            eng.write_batch(batch)?;
         }
+        Ok(Self {
+            engine: Arc::new(RwLock::new(eng)),
+            sync_on_commit: false,
+            isolation: IsolationLevel::default(),
+            ssi: Arc::new(Mutex::new(SsiTracker::new())),
+            snapshots: Arc::new(Mutex::new(Snapshots::new())),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            capture_changes: false,
+            vacuum_error: Arc::new(Mutex::new(None)),
+        })
     }
 
-    pub fn begin(&self) -> Result<MvccTransaction<E>> {
-        MvccTransaction::begin(self.engine.clone())
+    // Make every commit durable by syncing the engine at commit time,
+    // independent of the engine's own sync policy
+    pub fn sync_on_commit(mut self, enabled: bool) -> Self {
+        self.sync_on_commit = enabled;
+        self
     }
-}
 
-pub struct MvccTransaction<E: Engine> {
-    engine: Arc<Mutex<E>>,
-    state: TransactionState,
-}
-
-// Transaction state
-pub struct TransactionState {
-    // current transaction's version no.
-    pub version: Version,
-    // the list of current active transactions 
-    pub active_versions: HashSet<Version>,
-}
-
-impl TransactionState {
-    fn is_visible(&self, version: Version) -> bool {
-        if self.active_versions.contains(&version) {
-            return false;
-        } else {
-            return version <= self.version;
-        }
+    // The isolation level of the transactions begun by begin
+    pub fn isolation(mut self, isolation: IsolationLevel) -> Self {
+        self.isolation = isolation;
+        self
     }
-}
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-pub enum MvccKey {
-    NextVersion,
-    TxnAcvtive(Version),
-    TxnWrite(Version, #[serde(with = "serde_bytes")] Vec<u8>),
-    Version(#[serde(with = "serde_bytes")] Vec<u8>, Version),
-}
+    // How long a transaction waits for a key locked by others, there is no
+    // deadlock detection, a deadlock ends once this timeout expires
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
 
-// NextVersion 0
-// TxnAcvtive 1-100 1-101 1-102
-// Version key1-101 key2-101
+    // Record the committed writes for the change stream (Mvcc::changes), the
+    // records hold back the vacuum until they're truncated
+    pub fn capture_changes(mut self, enabled: bool) -> Self {
+        self.capture_changes = enabled;
+        self
+    }
 
-impl MvccKey {
-    pub fn encode(&self) -> Result<Vec<u8>> {
-        serialize_key(&self)
+    // The storage engine, for the tests to look into
+    #[cfg(test)]
+    pub(crate) fn engine(&self) -> Result<std::sync::RwLockReadGuard<'_, E>> {
+        Ok(self.engine.read()?)
     }
 
-    pub fn decode(data: Vec<u8>) -> Result<Self> {
-        deserialize_key(&data)
+    pub fn begin(&self) -> Result<MvccTransaction<E>> {
+        self.begin_with(TransactionOptions {
+            isolation: self.isolation,
+            read_only: false,
+        })
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum MvccKeyPrefix {
-    NextVersion,
-    TxnAcvtive,
-    TxnWrite(Version),
-    Version(#[serde(with = "serde_bytes")] Vec<u8>),
-}
+    pub fn begin_with(&self, options: TransactionOptions) -> Result<MvccTransaction<E>> {
+        let mut txn = if options.read_only {
+            MvccTransaction::begin_read_only(self.engine.clone(), &self.snapshots, None)?
+        } else {
+            MvccTransaction::begin(self.engine.clone())?
+        };
+        txn.sync_on_commit = self.sync_on_commit;
+        txn.lock_timeout = self.lock_timeout;
+        txn.capture_changes = self.capture_changes;
+        txn.isolation = options.isolation;
+        if options.isolation == IsolationLevel::Serializable {
+            let id = self
+                .ssi
+                .lock()?
+                .begin(txn.state.version, txn.state.active_versions.clone());
+            txn.ssi = Some((self.ssi.clone(), id));
+        }
+        Ok(txn)
+    }
 
-impl MvccKeyPrefix {
-    pub fn encode(&self) -> Result<Vec<u8>> {
-        serialize_key(&self)
+    // Begin a read-only transaction which sees the data as of a past version:
+    // the writes of every transaction up to that version which has committed by now
+    pub fn begin_as_of(&self, version: Version) -> Result<MvccTransaction<E>> {
+        MvccTransaction::begin_read_only(self.engine.clone(), &self.snapshots, Some(version))
     }
-}
 
-impl<E: Engine> MvccTransaction<E> {
-    // Begin a transaction
-    pub fn begin(eng: Arc<Mutex<E>>) -> Result<Self> {
-        // Fetch storage engine
-        let mut engine = eng.lock()?;
-        // Fetch the updated version no.
-        let next_version = match engine.get(MvccKey::NextVersion.encode()?)? {
+    // The newest version given to a transaction, 0 if none began yet
+    pub fn current_version(&self) -> Result<Version> {
+        let engine = self.engine.read()?;
+        let next_version: Version = match engine.get(MvccKey::NextVersion.encode()?)? {
             Some(value) => bincode::deserialize(&value)?,
             None => 1,
         };
-        // Store the next version
-        engine.set(
-            MvccKey::NextVersion.encode()?,
-            bincode::serialize(&(next_version + 1))?,
-        )?;
-
-        // Fetch the list of current active transactions
-        let active_versions = Self::scan_active(&mut engine)?;
-
-        // Add current transaction into the list
-        engine.set(MvccKey::TxnAcvtive(next_version).encode()?, vec![])?;
+        Ok(next_version - 1)
+    }
 
-        Ok(Self {
-            engine: eng.clone(),
-            state: TransactionState {
-                version: next_version,
-                active_versions,
-            },
-        })
+    // Remove the versions no transaction can read anymore, returns the number of
+    // removed versions
+    // Every version below the watermark is committed and visible to all active
+    // and future transactions, so only the newest of them is needed per key,
+    // and not even that one if it's a tombstone
+    // A failure of the periodic vacuum since the last call is returned instead.
+    pub fn vacuum(&self) -> Result<usize> {
+        if let Some(err) = self.vacuum_error.lock()?.take() {
+            return Err(err);
+        }
+        Self::vacuum_engine(&self.engine, &self.snapshots)
     }
 
-    // commit transaction
-    pub fn commit(&self) -> Result<()> {
-        // Fetch storage engine
-        let mut engine = self.engine.lock()?;
+    fn vacuum_engine(engine: &RwLock<E>, snapshots: &Mutex<Snapshots>) -> Result<usize> {
+        let mut engine = engine.write()?;
+        let mut watermark = Self::watermark(&engine)?;
+        if let Some(oldest) = snapshots.lock()?.keys().next() {
+            watermark = watermark.min(*oldest);
+        }
+        // the change stream reads the versions of the commit records, and
+        // the ones before them for the old values
+        for (_, version) in Self::commit_records(&engine)? {
+            watermark = watermark.min(version);
+        }
 
-        let mut delete_keys = Vec::new();
-        // Find current transactions TxnWrite
-        let mut iter = engine.scan_prefix(MvccKeyPrefix::TxnWrite(self.state.version).encode()?);
-        while let Some((key, _)) = iter.next().transpose()? {
-            delete_keys.push(key);
+        let mut enc_prefix = MvccKeyPrefix::Version(vec![]).encode()?;
+        enc_prefix.truncate(enc_prefix.len() - 2);
+        let mut batch = WriteBatch::new();
+        // the newest version below the watermark of the current key so far,
+        // and whether it's a tombstone
+        let mut newest: Option<(Vec<u8>, Vec<u8>, bool)> = None;
+        let mut iter = engine.scan_prefix(enc_prefix);
+        while let Some((key, value)) = iter.next().transpose()? {
+            let (raw_key, version) = match MvccKey::decode(key.clone())? {
+                MvccKey::Version(raw_key, version) => (raw_key, version),
+                _ => {
+                    return Err(Error::Internal(format!(
+                        "unexpected key: {:?}",
+                        String::from_utf8(key)
+                    )))
+                }
+            };
+            if newest.as_ref().is_some_and(|(k, _, _)| *k != raw_key) {
+                if let Some((_, enc_key, true)) = newest.take() {
+                    batch.delete(enc_key);
+                }
+            }
+            if version >= watermark {
+                continue;
+            }
+            let tombstone = bincode::deserialize::<Option<Vec<u8>>>(&value)?.is_none();
+            if let Some((_, shadowed, _)) = newest.replace((raw_key, key, tombstone)) {
+                batch.delete(shadowed);
+            }
         }
         drop(iter);
-
-        for key in delete_keys.into_iter() {
-            engine.delete(key)?;
+        if let Some((_, enc_key, true)) = newest {
+            batch.delete(enc_key);
         }
 
-        // remove from the active list
-        engine.delete(MvccKey::TxnAcvtive(self.state.version).encode()?)
+        let removed = batch.len();
+        // past versions below the watermark can't be read as of anymore
+        let vacuumed: Version = match engine.get(MvccKey::Vacuumed.encode()?)? {
+            Some(value) => bincode::deserialize(&value)?,
+            None => 0,
+        };
+        if watermark > vacuumed {
+            batch.set(MvccKey::Vacuumed.encode()?, bincode::serialize(&watermark)?);
+        }
+        engine.write_batch(batch)?;
+        Ok(removed)
     }
 
-    // roll back transaction
-    pub fn rollback(&self) -> Result<()> {
-        // fetch storage engine
-        let mut engine = self.engine.lock()?;
-
-        let mut delete_keys = Vec::new();
-        // find current transaction's TxnWrite 
-        let mut iter = engine.scan_prefix(MvccKeyPrefix::TxnWrite(self.state.version).encode()?);
-        while let Some((key, _)) = iter.next().transpose()? {
+    // The oldest version an active transaction may not see, every version
+    // below it is committed
+    fn watermark(engine: &E) -> Result<Version> {
+        let mut watermark = match engine.get(MvccKey::NextVersion.encode()?)? {
+            Some(value) => bincode::deserialize(&value)?,
+            None => 1,
+        };
+        let mut iter = engine.scan_prefix(MvccKeyPrefix::TxnAcvtive.encode()?);
+        while let Some((key, value)) = iter.next().transpose()? {
             match MvccKey::decode(key.clone())? {
-                MvccKey::TxnWrite(_, raw_key) => {
-                    delete_keys.push(MvccKey::Version(raw_key, self.state.version).encode()?);
+                // the value is the oldest version invisible to the transaction
+                MvccKey::TxnAcvtive(version) => {
+                    let oldest = if value.is_empty() {
+                        version
+                    } else {
+                        bincode::deserialize(&value)?
+                    };
+                    watermark = watermark.min(oldest);
                 }
                 _ => {
                     return Err(Error::Internal(format!(
@@ -170,42 +299,146 @@ impl<E: Engine> MvccTransaction<E> {
                     )))
                 }
             }
-            delete_keys.push(key);
         }
-        drop(iter);
+        Ok(watermark)
+    }
+
+    // Run a function with exclusive access to the storage engine,
+    // e.g. to take a DiskEngine snapshot for a backup
+    pub fn with_engine<T>(&self, f: impl FnOnce(&mut E) -> Result<T>) -> Result<T> {
+        let mut engine = self.engine.write()?;
+        f(&mut engine)
+    }
+
+    // Stream the committed transactions in commit order, starting after the
+    // transaction of the version, or at the oldest commit record kept
+    // The stream ends at the newest commit and picks up later ones when
+    // it's polled again, so a consumer can store the last version it saw
+    // and resume from it
+    pub fn changes(&self, after: Option<Version>) -> Result<ChangeStream<E>> {
+        let engine = self.engine.read()?;
+        let records = Self::commit_records(&engine)?;
+        let next = match after {
+            Some(version) => match records.iter().find(|(_, v)| *v == version) {
+                Some((seq, _)) => seq + 1,
+                None => {
+                    return Err(Error::Internal(format!(
+                        "no commit record of version {} is kept",
+                        version
+                    )))
+                }
+            },
+            None => match records.first() {
+                Some((seq, _)) => *seq,
+                None => match engine.get(MvccKey::NextCommit.encode()?)? {
+                    Some(value) => bincode::deserialize(&value)?,
+                    None => 1,
+                },
+            },
+        };
+        Ok(ChangeStream {
+            engine: self.engine.clone(),
+            next,
+        })
+    }
+
+    // Drop the commit records up to the one of the version, once every consumer
+    // has seen them, so the vacuum can remove the versions they read
+    pub fn truncate_changes(&self, through: Version) -> Result<()> {
+        let mut engine = self.engine.write()?;
+        let records = Self::commit_records(&engine)?;
+        let end = match records.iter().position(|(_, v)| *v == through) {
+            Some(index) => index + 1,
+            None => {
+                return Err(Error::Internal(format!(
+                    "no commit record of version {} is kept",
+                    through
+                )))
+            }
+        };
 
-        for key in delete_keys.into_iter() {
-            engine.delete(key)?;
+        let mut batch = WriteBatch::new();
+        for (seq, version) in &records[..end] {
+            batch.delete(MvccKey::Commit(*seq).encode()?);
+            let mut iter = engine.scan_prefix(MvccKeyPrefix::TxnWrite(*version).encode()?);
+            while let Some((key, _)) = iter.next().transpose()? {
+                batch.delete(key);
+            }
         }
+        engine.write_batch(batch)
+    }
 
-        // remove from the active list
-        engine.delete(MvccKey::TxnAcvtive(self.state.version).encode()?)
+    // The versions of the transactions of the kept commit records, in commit order
+    pub fn committed_versions(&self) -> Result<Vec<Version>> {
+        let engine = self.engine.read()?;
+        let records = Self::commit_records(&engine)?;
+        Ok(records.into_iter().map(|(_, version)| version).collect())
     }
 
-    pub fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        self.write_inner(key, Some(value))
+    // Write the changes of a transaction committed elsewhere, e.g. on a
+    // replication leader, in a transaction of its own, and remember its
+    // version as applied in the same commit
+    pub fn apply(&self, txn: &CommittedTxn) -> Result<()> {
+        let tx = self.begin()?;
+        for change in &txn.changes {
+            match &change.new {
+                Some(value) => tx.set(change.key.clone(), value.clone())?,
+                None => tx.delete(change.key.clone())?,
+            }
+        }
+        let mut batch = WriteBatch::new();
+        batch.set(MvccKey::Applied.encode()?, bincode::serialize(&txn.version)?);
+        tx.commit_with(batch)
     }
 
-    pub fn delete(&self, key: Vec<u8>) -> Result<()> {
-        self.write_inner(key, None)
+    // The version of the last transaction applied by Mvcc::apply
+    pub fn applied(&self) -> Result<Option<Version>> {
+        let engine = self.engine.read()?;
+        Ok(match engine.get(MvccKey::Applied.encode()?)? {
+            Some(value) => Some(bincode::deserialize(&value)?),
+            None => None,
+        })
     }
 
-    pub fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
-        // fetch storage engine
-        let mut engine = self.engine.lock()?;
+    // Whether commit records were truncated, so a stream from the oldest one
+    // kept misses the changes before it
+    pub fn changes_truncated(&self) -> Result<bool> {
+        let engine = self.engine.read()?;
+        let first = match Self::commit_records(&engine)?.first() {
+            Some((seq, _)) => *seq,
+            None => match engine.get(MvccKey::NextCommit.encode()?)? {
+                Some(value) => bincode::deserialize(&value)?,
+                None => 1,
+            },
+        };
+        Ok(first > 1)
+    }
 
-        // version: 9
-        // version range: 0-8
-        let from = MvccKey::Version(key.clone(), 0).encode()?;
-        let to = MvccKey::Version(key.clone(), self.state.version).encode()?;
-        let mut iter = engine.scan(from..=to).rev();
-        // read from the updated version, find a newest visible version
+    // Remember the last version a replication follower applied
+    pub fn set_follower_applied(&self, follower: &str, version: Option<Version>) -> Result<()> {
+        let mut engine = self.engine.write()?;
+        engine.set(
+            MvccKey::Follower(follower.as_bytes().to_vec()).encode()?,
+            bincode::serialize(&version)?,
+        )
+    }
+
+    pub fn remove_follower(&self, follower: &str) -> Result<()> {
+        let mut engine = self.engine.write()?;
+        engine.delete(MvccKey::Follower(follower.as_bytes().to_vec()).encode()?)
+    }
+
+    // The last versions the replication followers applied, by name
+    pub fn followers_applied(&self) -> Result<HashMap<String, Option<Version>>> {
+        let engine = self.engine.read()?;
+        let mut followers = HashMap::new();
+        let mut iter = engine.scan_prefix(MvccKeyPrefix::Follower.encode()?);
         while let Some((key, value)) = iter.next().transpose()? {
             match MvccKey::decode(key.clone())? {
-                MvccKey::Version(_, version) => {
-                    if self.state.is_visible(version) {
-                        return Ok(bincode::deserialize(&value)?);
-                    }
+                MvccKey::Follower(name) => {
+                    let name = String::from_utf8(name)
+                        .map_err(|err| Error::Internal(err.to_string()))?;
+                    followers.insert(name, bincode::deserialize(&value)?);
                 }
                 _ => {
                     return Err(Error::Internal(format!(
@@ -215,116 +448,99 @@ impl<E: Engine> MvccTransaction<E> {
                 }
             }
         }
-        Ok(None)
+        Ok(followers)
     }
 
-    pub fn scan_prefix(&self, prefix: Vec<u8>) -> Result<Vec<ScanResult>> {
-        let mut eng = self.engine.lock()?;
-        let mut enc_prefix = MvccKeyPrefix::Version(prefix).encode()?;
-        // Original           Encoded
-        // 97 98 99     -> 97 98 99 0 0
-        // Original prefix  Encoded prefix
-        // 97 98        -> 97 98 0 0         -> 97 98
-        // remove the last [0, 0] suffix
-        enc_prefix.truncate(enc_prefix.len() - 2);
+    // Record a transaction prepared on all participants of a two-phase commit
+    // coordinated here, before any of them commits, the record is synced
+    pub fn set_decision(&self, id: &str, commit: bool) -> Result<()> {
+        let mut engine = self.engine.write()?;
+        engine.set(
+            MvccKey::Decision(id.as_bytes().to_vec()).encode()?,
+            bincode::serialize(&commit)?,
+        )?;
+        engine.sync()
+    }
 
-        let mut iter = eng.scan_prefix(enc_prefix);
-        let mut results = BTreeMap::new();
+    pub fn remove_decision(&self, id: &str) -> Result<()> {
+        let mut engine = self.engine.write()?;
+        engine.delete(MvccKey::Decision(id.as_bytes().to_vec()).encode()?)
+    }
+
+    // The recorded decisions, by id
+    pub fn decisions(&self) -> Result<BTreeMap<String, bool>> {
+        let engine = self.engine.read()?;
+        let mut decisions = BTreeMap::new();
+        let mut iter = engine.scan_prefix(MvccKeyPrefix::Decision.encode()?);
         while let Some((key, value)) = iter.next().transpose()? {
             match MvccKey::decode(key.clone())? {
-                MvccKey::Version(raw_key, version) => {
-                    if self.state.is_visible(version) {
-                        match bincode::deserialize(&value)? {
-                            Some(raw_value) => results.insert(raw_key, raw_value),
-                            None => results.remove(&raw_key),
-                        };
-                    }
+                MvccKey::Decision(id) => {
+                    let id =
+                        String::from_utf8(id).map_err(|err| Error::Internal(err.to_string()))?;
+                    decisions.insert(id, bincode::deserialize(&value)?);
                 }
                 _ => {
                     return Err(Error::Internal(format!(
-                        "Unexepected key {:?}",
+                        "unexpected key: {:?}",
                         String::from_utf8(key)
                     )))
                 }
             }
         }
+        Ok(decisions)
+    }
 
-        Ok(results
-            .into_iter()
-            .map(|(key, value)| ScanResult { key, value })
+    // The ids of the prepared transactions, see MvccTransaction::prepare
+    pub fn prepared(&self) -> Result<Vec<String>> {
+        let engine = self.engine.read()?;
+        Ok(MvccTransaction::scan_prepared(&*engine)?
+            .into_keys()
             .collect())
     }
 
-    // update/delete data
-    fn write_inner(&self, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<()> {
-        // fetch storage engine
-        let mut engine = self.engine.lock()?;
-
-        // check conflicts
-        //  3 4 5
-        //  6
-        //  key1-3 key2-4 key3-5
-        let from = MvccKey::Version(
-            key.clone(),
-            self.state
-                .active_versions
-                .iter()
-                .min()
-                .copied()
-                .unwrap_or(self.state.version + 1),
-        )
-        .encode()?;
-        let to = MvccKey::Version(key.clone(), u64::MAX).encode()?;
-        // Currently active transactions: 3, 4, 5
-        // Current transaction: 6
-        // Only need to check the last version number
-        // 1. Keys are sorted, so scan results are in ascending order
-        // 2. If a new transaction (e.g., 10) modifies the key and commits, 
-        //    then if transaction 6 tries to modify that key, it’s a conflict
-        // 3. If one of the currently active transactions (e.g., 4) has modified the key, 
-        //    then transaction 5 can’t modify it
-
-        if let Some((k, _)) = engine.scan(from..=to).last().transpose()? {
-            match MvccKey::decode(k.clone())? {
-                MvccKey::Version(_, version) => {
-                    // check if the version is visible
-                    if !self.state.is_visible(version) {
-                        return Err(Error::WriteConflict);
-                    }
-                }
-                _ => {
-                    return Err(Error::Internal(format!(
-                        "unexpected key: {:?}",
-                        String::from_utf8(k)
-                    )))
-                }
-            }
-        }
+    // Commit the transaction prepared as the id, also one prepared before a restart
+    pub fn commit_prepared(&self, id: &str) -> Result<()> {
+        self.resume_prepared(id)?.commit()
+    }
 
-        // record what keys are writtenin to this version
-        // will be used for rollback
-        engine.set(
-            MvccKey::TxnWrite(self.state.version, key.clone()).encode()?,
-            vec![],
-        )?;
+    pub fn rollback_prepared(&self, id: &str) -> Result<()> {
+        self.resume_prepared(id)?.rollback()
+    }
 
-        // write in the actual key value
-        engine.set(
-            MvccKey::Version(key.clone(), self.state.version).encode()?,
-            bincode::serialize(&value)?,
-        )?;
-        Ok(())
+    // A transaction to end the prepared one, it reads nothing
+    fn resume_prepared(&self, id: &str) -> Result<MvccTransaction<E>> {
+        let version = MvccTransaction::prepared_version(&*self.engine.read()?, id)?;
+        Ok(MvccTransaction {
+            engine: self.engine.clone(),
+            state: TransactionState {
+                version,
+                active_versions: HashSet::new(),
+            },
+            sync_on_commit: self.sync_on_commit,
+            isolation: IsolationLevel::default(),
+            // a rollback forgets it in the tracker, it's committed there already
+            ssi: self
+                .ssi
+                .lock()?
+                .prepared(id)
+                .map(|ssi_id| (self.ssi.clone(), ssi_id)),
+            snapshots: None,
+            savepoints: Mutex::new(Savepoints::default()),
+            lock_timeout: self.lock_timeout,
+            capture_changes: self.capture_changes,
+            prepared: Mutex::new(Some(id.to_string())),
+            statement: Mutex::new(None),
+            committed: Mutex::new(false),
+        })
     }
 
-    // scan/fetch the active list
-    fn scan_active(engine: &mut MutexGuard<E>) -> Result<HashSet<Version>> {
-        let mut active_versions = HashSet::new();
-        let mut iter = engine.scan_prefix(MvccKeyPrefix::TxnAcvtive.encode()?);
-        while let Some((key, _)) = iter.next().transpose()? {
+    // The commit records with the versions of their transactions, in commit order
+    fn commit_records(engine: &E) -> Result<Vec<(u64, Version)>> {
+        let mut records = Vec::new();
+        let mut iter = engine.scan_prefix(MvccKeyPrefix::Commit.encode()?);
+        while let Some((key, value)) = iter.next().transpose()? {
             match MvccKey::decode(key.clone())? {
-                MvccKey::TxnAcvtive(version) => {
-                    active_versions.insert(version);
-                }
+                MvccKey::Commit(seq) => records.push((seq, bincode::deserialize(&value)?)),
                 _ => {
                     return Err(Error::Internal(format!(
                         "unexpected key: {:?}",
@@ -333,11 +549,1039 @@ impl<E: Engine> MvccTransaction<E> {
                 }
             }
         }
-        Ok(active_versions)
+        Ok(records)
     }
 }
 
-#[derive(Debug, PartialEq)]
+// A write of a committed transaction, a value is None if the key didn't
+// exist or was deleted
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub key: Vec<u8>,
+    pub old: Option<Vec<u8>>,
+    pub new: Option<Vec<u8>>,
+}
+
+// A committed transaction in the change stream
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommittedTxn {
+    pub version: Version,
+    pub changes: Vec<Change>,
+}
+
+pub struct ChangeStream<E: Engine> {
+    engine: Arc<RwLock<E>>,
+    // the number of the next commit record
+    next: u64,
+}
+
+impl<E: Engine> ChangeStream<E> {
+    fn read_next(&mut self) -> Result<Option<CommittedTxn>> {
+        let engine = self.engine.read()?;
+        let version: Version = match engine.get(MvccKey::Commit(self.next).encode()?)? {
+            Some(value) => bincode::deserialize(&value)?,
+            None => {
+                let next_commit: u64 = match engine.get(MvccKey::NextCommit.encode()?)? {
+                    Some(value) => bincode::deserialize(&value)?,
+                    None => 1,
+                };
+                if self.next < next_commit {
+                    return Err(Error::Internal(format!(
+                        "commit record {} was truncated",
+                        self.next
+                    )));
+                }
+                return Ok(None);
+            }
+        };
+
+        let mut keys = Vec::new();
+        let mut iter = engine.scan_prefix(MvccKeyPrefix::TxnWrite(version).encode()?);
+        while let Some((key, _)) = iter.next().transpose()? {
+            match MvccKey::decode(key.clone())? {
+                MvccKey::TxnWrite(_, raw_key) => keys.push(raw_key),
+                _ => {
+                    return Err(Error::Internal(format!(
+                        "unexpected key: {:?}",
+                        String::from_utf8(key)
+                    )))
+                }
+            }
+        }
+        drop(iter);
+
+        let mut changes = Vec::new();
+        for key in keys {
+            let new = match engine.get(MvccKey::Version(key.clone(), version).encode()?)? {
+                Some(value) => bincode::deserialize(&value)?,
+                None => None,
+            };
+            // the newest committed version before the transaction
+            let mut old = None;
+            let from = MvccKey::Version(key.clone(), 0).encode()?;
+            let to = MvccKey::Version(key.clone(), version).encode()?;
+            let mut iter = engine.scan(from..to).rev();
+            while let Some((k, value)) = iter.next().transpose()? {
+                match MvccKey::decode(k.clone())? {
+                    MvccKey::Version(_, v) => {
+                        if engine.get(MvccKey::TxnAcvtive(v).encode()?)?.is_none() {
+                            old = bincode::deserialize(&value)?;
+                            break;
+                        }
+                    }
+                    _ => {
+                        return Err(Error::Internal(format!(
+                            "unexpected key: {:?}",
+                            String::from_utf8(k)
+                        )))
+                    }
+                }
+            }
+            changes.push(Change { key, old, new });
+        }
+        self.next += 1;
+        Ok(Some(CommittedTxn { version, changes }))
+    }
+}
+
+impl<E: Engine> Iterator for ChangeStream<E> {
+    type Item = Result<CommittedTxn>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_next().transpose()
+    }
+}
+
+pub struct MvccTransaction<E: Engine> {
+    engine: Arc<RwLock<E>>,
+    state: TransactionState,
+    sync_on_commit: bool,
+    isolation: IsolationLevel,
+    // the tracker of a serializable transaction, and its id there
+    ssi: Option<(Arc<Mutex<SsiTracker>>, u64)>,
+    // the snapshots of the read-only transactions, None if it writes
+    snapshots: Option<Arc<Mutex<Snapshots>>>,
+    savepoints: Mutex<Savepoints>,
+    lock_timeout: Duration,
+    capture_changes: bool,
+    // the id it's prepared as, it can't write anymore
+    prepared: Mutex<Option<String>>,
+    // the snapshot of the current statement of a read committed transaction
+    statement: Mutex<Option<TransactionState>>,
+    // a committed transaction can't roll back anymore, its write markers may
+    // be the change list of its commit record
+    committed: Mutex<bool>,
+}
+
+// The savepoints of a transaction, with the log to undo the writes after them
+#[derive(Default)]
+struct Savepoints {
+    // name and the length of the undo log when it was set
+    marks: Vec<(String, usize)>,
+    // the key, and its value in the transaction before the write,
+    // None if the transaction hadn't written it
+    undo: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+// Transaction state
+#[derive(Clone)]
+pub struct TransactionState {
+    // current transaction's version no.
+    pub version: Version,
+    // the list of current active transactions 
+    pub active_versions: HashSet<Version>,
+}
+
+impl TransactionState {
+    // The oldest version the snapshot doesn't see
+    fn oldest_invisible(&self) -> Version {
+        self.active_versions
+            .iter()
+            .min()
+            .copied()
+            .unwrap_or(self.version + 1)
+    }
+
+    fn is_visible(&self, version: Version) -> bool {
+        if self.active_versions.contains(&version) {
+            return false;
+        } else {
+            return version <= self.version;
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum MvccKey {
+    NextVersion,
+    TxnAcvtive(Version),
+    TxnWrite(Version, #[serde(with = "serde_bytes")] Vec<u8>),
+    Version(#[serde(with = "serde_bytes")] Vec<u8>, Version),
+    // the watermark of the latest vacuum
+    Vacuumed,
+    // a lock of the key held by the transaction of the version, the value is the mode
+    Lock(#[serde(with = "serde_bytes")] Vec<u8>, Version),
+    // the keys a transaction locked, to release them when it ends
+    TxnLock(Version, #[serde(with = "serde_bytes")] Vec<u8>),
+    // the number of the next commit record
+    NextCommit,
+    // a commit record, by number in commit order, the value is the version of
+    // the transaction, whose TxnWrite markers are kept as its changes
+    Commit(u64),
+    // on a replication follower, the version of the last leader transaction applied
+    Applied,
+    // a prepared transaction by id, the value is its version
+    Prepared(#[serde(with = "serde_bytes")] Vec<u8>),
+    // on a replication leader, the version of the last transaction a follower
+    // applied by its name, None before the first
+    Follower(#[serde(with = "serde_bytes")] Vec<u8>),
+    // on the coordinator of a two-phase commit, a transaction prepared on all
+    // participants by id, the value is whether it was decided to commit, or
+    // waits for its commit or rollback by id
+    Decision(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+// NextVersion 0
+// TxnAcvtive 1-100 1-101 1-102
+// Version key1-101 key2-101
+
+impl MvccKey {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        serialize_key(&self)
+    }
+
+    pub fn decode(data: Vec<u8>) -> Result<Self> {
+        deserialize_key(&data)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MvccKeyPrefix {
+    NextVersion,
+    TxnAcvtive,
+    TxnWrite(Version),
+    Version(#[serde(with = "serde_bytes")] Vec<u8>),
+    // the variants line up with MvccKey
+    Vacuumed,
+    Lock(#[serde(with = "serde_bytes")] Vec<u8>),
+    TxnLock(Version),
+    NextCommit,
+    Commit,
+    Applied,
+    Prepared,
+    Follower,
+    Decision,
+}
+
+impl MvccKeyPrefix {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        serialize_key(&self)
+    }
+}
+
+impl<E: Engine + Send + Sync + 'static> Mvcc<E> {
+    // Vacuum in a background thread every interval, the thread stops
+    // once the Mvcc and all its clones are dropped
+    // A failed run is retried at the next interval, its error is kept
+    // for the next call to Mvcc::vacuum.
+    pub fn vacuum_every(self, interval: Duration) -> Self {
+        let engine = Arc::downgrade(&self.engine);
+        let snapshots = self.snapshots.clone();
+        let vacuum_error = self.vacuum_error.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let engine = match engine.upgrade() {
+                Some(engine) => engine,
+                None => return,
+            };
+            if let Err(err) = Self::vacuum_engine(&engine, &snapshots) {
+                if let Ok(mut vacuum_error) = vacuum_error.lock() {
+                    *vacuum_error = Some(err);
+                }
+            }
+        });
+        self
+    }
+}
+
+// A serializable transaction dropped without a commit or rollback is forgotten
+// by the tracker, a prepared one stays as it may still commit by its id
+impl<E: Engine> Drop for MvccTransaction<E> {
+    fn drop(&mut self) {
+        let committed = self.committed.get_mut().map_or(true, |c| *c);
+        let prepared = self.prepared.get_mut().map_or(true, |p| p.is_some());
+        if let Some((ssi, id)) = &self.ssi {
+            if !committed && !prepared {
+                if let Ok(mut ssi) = ssi.lock() {
+                    ssi.abort(*id);
+                }
+            }
+        }
+    }
+}
+
+impl<E: Engine> MvccTransaction<E> {
+    // Begin a transaction
+    pub fn begin(eng: Arc<RwLock<E>>) -> Result<Self> {
+        // Fetch storage engine
+        let mut engine = eng.write()?;
+        // Fetch the updated version no.
+        let next_version = match engine.get(MvccKey::NextVersion.encode()?)? {
+            Some(value) => bincode::deserialize(&value)?,
+            None => 1,
+        };
+        // Fetch the list of current active transactions
+        let active_versions = Self::scan_active(&engine)?;
+
+        // Store the next version and add current transaction into the list
+        let mut batch = WriteBatch::new();
+        batch.set(
+            MvccKey::NextVersion.encode()?,
+            bincode::serialize(&(next_version + 1))?,
+        );
+        // with the oldest version it can't see, for the vacuum watermark
+        let oldest = active_versions.iter().min().copied().unwrap_or(next_version);
+        batch.set(
+            MvccKey::TxnAcvtive(next_version).encode()?,
+            bincode::serialize(&oldest)?,
+        );
+        engine.write_batch(batch)?;
+
+        Ok(Self {
+            engine: eng.clone(),
+            state: TransactionState {
+                version: next_version,
+                active_versions,
+            },
+            sync_on_commit: false,
+            isolation: IsolationLevel::default(),
+            ssi: None,
+            snapshots: None,
+            savepoints: Mutex::new(Savepoints::default()),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            capture_changes: false,
+            prepared: Mutex::new(None),
+            statement: Mutex::new(None),
+            committed: Mutex::new(false),
+        })
+    }
+
+    // Begin a read-only transaction, it gets no version of its own but reads
+    // the snapshot of the newest version, or of a past one, and holds back
+    // the vacuum in memory
+    pub fn begin_read_only(
+        eng: Arc<RwLock<E>>,
+        snapshots: &Arc<Mutex<Snapshots>>,
+        as_of: Option<Version>,
+    ) -> Result<Self> {
+        let engine = eng.read()?;
+        let next_version: Version = match engine.get(MvccKey::NextVersion.encode()?)? {
+            Some(value) => bincode::deserialize(&value)?,
+            None => 1,
+        };
+        let version = match as_of {
+            Some(version) if version >= next_version => {
+                return Err(Error::Internal(format!(
+                    "version {} is newer than the current version {}",
+                    version,
+                    next_version - 1
+                )))
+            }
+            Some(version) => {
+                // the vacuum kept only the newest of the versions below its watermark
+                let vacuumed: Version = match engine.get(MvccKey::Vacuumed.encode()?)? {
+                    Some(value) => bincode::deserialize(&value)?,
+                    None => 0,
+                };
+                if version + 1 < vacuumed {
+                    return Err(Error::Internal(format!(
+                        "version {} was vacuumed, the oldest readable version is {}",
+                        version,
+                        vacuumed - 1
+                    )));
+                }
+                version
+            }
+            None => next_version - 1,
+        };
+        let mut active_versions = Self::scan_active(&engine)?;
+        active_versions.retain(|v| *v <= version);
+        let state = TransactionState {
+            version,
+            active_versions,
+        };
+        *snapshots.lock()?.entry(state.oldest_invisible()).or_default() += 1;
+
+        Ok(Self {
+            engine: eng.clone(),
+            state,
+            sync_on_commit: false,
+            isolation: IsolationLevel::default(),
+            ssi: None,
+            snapshots: Some(snapshots.clone()),
+            savepoints: Mutex::new(Savepoints::default()),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            capture_changes: false,
+            prepared: Mutex::new(None),
+            statement: Mutex::new(None),
+            committed: Mutex::new(false),
+        })
+    }
+
+    // commit transaction
+    pub fn commit(&self) -> Result<()> {
+        self.commit_with(WriteBatch::new())
+    }
+
+    // Commit, writing the batch along with the commit
+    fn commit_with(&self, mut batch: WriteBatch) -> Result<()> {
+        // Fetch storage engine
+        let mut engine = self.engine.write()?;
+
+        // a prepared transaction was checked already
+        let prepared = self.prepared.lock()?.clone();
+        if prepared.is_none() {
+            if let Err(err) = self.check_serializable(&engine) {
+                drop(engine);
+                self.rollback()?;
+                return Err(err);
+            }
+        }
+
+        // a read-only transaction has nothing stored
+        if let Some(snapshots) = &self.snapshots {
+            self.release_snapshot(snapshots)?;
+            *self.committed.lock()? = true;
+            return Ok(());
+        }
+
+        // Find current transactions TxnWrite
+        let mut iter = engine.scan_prefix(MvccKeyPrefix::TxnWrite(self.state.version).encode()?);
+        let mut written = false;
+        while let Some((key, _)) = iter.next().transpose()? {
+            written = true;
+            if !self.capture_changes {
+                batch.delete(key);
+            }
+        }
+        drop(iter);
+        // the write markers stay as the change list of the commit record,
+        // which is numbered in commit order
+        if self.capture_changes && written {
+            let seq: u64 = match engine.get(MvccKey::NextCommit.encode()?)? {
+                Some(value) => bincode::deserialize(&value)?,
+                None => 1,
+            };
+            batch.set(MvccKey::NextCommit.encode()?, bincode::serialize(&(seq + 1))?);
+            batch.set(
+                MvccKey::Commit(seq).encode()?,
+                bincode::serialize(&self.state.version)?,
+            );
+        }
+        Self::release_locks(&engine, self.state.version, &mut batch)?;
+        if let Some(id) = &prepared {
+            Self::end_prepared(&engine, id, &mut batch)?;
+        }
+
+        // remove from the active list, in the same batch so the commit is atomic
+        batch.delete(MvccKey::TxnAcvtive(self.state.version).encode()?);
+        engine.write_batch(batch)?;
+        *self.committed.lock()? = true;
+
+        if self.sync_on_commit {
+            engine.sync()?;
+        }
+        Ok(())
+    }
+
+    // Prepare the transaction under the id, the first phase of a two-phase
+    // commit: a serializable transaction is checked now, and the transaction
+    // is durably recorded as prepared, so only the storage can fail the commit
+    // or rollback which follows, here or by id with Mvcc::commit_prepared and
+    // Mvcc::rollback_prepared, also after a restart
+    // Until then it can't write anymore, and its writes and locks keep blocking
+    // other transactions. A transaction failing the check is rolled back.
+    pub fn prepare(&self, id: &str) -> Result<()> {
+        self.check_writable()?;
+        let mut engine = self.engine.write()?;
+        let key = MvccKey::Prepared(id.as_bytes().to_vec()).encode()?;
+        if engine.get(key.clone())?.is_some() {
+            return Err(Error::Internal(format!(
+                "a transaction is already prepared as {}",
+                id
+            )));
+        }
+        if let Err(err) = self.check_serializable(&engine) {
+            drop(engine);
+            self.rollback()?;
+            return Err(err);
+        }
+        engine.set(key, bincode::serialize(&self.state.version)?)?;
+        engine.sync()?;
+        *self.prepared.lock()? = Some(id.to_string());
+        if let Some((ssi, ssi_id)) = &self.ssi {
+            ssi.lock()?.prepare(*ssi_id, id);
+        }
+        Ok(())
+    }
+
+    // The version of the transaction prepared as the id
+    fn prepared_version(engine: &E, id: &str) -> Result<Version> {
+        match engine.get(MvccKey::Prepared(id.as_bytes().to_vec()).encode()?)? {
+            Some(value) => Ok(bincode::deserialize(&value)?),
+            None => Err(Error::Internal(format!(
+                "no transaction is prepared as {}",
+                id
+            ))),
+        }
+    }
+
+    // Add the removal of the prepared record to the batch which ends the
+    // transaction, it fails if the transaction was ended by id meanwhile
+    fn end_prepared(engine: &E, id: &str, batch: &mut WriteBatch) -> Result<()> {
+        Self::prepared_version(engine, id)?;
+        batch.delete(MvccKey::Prepared(id.as_bytes().to_vec()).encode()?);
+        Ok(())
+    }
+
+    // The prepared transactions, by id
+    fn scan_prepared(engine: &E) -> Result<BTreeMap<String, Version>> {
+        let mut prepared = BTreeMap::new();
+        let mut iter = engine.scan_prefix(MvccKeyPrefix::Prepared.encode()?);
+        while let Some((key, value)) = iter.next().transpose()? {
+            match MvccKey::decode(key.clone())? {
+                MvccKey::Prepared(id) => {
+                    let id =
+                        String::from_utf8(id).map_err(|err| Error::Internal(err.to_string()))?;
+                    prepared.insert(id, bincode::deserialize(&value)?);
+                }
+                _ => {
+                    return Err(Error::Internal(format!(
+                        "unexpected key: {:?}",
+                        String::from_utf8(key)
+                    )))
+                }
+            }
+        }
+        Ok(prepared)
+    }
+
+    // A serializable transaction is checked and marked committed in the
+    // tracker while the engine is locked, so no transaction begins in between
+    fn check_serializable(&self, engine: &E) -> Result<()> {
+        if let Some((ssi, id)) = &self.ssi {
+            let next_version = match engine.get(MvccKey::NextVersion.encode()?)? {
+                Some(value) => bincode::deserialize(&value)?,
+                None => self.state.version + 1,
+            };
+            ssi.lock()?.commit(*id, next_version)?;
+        }
+        Ok(())
+    }
+
+    // roll back transaction
+    // A write transaction rolled back again is left as it is, e.g. one whose
+    // prepare rolled it back, as it forgets the id it was prepared as
+    pub fn rollback(&self) -> Result<()> {
+        if *self.committed.lock()? {
+            return Err(Error::Internal(
+                "the transaction is committed, it can't roll back".to_string(),
+            ));
+        }
+        match &self.snapshots {
+            Some(snapshots) => self.release_snapshot(snapshots)?,
+            None => {
+                // fetch storage engine
+                let mut engine = self.engine.write()?;
+                let mut batch = Self::rollback_batch(&engine, self.state.version)?;
+                let mut prepared = self.prepared.lock()?;
+                if let Some(id) = prepared.as_ref() {
+                    Self::end_prepared(&engine, id, &mut batch)?;
+                }
+                engine.write_batch(batch)?;
+                *prepared = None;
+            }
+        }
+        if let Some((ssi, id)) = &self.ssi {
+            ssi.lock()?.abort(*id);
+        }
+        Ok(())
+    }
+
+    fn release_snapshot(&self, snapshots: &Mutex<Snapshots>) -> Result<()> {
+        let mut snapshots = snapshots.lock()?;
+        let oldest = self.state.oldest_invisible();
+        if let Some(count) = snapshots.get_mut(&oldest) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&oldest);
+            }
+        }
+        Ok(())
+    }
+
+    // Set a savepoint, a later one of the same name hides the earlier
+    // The engine is locked before the savepoints, as in writes, so a
+    // savepoint is never set in the middle of a write
+    pub fn savepoint(&self, name: &str) -> Result<()> {
+        let _engine = self.engine.read()?;
+        let mut savepoints = self.savepoints.lock()?;
+        let len = savepoints.undo.len();
+        savepoints.marks.push((name.to_string(), len));
+        Ok(())
+    }
+
+    // Undo the writes after the savepoint and drop the savepoints set after it,
+    // the savepoint itself stays
+    // A key first written after the savepoint loses its write marker as well,
+    // so the transaction no longer conflicts on it, a serializable transaction
+    // still counts the undone writes when checking for cycles
+    pub fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        let mut engine = self.engine.write()?;
+        let mut savepoints = self.savepoints.lock()?;
+        let index = Self::find_savepoint(&savepoints, name)?;
+        let len = savepoints.marks[index].1;
+        savepoints.marks.truncate(index + 1);
+
+        let mut batch = WriteBatch::new();
+        for (key, value) in savepoints.undo.drain(len..).rev() {
+            let enc_key = MvccKey::Version(key.clone(), self.state.version).encode()?;
+            match value {
+                Some(value) => batch.set(enc_key, value),
+                None => {
+                    batch.delete(enc_key);
+                    batch.delete(MvccKey::TxnWrite(self.state.version, key).encode()?);
+                }
+            }
+        }
+        engine.write_batch(batch)
+    }
+
+    // Drop the savepoint and the ones set after it, keeping their writes
+    pub fn release_savepoint(&self, name: &str) -> Result<()> {
+        let mut savepoints = self.savepoints.lock()?;
+        let index = Self::find_savepoint(&savepoints, name)?;
+        savepoints.marks.truncate(index);
+        // without savepoints no write will be undone
+        if savepoints.marks.is_empty() {
+            savepoints.undo.clear();
+        }
+        Ok(())
+    }
+
+    fn find_savepoint(savepoints: &Savepoints, name: &str) -> Result<usize> {
+        savepoints
+            .marks
+            .iter()
+            .rposition(|(n, _)| n == name)
+            .ok_or(Error::Internal(format!("savepoint {} does not exist", name)))
+    }
+
+    // Begin a statement, a read committed transaction takes the snapshot
+    // all reads of the statement see, so they see the same commits
+    pub fn begin_statement(&self) -> Result<()> {
+        if self.isolation == IsolationLevel::ReadCommitted {
+            let engine = self.engine.read()?;
+            *self.statement.lock()? = Some(self.latest_state(&engine)?);
+        }
+        Ok(())
+    }
+
+    // The snapshot reads see, a read committed transaction uses the one of
+    // its statement, or takes a new one for every read outside of statements
+    fn snapshot(&self, engine: &E) -> Result<Cow<'_, TransactionState>> {
+        if self.isolation != IsolationLevel::ReadCommitted {
+            return Ok(Cow::Borrowed(&self.state));
+        }
+        if let Some(state) = self.statement.lock()?.as_ref() {
+            return Ok(Cow::Owned(state.clone()));
+        }
+        Ok(Cow::Owned(self.latest_state(engine)?))
+    }
+
+    // A snapshot of the newest committed data and the transaction's own writes
+    fn latest_state(&self, engine: &E) -> Result<TransactionState> {
+        let next_version: Version = match engine.get(MvccKey::NextVersion.encode()?)? {
+            Some(value) => bincode::deserialize(&value)?,
+            None => 1,
+        };
+        let mut active_versions = Self::scan_active(engine)?;
+        active_versions.remove(&self.state.version);
+        Ok(TransactionState {
+            version: next_version - 1,
+            active_versions,
+        })
+    }
+
+    // The writes which roll back the transaction of the version
+    fn rollback_batch(engine: &E, version: Version) -> Result<WriteBatch> {
+        let mut batch = WriteBatch::new();
+        // find current transaction's TxnWrite 
+        let mut iter = engine.scan_prefix(MvccKeyPrefix::TxnWrite(version).encode()?);
+        while let Some((key, _)) = iter.next().transpose()? {
+            match MvccKey::decode(key.clone())? {
+                MvccKey::TxnWrite(_, raw_key) => {
+                    batch.delete(MvccKey::Version(raw_key, version).encode()?);
+                }
+                _ => {
+                    return Err(Error::Internal(format!(
+                        "unexpected key: {:?}",
+                        String::from_utf8(key)
+                    )))
+                }
+            }
+            batch.delete(key);
+        }
+        drop(iter);
+        Self::release_locks(engine, version, &mut batch)?;
+
+        // remove from the active list
+        batch.delete(MvccKey::TxnAcvtive(version).encode()?);
+        Ok(batch)
+    }
+
+    // Add the removal of the locks of the transaction of the version to the batch
+    fn release_locks(engine: &E, version: Version, batch: &mut WriteBatch) -> Result<()> {
+        let mut iter = engine.scan_prefix(MvccKeyPrefix::TxnLock(version).encode()?);
+        while let Some((key, _)) = iter.next().transpose()? {
+            match MvccKey::decode(key.clone())? {
+                MvccKey::TxnLock(_, raw_key) => {
+                    batch.delete(MvccKey::Lock(raw_key, version).encode()?);
+                }
+                _ => {
+                    return Err(Error::Internal(format!(
+                        "unexpected key: {:?}",
+                        String::from_utf8(key)
+                    )))
+                }
+            }
+            batch.delete(key);
+        }
+        Ok(())
+    }
+
+    // Lock the key until the transaction ends, returns false if it's locked
+    // by another transaction and skipped
+    // A key is locked by the locks of other transactions, unless all of them
+    // are shared, and by uncommitted writes. Like a write, it fails with a
+    // write conflict if the key changed after the snapshot.
+    pub fn lock(&self, key: Vec<u8>, mode: LockMode, wait: LockWait) -> Result<bool> {
+        self.check_writable()?;
+        let mut engine = match self.acquire(std::slice::from_ref(&key), mode, wait, true)? {
+            Some(engine) => engine,
+            None => return Ok(false),
+        };
+        let state = self.snapshot(&engine)?;
+        self.check_conflict(&engine, &state, &key)?;
+        drop(state);
+
+        // an update lock isn't weakened by a later share lock
+        let lock_key = MvccKey::Lock(key.clone(), self.state.version).encode()?;
+        if let Some(held) = engine.get(lock_key.clone())? {
+            if mode == LockMode::Share || bincode::deserialize::<LockMode>(&held)? == mode {
+                return Ok(true);
+            }
+        }
+        let mut batch = WriteBatch::new();
+        batch.set(lock_key, bincode::serialize(&mode)?);
+        batch.set(MvccKey::TxnLock(self.state.version, key).encode()?, vec![]);
+        engine.write_batch(batch)?;
+        Ok(true)
+    }
+
+    // Wait until no other transaction holds a lock on the key that conflicts
+    // with the mode, returns the engine locked for writing, or None to skip the key
+    fn acquire(
+        &self,
+        keys: &[Vec<u8>],
+        mode: LockMode,
+        wait: LockWait,
+        by_writes: bool,
+    ) -> Result<Option<RwLockWriteGuard<'_, E>>> {
+        let deadline = Instant::now() + self.lock_timeout;
+        let mut backoff = Duration::from_millis(1);
+        loop {
+            let engine = self.engine.write()?;
+            let mut locked = false;
+            for key in keys {
+                if self.is_locked(&engine, key, mode, by_writes)? {
+                    locked = true;
+                    break;
+                }
+            }
+            if !locked {
+                return Ok(Some(engine));
+            }
+            drop(engine);
+            match wait {
+                LockWait::SkipLocked => return Ok(None),
+                LockWait::NoWait => return Err(Error::LockNotAvailable),
+                LockWait::Block if Instant::now() >= deadline => {
+                    return Err(Error::LockNotAvailable)
+                }
+                LockWait::Block => {
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(Duration::from_millis(20));
+                }
+            }
+        }
+    }
+
+    // Whether another transaction holds a lock on the key that conflicts with
+    // the mode, or, if by_writes, has written the key and not committed yet
+    fn is_locked(&self, engine: &E, key: &[u8], mode: LockMode, by_writes: bool) -> Result<bool> {
+        let mut iter = engine.scan_prefix(MvccKeyPrefix::Lock(key.to_vec()).encode()?);
+        while let Some((k, value)) = iter.next().transpose()? {
+            match MvccKey::decode(k.clone())? {
+                MvccKey::Lock(_, version) => {
+                    let held: LockMode = bincode::deserialize(&value)?;
+                    if version != self.state.version
+                        && (mode == LockMode::Update || held == LockMode::Update)
+                    {
+                        return Ok(true);
+                    }
+                }
+                _ => {
+                    return Err(Error::Internal(format!(
+                        "unexpected key: {:?}",
+                        String::from_utf8(k)
+                    )))
+                }
+            }
+        }
+        drop(iter);
+        if !by_writes {
+            return Ok(false);
+        }
+
+        let from = MvccKey::Version(key.to_vec(), 0).encode()?;
+        let to = MvccKey::Version(key.to_vec(), u64::MAX).encode()?;
+        if let Some((k, _)) = engine.scan(from..=to).last().transpose()? {
+            if let MvccKey::Version(_, version) = MvccKey::decode(k)? {
+                return Ok(version != self.state.version
+                    && engine.get(MvccKey::TxnAcvtive(version).encode()?)?.is_some());
+            }
+        }
+        Ok(false)
+    }
+
+    pub fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write_inner(vec![(key, Some(value))])
+    }
+
+    // Set the keys in one write batch, either all of them are written or none
+    pub fn set_batch(&self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        self.write_inner(pairs.into_iter().map(|(k, v)| (k, Some(v))).collect())
+    }
+
+    pub fn delete(&self, key: Vec<u8>) -> Result<()> {
+        self.write_inner(vec![(key, None)])
+    }
+
+    pub fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some((ssi, id)) = &self.ssi {
+            ssi.lock()?.read(*id, &key)?;
+        }
+        // fetch storage engine
+        let engine = self.engine.read()?;
+        let state = self.snapshot(&engine)?;
+
+        // version: 9
+        // version range: 0-8
+        let from = MvccKey::Version(key.clone(), 0).encode()?;
+        let to = MvccKey::Version(key.clone(), state.version).encode()?;
+        let mut iter = engine.scan(from..=to).rev();
+        // read from the updated version, find a newest visible version
+        while let Some((key, value)) = iter.next().transpose()? {
+            match MvccKey::decode(key.clone())? {
+                MvccKey::Version(_, version) => {
+                    if state.is_visible(version) {
+                        return Ok(bincode::deserialize(&value)?);
+                    }
+                }
+                _ => {
+                    return Err(Error::Internal(format!(
+                        "unexpected key: {:?}",
+                        String::from_utf8(key)
+                    )))
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn scan_prefix(&self, prefix: Vec<u8>) -> Result<Vec<ScanResult>> {
+        if let Some((ssi, id)) = &self.ssi {
+            ssi.lock()?.scan(*id, &prefix)?;
+        }
+        let eng = self.engine.read()?;
+        let state = self.snapshot(&eng)?;
+        let mut enc_prefix = MvccKeyPrefix::Version(prefix).encode()?;
+        // Original           Encoded
+        // 97 98 99     -> 97 98 99 0 0
+        // Original prefix  Encoded prefix
+        // 97 98        -> 97 98 0 0         -> 97 98
+        // remove the last [0, 0] suffix
+        enc_prefix.truncate(enc_prefix.len() - 2);
+
+        let mut iter = eng.scan_prefix(enc_prefix);
+        let mut results = BTreeMap::new();
+        while let Some((key, value)) = iter.next().transpose()? {
+            match MvccKey::decode(key.clone())? {
+                MvccKey::Version(raw_key, version) => {
+                    if state.is_visible(version) {
+                        match bincode::deserialize(&value)? {
+                            Some(raw_value) => results.insert(raw_key, raw_value),
+                            None => results.remove(&raw_key),
+                        };
+                    }
+                }
+                _ => {
+                    return Err(Error::Internal(format!(
+                        "Unexepected key {:?}",
+                        String::from_utf8(key)
+                    )))
+                }
+            }
+        }
+
+        Ok(results
+            .into_iter()
+            .map(|(key, value)| ScanResult { key, value })
+            .collect())
+    }
+
+    // update/delete data
+    fn check_writable(&self) -> Result<()> {
+        if self.snapshots.is_some() {
+            return Err(Error::ReadOnly);
+        }
+        if self.prepared.lock()?.is_some() {
+            return Err(Error::Internal(
+                "the transaction is prepared, it can only commit or roll back".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn write_inner(&self, writes: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Result<()> {
+        self.check_writable()?;
+        let keys: Vec<Vec<u8>> = writes.iter().map(|(key, _)| key.clone()).collect();
+        // fetch storage engine, once no other transaction holds a lock on the keys
+        let mut engine = match self.acquire(&keys, LockMode::Update, LockWait::Block, false)? {
+            Some(engine) => engine,
+            None => return Err(Error::LockNotAvailable),
+        };
+        let state = self.snapshot(&engine)?;
+        for key in &keys {
+            self.check_conflict(&engine, &state, key)?;
+        }
+
+        // remember the previous values, for a rollback to a savepoint
+        let mut savepoints = self.savepoints.lock()?;
+        let mark = savepoints.undo.len();
+        if !savepoints.marks.is_empty() {
+            for key in &keys {
+                let previous =
+                    engine.get(MvccKey::Version(key.clone(), self.state.version).encode()?)?;
+                savepoints.undo.push((key.clone(), previous));
+            }
+        }
+
+        let mut batch = WriteBatch::new();
+        for (key, value) in writes {
+            // record what keys are writtenin to this version
+            // will be used for rollback
+            batch.set(
+                MvccKey::TxnWrite(self.state.version, key.clone()).encode()?,
+                vec![],
+            );
+
+            // write in the actual key value, in the same batch as its marker
+            batch.set(
+                MvccKey::Version(key, self.state.version).encode()?,
+                bincode::serialize(&value)?,
+            );
+        }
+        // a failed batch wrote nothing to undo
+        if let Err(e) = engine.write_batch(batch) {
+            savepoints.undo.truncate(mark);
+            return Err(e);
+        }
+        drop(savepoints);
+        drop(state);
+        drop(engine);
+
+        if let Some((ssi, id)) = &self.ssi {
+            let mut ssi = ssi.lock()?;
+            for key in &keys {
+                ssi.write(*id, key)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Fails with a write conflict if the newest version of the key is
+    // invisible to the snapshot, or newer than the transaction
+    fn check_conflict(&self, engine: &E, state: &TransactionState, key: &[u8]) -> Result<()> {
+        // check conflicts
+        //  3 4 5
+        //  6
+        //  key1-3 key2-4 key3-5
+        let oldest = state.oldest_invisible().min(self.state.version + 1);
+        let from = MvccKey::Version(key.to_vec(), oldest).encode()?;
+        let to = MvccKey::Version(key.to_vec(), u64::MAX).encode()?;
+        // Currently active transactions: 3, 4, 5
+        // Current transaction: 6
+        // Only need to check the last version number
+        // 1. Keys are sorted, so scan results are in ascending order
+        // 2. If a new transaction (e.g., 10) modifies the key and commits, 
+        //    then if transaction 6 tries to modify that key, it’s a conflict
+        // 3. If one of the currently active transactions (e.g., 4) has modified the key, 
+        //    then transaction 5 can’t modify it
+
+        if let Some((k, _)) = engine.scan(from..=to).last().transpose()? {
+            match MvccKey::decode(k.clone())? {
+                MvccKey::Version(_, version) => {
+                    // check if the version is visible, a read committed transaction
+                    // also sees versions newer than its own, but can't write over them
+                    if !state.is_visible(version) || version > self.state.version {
+                        return Err(Error::WriteConflict);
+                    }
+                }
+                _ => {
+                    return Err(Error::Internal(format!(
+                        "unexpected key: {:?}",
+                        String::from_utf8(k)
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // scan/fetch the active list
+    fn scan_active(engine: &E) -> Result<HashSet<Version>> {
+        let mut active_versions = HashSet::new();
+        let mut iter = engine.scan_prefix(MvccKeyPrefix::TxnAcvtive.encode()?);
+        while let Some((key, _)) = iter.next().transpose()? {
+            match MvccKey::decode(key.clone())? {
+                MvccKey::TxnAcvtive(version) => {
+                    active_versions.insert(version);
+                }
+                _ => {
+                    return Err(Error::Internal(format!(
+                        "unexpected key: {:?}",
+                        String::from_utf8(key)
+                    )))
+                }
+            }
+        }
+        Ok(active_versions)
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub struct ScanResult {
     pub key: Vec<u8>,
     pub value: Vec<u8>,
@@ -346,15 +1590,46 @@ pub struct ScanResult {
 #[cfg(test)]
 mod tests {
     use crate::{
-        error::Result,
-        storage::{disk::DiskEngine, engine::Engine, memory::MemoryEngine},
+        error::{Error, Result},
+        storage::{
+            btree::{BTreeEngine, BTreeOptions},
+            disk::DiskEngine,
+            engine::Engine,
+            lsm::{LsmEngine, LsmOptions},
+            memory::MemoryEngine,
+        },
+    };
+
+    use super::{
+        Change, CommittedTxn, IsolationLevel, LockMode, LockWait, Mvcc, MvccKey, MvccKeyPrefix,
+        ScanResult, TransactionOptions,
+    };
+    use std::{
+        path::Path,
+        sync::Arc,
+        time::{Duration, Instant},
     };
 
-    use super::Mvcc;
+    // An LsmEngine with a small memtable, so the versions spread over several tables
+    fn lsm_engine(dir: &Path) -> Result<LsmEngine> {
+        LsmEngine::new_with_options(
+            dir.to_path_buf(),
+            LsmOptions {
+                memtable_size: 64,
+                block_size: 64,
+                tier_size: 2,
+            },
+        )
+    }
+
+    // A BTreeEngine with a small buffer pool, so pages are evicted and read back
+    fn btree_engine(dir: &Path) -> Result<BTreeEngine> {
+        BTreeEngine::new_with_options(dir.join("sqldb-btree"), BTreeOptions { pool_pages: 8 })
+    }
 
     // 1. Get
     fn get(eng: impl Engine) -> Result<()> {
-        let mvcc = Mvcc::new(eng);
+        let mvcc = Mvcc::new(eng)?;
         let tx = mvcc.begin()?;
         tx.set(b"key1".to_vec(), b"val1".to_vec())?;
         tx.set(b"key2".to_vec(), b"val2".to_vec())?;
@@ -378,12 +1653,18 @@ mod tests {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
         get(DiskEngine::new(p.clone())?)?;
         std::fs::remove_dir_all(p.parent().unwrap())?;
+
+        let dir = tempfile::tempdir()?;
+        get(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        get(btree_engine(dir.path())?)?;
         Ok(())
     }
 
     // 2. Get Isolation
     fn get_isolation(eng: impl Engine) -> Result<()> {
-        let mvcc = Mvcc::new(eng);
+        let mvcc = Mvcc::new(eng)?;
         let tx = mvcc.begin()?;
         tx.set(b"key1".to_vec(), b"val1".to_vec())?;
         tx.set(b"key2".to_vec(), b"val2".to_vec())?;
@@ -407,19 +1688,103 @@ mod tests {
 
         Ok(())
     }
-    #[test]
-    fn test_get_isolation() -> Result<()> {
-        get_isolation(MemoryEngine::new())?;
+    #[test]
+    fn test_get_isolation() -> Result<()> {
+        get_isolation(MemoryEngine::new())?;
+
+        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
+        get_isolation(DiskEngine::new(p.clone())?)?;
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+
+        let dir = tempfile::tempdir()?;
+        get_isolation(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        get_isolation(btree_engine(dir.path())?)?;
+        Ok(())
+    }
+
+    // 3. scan prefix
+    fn scan_prefix(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
+        let tx = mvcc.begin()?;
+        tx.set(b"aabb".to_vec(), b"val1".to_vec())?;
+        tx.set(b"abcc".to_vec(), b"val2".to_vec())?;
+        tx.set(b"bbaa".to_vec(), b"val3".to_vec())?;
+        tx.set(b"acca".to_vec(), b"val4".to_vec())?;
+        tx.set(b"aaca".to_vec(), b"val5".to_vec())?;
+        tx.set(b"bcca".to_vec(), b"val6".to_vec())?;
+        tx.commit()?;
+
+        let tx1 = mvcc.begin()?;
+        let iter1 = tx1.scan_prefix(b"aa".to_vec())?;
+        assert_eq!(
+            iter1,
+            vec![
+                super::ScanResult {
+                    key: b"aabb".to_vec(),
+                    value: b"val1".to_vec()
+                },
+                super::ScanResult {
+                    key: b"aaca".to_vec(),
+                    value: b"val5".to_vec()
+                },
+            ]
+        );
+
+        let iter2 = tx1.scan_prefix(b"a".to_vec())?;
+        assert_eq!(
+            iter2,
+            vec![
+                super::ScanResult {
+                    key: b"aabb".to_vec(),
+                    value: b"val1".to_vec()
+                },
+                super::ScanResult {
+                    key: b"aaca".to_vec(),
+                    value: b"val5".to_vec()
+                },
+                super::ScanResult {
+                    key: b"abcc".to_vec(),
+                    value: b"val2".to_vec()
+                },
+                super::ScanResult {
+                    key: b"acca".to_vec(),
+                    value: b"val4".to_vec()
+                },
+            ]
+        );
+
+        let iter3 = tx1.scan_prefix(b"bcca".to_vec())?;
+        assert_eq!(
+            iter3,
+            vec![super::ScanResult {
+                key: b"bcca".to_vec(),
+                value: b"val6".to_vec()
+            },]
+        );
+
+        Ok(())
+    }
 
+    #[test]
+    fn test_scan_prefix() -> Result<()> {
+        scan_prefix(MemoryEngine::new())?;
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        get_isolation(DiskEngine::new(p.clone())?)?;
+        scan_prefix(DiskEngine::new(p.clone())?)?;
         std::fs::remove_dir_all(p.parent().unwrap())?;
+
+        let dir = tempfile::tempdir()?;
+        scan_prefix(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        scan_prefix(btree_engine(dir.path())?)?;
         Ok(())
     }
 
-    // 3. scan prefix
-    fn scan_prefix(eng: impl Engine) -> Result<()> {
-        let mvcc = Mvcc::new(eng);
+    // 4. scan isolation
+    fn scan_isolation(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
         let tx = mvcc.begin()?;
         tx.set(b"aabb".to_vec(), b"val1".to_vec())?;
         tx.set(b"abcc".to_vec(), b"val2".to_vec())?;
@@ -430,6 +1795,15 @@ mod tests {
         tx.commit()?;
 
         let tx1 = mvcc.begin()?;
+        let tx2 = mvcc.begin()?;
+        tx2.set(b"acca".to_vec(), b"val4-1".to_vec())?;
+        tx2.set(b"aabb".to_vec(), b"val1-1".to_vec())?;
+
+        let tx3 = mvcc.begin()?;
+        tx3.set(b"bbaa".to_vec(), b"val3-1".to_vec())?;
+        tx3.delete(b"bcca".to_vec())?;
+        tx3.commit()?;
+
         let iter1 = tx1.scan_prefix(b"aa".to_vec())?;
         assert_eq!(
             iter1,
@@ -481,405 +1855,1426 @@ mod tests {
     }
 
     #[test]
-    fn test_scan_prefix() -> Result<()> {
-        scan_prefix(MemoryEngine::new())?;
+    fn test_scan_isolation() -> Result<()> {
+        scan_isolation(MemoryEngine::new())?;
+        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
+        scan_isolation(DiskEngine::new(p.clone())?)?;
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+
+        let dir = tempfile::tempdir()?;
+        scan_isolation(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        scan_isolation(btree_engine(dir.path())?)?;
+        Ok(())
+    }
+
+    // 5. set
+    fn set(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val3".to_vec())?;
+        tx.set(b"key3".to_vec(), b"val4".to_vec())?;
+        tx.set(b"key4".to_vec(), b"val5".to_vec())?;
+        tx.commit()?;
+
+        let tx1 = mvcc.begin()?;
+        let tx2 = mvcc.begin()?;
+
+        tx1.set(b"key1".to_vec(), b"val1-1".to_vec())?;
+        tx1.set(b"key2".to_vec(), b"val3-1".to_vec())?;
+        tx1.set(b"key2".to_vec(), b"val3-2".to_vec())?;
+
+        tx2.set(b"key3".to_vec(), b"val4-1".to_vec())?;
+        tx2.set(b"key4".to_vec(), b"val5-1".to_vec())?;
+
+        tx1.commit()?;
+        tx2.commit()?;
+
+        let tx = mvcc.begin()?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val1-1".to_vec()));
+        assert_eq!(tx.get(b"key2".to_vec())?, Some(b"val3-2".to_vec()));
+        assert_eq!(tx.get(b"key3".to_vec())?, Some(b"val4-1".to_vec()));
+        assert_eq!(tx.get(b"key4".to_vec())?, Some(b"val5-1".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set() -> Result<()> {
+        set(MemoryEngine::new())?;
+        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
+        set(DiskEngine::new(p.clone())?)?;
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+
+        let dir = tempfile::tempdir()?;
+        set(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        set(btree_engine(dir.path())?)?;
+        Ok(())
+    }
+
+    // 6. set conflict
+    fn set_conflict(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val3".to_vec())?;
+        tx.set(b"key3".to_vec(), b"val4".to_vec())?;
+        tx.set(b"key4".to_vec(), b"val5".to_vec())?;
+        tx.commit()?;
+
+        let tx1 = mvcc.begin()?;
+        let tx2 = mvcc.begin()?;
+
+        tx1.set(b"key1".to_vec(), b"val1-1".to_vec())?;
+        tx1.set(b"key1".to_vec(), b"val1-2".to_vec())?;
+
+        assert_eq!(
+            tx2.set(b"key1".to_vec(), b"val1-3".to_vec()),
+            Err(super::Error::WriteConflict)
+        );
+
+        let tx3 = mvcc.begin()?;
+        tx3.set(b"key5".to_vec(), b"val6".to_vec())?;
+        tx3.commit()?;
+
+        assert_eq!(
+            tx1.set(b"key5".to_vec(), b"val6-1".to_vec()),
+            Err(super::Error::WriteConflict)
+        );
+
+        tx1.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_conflict() -> Result<()> {
+        set_conflict(MemoryEngine::new())?;
+        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
+        set_conflict(DiskEngine::new(p.clone())?)?;
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+
+        let dir = tempfile::tempdir()?;
+        set_conflict(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        set_conflict(btree_engine(dir.path())?)?;
+        Ok(())
+    }
+
+    // a batch with a conflicting key writes none of its keys
+    #[test]
+    fn test_set_batch() -> Result<()> {
+        let mvcc = Mvcc::new(MemoryEngine::new())?;
+        let tx1 = mvcc.begin()?;
+        let tx2 = mvcc.begin()?;
+        tx1.set(b"key2".to_vec(), b"val2".to_vec())?;
+
+        let batch = |key: &[u8]| vec![(b"key1".to_vec(), b"val1".to_vec()), (key.to_vec(), vec![])];
+        assert_eq!(
+            tx2.set_batch(batch(b"key2")),
+            Err(super::Error::WriteConflict)
+        );
+        assert_eq!(tx2.get(b"key1".to_vec())?, None);
+        tx2.set_batch(batch(b"key3"))?;
+        assert_eq!(tx2.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+        tx2.commit()?;
+        tx1.commit()?;
+        Ok(())
+    }
+
+    // 7. delete
+    fn delete(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
+        tx.set(b"key3".to_vec(), b"val3".to_vec())?;
+        tx.delete(b"key2".to_vec())?;
+        tx.delete(b"key3".to_vec())?;
+        tx.set(b"key3".to_vec(), b"val3-1".to_vec())?;
+        tx.commit()?;
+
+        let tx1 = mvcc.begin()?;
+        assert_eq!(tx1.get(b"key2".to_vec())?, None);
+
+        let iter = tx1.scan_prefix(b"ke".to_vec())?;
+        assert_eq!(
+            iter,
+            vec![
+                super::ScanResult {
+                    key: b"key1".to_vec(),
+                    value: b"val1".to_vec()
+                },
+                super::ScanResult {
+                    key: b"key3".to_vec(),
+                    value: b"val3-1".to_vec()
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete() -> Result<()> {
+        delete(MemoryEngine::new())?;
+        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
+        delete(DiskEngine::new(p.clone())?)?;
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+
+        let dir = tempfile::tempdir()?;
+        delete(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        delete(btree_engine(dir.path())?)?;
+        Ok(())
+    }
+
+    // 8. delete conflict
+    fn delete_conflict(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
+        tx.commit()?;
+
+        let tx1 = mvcc.begin()?;
+        let tx2 = mvcc.begin()?;
+        tx1.delete(b"key1".to_vec())?;
+        tx1.set(b"key2".to_vec(), b"val2-1".to_vec())?;
+
+        assert_eq!(
+            tx2.delete(b"key1".to_vec()),
+            Err(super::Error::WriteConflict)
+        );
+        assert_eq!(
+            tx2.delete(b"key2".to_vec()),
+            Err(super::Error::WriteConflict)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_conflict() -> Result<()> {
+        delete_conflict(MemoryEngine::new())?;
+        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
+        delete_conflict(DiskEngine::new(p.clone())?)?;
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+
+        let dir = tempfile::tempdir()?;
+        delete_conflict(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        delete_conflict(btree_engine(dir.path())?)?;
+        Ok(())
+    }
+
+    // 9. dirty read
+    fn dirty_read(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
+        tx.set(b"key3".to_vec(), b"val3".to_vec())?;
+        tx.commit()?;
+
+        let tx1 = mvcc.begin()?;
+        let tx2 = mvcc.begin()?;
+
+        tx2.set(b"key1".to_vec(), b"val1-1".to_vec())?;
+        assert_eq!(tx1.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dirty_read() -> Result<()> {
+        dirty_read(MemoryEngine::new())?;
+        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
+        dirty_read(DiskEngine::new(p.clone())?)?;
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+
+        let dir = tempfile::tempdir()?;
+        dirty_read(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        dirty_read(btree_engine(dir.path())?)?;
+        Ok(())
+    }
+
+    // 10. unrepeatable read
+    fn unrepeatable_read(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
+        tx.set(b"key3".to_vec(), b"val3".to_vec())?;
+        tx.commit()?;
+
+        let tx1 = mvcc.begin()?;
+        let tx2 = mvcc.begin()?;
+
+        tx2.set(b"key1".to_vec(), b"val1-1".to_vec())?;
+        assert_eq!(tx1.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+        tx2.commit()?;
+        assert_eq!(tx1.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unrepeatable_read() -> Result<()> {
+        unrepeatable_read(MemoryEngine::new())?;
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        scan_prefix(DiskEngine::new(p.clone())?)?;
+        unrepeatable_read(DiskEngine::new(p.clone())?)?;
         std::fs::remove_dir_all(p.parent().unwrap())?;
+
+        let dir = tempfile::tempdir()?;
+        unrepeatable_read(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        unrepeatable_read(btree_engine(dir.path())?)?;
         Ok(())
     }
 
-    // 4. scan isolation
-    fn scan_isolation(eng: impl Engine) -> Result<()> {
-        let mvcc = Mvcc::new(eng);
+    // 11. phantom read
+    fn phantom_read(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
         let tx = mvcc.begin()?;
-        tx.set(b"aabb".to_vec(), b"val1".to_vec())?;
-        tx.set(b"abcc".to_vec(), b"val2".to_vec())?;
-        tx.set(b"bbaa".to_vec(), b"val3".to_vec())?;
-        tx.set(b"acca".to_vec(), b"val4".to_vec())?;
-        tx.set(b"aaca".to_vec(), b"val5".to_vec())?;
-        tx.set(b"bcca".to_vec(), b"val6".to_vec())?;
+        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
+        tx.set(b"key3".to_vec(), b"val3".to_vec())?;
         tx.commit()?;
 
         let tx1 = mvcc.begin()?;
         let tx2 = mvcc.begin()?;
-        tx2.set(b"acca".to_vec(), b"val4-1".to_vec())?;
-        tx2.set(b"aabb".to_vec(), b"val1-1".to_vec())?;
-
-        let tx3 = mvcc.begin()?;
-        tx3.set(b"bbaa".to_vec(), b"val3-1".to_vec())?;
-        tx3.delete(b"bcca".to_vec())?;
-        tx3.commit()?;
 
-        let iter1 = tx1.scan_prefix(b"aa".to_vec())?;
+        let iter1 = tx1.scan_prefix(b"key".to_vec())?;
         assert_eq!(
             iter1,
             vec![
                 super::ScanResult {
-                    key: b"aabb".to_vec(),
+                    key: b"key1".to_vec(),
                     value: b"val1".to_vec()
                 },
                 super::ScanResult {
-                    key: b"aaca".to_vec(),
-                    value: b"val5".to_vec()
+                    key: b"key2".to_vec(),
+                    value: b"val2".to_vec()
+                },
+                super::ScanResult {
+                    key: b"key3".to_vec(),
+                    value: b"val3".to_vec()
                 },
             ]
         );
 
-        let iter2 = tx1.scan_prefix(b"a".to_vec())?;
+        tx2.set(b"key2".to_vec(), b"val2-1".to_vec())?;
+        tx2.set(b"key4".to_vec(), b"val4".to_vec())?;
+        tx2.commit()?;
+
+        let iter1 = tx1.scan_prefix(b"key".to_vec())?;
         assert_eq!(
-            iter2,
+            iter1,
             vec![
                 super::ScanResult {
-                    key: b"aabb".to_vec(),
+                    key: b"key1".to_vec(),
                     value: b"val1".to_vec()
                 },
                 super::ScanResult {
-                    key: b"aaca".to_vec(),
-                    value: b"val5".to_vec()
-                },
-                super::ScanResult {
-                    key: b"abcc".to_vec(),
+                    key: b"key2".to_vec(),
                     value: b"val2".to_vec()
                 },
                 super::ScanResult {
-                    key: b"acca".to_vec(),
-                    value: b"val4".to_vec()
+                    key: b"key3".to_vec(),
+                    value: b"val3".to_vec()
                 },
             ]
         );
+        Ok(())
+    }
 
-        let iter3 = tx1.scan_prefix(b"bcca".to_vec())?;
-        assert_eq!(
-            iter3,
-            vec![super::ScanResult {
-                key: b"bcca".to_vec(),
-                value: b"val6".to_vec()
-            },]
-        );
+    #[test]
+    fn test_phantom_read() -> Result<()> {
+        phantom_read(MemoryEngine::new())?;
+        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
+        phantom_read(DiskEngine::new(p.clone())?)?;
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+
+        let dir = tempfile::tempdir()?;
+        phantom_read(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        phantom_read(btree_engine(dir.path())?)?;
+        Ok(())
+    }
+
+    // 12. rollback
+    fn rollback(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
+        tx.set(b"key3".to_vec(), b"val3".to_vec())?;
+        tx.commit()?;
+
+        let tx1 = mvcc.begin()?;
+        tx1.set(b"key1".to_vec(), b"val1-1".to_vec())?;
+        tx1.set(b"key2".to_vec(), b"val2-1".to_vec())?;
+        tx1.set(b"key3".to_vec(), b"val3-1".to_vec())?;
+        tx1.rollback()?;
+
+        let tx2 = mvcc.begin()?;
+        assert_eq!(tx2.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+        assert_eq!(tx2.get(b"key2".to_vec())?, Some(b"val2".to_vec()));
+        assert_eq!(tx2.get(b"key3".to_vec())?, Some(b"val3".to_vec()));
 
         Ok(())
     }
 
     #[test]
-    fn test_scan_isolation() -> Result<()> {
-        scan_isolation(MemoryEngine::new())?;
+    fn test_rollback() -> Result<()> {
+        rollback(MemoryEngine::new())?;
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        scan_isolation(DiskEngine::new(p.clone())?)?;
+        rollback(DiskEngine::new(p.clone())?)?;
         std::fs::remove_dir_all(p.parent().unwrap())?;
+
+        let dir = tempfile::tempdir()?;
+        rollback(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        rollback(btree_engine(dir.path())?)?;
         Ok(())
     }
 
-    // 5. set
-    fn set(eng: impl Engine) -> Result<()> {
-        let mvcc = Mvcc::new(eng);
+    // 13. online backup
+    #[test]
+    fn test_backup() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mvcc = Mvcc::new(DiskEngine::new(dir.path().join("sqldb-log"))?)?;
         let tx = mvcc.begin()?;
         tx.set(b"key1".to_vec(), b"val1".to_vec())?;
         tx.set(b"key2".to_vec(), b"val2".to_vec())?;
-        tx.set(b"key2".to_vec(), b"val3".to_vec())?;
-        tx.set(b"key3".to_vec(), b"val4".to_vec())?;
-        tx.set(b"key4".to_vec(), b"val5".to_vec())?;
+        tx.commit()?;
+
+        // the engine lock is only held while taking the snapshot
+        let snapshot = mvcc.with_engine(|eng| eng.snapshot())?;
+        let tx1 = mvcc.begin()?;
+        tx1.set(b"key1".to_vec(), b"val1-1".to_vec())?;
+        tx1.commit()?;
+
+        let backup = dir.path().join("backup");
+        snapshot.write_backup(backup.clone())?;
+        DiskEngine::verify_backup(backup.clone())?;
+
+        let restored = Mvcc::new(DiskEngine::new(backup)?)?;
+        let tx2 = restored.begin()?;
+        assert_eq!(tx2.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+        assert_eq!(tx2.get(b"key2".to_vec())?, Some(b"val2".to_vec()));
+        Ok(())
+    }
+
+    // 14. sync on commit
+    #[test]
+    fn test_sync_on_commit() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mvcc = Mvcc::new(DiskEngine::new(dir.path().join("sqldb-log"))?)?.sync_on_commit(true);
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
+        tx.commit()?;
+        assert_eq!(mvcc.with_engine(|eng| Ok(eng.sync_count()))?, 1);
+
+        let tx = mvcc.begin()?;
+        tx.set(b"key3".to_vec(), b"val3".to_vec())?;
+        tx.rollback()?;
+        assert_eq!(mvcc.with_engine(|eng| Ok(eng.sync_count()))?, 1);
+        Ok(())
+    }
+
+    // The number of stored versions of the key
+    fn versions(mvcc: &Mvcc<impl Engine>, key: &[u8]) -> Result<usize> {
+        let prefix = MvccKeyPrefix::Version(key.to_vec()).encode()?;
+        mvcc.with_engine(|eng| Ok(eng.scan_prefix(prefix).count()))
+    }
+
+    // 13. Vacuum
+    fn vacuum(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val1".to_vec())?;
+        tx.commit()?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val2".to_vec())?;
+        tx.delete(b"key2".to_vec())?;
+        tx.commit()?;
+
+        // a writer that's still active when the reader begins
+        let writer = mvcc.begin()?;
+        writer.set(b"key3".to_vec(), b"val1".to_vec())?;
+        let reader = mvcc.begin()?;
+        writer.commit()?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val3".to_vec())?;
+        tx.commit()?;
+
+        // key1-1 is shadowed, key2 is deleted, the rest is still visible to the reader
+        assert_eq!(mvcc.vacuum()?, 3);
+        assert_eq!(versions(&mvcc, b"key1")?, 2);
+        assert_eq!(versions(&mvcc, b"key2")?, 0);
+        assert_eq!(versions(&mvcc, b"key3")?, 1);
+        assert_eq!(reader.get(b"key1".to_vec())?, Some(b"val2".to_vec()));
+        assert_eq!(reader.get(b"key2".to_vec())?, None);
+        assert_eq!(reader.get(b"key3".to_vec())?, None);
+        reader.commit()?;
+
+        assert_eq!(mvcc.vacuum()?, 1);
+        assert_eq!(versions(&mvcc, b"key1")?, 1);
+        let tx = mvcc.begin()?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val3".to_vec()));
+        assert_eq!(tx.get(b"key2".to_vec())?, None);
+        assert_eq!(tx.get(b"key3".to_vec())?, Some(b"val1".to_vec()));
+        assert_eq!(mvcc.vacuum()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_vacuum() -> Result<()> {
+        vacuum(MemoryEngine::new())?;
+
+        let dir = tempfile::tempdir()?;
+        vacuum(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        vacuum(btree_engine(dir.path())?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_vacuum_every() -> Result<()> {
+        let mvcc = Mvcc::new(MemoryEngine::new())?.vacuum_every(Duration::from_millis(10));
+        for i in 0..10 {
+            let tx = mvcc.begin()?;
+            tx.set(b"key".to_vec(), vec![i])?;
+            tx.commit()?;
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while versions(&mvcc, b"key")? > 1 {
+            assert!(Instant::now() < deadline, "the background vacuum didn't run");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_abandoned() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        {
+            let mvcc = Mvcc::new(DiskEngine::new(path.clone())?)?;
+            let tx = mvcc.begin()?;
+            tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+            tx.commit()?;
+
+            // the process dies in the middle of a transaction
+            let tx = mvcc.begin()?;
+            tx.set(b"key1".to_vec(), b"val2".to_vec())?;
+            tx.set(b"key2".to_vec(), b"val2".to_vec())?;
+        }
+
+        let mvcc = Mvcc::new(DiskEngine::new(path)?)?;
+        for prefix in [MvccKeyPrefix::TxnAcvtive, MvccKeyPrefix::TxnWrite(2)] {
+            let prefix = prefix.encode()?;
+            assert_eq!(mvcc.with_engine(|eng| Ok(eng.scan_prefix(prefix).count()))?, 0);
+        }
+        assert_eq!(versions(&mvcc, b"key1")?, 1);
+        assert_eq!(versions(&mvcc, b"key2")?, 0);
+
+        // the abandoned writes neither show up nor conflict
+        let tx = mvcc.begin()?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+        assert_eq!(tx.get(b"key2".to_vec())?, None);
+        tx.set(b"key1".to_vec(), b"val3".to_vec())?;
+        tx.commit()?;
+        let tx = mvcc.begin()?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val3".to_vec()));
+        Ok(())
+    }
+
+    // Doctors on call, each of them can go off call as long as another one stays
+    fn go_off_call(tx: &super::MvccTransaction<impl Engine>, doctor: &[u8]) -> Result<()> {
+        let on_call = tx
+            .scan_prefix(b"oncall-".to_vec())?
+            .into_iter()
+            .filter(|r| r.value == b"yes")
+            .count();
+        if on_call >= 2 {
+            let mut key = b"oncall-".to_vec();
+            key.extend_from_slice(doctor);
+            tx.set(key, b"no".to_vec())?;
+        }
+        Ok(())
+    }
+
+    // 14. Write skew
+    fn write_skew(eng: impl Engine, isolation: IsolationLevel) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?.isolation(isolation);
+        let tx = mvcc.begin()?;
+        tx.set(b"oncall-alice".to_vec(), b"yes".to_vec())?;
+        tx.set(b"oncall-bob".to_vec(), b"yes".to_vec())?;
         tx.commit()?;
 
         let tx1 = mvcc.begin()?;
         let tx2 = mvcc.begin()?;
+        go_off_call(&tx1, b"alice")?;
+        go_off_call(&tx2, b"bob")?;
+        let committed = [tx1.commit(), tx2.commit()];
 
-        tx1.set(b"key1".to_vec(), b"val1-1".to_vec())?;
-        tx1.set(b"key2".to_vec(), b"val3-1".to_vec())?;
-        tx1.set(b"key2".to_vec(), b"val3-2".to_vec())?;
+        let tx = mvcc.begin()?;
+        let on_call = tx
+            .scan_prefix(b"oncall-".to_vec())?
+            .into_iter()
+            .filter(|r| r.value == b"yes")
+            .count();
+        match isolation {
+            // both see two doctors on call and leave nobody on call
+            IsolationLevel::ReadCommitted | IsolationLevel::Snapshot => {
+                assert_eq!(committed, [Ok(()), Ok(())]);
+                assert_eq!(on_call, 0);
+            }
+            // the first commit would make a cycle, so it's rolled back
+            IsolationLevel::Serializable => {
+                assert_eq!(committed, [Err(Error::Serialization), Ok(())]);
+                assert_eq!(on_call, 1);
+                assert_eq!(tx.get(b"oncall-alice".to_vec())?, Some(b"yes".to_vec()));
+            }
+        }
+        Ok(())
+    }
 
-        tx2.set(b"key3".to_vec(), b"val4-1".to_vec())?;
-        tx2.set(b"key4".to_vec(), b"val5-1".to_vec())?;
+    #[test]
+    fn test_write_skew() -> Result<()> {
+        for isolation in [
+            IsolationLevel::ReadCommitted,
+            IsolationLevel::Snapshot,
+            IsolationLevel::Serializable,
+        ] {
+            write_skew(MemoryEngine::new(), isolation)?;
+
+            let dir = tempfile::tempdir()?;
+            write_skew(lsm_engine(dir.path())?, isolation)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_serializable() -> Result<()> {
+        let mvcc = Mvcc::new(MemoryEngine::new())?.isolation(IsolationLevel::Serializable);
+        let tx = mvcc.begin()?;
+        tx.set(b"x".to_vec(), b"1".to_vec())?;
+        tx.set(b"y".to_vec(), b"1".to_vec())?;
+        tx.commit()?;
+
+        // disjoint reads and writes commit
+        let tx1 = mvcc.begin()?;
+        let tx2 = mvcc.begin()?;
+        tx1.get(b"x".to_vec())?;
+        tx1.set(b"x".to_vec(), b"2".to_vec())?;
+        tx2.get(b"y".to_vec())?;
+        tx2.set(b"y".to_vec(), b"2".to_vec())?;
+        tx1.commit()?;
+        tx2.commit()?;
+
+        // a read-only transaction would see a state no serial order produces:
+        // tx2 -rw-> tx1 (x) and reader -rw-> tx2 (y) with tx1 visible to the reader,
+        // so the pivot tx2 fails
+        let tx1 = mvcc.begin()?;
+        let tx2 = mvcc.begin()?;
+        tx2.get(b"x".to_vec())?;
+        tx1.set(b"x".to_vec(), b"3".to_vec())?;
+        tx2.set(b"y".to_vec(), b"3".to_vec())?;
+        tx1.commit()?;
+        let reader = mvcc.begin()?;
+        assert_eq!(reader.get(b"x".to_vec())?, Some(b"3".to_vec()));
+        assert_eq!(reader.get(b"y".to_vec())?, Some(b"2".to_vec()));
+        reader.commit()?;
+        assert_eq!(tx2.commit(), Err(Error::Serialization));
 
+        // an edge into a committed pivot fails the transaction adding it
+        let tx1 = mvcc.begin()?;
+        let tx2 = mvcc.begin()?;
+        let tx3 = mvcc.begin()?;
+        tx2.get(b"x".to_vec())?;
+        tx1.set(b"x".to_vec(), b"4".to_vec())?;
         tx1.commit()?;
+        tx2.set(b"y".to_vec(), b"4".to_vec())?;
         tx2.commit()?;
+        assert_eq!(tx3.get(b"y".to_vec()), Err(Error::Serialization));
+        tx3.rollback()?;
 
         let tx = mvcc.begin()?;
-        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val1-1".to_vec()));
-        assert_eq!(tx.get(b"key2".to_vec())?, Some(b"val3-2".to_vec()));
-        assert_eq!(tx.get(b"key3".to_vec())?, Some(b"val4-1".to_vec()));
-        assert_eq!(tx.get(b"key4".to_vec())?, Some(b"val5-1".to_vec()));
+        assert_eq!(tx.get(b"x".to_vec())?, Some(b"4".to_vec()));
+        assert_eq!(tx.get(b"y".to_vec())?, Some(b"4".to_vec()));
         Ok(())
     }
 
     #[test]
-    fn test_set() -> Result<()> {
-        set(MemoryEngine::new())?;
-        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        set(DiskEngine::new(p.clone())?)?;
-        std::fs::remove_dir_all(p.parent().unwrap())?;
+    fn test_prepare() -> Result<()> {
+        let mvcc = Mvcc::new(MemoryEngine::new())?.isolation(IsolationLevel::Serializable);
+
+        // a prepared transaction only commits or rolls back
+        let tx = mvcc.begin()?;
+        tx.set(b"x".to_vec(), b"1".to_vec())?;
+        tx.prepare("tx1")?;
+        assert!(tx.set(b"y".to_vec(), b"1".to_vec()).is_err());
+        assert!(tx.lock(b"y".to_vec(), LockMode::Share, LockWait::NoWait).is_err());
+        assert!(tx.prepare("tx2").is_err());
+        assert_eq!(tx.get(b"x".to_vec())?, Some(b"1".to_vec()));
+        assert_eq!(mvcc.prepared()?, vec!["tx1".to_string()]);
+        tx.commit()?;
+        assert!(mvcc.prepared()?.is_empty());
+
+        // an id is taken until the transaction ends
+        let tx = mvcc.begin()?;
+        tx.set(b"x".to_vec(), b"2".to_vec())?;
+        tx.prepare("tx1")?;
+        let other = mvcc.begin()?;
+        assert!(other.prepare("tx1").is_err());
+        other.rollback()?;
+        tx.rollback()?;
+        assert!(mvcc.rollback_prepared("tx1").is_err());
+
+        // the pivot of a dangerous structure fails to prepare and is rolled back
+        let tx1 = mvcc.begin()?;
+        let tx2 = mvcc.begin()?;
+        tx2.get(b"x".to_vec())?;
+        tx1.set(b"x".to_vec(), b"3".to_vec())?;
+        tx2.set(b"y".to_vec(), b"3".to_vec())?;
+        tx1.prepare("tx1")?;
+        tx1.commit()?;
+        let reader = mvcc.begin()?;
+        reader.get(b"y".to_vec())?;
+        reader.commit()?;
+        assert_eq!(tx2.prepare("tx2"), Err(Error::Serialization));
+        assert!(mvcc.prepared()?.is_empty());
+
+        let tx = mvcc.begin()?;
+        assert_eq!(tx.get(b"x".to_vec())?, Some(b"3".to_vec()));
+        assert_eq!(tx.get(b"y".to_vec())?, None);
         Ok(())
     }
 
-    // 6. set conflict
-    fn set_conflict(eng: impl Engine) -> Result<()> {
-        let mvcc = Mvcc::new(eng);
+    #[test]
+    fn test_prepare_recover() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        {
+            let mvcc = Mvcc::new(DiskEngine::new(path.clone())?)?;
+            let tx = mvcc.begin()?;
+            tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+            tx.commit()?;
+
+            // the process dies with two prepared transactions and one which isn't
+            let tx = mvcc.begin()?;
+            tx.set(b"key1".to_vec(), b"val2".to_vec())?;
+            tx.lock(b"key3".to_vec(), LockMode::Update, LockWait::NoWait)?;
+            tx.prepare("p1")?;
+            let tx = mvcc.begin()?;
+            tx.set(b"key2".to_vec(), b"val2".to_vec())?;
+            tx.prepare("p2")?;
+            let tx = mvcc.begin()?;
+            tx.set(b"key4".to_vec(), b"val4".to_vec())?;
+        }
+
+        let mvcc = Mvcc::new(DiskEngine::new(path)?)?.lock_timeout(Duration::ZERO);
+        assert_eq!(mvcc.prepared()?, vec!["p1".to_string(), "p2".to_string()]);
+
+        // the prepared writes stay invisible and block the writers
+        let tx = mvcc.begin()?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+        assert_eq!(tx.get(b"key2".to_vec())?, None);
+        assert_eq!(tx.get(b"key4".to_vec())?, None);
+        assert_eq!(
+            tx.set(b"key1".to_vec(), b"val3".to_vec()),
+            Err(Error::WriteConflict)
+        );
+        tx.rollback()?;
+        let tx = mvcc.begin()?;
+        assert_eq!(
+            tx.lock(b"key3".to_vec(), LockMode::Share, LockWait::NoWait),
+            Err(Error::LockNotAvailable)
+        );
+        tx.rollback()?;
+
+        // until they are ended by id
+        mvcc.commit_prepared("p1")?;
+        mvcc.rollback_prepared("p2")?;
+        assert!(mvcc.prepared()?.is_empty());
+        assert!(mvcc.commit_prepared("p1").is_err());
+        let tx = mvcc.begin()?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val2".to_vec()));
+        assert_eq!(tx.get(b"key2".to_vec())?, None);
+        assert!(tx.lock(b"key3".to_vec(), LockMode::Update, LockWait::NoWait)?);
+        tx.set(b"key2".to_vec(), b"val3".to_vec())?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_committed() -> Result<()> {
+        let mvcc = Mvcc::new(MemoryEngine::new())?;
+        let read_committed = TransactionOptions {
+            isolation: IsolationLevel::ReadCommitted,
+            read_only: false,
+        };
         let tx = mvcc.begin()?;
         tx.set(b"key1".to_vec(), b"val1".to_vec())?;
-        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
-        tx.set(b"key2".to_vec(), b"val3".to_vec())?;
-        tx.set(b"key3".to_vec(), b"val4".to_vec())?;
-        tx.set(b"key4".to_vec(), b"val5".to_vec())?;
         tx.commit()?;
 
-        let tx1 = mvcc.begin()?;
         let tx2 = mvcc.begin()?;
+        let tx1 = mvcc.begin_with(read_committed)?;
+        assert_eq!(tx1.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
 
-        tx1.set(b"key1".to_vec(), b"val1-1".to_vec())?;
-        tx1.set(b"key1".to_vec(), b"val1-2".to_vec())?;
-
+        // uncommitted writes stay invisible and conflict
+        tx2.set(b"key1".to_vec(), b"val2".to_vec())?;
+        tx2.set(b"key2".to_vec(), b"val2".to_vec())?;
+        assert_eq!(tx1.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
         assert_eq!(
-            tx2.set(b"key1".to_vec(), b"val1-3".to_vec()),
-            Err(super::Error::WriteConflict)
+            tx1.set(b"key1".to_vec(), b"val3".to_vec()),
+            Err(Error::WriteConflict)
         );
 
+        // committed ones show up in the next read, and can be overwritten
+        tx2.commit()?;
+        assert_eq!(tx1.get(b"key1".to_vec())?, Some(b"val2".to_vec()));
+        assert_eq!(tx1.scan_prefix(b"key".to_vec())?.len(), 2);
+        tx1.set(b"key1".to_vec(), b"val3".to_vec())?;
+        assert_eq!(tx1.get(b"key1".to_vec())?, Some(b"val3".to_vec()));
+
+        // a version newer than the transaction's own can't be overwritten
         let tx3 = mvcc.begin()?;
-        tx3.set(b"key5".to_vec(), b"val6".to_vec())?;
+        tx3.set(b"key2".to_vec(), b"val3".to_vec())?;
         tx3.commit()?;
-
+        assert_eq!(tx1.get(b"key2".to_vec())?, Some(b"val3".to_vec()));
         assert_eq!(
-            tx1.set(b"key5".to_vec(), b"val6-1".to_vec()),
-            Err(super::Error::WriteConflict)
+            tx1.delete(b"key2".to_vec()),
+            Err(Error::WriteConflict)
         );
-
         tx1.commit()?;
+
+        let tx = mvcc.begin()?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val3".to_vec()));
         Ok(())
     }
 
     #[test]
-    fn test_set_conflict() -> Result<()> {
-        set_conflict(MemoryEngine::new())?;
-        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        set_conflict(DiskEngine::new(p.clone())?)?;
-        std::fs::remove_dir_all(p.parent().unwrap())?;
+    fn test_read_committed_statement() -> Result<()> {
+        let mvcc = Mvcc::new(MemoryEngine::new())?;
+        let tx = mvcc.begin_with(TransactionOptions {
+            isolation: IsolationLevel::ReadCommitted,
+            read_only: false,
+        })?;
+        tx.begin_statement()?;
+
+        // a commit during the statement stays invisible to all of its reads
+        let tx2 = mvcc.begin()?;
+        tx2.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx2.set(b"key2".to_vec(), b"val1".to_vec())?;
+        tx2.commit()?;
+        assert_eq!(tx.get(b"key1".to_vec())?, None);
+        assert!(tx.scan_prefix(b"key".to_vec())?.is_empty());
+
+        // the next statement sees it
+        tx.begin_statement()?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+        assert_eq!(tx.scan_prefix(b"key".to_vec())?.len(), 2);
+        assert_eq!(
+            tx.set(b"key1".to_vec(), b"val2".to_vec()),
+            Err(Error::WriteConflict)
+        );
+        tx.rollback()?;
         Ok(())
     }
 
-    // 7. delete
-    fn delete(eng: impl Engine) -> Result<()> {
-        let mvcc = Mvcc::new(eng);
+    #[test]
+    fn test_read_only() -> Result<()> {
+        let mvcc = Mvcc::new(MemoryEngine::new())?;
         let tx = mvcc.begin()?;
         tx.set(b"key1".to_vec(), b"val1".to_vec())?;
-        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
-        tx.set(b"key3".to_vec(), b"val3".to_vec())?;
+        tx.commit()?;
+        let writer = mvcc.begin()?;
+        writer.set(b"key2".to_vec(), b"val2".to_vec())?;
+
+        for isolation in [IsolationLevel::Snapshot, IsolationLevel::Serializable] {
+            let next_version = mvcc.with_engine(|eng| eng.get(MvccKey::NextVersion.encode()?))?;
+            let reader = mvcc.begin_with(TransactionOptions {
+                isolation,
+                read_only: true,
+            })?;
+            // neither a version nor an active marker is stored
+            assert_eq!(
+                mvcc.with_engine(|eng| eng.get(MvccKey::NextVersion.encode()?))?,
+                next_version
+            );
+            let prefix = MvccKeyPrefix::TxnAcvtive.encode()?;
+            assert_eq!(mvcc.with_engine(|eng| Ok(eng.scan_prefix(prefix).count()))?, 1);
+
+            assert_eq!(
+                reader.set(b"key1".to_vec(), b"val3".to_vec()),
+                Err(Error::ReadOnly)
+            );
+            assert_eq!(reader.delete(b"key1".to_vec()), Err(Error::ReadOnly));
+
+            // the snapshot survives newer commits and the vacuum
+            let tx = mvcc.begin()?;
+            tx.set(b"key1".to_vec(), b"val3".to_vec())?;
+            tx.commit()?;
+            mvcc.vacuum()?;
+            assert_eq!(reader.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+            assert_eq!(reader.get(b"key2".to_vec())?, None);
+            reader.commit()?;
+
+            let tx = mvcc.begin()?;
+            tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+            tx.commit()?;
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    // Time travel
+    fn as_of(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
+        assert_eq!(mvcc.current_version()?, 0);
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val1".to_vec())?;
+        tx.commit()?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val2".to_vec())?;
         tx.delete(b"key2".to_vec())?;
-        tx.delete(b"key3".to_vec())?;
-        tx.set(b"key3".to_vec(), b"val3-1".to_vec())?;
         tx.commit()?;
+        // version 3 is still active, version 4 committed
+        let writer = mvcc.begin()?;
+        writer.set(b"key3".to_vec(), b"val1".to_vec())?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val3".to_vec())?;
+        tx.commit()?;
+        assert_eq!(mvcc.current_version()?, 4);
 
-        let tx1 = mvcc.begin()?;
-        assert_eq!(tx1.get(b"key2".to_vec())?, None);
+        let tx = mvcc.begin_as_of(1)?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+        assert_eq!(tx.get(b"key2".to_vec())?, Some(b"val1".to_vec()));
+        assert_eq!(tx.set(b"key1".to_vec(), vec![]), Err(Error::ReadOnly));
+        tx.commit()?;
 
-        let iter = tx1.scan_prefix(b"ke".to_vec())?;
+        let tx = mvcc.begin_as_of(4)?;
         assert_eq!(
-            iter,
-            vec![
-                super::ScanResult {
-                    key: b"key1".to_vec(),
-                    value: b"val1".to_vec()
-                },
-                super::ScanResult {
-                    key: b"key3".to_vec(),
-                    value: b"val3-1".to_vec()
-                },
-            ]
+            tx.scan_prefix(b"key".to_vec())?,
+            vec![ScanResult {
+                key: b"key1".to_vec(),
+                value: b"val3".to_vec()
+            }]
         );
+        tx.commit()?;
+        // the writes of version 3 are seen as of it once it committed
+        writer.commit()?;
+        let tx = mvcc.begin_as_of(3)?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val2".to_vec()));
+        assert_eq!(tx.get(b"key3".to_vec())?, Some(b"val1".to_vec()));
+        tx.commit()?;
+        assert!(mvcc.begin_as_of(5).is_err());
+
+        // a past snapshot holds back the vacuum, afterwards it's gone
+        let tx = mvcc.begin_as_of(1)?;
+        assert_eq!(mvcc.vacuum()?, 0);
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+        tx.commit()?;
+        assert_eq!(mvcc.vacuum()?, 4);
+        assert!(mvcc.begin_as_of(1).is_err());
+        let tx = mvcc.begin_as_of(4)?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val3".to_vec()));
+        assert_eq!(tx.get(b"key2".to_vec())?, None);
+        tx.commit()?;
         Ok(())
     }
 
     #[test]
-    fn test_delete() -> Result<()> {
-        delete(MemoryEngine::new())?;
-        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        delete(DiskEngine::new(p.clone())?)?;
-        std::fs::remove_dir_all(p.parent().unwrap())?;
+    fn test_as_of() -> Result<()> {
+        as_of(MemoryEngine::new())?;
+
+        let dir = tempfile::tempdir()?;
+        as_of(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        as_of(btree_engine(dir.path())?)?;
         Ok(())
     }
 
-    // 8. delete conflict
-    fn delete_conflict(eng: impl Engine) -> Result<()> {
-        let mvcc = Mvcc::new(eng);
+    // Savepoints
+    fn savepoint(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
         let tx = mvcc.begin()?;
         tx.set(b"key1".to_vec(), b"val1".to_vec())?;
-        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val1".to_vec())?;
         tx.commit()?;
 
-        let tx1 = mvcc.begin()?;
-        let tx2 = mvcc.begin()?;
-        tx1.delete(b"key1".to_vec())?;
-        tx1.set(b"key2".to_vec(), b"val2-1".to_vec())?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val2".to_vec())?;
+        tx.savepoint("a")?;
+        tx.set(b"key1".to_vec(), b"val3".to_vec())?;
+        tx.delete(b"key2".to_vec())?;
+        tx.set(b"key3".to_vec(), b"val3".to_vec())?;
+        tx.savepoint("b")?;
+        tx.set(b"key1".to_vec(), b"val4".to_vec())?;
+
+        tx.rollback_to_savepoint("b")?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val3".to_vec()));
+        tx.rollback_to_savepoint("a")?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val2".to_vec()));
+        assert_eq!(tx.get(b"key2".to_vec())?, Some(b"val1".to_vec()));
+        assert_eq!(tx.get(b"key3".to_vec())?, None);
+        // b was set after a, so it's gone, a stays
+        assert!(tx.rollback_to_savepoint("b").is_err());
+        tx.set(b"key1".to_vec(), b"val5".to_vec())?;
+        tx.rollback_to_savepoint("a")?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val2".to_vec()));
+
+        // the undone write of key3 left no marker, another transaction may write it
+        let other = mvcc.begin()?;
+        other.set(b"key3".to_vec(), b"val6".to_vec())?;
+        other.commit()?;
+        let prefix = MvccKeyPrefix::TxnWrite(2).encode()?;
+        assert_eq!(mvcc.with_engine(|eng| Ok(eng.scan_prefix(prefix).count()))?, 1);
+
+        // a released savepoint keeps its writes
+        tx.savepoint("c")?;
+        tx.set(b"key2".to_vec(), b"val7".to_vec())?;
+        tx.release_savepoint("a")?;
+        assert!(tx.rollback_to_savepoint("c").is_err());
+        assert!(tx.release_savepoint("a").is_err());
+        tx.commit()?;
 
-        assert_eq!(
-            tx2.delete(b"key1".to_vec()),
-            Err(super::Error::WriteConflict)
-        );
-        assert_eq!(
-            tx2.delete(b"key2".to_vec()),
-            Err(super::Error::WriteConflict)
-        );
+        let tx = mvcc.begin()?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val2".to_vec()));
+        assert_eq!(tx.get(b"key2".to_vec())?, Some(b"val7".to_vec()));
+        assert_eq!(tx.get(b"key3".to_vec())?, Some(b"val6".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_savepoint() -> Result<()> {
+        savepoint(MemoryEngine::new())?;
 
+        let dir = tempfile::tempdir()?;
+        savepoint(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        savepoint(btree_engine(dir.path())?)?;
         Ok(())
     }
 
+    // Writes and rollbacks to savepoints of a shared transaction lock the
+    // engine and the savepoints in the same order, so they never deadlock
     #[test]
-    fn test_delete_conflict() -> Result<()> {
-        delete_conflict(MemoryEngine::new())?;
-        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        delete_conflict(DiskEngine::new(p.clone())?)?;
-        std::fs::remove_dir_all(p.parent().unwrap())?;
+    fn test_savepoint_shared() -> Result<()> {
+        let mvcc = Mvcc::new(MemoryEngine::new())?;
+        let tx = Arc::new(mvcc.begin()?);
+        tx.savepoint("a")?;
+        let writer = {
+            let tx = tx.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..500u64 {
+                    tx.set(b"key".to_vec(), i.to_be_bytes().to_vec())?;
+                }
+                Ok(())
+            })
+        };
+        for _ in 0..500 {
+            tx.rollback_to_savepoint("a")?;
+        }
+        writer.join().unwrap()?;
+        tx.rollback_to_savepoint("a")?;
+        assert_eq!(tx.get(b"key".to_vec())?, None);
+        tx.rollback()?;
         Ok(())
     }
 
-    // 9. dirty read
-    fn dirty_read(eng: impl Engine) -> Result<()> {
-        let mvcc = Mvcc::new(eng);
+    // Readers share the engine while a writer moves a balance between two keys,
+    // every snapshot sees the same total
+    fn concurrent_reads(eng: impl Engine + Send + Sync + 'static) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?;
         let tx = mvcc.begin()?;
-        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
-        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
-        tx.set(b"key3".to_vec(), b"val3".to_vec())?;
+        tx.set(b"a".to_vec(), 100u64.to_be_bytes().to_vec())?;
+        tx.set(b"b".to_vec(), 0u64.to_be_bytes().to_vec())?;
         tx.commit()?;
 
-        let tx1 = mvcc.begin()?;
-        let tx2 = mvcc.begin()?;
+        let balance = |tx: &super::MvccTransaction<_>, key: &[u8]| -> Result<u64> {
+            let value = tx.get(key.to_vec())?.unwrap_or_default();
+            Ok(u64::from_be_bytes(value.as_slice().try_into()?))
+        };
+        let readers = (0..4)
+            .map(|_| {
+                let mvcc = mvcc.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    for _ in 0..200 {
+                        let tx = mvcc.begin_with(TransactionOptions {
+                            read_only: true,
+                            ..Default::default()
+                        })?;
+                        assert_eq!(balance(&tx, b"a")? + balance(&tx, b"b")?, 100);
+                        assert_eq!(tx.scan_prefix(vec![])?.len(), 2);
+                        tx.commit()?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..100 {
+            let tx = mvcc.begin()?;
+            let (a, b) = (balance(&tx, b"a")?, balance(&tx, b"b")?);
+            tx.set(b"a".to_vec(), (a - 1).to_be_bytes().to_vec())?;
+            tx.set(b"b".to_vec(), (b + 1).to_be_bytes().to_vec())?;
+            tx.commit()?;
+        }
+        for reader in readers {
+            reader.join().unwrap()?;
+        }
+        Ok(())
+    }
 
-        tx2.set(b"key1".to_vec(), b"val1-1".to_vec())?;
-        assert_eq!(tx1.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+    #[test]
+    fn test_concurrent_reads() -> Result<()> {
+        concurrent_reads(MemoryEngine::new())?;
+
+        let dir = tempfile::tempdir()?;
+        concurrent_reads(DiskEngine::new(dir.path().join("sqldb-log"))?)?;
 
+        let dir = tempfile::tempdir()?;
+        concurrent_reads(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        concurrent_reads(btree_engine(dir.path())?)?;
         Ok(())
     }
 
+    // Read throughput with a growing number of reader threads
     #[test]
-    fn test_dirty_read() -> Result<()> {
-        dirty_read(MemoryEngine::new())?;
-        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        dirty_read(DiskEngine::new(p.clone())?)?;
-        std::fs::remove_dir_all(p.parent().unwrap())?;
+    #[ignore]
+    fn bench_concurrent_reads() -> Result<()> {
+        fn run(name: &str, eng: impl Engine + Send + Sync + 'static) -> Result<()> {
+            let mvcc = Mvcc::new(eng)?;
+            let keys = 1000;
+            let tx = mvcc.begin()?;
+            for i in 0..keys {
+                tx.set(format!("key{}", i).into_bytes(), vec![0; 100])?;
+            }
+            tx.commit()?;
+
+            for threads in [1, 2, 4, 8] {
+                let reads = 20000;
+                let start = Instant::now();
+                let handles = (0..threads)
+                    .map(|t| {
+                        let mvcc = mvcc.clone();
+                        std::thread::spawn(move || -> Result<()> {
+                            let tx = mvcc.begin_with(TransactionOptions {
+                                read_only: true,
+                                ..Default::default()
+                            })?;
+                            for i in 0..reads {
+                                let key = format!("key{}", (i * 7 + t) % keys);
+                                assert!(tx.get(key.into_bytes())?.is_some());
+                            }
+                            tx.commit()
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.join().unwrap()?;
+                }
+                let elapsed = start.elapsed();
+                println!(
+                    "{}: {} threads, {} reads in {:?}, {:.0} reads/s",
+                    name,
+                    threads,
+                    threads * reads,
+                    elapsed,
+                    (threads * reads) as f64 / elapsed.as_secs_f64()
+                );
+            }
+            Ok(())
+        }
+
+        run("MemoryEngine", MemoryEngine::new())?;
+        let dir = tempfile::tempdir()?;
+        run("DiskEngine", DiskEngine::new(dir.path().join("sqldb-log"))?)?;
         Ok(())
     }
 
-    // 10. unrepeatable read
-    fn unrepeatable_read(eng: impl Engine) -> Result<()> {
-        let mvcc = Mvcc::new(eng);
+    // Row locks
+    fn lock(eng: impl Engine + Send + Sync + 'static) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?.lock_timeout(Duration::from_millis(50));
         let tx = mvcc.begin()?;
         tx.set(b"key1".to_vec(), b"val1".to_vec())?;
-        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
-        tx.set(b"key3".to_vec(), b"val3".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val1".to_vec())?;
         tx.commit()?;
 
+        // share locks go together, but keep out update locks and writes
         let tx1 = mvcc.begin()?;
         let tx2 = mvcc.begin()?;
+        assert!(tx1.lock(b"key1".to_vec(), LockMode::Share, LockWait::NoWait)?);
+        assert!(tx2.lock(b"key1".to_vec(), LockMode::Share, LockWait::NoWait)?);
+        assert_eq!(
+            tx2.lock(b"key1".to_vec(), LockMode::Update, LockWait::NoWait),
+            Err(Error::LockNotAvailable)
+        );
+        assert_eq!(
+            tx2.set(b"key1".to_vec(), b"val2".to_vec()),
+            Err(Error::LockNotAvailable)
+        );
+        tx1.commit()?;
+        assert!(tx2.lock(b"key1".to_vec(), LockMode::Update, LockWait::NoWait)?);
+        tx2.set(b"key1".to_vec(), b"val2".to_vec())?;
 
-        tx2.set(b"key1".to_vec(), b"val1-1".to_vec())?;
-        assert_eq!(tx1.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
-        tx2.commit()?;
-        assert_eq!(tx1.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+        // an update lock or an uncommitted write keeps out other locks
+        let tx3 = mvcc.begin()?;
+        assert!(!tx3.lock(b"key1".to_vec(), LockMode::Share, LockWait::SkipLocked)?);
+        assert!(tx3.lock(b"key2".to_vec(), LockMode::Update, LockWait::SkipLocked)?);
+        let tx4 = mvcc.begin()?;
+        tx4.set(b"key3".to_vec(), b"val1".to_vec())?;
+        assert_eq!(
+            tx3.lock(b"key3".to_vec(), LockMode::Update, LockWait::Block),
+            Err(Error::LockNotAvailable)
+        );
+        tx4.rollback()?;
+        tx2.rollback()?;
+        // tx3 is older than tx2's write, which was rolled back
+        assert!(tx3.lock(b"key1".to_vec(), LockMode::Update, LockWait::NoWait)?);
+
+        // a blocked lock goes through once the holder ends
+        let mvcc = mvcc.lock_timeout(Duration::from_secs(5));
+        let waiter = {
+            let mvcc = mvcc.clone();
+            std::thread::spawn(move || -> Result<()> {
+                let tx = mvcc.begin()?;
+                assert!(tx.lock(b"key2".to_vec(), LockMode::Update, LockWait::Block)?);
+                tx.commit()
+            })
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        tx3.commit()?;
+        waiter.join().unwrap()?;
+
+        // locking a key changed after the snapshot conflicts
+        let tx5 = mvcc.begin()?;
+        let tx6 = mvcc.begin()?;
+        tx6.set(b"key2".to_vec(), b"val2".to_vec())?;
+        tx6.commit()?;
+        assert_eq!(
+            tx5.lock(b"key2".to_vec(), LockMode::Update, LockWait::NoWait),
+            Err(Error::WriteConflict)
+        );
+        tx5.rollback()?;
 
+        // no lock is left
+        let prefix = MvccKeyPrefix::Lock(b"key1".to_vec()).encode()?;
+        assert_eq!(mvcc.with_engine(|eng| Ok(eng.scan_prefix(prefix).count()))?, 0);
         Ok(())
     }
 
     #[test]
-    fn test_unrepeatable_read() -> Result<()> {
-        unrepeatable_read(MemoryEngine::new())?;
-        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        unrepeatable_read(DiskEngine::new(p.clone())?)?;
-        std::fs::remove_dir_all(p.parent().unwrap())?;
+    fn test_lock() -> Result<()> {
+        lock(MemoryEngine::new())?;
+
+        let dir = tempfile::tempdir()?;
+        lock(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        lock(btree_engine(dir.path())?)?;
         Ok(())
     }
 
-    // 11. phantom read
-    fn phantom_read(eng: impl Engine) -> Result<()> {
-        let mvcc = Mvcc::new(eng);
+    // the locks of a transaction abandoned by a crash are released on open
+    #[test]
+    fn test_recover_locks() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        {
+            let mvcc = Mvcc::new(DiskEngine::new(path.clone())?)?;
+            // the process dies while holding a lock
+            let tx = mvcc.begin()?;
+            tx.lock(b"key1".to_vec(), LockMode::Update, LockWait::NoWait)?;
+        }
+        let mvcc = Mvcc::new(DiskEngine::new(path)?)?;
         let tx = mvcc.begin()?;
-        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
-        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
-        tx.set(b"key3".to_vec(), b"val3".to_vec())?;
+        assert!(tx.lock(b"key1".to_vec(), LockMode::Update, LockWait::NoWait)?);
         tx.commit()?;
+        let prefix = MvccKeyPrefix::Lock(b"key1".to_vec()).encode()?;
+        assert_eq!(mvcc.with_engine(|eng| Ok(eng.scan_prefix(prefix).count()))?, 0);
+        Ok(())
+    }
+
+    // Change stream
+    fn changes(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng)?.capture_changes(true);
+        let change = |key: &[u8], old: Option<&[u8]>, new: Option<&[u8]>| Change {
+            key: key.to_vec(),
+            old: old.map(|v| v.to_vec()),
+            new: new.map(|v| v.to_vec()),
+        };
 
         let tx1 = mvcc.begin()?;
+        tx1.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx1.set(b"key2".to_vec(), b"val1".to_vec())?;
+        tx1.commit()?;
+
+        // tx2 begins first but commits last
         let tx2 = mvcc.begin()?;
+        tx2.set(b"key2".to_vec(), b"val2".to_vec())?;
+        let tx3 = mvcc.begin()?;
+        tx3.delete(b"key1".to_vec())?;
+        tx3.set(b"key3".to_vec(), b"val3".to_vec())?;
+        tx3.commit()?;
+        // a transaction without writes, or rolled back, has no record
+        mvcc.begin()?.commit()?;
+        let tx4 = mvcc.begin()?;
+        tx4.set(b"key1".to_vec(), b"val4".to_vec())?;
+        tx4.rollback()?;
+        tx2.commit()?;
 
-        let iter1 = tx1.scan_prefix(b"key".to_vec())?;
+        let txns = mvcc.changes(None)?.collect::<Result<Vec<_>>>()?;
         assert_eq!(
-            iter1,
+            txns,
             vec![
-                super::ScanResult {
-                    key: b"key1".to_vec(),
-                    value: b"val1".to_vec()
+                CommittedTxn {
+                    version: 1,
+                    changes: vec![
+                        change(b"key1", None, Some(b"val1")),
+                        change(b"key2", None, Some(b"val1")),
+                    ],
                 },
-                super::ScanResult {
-                    key: b"key2".to_vec(),
-                    value: b"val2".to_vec()
+                CommittedTxn {
+                    version: 3,
+                    changes: vec![
+                        change(b"key1", Some(b"val1"), None),
+                        change(b"key3", None, Some(b"val3")),
+                    ],
                 },
-                super::ScanResult {
-                    key: b"key3".to_vec(),
-                    value: b"val3".to_vec()
+                CommittedTxn {
+                    version: 2,
+                    changes: vec![change(b"key2", Some(b"val1"), Some(b"val2"))],
                 },
             ]
         );
 
-        tx2.set(b"key2".to_vec(), b"val2-1".to_vec())?;
-        tx2.set(b"key4".to_vec(), b"val4".to_vec())?;
-        tx2.commit()?;
-
-        let iter1 = tx1.scan_prefix(b"key".to_vec())?;
+        // a stream resumes after a version and picks up later commits
+        let mut stream = mvcc.changes(Some(3))?;
+        assert_eq!(stream.next().transpose()?.map(|t| t.version), Some(2));
+        assert!(stream.next().is_none());
+        let tx6 = mvcc.begin()?;
+        tx6.set(b"key3".to_vec(), b"val6".to_vec())?;
+        tx6.commit()?;
         assert_eq!(
-            iter1,
-            vec![
-                super::ScanResult {
-                    key: b"key1".to_vec(),
-                    value: b"val1".to_vec()
-                },
-                super::ScanResult {
-                    key: b"key2".to_vec(),
-                    value: b"val2".to_vec()
-                },
-                super::ScanResult {
-                    key: b"key3".to_vec(),
-                    value: b"val3".to_vec()
-                },
-            ]
+            stream.next().transpose()?,
+            Some(CommittedTxn {
+                version: 6,
+                changes: vec![change(b"key3", Some(b"val3"), Some(b"val6"))],
+            })
         );
+        assert!(mvcc.changes(Some(4)).is_err());
+
+        // the records hold back the vacuum until they are truncated
+        assert_eq!(mvcc.vacuum()?, 0);
+        mvcc.truncate_changes(3)?;
+        assert!(stream.next().is_none());
+        assert_eq!(mvcc.changes(None)?.next().transpose()?.map(|t| t.version), Some(2));
+        assert!(mvcc.changes(Some(1)).is_err());
+        assert!(mvcc.changes(Some(3)).is_err());
+        mvcc.truncate_changes(6)?;
+        assert!(mvcc.changes(None)?.next().is_none());
+        assert!(mvcc.vacuum()? > 0);
+        for prefix in [MvccKeyPrefix::TxnWrite(2), MvccKeyPrefix::TxnWrite(6)] {
+            let prefix = prefix.encode()?;
+            assert_eq!(mvcc.with_engine(|eng| Ok(eng.scan_prefix(prefix).count()))?, 0);
+        }
         Ok(())
     }
 
     #[test]
-    fn test_phantom_read() -> Result<()> {
-        phantom_read(MemoryEngine::new())?;
-        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        phantom_read(DiskEngine::new(p.clone())?)?;
-        std::fs::remove_dir_all(p.parent().unwrap())?;
+    fn test_changes() -> Result<()> {
+        changes(MemoryEngine::new())?;
+
+        let dir = tempfile::tempdir()?;
+        changes(DiskEngine::new(dir.path().join("sqldb-log"))?)?;
+
+        let dir = tempfile::tempdir()?;
+        changes(lsm_engine(dir.path())?)?;
+
+        let dir = tempfile::tempdir()?;
+        changes(btree_engine(dir.path())?)?;
         Ok(())
     }
 
-    // 12. rollback
-    fn rollback(eng: impl Engine) -> Result<()> {
-        let mvcc = Mvcc::new(eng);
+    // a stream falls behind a truncation
+    #[test]
+    fn test_changes_truncated() -> Result<()> {
+        let mvcc = Mvcc::new(MemoryEngine::new())?.capture_changes(true);
+        for _ in 0..2 {
+            let tx = mvcc.begin()?;
+            tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+            tx.commit()?;
+        }
+        let mut stream = mvcc.changes(None)?;
+        mvcc.truncate_changes(1)?;
+        assert!(stream.next().transpose().is_err());
+        Ok(())
+    }
+
+    // a rollback after the commit fails and leaves the commit as it is
+    #[test]
+    fn test_rollback_committed() -> Result<()> {
+        let mvcc = Mvcc::new(MemoryEngine::new())?.capture_changes(true);
         let tx = mvcc.begin()?;
         tx.set(b"key1".to_vec(), b"val1".to_vec())?;
-        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
-        tx.set(b"key3".to_vec(), b"val3".to_vec())?;
         tx.commit()?;
+        assert!(tx.rollback().is_err());
 
-        let tx1 = mvcc.begin()?;
-        tx1.set(b"key1".to_vec(), b"val1-1".to_vec())?;
-        tx1.set(b"key2".to_vec(), b"val2-1".to_vec())?;
-        tx1.set(b"key3".to_vec(), b"val3-1".to_vec())?;
-        tx1.rollback()?;
-
-        let tx2 = mvcc.begin()?;
-        assert_eq!(tx2.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
-        assert_eq!(tx2.get(b"key2".to_vec())?, Some(b"val2".to_vec()));
-        assert_eq!(tx2.get(b"key3".to_vec())?, Some(b"val3".to_vec()));
-
+        let tx = mvcc.begin()?;
+        assert_eq!(tx.get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+        tx.commit()?;
+        let changes = mvcc.changes(None)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].changes.len(), 1);
         Ok(())
     }
 
+    // a serializable transaction rolled back by its prepared id, or dropped,
+    // leaves the tracker
     #[test]
-    fn test_rollback() -> Result<()> {
-        rollback(MemoryEngine::new())?;
-        let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        rollback(DiskEngine::new(p.clone())?)?;
-        std::fs::remove_dir_all(p.parent().unwrap())?;
+    fn test_serializable_forgotten() -> Result<()> {
+        let mvcc = Mvcc::new(MemoryEngine::new())?.isolation(IsolationLevel::Serializable);
+        let other = mvcc.begin()?;
+        let tx = mvcc.begin()?;
+        tx.get(b"x".to_vec())?;
+        tx.set(b"y".to_vec(), b"1".to_vec())?;
+        tx.prepare("p1")?;
+        drop(tx);
+        assert_eq!(mvcc.ssi.lock()?.tracked(), 2);
+        mvcc.rollback_prepared("p1")?;
+        assert_eq!(mvcc.ssi.lock()?.tracked(), 1);
+
+        let tx = mvcc.begin()?;
+        tx.get(b"x".to_vec())?;
+        drop(tx);
+        assert_eq!(mvcc.ssi.lock()?.tracked(), 1);
+        other.commit()?;
+        assert_eq!(mvcc.ssi.lock()?.tracked(), 0);
         Ok(())
     }
 }
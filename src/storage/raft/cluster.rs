@@ -0,0 +1,410 @@
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    path::PathBuf,
+};
+
+use crate::{
+    error::{Error, Result},
+    storage::disk::DiskEngine,
+};
+
+use super::node::{Command, Index, Node, NodeId, RaftOptions};
+
+// How many ticks a request waits for the cluster
+const REQUEST_TICKS: u64 = 1000;
+// The most messages delivered after a tick, more means the nodes keep talking
+const MAX_DELIVERIES: usize = 100_000;
+
+// Raft nodes connected by an in-memory network, the test harness of Node
+// Time passes only by tick, and messages are delivered in the order they
+// were sent, so a run repeats exactly. The network can be partitioned, and
+// nodes can crash and restart from their directories.
+pub struct Cluster {
+    dir: PathBuf,
+    options: RaftOptions,
+    // the running nodes
+    nodes: BTreeMap<NodeId, Node>,
+    // the links cut by partitions, in both directions
+    cut: HashSet<(NodeId, NodeId)>,
+}
+
+impl Cluster {
+    // Start a cluster of the nodes, each stored in dir/node<id>
+    pub fn new(dir: PathBuf, ids: &[NodeId], options: RaftOptions) -> Result<Self> {
+        let mut nodes = BTreeMap::new();
+        for id in ids {
+            let node = Node::open(*id, &Self::node_dir(&dir, *id), ids, options.clone())?;
+            nodes.insert(*id, node);
+        }
+        Ok(Self {
+            dir,
+            options,
+            nodes,
+            cut: HashSet::new(),
+        })
+    }
+
+    fn node_dir(dir: &std::path::Path, id: NodeId) -> PathBuf {
+        dir.join(format!("node{}", id))
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&Node> {
+        self.nodes.get(&id)
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> Option<&mut Node> {
+        self.nodes.get_mut(&id)
+    }
+
+    // The ids of the running nodes
+    pub fn running(&self) -> Vec<NodeId> {
+        self.nodes.keys().copied().collect()
+    }
+
+    // The running leader of the newest term, a leader cut off from the others
+    // may not know yet it was replaced
+    pub fn leader(&self) -> Option<NodeId> {
+        self.nodes
+            .values()
+            .filter(|node| node.is_leader())
+            .max_by_key(|node| node.term())
+            .map(|node| node.id())
+    }
+
+    // Advance every running node by a tick and deliver the messages
+    pub fn tick(&mut self) -> Result<()> {
+        for node in self.nodes.values_mut() {
+            node.tick()?;
+        }
+        self.deliver()
+    }
+
+    pub fn run(&mut self, ticks: u64) -> Result<()> {
+        for _ in 0..ticks {
+            self.tick()?;
+        }
+        Ok(())
+    }
+
+    // Tick until a leader is elected, returns it
+    pub fn elect(&mut self) -> Result<NodeId> {
+        for _ in 0..REQUEST_TICKS {
+            if let Some(id) = self.leader() {
+                return Ok(id);
+            }
+            self.tick()?;
+        }
+        Err(Error::Internal("no leader was elected".to_string()))
+    }
+
+    // Deliver the messages until none is left, the ones to stopped nodes or
+    // over cut links are lost
+    pub fn deliver(&mut self) -> Result<()> {
+        let mut queue = VecDeque::new();
+        for node in self.nodes.values_mut() {
+            queue.extend(node.take_messages());
+        }
+        let mut delivered = 0;
+        while let Some(msg) = queue.pop_front() {
+            if self.cut.contains(&(msg.from, msg.to)) {
+                continue;
+            }
+            if let Some(node) = self.nodes.get_mut(&msg.to) {
+                node.step(msg)?;
+                queue.extend(node.take_messages());
+            }
+            delivered += 1;
+            if delivered > MAX_DELIVERIES {
+                return Err(Error::Internal("the network doesn't settle".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    // Cut the nodes of the group off from all the others
+    pub fn partition(&mut self, group: &[NodeId]) {
+        let ids = self.all_ids();
+        for a in group {
+            for b in ids.iter().filter(|id| !group.contains(id)) {
+                self.cut.insert((*a, *b));
+                self.cut.insert((*b, *a));
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.cut.clear();
+    }
+
+    // The ids of all nodes with a directory, running or not
+    fn all_ids(&self) -> Vec<NodeId> {
+        let mut ids = self.running();
+        if let Ok(entries) = std::fs::read_dir(&self.dir) {
+            for entry in entries.flatten() {
+                let name = entry.file_name();
+                if let Some(id) = name.to_str().and_then(|n| n.strip_prefix("node")) {
+                    if let Ok(id) = id.parse() {
+                        if !ids.contains(&id) {
+                            ids.push(id);
+                        }
+                    }
+                }
+            }
+        }
+        ids
+    }
+
+    // Stop the node, losing everything it didn't store
+    pub fn crash(&mut self, id: NodeId) {
+        self.nodes.remove(&id);
+    }
+
+    pub fn restart(&mut self, id: NodeId) -> Result<()> {
+        let node = Node::open(
+            id,
+            &Self::node_dir(&self.dir, id),
+            &[],
+            self.options.clone(),
+        )?;
+        self.nodes.insert(id, node);
+        Ok(())
+    }
+
+    // Start a new node and add it to the voters
+    pub fn add_node(&mut self, id: NodeId) -> Result<()> {
+        let node = Node::open(
+            id,
+            &Self::node_dir(&self.dir, id),
+            &[],
+            self.options.clone(),
+        )?;
+        self.nodes.insert(id, node);
+        self.request(|node| node.add_voter(id))
+    }
+
+    // Remove the node from the voters and stop it
+    pub fn remove_node(&mut self, id: NodeId) -> Result<()> {
+        self.request(|node| node.remove_voter(id))?;
+        self.crash(id);
+        Ok(())
+    }
+
+    // Replicate a write batch, a None value deletes the key
+    pub fn write(&mut self, ops: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Result<()> {
+        self.request(|node| node.propose(Command::Write(ops.clone())))
+    }
+
+    // Propose on the leader and wait until it's applied there, an entry
+    // replaced by another leader, or lost in a crash, is proposed again
+    fn request(&mut self, propose: impl Fn(&mut Node) -> Result<Index>) -> Result<()> {
+        let mut pending: Option<(NodeId, Index)> = None;
+        for _ in 0..REQUEST_TICKS {
+            if pending.is_none() {
+                if let Some(id) = self.leader() {
+                    let index = propose(self.nodes.get_mut(&id).unwrap())?;
+                    pending = Some((id, index));
+                    self.deliver()?;
+                }
+            }
+            if let Some((id, index)) = pending {
+                match self.nodes.get_mut(&id).map(|node| node.take_outcome(index)) {
+                    Some(Some(Ok(()))) => return Ok(()),
+                    Some(Some(Err(_))) | None => pending = None,
+                    Some(None) => {}
+                }
+            }
+            self.tick()?;
+        }
+        Err(Error::Internal("the request timed out".to_string()))
+    }
+
+    // Read the state machine of the leader, once it knows it's still the leader
+    // and has applied all entries committed when the read began
+    pub fn read<T>(&mut self, f: impl FnOnce(&DiskEngine) -> Result<T>) -> Result<T> {
+        let mut pending: Option<(NodeId, u64)> = None;
+        for _ in 0..REQUEST_TICKS {
+            if let Some((id, seq)) = pending {
+                match self.nodes.get(&id).map(|node| node.read_ready(seq)) {
+                    Some(Ok(true)) => return f(self.nodes[&id].state()),
+                    Some(Ok(false)) => {}
+                    Some(Err(_)) | None => pending = None,
+                }
+            }
+            if pending.is_none() {
+                if let Some(id) = self.leader() {
+                    if let Some(node) = self.nodes.get_mut(&id) {
+                        pending = Some((id, node.read_barrier()?));
+                        self.deliver()?;
+                        continue;
+                    }
+                }
+            }
+            self.tick()?;
+        }
+        Err(Error::Internal("the read timed out".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Result,
+        storage::{
+            engine::Engine,
+            raft::node::{Command, NodeId, RaftOptions},
+        },
+    };
+
+    use super::Cluster;
+
+    fn cluster(dir: &std::path::Path, ids: &[NodeId], options: RaftOptions) -> Result<Cluster> {
+        Cluster::new(dir.to_path_buf(), ids, options)
+    }
+
+    fn set(c: &mut Cluster, key: &[u8], value: &[u8]) -> Result<()> {
+        c.write(vec![(key.to_vec(), Some(value.to_vec()))])
+    }
+
+    // Run until the nodes have applied the same entries, and check their state
+    // machines are equal
+    fn assert_converged(c: &mut Cluster) -> Result<()> {
+        c.run(20)?;
+        let ids = c.running();
+        let first = c.node(ids[0]).unwrap();
+        let data = first.state().scan(..).collect::<Result<Vec<_>>>()?;
+        for id in &ids[1..] {
+            let node = c.node(*id).unwrap();
+            assert_eq!(node.applied_index(), first.applied_index(), "node {}", id);
+            assert_eq!(node.state().scan(..).collect::<Result<Vec<_>>>()?, data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_replicate() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut c = cluster(dir.path(), &[1, 2, 3], RaftOptions::default())?;
+        let leader = c.elect()?;
+        set(&mut c, b"key1", b"val1")?;
+        set(&mut c, b"key2", b"val2")?;
+        c.write(vec![(b"key1".to_vec(), None)])?;
+        assert_eq!(c.read(|s| s.get(b"key2".to_vec()))?, Some(b"val2".to_vec()));
+        assert_eq!(c.read(|s| s.get(b"key1".to_vec()))?, None);
+        assert_converged(&mut c)?;
+        assert_eq!(c.leader(), Some(leader));
+        Ok(())
+    }
+
+    #[test]
+    fn test_failover() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut c = cluster(dir.path(), &[1, 2, 3, 4, 5], RaftOptions::default())?;
+        let old = c.elect()?;
+        set(&mut c, b"key1", b"val1")?;
+
+        // the leader is cut off with one follower, its writes can't commit
+        let follower = c.running().into_iter().find(|id| *id != old).unwrap();
+        c.partition(&[old, follower]);
+        let index = c.node_mut(old).unwrap().propose(Command::Write(vec![(
+            b"key1".to_vec(),
+            Some(b"lost".to_vec()),
+        )]))?;
+        c.run(50)?;
+        assert!(c.node_mut(old).unwrap().take_outcome(index).is_none());
+        assert_eq!(c.node(old).unwrap().commit_index(), index - 1);
+
+        // nor serve a read, it may miss the writes of a new leader
+        let seq = c.node_mut(old).unwrap().read_barrier()?;
+        c.run(10)?;
+        assert!(!c.node(old).unwrap().read_ready(seq)?);
+
+        // the majority elects a new leader, which serves reads and writes
+        let new = c.leader().unwrap();
+        assert_ne!(new, old);
+        assert!(c.node(new).unwrap().term() > c.node(old).unwrap().term());
+        set(&mut c, b"key2", b"val2")?;
+        assert_eq!(c.read(|s| s.get(b"key1".to_vec()))?, Some(b"val1".to_vec()));
+
+        // the old leader steps down once healed, its entry is replaced
+        c.heal();
+        c.run(20)?;
+        assert!(!c.node(old).unwrap().is_leader());
+        assert!(c
+            .node_mut(old)
+            .unwrap()
+            .take_outcome(index)
+            .unwrap()
+            .is_err());
+        assert_converged(&mut c)?;
+        assert_eq!(
+            c.node(old).unwrap().state().get(b"key1".to_vec())?,
+            Some(b"val1".to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_catch_up() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = RaftOptions {
+            snapshot_entries: 5,
+            ..Default::default()
+        };
+        let mut c = cluster(dir.path(), &[1, 2, 3], options)?;
+        let leader = c.elect()?;
+        set(&mut c, b"key1", b"val1")?;
+
+        // a follower is down while the leader compacts its log
+        let follower = c.running().into_iter().find(|id| *id != leader).unwrap();
+        c.crash(follower);
+        for i in 0..12u8 {
+            set(&mut c, &[b'k', i], &[i])?;
+        }
+        assert!(c.node(leader).unwrap().snapshot_index() > 5);
+
+        // it restarts from its own log and gets the snapshot for the rest
+        c.restart(follower)?;
+        assert_eq!(c.node(follower).unwrap().applied_index(), 0);
+        c.run(20)?;
+        assert!(c.node(follower).unwrap().snapshot_index() > 5);
+        assert_converged(&mut c)?;
+
+        // and keeps it across restarts
+        c.crash(follower);
+        c.restart(follower)?;
+        let node = c.node(follower).unwrap();
+        assert!(node.applied_index() > 5);
+        assert_eq!(node.state().get(b"key1".to_vec())?, Some(b"val1".to_vec()));
+        assert_converged(&mut c)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_membership() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut c = cluster(dir.path(), &[1, 2, 3], RaftOptions::default())?;
+        c.elect()?;
+        set(&mut c, b"key1", b"val1")?;
+
+        // a new node gets the log and votes
+        c.add_node(4)?;
+        set(&mut c, b"key2", b"val2")?;
+        assert_converged(&mut c)?;
+        for id in c.running() {
+            assert_eq!(c.node(id).unwrap().voters().len(), 4);
+        }
+
+        // the removed leader steps down and the others elect another one
+        let leader = c.leader().unwrap();
+        c.remove_node(leader)?;
+        let new = c.elect()?;
+        assert_ne!(new, leader);
+        set(&mut c, b"key3", b"val3")?;
+        assert_eq!(c.read(|s| s.get(b"key1".to_vec()))?, Some(b"val1".to_vec()));
+        assert_converged(&mut c)?;
+        for id in c.running() {
+            assert!(!c.node(id).unwrap().voters().contains(&leader));
+        }
+        Ok(())
+    }
+}
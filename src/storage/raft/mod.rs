@@ -0,0 +1,355 @@
+use std::{
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Condvar, Mutex, MutexGuard, Weak},
+    time::{Duration, Instant},
+};
+
+use crate::error::{Error, Result};
+
+use self::{
+    node::{Command, Node},
+    transport::Transport,
+};
+
+use super::{
+    disk::DiskEngine,
+    engine::{Engine, EngineIterator, WriteBatch},
+};
+
+#[cfg(test)]
+pub mod cluster;
+pub mod node;
+pub mod transport;
+
+// How often the node ticks
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+// How long a request waits for the cluster
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A storage engine replicated by Raft, writes are committed by a majority of
+// the nodes before they return and reads are served by the leader once it
+// confirmed its leadership, so every node applies the same writes in the
+// same order
+// The engine runs one node of the cluster, a background thread ticks it and
+// exchanges its messages over the transport until the engine and all its
+// clones are dropped. Only the leader serves requests, a follower returns an
+// error naming the leader, so an Mvcc on top of the leader's engine gets
+// transactions on the replicated data.
+#[derive(Clone)]
+pub struct RaftEngine {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    node: Mutex<Node>,
+    // notified whenever the node stepped or ticked
+    changed: Condvar,
+    // the error which stopped the background thread
+    error: Mutex<Option<Error>>,
+}
+
+impl RaftEngine {
+    pub fn new(node: Node, transport: impl Transport + 'static) -> Self {
+        let shared = Arc::new(Shared {
+            node: Mutex::new(node),
+            changed: Condvar::new(),
+            error: Mutex::new(None),
+        });
+        let weak = Arc::downgrade(&shared);
+        std::thread::spawn(move || Self::run(weak, Box::new(transport)));
+        Self { shared }
+    }
+
+    // The node, e.g. to find out whether it's the leader
+    pub fn node(&self) -> Result<MutexGuard<'_, Node>> {
+        Ok(self.shared.node.lock()?)
+    }
+
+    // Step the messages as they arrive and tick the node, until the engine is
+    // dropped or the node fails
+    fn run(shared: Weak<Shared>, transport: Box<dyn Transport>) {
+        let mut next_tick = Instant::now() + TICK_INTERVAL;
+        loop {
+            let message = transport.recv(next_tick.saturating_duration_since(Instant::now()));
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+            let result = message.and_then(|message| {
+                let mut node = shared.node.lock()?;
+                if let Some(message) = message {
+                    node.step(message)?;
+                }
+                if Instant::now() >= next_tick {
+                    node.tick()?;
+                    next_tick += TICK_INTERVAL;
+                }
+                let messages = node.take_messages();
+                drop(node);
+                shared.changed.notify_all();
+                for message in messages {
+                    transport.send(message)?;
+                }
+                Ok(())
+            });
+            if let Err(err) = result {
+                if let Ok(mut error) = shared.error.lock() {
+                    *error = Some(err);
+                }
+                shared.changed.notify_all();
+                return;
+            }
+        }
+    }
+
+    // Poll the node whenever it changed, until the poll returns a value or
+    // the request times out
+    fn wait<T>(
+        &self,
+        mut poll: impl FnMut(&mut Node) -> Result<Option<T>>,
+    ) -> Result<(MutexGuard<'_, Node>, T)> {
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        let mut node = self.node()?;
+        loop {
+            if let Some(err) = self.shared.error.lock()?.clone() {
+                return Err(err);
+            }
+            if let Some(value) = poll(&mut node)? {
+                return Ok((node, value));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Internal("the request timed out".to_string()));
+            }
+            node = self.shared.changed.wait_timeout(node, deadline - now)?.0;
+        }
+    }
+
+    // A follower which knows the leader can't serve requests, while the
+    // leader is unknown they wait for the election
+    fn check_leader(node: &Node) -> Result<()> {
+        match node.leader() {
+            Some(leader) if leader != node.id() => Err(Error::Internal(format!(
+                "node {} is not the leader, node {} is",
+                node.id(),
+                leader
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    // Propose on the leader and wait until it's applied, an entry replaced
+    // by another leader is proposed again
+    fn request(&self, command: Command) -> Result<()> {
+        let mut pending = None;
+        self.wait(|node| {
+            if pending.is_none() {
+                Self::check_leader(node)?;
+                if node.is_leader() {
+                    pending = Some(node.propose(command.clone())?);
+                }
+            }
+            if let Some(index) = pending {
+                match node.take_outcome(index) {
+                    Some(Ok(())) => return Ok(Some(())),
+                    Some(Err(_)) => pending = None,
+                    None => {}
+                }
+            }
+            Ok(None)
+        })
+        .map(|_| ())
+    }
+
+    // Read the state machine of the leader, once it knows it's still the leader
+    // and has applied all entries committed when the read began
+    fn read<T>(&self, f: impl FnOnce(&DiskEngine) -> Result<T>) -> Result<T> {
+        let mut pending = None;
+        let (node, ()) = self.wait(|node| {
+            if let Some(seq) = pending {
+                match node.read_ready(seq) {
+                    Ok(true) => return Ok(Some(())),
+                    Ok(false) => return Ok(None),
+                    Err(_) => pending = None,
+                }
+            }
+            Self::check_leader(node)?;
+            if node.is_leader() {
+                pending = Some(node.read_barrier()?);
+            }
+            Ok(None)
+        })?;
+        f(node.state())
+    }
+}
+
+impl Engine for RaftEngine {
+    type EngineIterator<'a> = RaftEngineIterator;
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.set(key, value);
+        self.write_batch(batch)
+    }
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.read(|state| state.get(key))
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write_batch(batch)
+    }
+
+    // The rows are read at once, the leader may change before the
+    // iterator is done
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Self::EngineIterator<'_> {
+        let range: (Bound<Vec<u8>>, Bound<Vec<u8>>) =
+            (range.start_bound().cloned(), range.end_bound().cloned());
+        let items = self.read(|state| state.scan(range).collect::<Result<Vec<_>>>());
+        let items = match items {
+            Ok(items) => items.into_iter().map(Ok).collect::<Vec<_>>(),
+            Err(err) => vec![Err(err)],
+        };
+        RaftEngineIterator {
+            inner: items.into_iter(),
+        }
+    }
+
+    // A batch is one log entry, so it's applied as a unit on every node
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.request(Command::Write(batch.into_ops()))
+    }
+}
+
+pub struct RaftEngineIterator {
+    inner: std::vec::IntoIter<Result<(Vec<u8>, Vec<u8>)>>,
+}
+
+impl EngineIterator for RaftEngineIterator {}
+
+impl Iterator for RaftEngineIterator {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+impl DoubleEndedIterator for RaftEngineIterator {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, path::Path};
+
+    use crate::{
+        error::{Error, Result},
+        storage::{
+            engine::Engine,
+            mvcc::Mvcc,
+            raft::{
+                node::{Node, NodeId, RaftOptions},
+                transport::ChannelNetwork,
+            },
+        },
+    };
+
+    use super::{RaftEngine, TICK_INTERVAL};
+
+    // Start the node stored in dir/node<id>
+    fn start(
+        dir: &Path,
+        network: &ChannelNetwork,
+        id: NodeId,
+        voters: &[NodeId],
+    ) -> Result<RaftEngine> {
+        let node = Node::open(
+            id,
+            &dir.join(format!("node{}", id)),
+            voters,
+            RaftOptions::default(),
+        )?;
+        Ok(RaftEngine::new(node, network.connect(id)?))
+    }
+
+    // Wait until one of the nodes is the leader
+    fn leader(engines: &BTreeMap<NodeId, RaftEngine>) -> Result<NodeId> {
+        for _ in 0..1000 {
+            for (id, engine) in engines {
+                if engine.node()?.is_leader() {
+                    return Ok(*id);
+                }
+            }
+            std::thread::sleep(TICK_INTERVAL);
+        }
+        Err(Error::Internal("no leader was elected".to_string()))
+    }
+
+    #[test]
+    fn test_raft_mvcc() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let network = ChannelNetwork::new();
+        let mut engines = BTreeMap::new();
+        for id in [1, 2, 3] {
+            engines.insert(id, start(dir.path(), &network, id, &[1, 2, 3])?);
+        }
+        let leader = leader(&engines)?;
+        let mvcc = Mvcc::new(engines[&leader].clone())?;
+
+        let tx = mvcc.begin()?;
+        tx.set(b"key1".to_vec(), b"val1".to_vec())?;
+        tx.set(b"key2".to_vec(), b"val2".to_vec())?;
+        tx.commit()?;
+
+        // a follower doesn't serve requests
+        let follower = *engines.keys().find(|id| **id != leader).unwrap();
+        assert!(engines[&follower].get(b"key1".to_vec()).is_err());
+
+        // the transactions go on at the new leader after the leader fails
+        drop((tx, mvcc));
+        engines.remove(&leader);
+        let new = self::leader(&engines)?;
+        let mvcc = Mvcc::new(engines[&new].clone())?;
+        let tx = mvcc.begin()?;
+        tx.delete(b"key1".to_vec())?;
+        tx.set(b"key3".to_vec(), b"val3".to_vec())?;
+        tx.commit()?;
+
+        let tx = mvcc.begin()?;
+        assert_eq!(tx.get(b"key1".to_vec())?, None);
+        assert_eq!(tx.get(b"key3".to_vec())?, Some(b"val3".to_vec()));
+        assert_eq!(tx.scan_prefix(b"key".to_vec())?.len(), 2);
+        tx.commit()?;
+
+        // the old leader restarts and catches up, all nodes hold the same data
+        std::thread::sleep(TICK_INTERVAL * 5);
+        engines.insert(leader, start(dir.path(), &network, leader, &[])?);
+        let data = engines[&new]
+            .node()?
+            .state()
+            .scan(..)
+            .collect::<Result<Vec<_>>>()?;
+        for engine in engines.values() {
+            let mut converged = false;
+            for _ in 0..1000 {
+                let node = engine.node()?;
+                if node.state().scan(..).collect::<Result<Vec<_>>>()? == data {
+                    converged = true;
+                    break;
+                }
+                drop(node);
+                std::thread::sleep(TICK_INTERVAL);
+            }
+            assert!(converged);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1402 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    storage::{
+        disk::{sync_dir, DiskEngine, DiskOptions, SyncPolicy},
+        engine::{Engine, WriteBatch},
+        keycode::serialize_key,
+    },
+};
+
+pub type NodeId = u64;
+pub type Term = u64;
+pub type Index = u64;
+
+#[derive(Debug, Clone)]
+pub struct RaftOptions {
+    // a follower which hears nothing from a leader for this many ticks, plus
+    // a random number below it, starts an election
+    pub election_ticks: u64,
+    // a leader sends heartbeats every this many ticks
+    pub heartbeat_ticks: u64,
+    // a snapshot is taken once this many entries were applied after the last one
+    pub snapshot_entries: u64,
+    // the most entries sent in one append
+    pub max_append: usize,
+}
+
+impl Default for RaftOptions {
+    fn default() -> Self {
+        Self {
+            election_ticks: 10,
+            heartbeat_ticks: 3,
+            snapshot_entries: 1000,
+            max_append: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    // appended by a new leader, to commit the entries of earlier terms
+    Noop,
+    // a write batch for the state machine
+    Write(Vec<(Vec<u8>, Option<Vec<u8>>)>),
+    // the new set of voters, in effect as soon as it's appended
+    Voters(BTreeSet<NodeId>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub index: Index,
+    pub term: Term,
+    pub command: Command,
+}
+
+// The last entry a snapshot covers, and the voters at that entry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotMeta {
+    pub index: Index,
+    pub term: Term,
+    pub voters: BTreeSet<NodeId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub term: Term,
+    pub body: Body,
+}
+
+// A leader sends a read sequence number along with its appends, the
+// followers echo it, so a read knows when a quorum still follows the leader
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Body {
+    RequestVote {
+        last_index: Index,
+        last_term: Term,
+    },
+    Vote {
+        granted: bool,
+    },
+    Append {
+        prev_index: Index,
+        prev_term: Term,
+        entries: Vec<Entry>,
+        commit: Index,
+        read_seq: u64,
+    },
+    // the snapshot file, in the DiskEngine compacted log format, and its manifest
+    Snapshot {
+        meta: SnapshotMeta,
+        data: Vec<u8>,
+        manifest: Vec<u8>,
+        read_seq: u64,
+    },
+    // the follower's log matches the leader's up to the index
+    Accepted {
+        matched: Index,
+        read_seq: u64,
+    },
+    // the follower's log doesn't match, it holds no more than last_index entries
+    // which might
+    Rejected {
+        last_index: Index,
+        read_seq: u64,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum RaftKey {
+    // the current term and the vote in it
+    HardState,
+    Snapshot,
+    Entry(Index),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum RaftKeyPrefix {
+    HardState,
+    Snapshot,
+    Entry,
+}
+
+// The Raft log, kept in memory and stored in a DiskEngine which syncs every
+// write, so a vote or an accepted entry is on disk before the node answers
+struct RaftLog {
+    store: DiskEngine,
+    snapshot: SnapshotMeta,
+    // the entries after the snapshot, entries[0] has index snapshot.index + 1
+    entries: Vec<Entry>,
+}
+
+impl RaftLog {
+    // Open the log, a new one starts with the voters, returns the term and vote
+    fn open(path: PathBuf, voters: &[NodeId]) -> Result<(Self, Term, Option<NodeId>)> {
+        let mut store = DiskEngine::new_with_options(
+            path,
+            DiskOptions {
+                sync_policy: SyncPolicy::Always,
+                ..Default::default()
+            },
+        )?;
+        let snapshot = match store.get(serialize_key(&RaftKey::Snapshot)?)? {
+            Some(value) => bincode::deserialize(&value)?,
+            None => {
+                let snapshot = SnapshotMeta {
+                    index: 0,
+                    term: 0,
+                    voters: voters.iter().copied().collect(),
+                };
+                store.set(
+                    serialize_key(&RaftKey::Snapshot)?,
+                    bincode::serialize(&snapshot)?,
+                )?;
+                snapshot
+            }
+        };
+        let (term, vote) = match store.get(serialize_key(&RaftKey::HardState)?)? {
+            Some(value) => bincode::deserialize(&value)?,
+            None => (0, None),
+        };
+
+        let mut entries = Vec::new();
+        for item in store.scan_prefix(serialize_key(&RaftKeyPrefix::Entry)?) {
+            let entry: Entry = bincode::deserialize(&item?.1)?;
+            if entry.index > snapshot.index {
+                entries.push(entry);
+            }
+        }
+        Ok((
+            Self {
+                store,
+                snapshot,
+                entries,
+            },
+            term,
+            vote,
+        ))
+    }
+
+    fn save_hard_state(&mut self, term: Term, vote: Option<NodeId>) -> Result<()> {
+        self.store.set(
+            serialize_key(&RaftKey::HardState)?,
+            bincode::serialize(&(term, vote))?,
+        )
+    }
+
+    fn last_index(&self) -> Index {
+        self.snapshot.index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> Term {
+        self.entries.last().map_or(self.snapshot.term, |e| e.term)
+    }
+
+    fn entry(&self, index: Index) -> Option<&Entry> {
+        if index <= self.snapshot.index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot.index - 1) as usize)
+    }
+
+    // The term of the entry, None if it's missing or covered by the snapshot
+    fn term_at(&self, index: Index) -> Option<Term> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        self.entry(index).map(|e| e.term)
+    }
+
+    fn entries_from(&self, index: Index, max: usize) -> Vec<Entry> {
+        let start = (index.max(self.snapshot.index + 1) - self.snapshot.index - 1) as usize;
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for entry in &entries {
+            batch.set(
+                serialize_key(&RaftKey::Entry(entry.index))?,
+                bincode::serialize(entry)?,
+            );
+        }
+        self.store.write_batch(batch)?;
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    // Remove the entries from the index on
+    fn truncate(&mut self, index: Index) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for i in index..=self.last_index() {
+            batch.delete(serialize_key(&RaftKey::Entry(i))?);
+        }
+        self.store.write_batch(batch)?;
+        self.entries
+            .truncate((index - self.snapshot.index - 1) as usize);
+        Ok(())
+    }
+
+    // Replace the entries up to the snapshot by it, entries after it are kept
+    // if the log holds the snapshot's last entry, otherwise all are dropped
+    fn compact(&mut self, snapshot: SnapshotMeta) -> Result<()> {
+        let keep = self.term_at(snapshot.index) == Some(snapshot.term);
+        let mut batch = WriteBatch::new();
+        let end = if keep {
+            snapshot.index
+        } else {
+            self.last_index()
+        };
+        for i in self.snapshot.index + 1..=end {
+            batch.delete(serialize_key(&RaftKey::Entry(i))?);
+        }
+        batch.set(
+            serialize_key(&RaftKey::Snapshot)?,
+            bincode::serialize(&snapshot)?,
+        );
+        self.store.write_batch(batch)?;
+
+        if keep {
+            self.entries
+                .drain(..(snapshot.index - self.snapshot.index) as usize);
+        } else {
+            self.entries.clear();
+        }
+        self.snapshot = snapshot;
+        Ok(())
+    }
+
+    // The voters after the entries up to the index, and the index of the
+    // entry which set them
+    fn voters_at(&self, index: Index) -> (BTreeSet<NodeId>, Index) {
+        let mut voters = (self.snapshot.voters.clone(), self.snapshot.index);
+        for entry in self.entries.iter().take_while(|e| e.index <= index) {
+            if let Command::Voters(set) = &entry.command {
+                voters = (set.clone(), entry.index);
+            }
+        }
+        voters
+    }
+}
+
+struct Progress {
+    // the next entry to send, and the last one known to match
+    next: Index,
+    matched: Index,
+    read_seq: u64,
+    // the snapshot index sent and not acknowledged yet, with the ticks since,
+    // it's sent again once an election timeout passed
+    snapshot_sent: Option<(Index, u64)>,
+}
+
+struct Leadership {
+    progress: BTreeMap<NodeId, Progress>,
+    since_heartbeat: u64,
+    // the first entry of the term, reads wait until it's committed
+    term_start: Index,
+    read_seq: u64,
+    // the read barriers, by sequence number, with the commit index when asked
+    reads: BTreeMap<u64, Index>,
+}
+
+enum Role {
+    Follower {
+        leader: Option<NodeId>,
+        elapsed: u64,
+        timeout: u64,
+    },
+    Candidate {
+        votes: BTreeSet<NodeId>,
+        elapsed: u64,
+        timeout: u64,
+    },
+    Leader(Leadership),
+}
+
+// A Raft node
+// The node is a state machine without threads or timers: time passes by
+// tick, messages arrive by step and the messages to send are collected by
+// take_messages, so a harness can run a cluster deterministically.
+// The applied entries go into a DiskEngine, which is rebuilt from the last
+// snapshot when the node opens. A snapshot is a DiskEngine backup, and is
+// sent as such to a follower missing the entries it covers.
+// Membership changes add or remove one voter at a time.
+pub struct Node {
+    id: NodeId,
+    dir: PathBuf,
+    options: RaftOptions,
+    term: Term,
+    vote: Option<NodeId>,
+    log: RaftLog,
+    voters: BTreeSet<NodeId>,
+    // the entry which set the voters
+    voters_index: Index,
+    commit: Index,
+    applied: Index,
+    role: Role,
+    state: DiskEngine,
+    outbox: Vec<Message>,
+    rng: u64,
+    // the terms of the entries proposed here, until they are applied, and
+    // whether they were
+    proposals: HashMap<Index, Term>,
+    outcomes: HashMap<Index, bool>,
+}
+
+impl Node {
+    // Open the node stored in the directory, a new node starts with the voters,
+    // a node joining a cluster with none
+    pub fn open(id: NodeId, dir: &Path, voters: &[NodeId], options: RaftOptions) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let (log, term, vote) = RaftLog::open(dir.join("raft-log"), voters)?;
+
+        // the state machine restarts from the snapshot
+        let snapshot = snapshot_path(dir, log.snapshot.index);
+        remove_files(dir, "state-", None)?;
+        remove_files(dir, "snapshot.", Some(&snapshot))?;
+        let state_path = dir.join(format!("state-{:08}", log.snapshot.index));
+        if log.snapshot.index > 0 {
+            std::fs::copy(&snapshot, &state_path)?;
+        }
+
+        let applied = log.snapshot.index;
+        let (voters, voters_index) = log.voters_at(log.last_index());
+        let mut node = Self {
+            id,
+            dir: dir.to_path_buf(),
+            options,
+            term,
+            vote,
+            log,
+            voters,
+            voters_index,
+            commit: applied,
+            applied,
+            role: Role::Follower {
+                leader: None,
+                elapsed: 0,
+                timeout: 0,
+            },
+            state: DiskEngine::new(state_path)?,
+            outbox: Vec::new(),
+            rng: id.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+            proposals: HashMap::new(),
+            outcomes: HashMap::new(),
+        };
+        node.role = Role::Follower {
+            leader: None,
+            elapsed: 0,
+            timeout: node.random_timeout(),
+        };
+        Ok(node)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn term(&self) -> Term {
+        self.term
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader(_))
+    }
+
+    // The leader as far as the node knows
+    pub fn leader(&self) -> Option<NodeId> {
+        match &self.role {
+            Role::Leader(_) => Some(self.id),
+            Role::Follower { leader, .. } => *leader,
+            Role::Candidate { .. } => None,
+        }
+    }
+
+    pub fn voters(&self) -> &BTreeSet<NodeId> {
+        &self.voters
+    }
+
+    pub fn commit_index(&self) -> Index {
+        self.commit
+    }
+
+    pub fn applied_index(&self) -> Index {
+        self.applied
+    }
+
+    // The index of the last entry the snapshot covers
+    pub fn snapshot_index(&self) -> Index {
+        self.log.snapshot.index
+    }
+
+    // The state machine, with the entries up to the applied index
+    pub fn state(&self) -> &DiskEngine {
+        &self.state
+    }
+
+    pub fn take_messages(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    // Whether the entry proposed at the index was applied, None while it's
+    // pending, an error if another leader replaced it
+    pub fn take_outcome(&mut self, index: Index) -> Option<Result<()>> {
+        self.outcomes.remove(&index).map(|applied| match applied {
+            true => Ok(()),
+            false => Err(Error::Internal(format!(
+                "entry {} was replaced by another leader",
+                index
+            ))),
+        })
+    }
+
+    pub fn tick(&mut self) -> Result<()> {
+        match &mut self.role {
+            Role::Follower {
+                elapsed, timeout, ..
+            }
+            | Role::Candidate {
+                elapsed, timeout, ..
+            } => {
+                *elapsed += 1;
+                if *elapsed >= *timeout && self.voters.contains(&self.id) {
+                    self.campaign()?;
+                }
+            }
+            Role::Leader(leadership) => {
+                for progress in leadership.progress.values_mut() {
+                    if let Some((_, ticks)) = &mut progress.snapshot_sent {
+                        *ticks += 1;
+                    }
+                }
+                leadership.since_heartbeat += 1;
+                if leadership.since_heartbeat >= self.options.heartbeat_ticks {
+                    leadership.since_heartbeat = 0;
+                    self.broadcast_append()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Append a command to the log, on the leader only, returns its index
+    pub fn propose(&mut self, command: Command) -> Result<Index> {
+        if !self.is_leader() {
+            return Err(Error::Internal(format!(
+                "node {} is not the leader",
+                self.id
+            )));
+        }
+        let index = self.log.last_index() + 1;
+        self.append_entries(vec![Entry {
+            index,
+            term: self.term,
+            command,
+        }])?;
+        self.proposals.insert(index, self.term);
+        self.maybe_commit()?;
+        self.broadcast_append()?;
+        Ok(index)
+    }
+
+    pub fn add_voter(&mut self, id: NodeId) -> Result<Index> {
+        let mut voters = self.voters.clone();
+        voters.insert(id);
+        self.change_voters(voters)
+    }
+
+    pub fn remove_voter(&mut self, id: NodeId) -> Result<Index> {
+        let mut voters = self.voters.clone();
+        voters.remove(&id);
+        self.change_voters(voters)
+    }
+
+    // Only one change is in progress at a time, so the majorities of the old
+    // and the new voters always overlap
+    // The leader first commits an entry of its term, otherwise a change of
+    // an earlier leader it doesn't have yet could still commit next to its own.
+    fn change_voters(&mut self, voters: BTreeSet<NodeId>) -> Result<Index> {
+        if let Role::Leader(leadership) = &self.role {
+            if self.commit < leadership.term_start {
+                return Err(Error::Internal(
+                    "the leader has not committed an entry of its term yet".to_string(),
+                ));
+            }
+        }
+        if self.voters_index > self.commit {
+            return Err(Error::Internal(
+                "a membership change is in progress".to_string(),
+            ));
+        }
+        if voters.is_empty() {
+            return Err(Error::Internal("a cluster needs a voter".to_string()));
+        }
+        self.propose(Command::Voters(voters))
+    }
+
+    // Start a linearizable read on the leader, returns its sequence number
+    // for read_ready
+    pub fn read_barrier(&mut self) -> Result<u64> {
+        let commit = self.commit;
+        let leadership = match &mut self.role {
+            Role::Leader(leadership) => leadership,
+            _ => {
+                return Err(Error::Internal(format!(
+                    "node {} is not the leader",
+                    self.id
+                )))
+            }
+        };
+        leadership.read_seq += 1;
+        let seq = leadership.read_seq;
+        leadership.reads.insert(seq, commit);
+        self.broadcast_append()?;
+        Ok(seq)
+    }
+
+    // Whether the state machine can serve the read: a quorum still followed
+    // the leader after the read began, and the entries committed by then are
+    // applied
+    pub fn read_ready(&self, seq: u64) -> Result<bool> {
+        let leadership = match &self.role {
+            Role::Leader(leadership) => leadership,
+            _ => {
+                return Err(Error::Internal(format!(
+                    "node {} is not the leader",
+                    self.id
+                )))
+            }
+        };
+        let commit = match leadership.reads.get(&seq) {
+            Some(commit) => *commit,
+            None => return Err(Error::Internal(format!("read {} is unknown", seq))),
+        };
+        let acks = self
+            .voters
+            .iter()
+            .filter(|id| {
+                **id == self.id
+                    || leadership
+                        .progress
+                        .get(id)
+                        .is_some_and(|p| p.read_seq >= seq)
+            })
+            .count();
+        Ok(acks > self.voters.len() / 2
+            && self.commit >= leadership.term_start
+            && self.applied >= commit)
+    }
+
+    pub fn step(&mut self, msg: Message) -> Result<()> {
+        if msg.to != self.id {
+            return Ok(());
+        }
+        // a node which heard from the leader lately ignores a candidate, e.g.
+        // a removed node which doesn't know it was removed
+        if matches!(msg.body, Body::RequestVote { .. }) && msg.term > self.term {
+            let recent = match &self.role {
+                Role::Leader(_) => true,
+                Role::Follower {
+                    leader: Some(_),
+                    elapsed,
+                    ..
+                } => *elapsed < self.options.election_ticks,
+                _ => false,
+            };
+            if recent {
+                return Ok(());
+            }
+        }
+
+        if msg.term > self.term {
+            let leader = match msg.body {
+                Body::Append { .. } | Body::Snapshot { .. } => Some(msg.from),
+                _ => None,
+            };
+            self.become_follower(msg.term, leader)?;
+        }
+        if msg.term < self.term {
+            // a stale candidate or leader learns the term from the response
+            match msg.body {
+                Body::RequestVote { .. } => self.send(msg.from, Body::Vote { granted: false }),
+                Body::Append { .. } | Body::Snapshot { .. } => self.send(
+                    msg.from,
+                    Body::Rejected {
+                        last_index: self.log.last_index(),
+                        read_seq: 0,
+                    },
+                ),
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        match msg.body {
+            Body::RequestVote {
+                last_index,
+                last_term,
+            } => {
+                let up_to_date =
+                    (last_term, last_index) >= (self.log.last_term(), self.log.last_index());
+                let granted = matches!(self.role, Role::Follower { .. })
+                    && self.vote.is_none_or(|v| v == msg.from)
+                    && up_to_date;
+                if granted {
+                    self.vote = Some(msg.from);
+                    self.log.save_hard_state(self.term, self.vote)?;
+                    if let Role::Follower { elapsed, .. } = &mut self.role {
+                        *elapsed = 0;
+                    }
+                }
+                self.send(msg.from, Body::Vote { granted });
+            }
+
+            Body::Vote { granted } => {
+                if let Role::Candidate { votes, .. } = &mut self.role {
+                    if granted {
+                        votes.insert(msg.from);
+                    }
+                    let votes = votes.clone();
+                    if self.is_quorum(&votes) {
+                        self.become_leader()?;
+                    }
+                }
+            }
+
+            Body::Append {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+                read_seq,
+            } => {
+                self.follow(msg.from);
+                self.handle_append(msg.from, prev_index, prev_term, entries, commit, read_seq)?;
+            }
+
+            Body::Snapshot {
+                meta,
+                data,
+                manifest,
+                read_seq,
+            } => {
+                self.follow(msg.from);
+                // a snapshot which doesn't match its manifest is dropped
+                // unanswered, the leader sends it again
+                if meta.index > self.commit && !self.install_snapshot(meta, data, manifest)? {
+                    return Ok(());
+                }
+                let matched = self.commit;
+                self.send(msg.from, Body::Accepted { matched, read_seq });
+            }
+
+            Body::Accepted { matched, read_seq } => {
+                let last_index = self.log.last_index();
+                let behind = match &mut self.role {
+                    Role::Leader(leadership) => match leadership.progress.get_mut(&msg.from) {
+                        Some(progress) => {
+                            progress.matched = progress.matched.max(matched);
+                            progress.next = progress.next.max(matched + 1);
+                            progress.read_seq = progress.read_seq.max(read_seq);
+                            if progress
+                                .snapshot_sent
+                                .is_some_and(|(index, _)| matched >= index)
+                            {
+                                progress.snapshot_sent = None;
+                            }
+                            progress.next <= last_index
+                        }
+                        None => return Ok(()),
+                    },
+                    _ => return Ok(()),
+                };
+                self.maybe_commit()?;
+                if behind {
+                    self.send_append(msg.from)?;
+                }
+            }
+
+            Body::Rejected {
+                last_index,
+                read_seq,
+            } => {
+                if let Role::Leader(leadership) = &mut self.role {
+                    if let Some(progress) = leadership.progress.get_mut(&msg.from) {
+                        progress.read_seq = progress.read_seq.max(read_seq);
+                        progress.next = (progress.next - 1).min(last_index + 1).max(1);
+                        self.send_append(msg.from)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_append(
+        &mut self,
+        from: NodeId,
+        mut prev_index: Index,
+        mut prev_term: Term,
+        mut entries: Vec<Entry>,
+        commit: Index,
+        read_seq: u64,
+    ) -> Result<()> {
+        // the entries up to the snapshot are committed, so they match
+        if prev_index < self.log.snapshot.index {
+            entries.retain(|e| e.index > self.log.snapshot.index);
+            prev_index = self.log.snapshot.index;
+            prev_term = self.log.snapshot.term;
+        }
+        if self.log.term_at(prev_index) != Some(prev_term) {
+            let last_index = self.log.last_index().min(prev_index.saturating_sub(1));
+            self.send(
+                from,
+                Body::Rejected {
+                    last_index,
+                    read_seq,
+                },
+            );
+            return Ok(());
+        }
+
+        let last_new = prev_index + entries.len() as u64;
+        let mut new = Vec::new();
+        for entry in entries {
+            if new.is_empty() {
+                match self.log.term_at(entry.index) {
+                    Some(term) if term == entry.term => continue,
+                    Some(_) => self.truncate(entry.index)?,
+                    None => {}
+                }
+            }
+            new.push(entry);
+        }
+        if !new.is_empty() {
+            self.append_entries(new)?;
+        }
+
+        if commit.min(last_new) > self.commit {
+            self.commit = commit.min(last_new);
+            self.apply()?;
+        }
+        self.send(
+            from,
+            Body::Accepted {
+                matched: last_new,
+                read_seq,
+            },
+        );
+        Ok(())
+    }
+
+    fn append_entries(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let config = entries
+            .iter()
+            .any(|e| matches!(e.command, Command::Voters(_)));
+        self.log.append(entries)?;
+        if config {
+            self.update_voters();
+        }
+        Ok(())
+    }
+
+    // Drop the entries from the index on, the ones proposed here are lost
+    fn truncate(&mut self, index: Index) -> Result<()> {
+        self.log.truncate(index)?;
+        for (i, _) in self.proposals.extract_if(|i, _| *i >= index) {
+            self.outcomes.insert(i, false);
+        }
+        self.update_voters();
+        Ok(())
+    }
+
+    fn update_voters(&mut self) {
+        let (voters, index) = self.log.voters_at(self.log.last_index());
+        self.voters = voters;
+        self.voters_index = index;
+        let next = self.log.last_index() + 1;
+        if let Role::Leader(leadership) = &mut self.role {
+            leadership.progress.retain(|id, _| self.voters.contains(id));
+            for id in &self.voters {
+                if *id != self.id && !leadership.progress.contains_key(id) {
+                    leadership.progress.insert(
+                        *id,
+                        Progress {
+                            next,
+                            matched: 0,
+                            read_seq: 0,
+                            snapshot_sent: None,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    fn campaign(&mut self) -> Result<()> {
+        self.term += 1;
+        self.vote = Some(self.id);
+        self.log.save_hard_state(self.term, self.vote)?;
+        self.role = Role::Candidate {
+            votes: BTreeSet::from([self.id]),
+            elapsed: 0,
+            timeout: self.random_timeout(),
+        };
+        if self.is_quorum(&BTreeSet::from([self.id])) {
+            return self.become_leader();
+        }
+        let body = Body::RequestVote {
+            last_index: self.log.last_index(),
+            last_term: self.log.last_term(),
+        };
+        for id in self.voters.clone() {
+            if id != self.id {
+                self.send(id, body.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: Term, leader: Option<NodeId>) -> Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.vote = None;
+            self.log.save_hard_state(self.term, self.vote)?;
+        }
+        self.role = Role::Follower {
+            leader,
+            elapsed: 0,
+            timeout: self.random_timeout(),
+        };
+        Ok(())
+    }
+
+    // Follow the leader of the current term
+    fn follow(&mut self, leader: NodeId) {
+        match &mut self.role {
+            Role::Follower {
+                leader: known,
+                elapsed,
+                ..
+            } => {
+                *known = Some(leader);
+                *elapsed = 0;
+            }
+            _ => {
+                self.role = Role::Follower {
+                    leader: Some(leader),
+                    elapsed: 0,
+                    timeout: self.random_timeout(),
+                }
+            }
+        }
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        let next = self.log.last_index() + 1;
+        let progress = self
+            .voters
+            .iter()
+            .filter(|id| **id != self.id)
+            .map(|id| {
+                (
+                    *id,
+                    Progress {
+                        next,
+                        matched: 0,
+                        read_seq: 0,
+                        snapshot_sent: None,
+                    },
+                )
+            })
+            .collect();
+        self.role = Role::Leader(Leadership {
+            progress,
+            since_heartbeat: 0,
+            term_start: next,
+            read_seq: 0,
+            reads: BTreeMap::new(),
+        });
+        self.append_entries(vec![Entry {
+            index: next,
+            term: self.term,
+            command: Command::Noop,
+        }])?;
+        self.maybe_commit()?;
+        self.broadcast_append()
+    }
+
+    fn is_quorum(&self, ids: &BTreeSet<NodeId>) -> bool {
+        ids.intersection(&self.voters).count() > self.voters.len() / 2
+    }
+
+    // Commit the newest entry of the term a quorum has
+    fn maybe_commit(&mut self) -> Result<()> {
+        let leadership = match &self.role {
+            Role::Leader(leadership) => leadership,
+            _ => return Ok(()),
+        };
+        let mut matched = self
+            .voters
+            .iter()
+            .map(|id| match id == &self.id {
+                true => self.log.last_index(),
+                false => leadership.progress.get(id).map_or(0, |p| p.matched),
+            })
+            .collect::<Vec<_>>();
+        if matched.is_empty() {
+            return Ok(());
+        }
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.voters.len() / 2];
+        if index > self.commit && self.log.term_at(index) == Some(self.term) {
+            self.commit = index;
+            self.apply()?;
+            // tell the followers about the commit
+            if self.is_leader() {
+                self.broadcast_append()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self) -> Result<()> {
+        while self.applied < self.commit {
+            let entry = match self.log.entry(self.applied + 1) {
+                Some(entry) => entry.clone(),
+                None => {
+                    return Err(Error::Internal(format!(
+                        "entry {} is missing",
+                        self.applied + 1
+                    )))
+                }
+            };
+            if let Command::Write(ops) = entry.command {
+                let mut batch = WriteBatch::new();
+                for (key, value) in ops {
+                    match value {
+                        Some(value) => batch.set(key, value),
+                        None => batch.delete(key),
+                    }
+                }
+                self.state.write_batch(batch)?;
+            }
+            self.applied = entry.index;
+            if let Some(term) = self.proposals.remove(&entry.index) {
+                self.outcomes.insert(entry.index, term == entry.term);
+            }
+        }
+
+        // a leader which removed itself steps down once that's committed
+        if self.is_leader() && !self.voters.contains(&self.id) && self.commit >= self.voters_index {
+            self.become_follower(self.term, None)?;
+        }
+        if self.applied - self.log.snapshot.index >= self.options.snapshot_entries {
+            self.take_snapshot()?;
+        }
+        Ok(())
+    }
+
+    // Back up the state machine as the snapshot of the applied entries
+    fn take_snapshot(&mut self) -> Result<()> {
+        let index = self.applied;
+        let meta = SnapshotMeta {
+            index,
+            term: self.log.term_at(index).unwrap_or(self.term),
+            voters: self.log.voters_at(index).0,
+        };
+        let path = snapshot_path(&self.dir, index);
+        remove_snapshot(&path)?;
+        self.state.backup(path.clone())?;
+        sync_dir(&path)?;
+
+        let old = snapshot_path(&self.dir, self.log.snapshot.index);
+        self.log.compact(meta)?;
+        remove_snapshot(&old)
+    }
+
+    // Replace the state machine by the snapshot of the leader, once it's
+    // stored and matches its manifest, returns whether it did
+    fn install_snapshot(
+        &mut self,
+        meta: SnapshotMeta,
+        data: Vec<u8>,
+        manifest: Vec<u8>,
+    ) -> Result<bool> {
+        let path = snapshot_path(&self.dir, meta.index);
+        remove_snapshot(&path)?;
+        write_synced(&path, &data)?;
+        write_synced(&manifest_path(&path), &manifest)?;
+        sync_dir(&path)?;
+        if DiskEngine::verify_backup(path.clone()).is_err() {
+            remove_snapshot(&path)?;
+            return Ok(false);
+        }
+        // the new state machine gets a new name, the old one is removed once closed
+        let state_path = self.dir.join(format!("state-{:08}", meta.index));
+        std::fs::copy(&path, &state_path)?;
+        let state = DiskEngine::new(state_path.clone())?;
+        drop(std::mem::replace(&mut self.state, state));
+        remove_files(&self.dir, "state-", Some(&state_path))?;
+
+        let old = snapshot_path(&self.dir, self.log.snapshot.index);
+        let index = meta.index;
+        self.log.compact(meta)?;
+        if old != path {
+            remove_snapshot(&old)?;
+        }
+        self.commit = index;
+        self.applied = index;
+        self.update_voters();
+        Ok(true)
+    }
+
+    fn broadcast_append(&mut self) -> Result<()> {
+        let ids = match &self.role {
+            Role::Leader(leadership) => leadership.progress.keys().copied().collect::<Vec<_>>(),
+            _ => return Ok(()),
+        };
+        for id in ids {
+            self.send_append(id)?;
+        }
+        Ok(())
+    }
+
+    // Send the follower the entries from its next one on, or the snapshot if
+    // the log doesn't hold them anymore, unless it's on its way
+    fn send_append(&mut self, id: NodeId) -> Result<()> {
+        let snapshot = self.log.snapshot.index;
+        let election_ticks = self.options.election_ticks;
+        let (next, read_seq) = match &mut self.role {
+            Role::Leader(leadership) => match leadership.progress.get_mut(&id) {
+                Some(progress) => {
+                    if progress.next <= snapshot {
+                        match progress.snapshot_sent {
+                            Some((index, ticks)) if index == snapshot && ticks < election_ticks => {
+                                return Ok(())
+                            }
+                            _ => progress.snapshot_sent = Some((snapshot, 0)),
+                        }
+                    }
+                    (progress.next, leadership.read_seq)
+                }
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        if next <= snapshot {
+            let path = snapshot_path(&self.dir, self.log.snapshot.index);
+            let data = std::fs::read(&path)?;
+            let manifest = std::fs::read(manifest_path(&path))?;
+            let meta = self.log.snapshot.clone();
+            self.send(
+                id,
+                Body::Snapshot {
+                    meta,
+                    data,
+                    manifest,
+                    read_seq,
+                },
+            );
+            return Ok(());
+        }
+        let prev_index = next - 1;
+        let prev_term = self.log.term_at(prev_index).unwrap_or(0);
+        let entries = self.log.entries_from(next, self.options.max_append);
+        self.send(
+            id,
+            Body::Append {
+                prev_index,
+                prev_term,
+                entries,
+                commit: self.commit,
+                read_seq,
+            },
+        );
+        Ok(())
+    }
+
+    fn send(&mut self, to: NodeId, body: Body) {
+        self.outbox.push(Message {
+            from: self.id,
+            to,
+            term: self.term,
+            body,
+        });
+    }
+
+    // xorshift, seeded by the node id so runs repeat
+    fn random_timeout(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.options.election_ticks + self.rng % self.options.election_ticks.max(1)
+    }
+}
+
+// dir/snapshot.00000012, a DiskEngine backup with its manifest next to it
+fn snapshot_path(dir: &Path, index: Index) -> PathBuf {
+    dir.join(format!("snapshot.{:08}", index))
+}
+
+fn manifest_path(path: &Path) -> PathBuf {
+    let mut manifest = path.as_os_str().to_os_string();
+    manifest.push(".manifest");
+    PathBuf::from(manifest)
+}
+
+fn remove_snapshot(path: &Path) -> Result<()> {
+    for path in [path.to_path_buf(), manifest_path(path)] {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+// Write the file and sync it, the caller syncs the directory
+fn write_synced(path: &Path, data: &[u8]) -> Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// Remove the files of the directory whose names start with the prefix, but
+// the ones starting with the name of keep
+fn remove_files(dir: &Path, prefix: &str, keep: Option<&Path>) -> Result<()> {
+    let keep = keep
+        .and_then(|p| p.file_name())
+        .and_then(|n| n.to_str())
+        .map(|n| n.to_string());
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if name.starts_with(prefix) && !keep.as_ref().is_some_and(|k| name.starts_with(k)) {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::Result, storage::engine::Engine};
+
+    use super::{manifest_path, snapshot_path, Body, Command, Message, Node, RaftOptions};
+
+    // a single voter commits on its own, and reopens with its term and state
+    #[test]
+    fn test_single_node() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = RaftOptions {
+            snapshot_entries: 3,
+            ..Default::default()
+        };
+        let mut node = Node::open(1, dir.path(), &[1], options.clone())?;
+        while !node.is_leader() {
+            node.tick()?;
+        }
+        // the noop of the term is entry 1
+        for i in 0..6u8 {
+            let index = node.propose(Command::Write(vec![(vec![i], Some(vec![i]))]))?;
+            assert_eq!(node.take_outcome(index), Some(Ok(())));
+        }
+        assert!(node.take_messages().is_empty());
+        assert_eq!(node.commit_index(), 7);
+        assert_eq!(node.snapshot_index(), 6);
+        let term = node.term();
+        drop(node);
+
+        // the state is rebuilt from the snapshot, and the rest once committed
+        let mut node = Node::open(1, dir.path(), &[], options)?;
+        assert_eq!(node.term(), term);
+        assert_eq!(node.applied_index(), 6);
+        assert_eq!(node.state().scan(..).count(), 5);
+        while !node.is_leader() {
+            node.tick()?;
+        }
+        assert_eq!(node.term(), term + 1);
+        assert_eq!(node.applied_index(), 8);
+        assert_eq!(node.state().get(vec![5])?, Some(vec![5]));
+        Ok(())
+    }
+
+    // a new leader changes the voters only once an entry of its term is committed
+    #[test]
+    fn test_change_voters_term_start() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut node = Node::open(1, dir.path(), &[1, 2], RaftOptions::default())?;
+        while node.take_messages().is_empty() {
+            node.tick()?;
+        }
+        let message = |node: &Node, body| Message {
+            from: 2,
+            to: 1,
+            term: node.term(),
+            body,
+        };
+        node.step(message(&node, Body::Vote { granted: true }))?;
+        assert!(node.is_leader());
+        assert!(node.add_voter(3).is_err());
+
+        node.step(message(
+            &node,
+            Body::Accepted {
+                matched: 1,
+                read_seq: 0,
+            },
+        ))?;
+        assert_eq!(node.commit_index(), 1);
+        assert_eq!(node.add_voter(3)?, 2);
+        Ok(())
+    }
+
+    // votes and entries are synced before the node answers
+    #[test]
+    fn test_log_synced() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut node = Node::open(1, dir.path(), &[1], RaftOptions::default())?;
+        let synced = node.log.store.sync_count();
+        while !node.is_leader() {
+            node.tick()?;
+        }
+        // the vote for itself and the noop of the term
+        assert_eq!(node.log.store.sync_count(), synced + 2);
+        node.propose(Command::Write(vec![(vec![1], Some(vec![1]))]))?;
+        assert_eq!(node.log.store.sync_count(), synced + 3);
+        Ok(())
+    }
+
+    // a snapshot is only installed if it matches its manifest, a corrupt one
+    // is dropped and the node goes on
+    #[test]
+    fn test_snapshot_verified() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = RaftOptions {
+            snapshot_entries: 3,
+            ..Default::default()
+        };
+        let mut leader = Node::open(1, &dir.path().join("node1"), &[1], options.clone())?;
+        while !leader.is_leader() {
+            leader.tick()?;
+        }
+        for i in 0..3u8 {
+            leader.propose(Command::Write(vec![(vec![i], Some(vec![i]))]))?;
+        }
+        let meta = leader.log.snapshot.clone();
+        let path = snapshot_path(&dir.path().join("node1"), meta.index);
+        let data = std::fs::read(&path)?;
+        let manifest = std::fs::read(manifest_path(&path))?;
+
+        let mut node = Node::open(2, &dir.path().join("node2"), &[1, 2], options)?;
+        let snapshot = |data: Vec<u8>| Message {
+            from: 1,
+            to: 2,
+            term: leader.term(),
+            body: Body::Snapshot {
+                meta: meta.clone(),
+                data,
+                manifest: manifest.clone(),
+                read_seq: 0,
+            },
+        };
+        let mut corrupt = data.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        node.step(snapshot(corrupt))?;
+        assert!(node.take_messages().is_empty());
+        assert_eq!(node.snapshot_index(), 0);
+        assert!(!snapshot_path(&dir.path().join("node2"), meta.index).exists());
+        node.tick()?;
+
+        node.step(snapshot(data))?;
+        assert_eq!(node.snapshot_index(), meta.index);
+        assert_eq!(node.state().get(vec![1])?, Some(vec![1]));
+        assert!(matches!(
+            node.take_messages()[..],
+            [Message {
+                body: Body::Accepted { .. },
+                ..
+            }]
+        ));
+        Ok(())
+    }
+
+    // a snapshot on its way to a follower isn't sent again until it's
+    // acknowledged or an election timeout passed
+    #[test]
+    fn test_snapshot_in_flight() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = RaftOptions {
+            snapshot_entries: 3,
+            ..Default::default()
+        };
+        let mut node = Node::open(1, dir.path(), &[1, 2], options.clone())?;
+        while node.take_messages().is_empty() {
+            node.tick()?;
+        }
+        let message = |node: &Node, body| Message {
+            from: 2,
+            to: 1,
+            term: node.term(),
+            body,
+        };
+        node.step(message(&node, Body::Vote { granted: true }))?;
+        for i in 0..3u8 {
+            let index = node.propose(Command::Write(vec![(vec![i], Some(vec![i]))]))?;
+            let accepted = Body::Accepted {
+                matched: index,
+                read_seq: 0,
+            };
+            node.step(message(&node, accepted))?;
+        }
+        assert_eq!(node.snapshot_index(), 3);
+
+        // the follower lost its log
+        node.step(message(
+            &node,
+            Body::Rejected {
+                last_index: 0,
+                read_seq: 0,
+            },
+        ))?;
+        let snapshots = |node: &mut Node| {
+            node.take_messages()
+                .iter()
+                .filter(|m| matches!(m.body, Body::Snapshot { .. }))
+                .count()
+        };
+        assert_eq!(snapshots(&mut node), 1);
+        for _ in 1..options.election_ticks {
+            node.tick()?;
+            node.read_barrier()?;
+            assert_eq!(snapshots(&mut node), 0);
+        }
+        node.tick()?;
+        node.read_barrier()?;
+        assert_eq!(snapshots(&mut node), 1);
+
+        // once acknowledged the follower gets the entries after it
+        node.step(message(
+            &node,
+            Body::Accepted {
+                matched: 3,
+                read_seq: 0,
+            },
+        ))?;
+        node.read_barrier()?;
+        assert_eq!(snapshots(&mut node), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::error::{Error, Result};
+
+use super::node::{Message, NodeId};
+
+// Carries the messages of a node to the other nodes of the cluster, e.g. over
+// the network
+// A message may be lost, Raft sends it again, so a send to a node which can't
+// be reached still succeeds.
+pub trait Transport: Send {
+    fn send(&self, message: Message) -> Result<()>;
+
+    // The next message to the node, None if none arrives within the timeout
+    fn recv(&self, timeout: Duration) -> Result<Option<Message>>;
+}
+
+// Nodes of one process connected by channels
+#[derive(Clone, Default)]
+pub struct ChannelNetwork {
+    nodes: Arc<Mutex<HashMap<NodeId, Sender<Message>>>>,
+}
+
+impl ChannelNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Connect the node, a restarted node replaces its earlier connection
+    pub fn connect(&self, id: NodeId) -> Result<ChannelTransport> {
+        let (tx, rx) = mpsc::channel();
+        self.nodes.lock()?.insert(id, tx);
+        Ok(ChannelTransport {
+            network: self.clone(),
+            rx,
+        })
+    }
+}
+
+pub struct ChannelTransport {
+    network: ChannelNetwork,
+    rx: Receiver<Message>,
+}
+
+impl Transport for ChannelTransport {
+    fn send(&self, message: Message) -> Result<()> {
+        // the messages to a stopped node are lost
+        if let Some(tx) = self.network.nodes.lock()?.get(&message.to) {
+            let _ = tx.send(message);
+        }
+        Ok(())
+    }
+
+    fn recv(&self, timeout: Duration) -> Result<Option<Message>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(message) => Ok(Some(message)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(Error::Internal("the node was connected again".to_string()))
+            }
+        }
+    }
+}
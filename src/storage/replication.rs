@@ -0,0 +1,460 @@
+use std::{
+    collections::HashMap,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+use super::{
+    engine::Engine,
+    mvcc::{ChangeStream, CommittedTxn, Mvcc, MvccTransaction, TransactionOptions, Version},
+};
+
+// The messages between a leader and a follower
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Message {
+    // follower to leader: send the transactions committed after the version,
+    // or all the leader keeps
+    Subscribe {
+        follower: String,
+        after: Option<Version>,
+    },
+    // leader to follower: a committed transaction, in commit order
+    Commit(CommittedTxn),
+    // follower to leader: the transactions up to the version are applied
+    Applied {
+        follower: String,
+        version: Version,
+    },
+    // leader to follower: the subscription failed, e.g. the transactions it
+    // needs were truncated, and the follower must be restored from a backup
+    Rejected(String),
+}
+
+// A connection between a leader and a follower, a send or receive fails
+// once the other end is gone
+pub trait Transport: Send {
+    fn send(&self, message: Message) -> Result<()>;
+
+    // The next message, None if none arrives within the timeout
+    fn recv(&self, timeout: Duration) -> Result<Option<Message>>;
+}
+
+// An in-process transport over channels
+pub struct ChannelTransport {
+    tx: Sender<Message>,
+    rx: Receiver<Message>,
+}
+
+impl ChannelTransport {
+    // The two ends of a connection
+    pub fn pair() -> (Self, Self) {
+        let (tx1, rx1) = mpsc::channel();
+        let (tx2, rx2) = mpsc::channel();
+        (Self { tx: tx1, rx: rx2 }, Self { tx: tx2, rx: rx1 })
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&self, message: Message) -> Result<()> {
+        self.tx
+            .send(message)
+            .map_err(|_| Error::Internal("replication peer disconnected".to_string()))
+    }
+
+    fn recv(&self, timeout: Duration) -> Result<Option<Message>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(message) => Ok(Some(message)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(Error::Internal("replication peer disconnected".to_string()))
+            }
+        }
+    }
+}
+
+// A follower connected to the leader, it gets no transaction before it subscribes
+struct Connection<E: Engine> {
+    transport: Box<dyn Transport>,
+    follower: Option<String>,
+    stream: Option<ChangeStream<E>>,
+}
+
+// Ships the commit records of an Mvcc to the followers, the Mvcc must capture
+// changes (Mvcc::capture_changes)
+// The leader keeps the last version every follower applied in the Mvcc, also
+// across reconnects and its own restarts, and truncates the commit records
+// once all have applied them, so a restarted follower catches up from where
+// it stopped. A follower gone for good holds back the truncation until it's
+// removed. A new follower is rejected once records were truncated, it has to
+// start from a backup of the leader.
+pub struct Leader<E: Engine> {
+    mvcc: Mvcc<E>,
+    connections: Vec<Connection<E>>,
+    // the last version applied by each follower, None before the first
+    applied: HashMap<String, Option<Version>>,
+}
+
+impl<E: Engine> Leader<E> {
+    pub fn new(mvcc: Mvcc<E>) -> Result<Self> {
+        let applied = mvcc.followers_applied()?;
+        Ok(Self {
+            mvcc,
+            connections: Vec::new(),
+            applied,
+        })
+    }
+
+    pub fn add_follower(&mut self, transport: impl Transport + 'static) {
+        self.connections.push(Connection {
+            transport: Box::new(transport),
+            follower: None,
+            stream: None,
+        });
+    }
+
+    // Forget a follower, its records are truncated without waiting for it
+    pub fn remove_follower(&mut self, follower: &str) -> Result<()> {
+        self.mvcc.remove_follower(follower)?;
+        self.applied.remove(follower);
+        Ok(())
+    }
+
+    // Handle the messages of the followers and send them the transactions
+    // committed since, returns the number of transactions sent
+    // A follower whose connection fails is dropped until it connects again.
+    pub fn replicate(&mut self) -> Result<usize> {
+        let mut sent = 0;
+        let mut connections = std::mem::take(&mut self.connections);
+        connections.retain_mut(|conn| match self.replicate_to(conn) {
+            Ok(n) => {
+                sent += n;
+                true
+            }
+            Err(_) => false,
+        });
+        self.connections = connections;
+        self.truncate()?;
+        Ok(sent)
+    }
+
+    fn replicate_to(&mut self, conn: &mut Connection<E>) -> Result<usize> {
+        while let Some(message) = conn.transport.recv(Duration::ZERO)? {
+            match message {
+                Message::Subscribe { follower, after } => {
+                    let stream = match after {
+                        None if self.mvcc.changes_truncated()? => Err(Error::Internal(format!(
+                            "follower {} has no data and the commit records were truncated",
+                            follower
+                        ))),
+                        _ => self.mvcc.changes(after),
+                    };
+                    match stream {
+                        Ok(stream) => conn.stream = Some(stream),
+                        Err(err) => {
+                            conn.transport.send(Message::Rejected(err.to_string()))?;
+                            return Err(err);
+                        }
+                    }
+                    self.mvcc.set_follower_applied(&follower, after)?;
+                    self.applied.insert(follower.clone(), after);
+                    conn.follower = Some(follower);
+                }
+                Message::Applied { follower, version } => {
+                    self.mvcc.set_follower_applied(&follower, Some(version))?;
+                    self.applied.insert(follower, Some(version));
+                }
+                Message::Commit(_) | Message::Rejected(_) => {
+                    return Err(Error::Internal(
+                        "unexpected commit from a follower".to_string(),
+                    ))
+                }
+            }
+        }
+
+        let mut sent = 0;
+        if let Some(stream) = &mut conn.stream {
+            for txn in stream {
+                conn.transport.send(Message::Commit(txn?))?;
+                sent += 1;
+            }
+        }
+        Ok(sent)
+    }
+
+    // Truncate the commit records every known follower has applied, but the
+    // last one applied, a follower resumes after it
+    fn truncate(&self) -> Result<()> {
+        let versions = self.mvcc.committed_versions()?;
+        let mut end = versions.len();
+        for applied in self.applied.values() {
+            let applied = match applied {
+                Some(version) => versions.iter().position(|v| v == version),
+                None => None,
+            };
+            end = end.min(applied.unwrap_or(0));
+        }
+        if self.applied.is_empty() || end == 0 {
+            return Ok(());
+        }
+        self.mvcc.truncate_changes(versions[end - 1])
+    }
+}
+
+// Applies the transactions of the leader to its own engine, in the leader's
+// commit order, and serves read-only snapshot transactions of them
+// The follower's versions are its own, a snapshot sees the leader's
+// transactions up to some point of its commit order.
+pub struct Follower<E: Engine> {
+    mvcc: Mvcc<E>,
+    transport: Box<dyn Transport>,
+    name: String,
+}
+
+impl<E: Engine> Follower<E> {
+    // Open the follower and subscribe to the transactions after the last one applied
+    pub fn new(engine: E, name: &str, transport: impl Transport + 'static) -> Result<Self> {
+        let mvcc = Mvcc::new(engine)?;
+        transport.send(Message::Subscribe {
+            follower: name.to_string(),
+            after: mvcc.applied()?,
+        })?;
+        Ok(Self {
+            mvcc,
+            transport: Box::new(transport),
+            name: name.to_string(),
+        })
+    }
+
+    // Apply the transactions which arrive until none does within the timeout,
+    // returns the number applied
+    pub fn poll(&self, timeout: Duration) -> Result<usize> {
+        let mut applied = 0;
+        while let Some(message) = self.transport.recv(timeout)? {
+            match message {
+                Message::Commit(txn) => {
+                    self.mvcc.apply(&txn)?;
+                    self.transport.send(Message::Applied {
+                        follower: self.name.clone(),
+                        version: txn.version,
+                    })?;
+                    applied += 1;
+                }
+                Message::Rejected(reason) => {
+                    return Err(Error::Internal(format!(
+                        "rejected by the leader: {}",
+                        reason
+                    )))
+                }
+                message => {
+                    return Err(Error::Internal(format!(
+                        "unexpected message from the leader: {:?}",
+                        message
+                    )))
+                }
+            }
+        }
+        Ok(applied)
+    }
+
+    // The version of the last leader transaction applied
+    pub fn applied(&self) -> Result<Option<Version>> {
+        self.mvcc.applied()
+    }
+
+    pub fn begin(&self) -> Result<MvccTransaction<E>> {
+        self.mvcc.begin_with(TransactionOptions {
+            read_only: true,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        error::Result,
+        storage::{
+            disk::DiskEngine,
+            engine::Engine,
+            memory::MemoryEngine,
+            mvcc::{Mvcc, MvccTransaction, ScanResult},
+        },
+    };
+
+    use super::{ChannelTransport, Follower, Leader};
+
+    fn scan<E: Engine>(txn: MvccTransaction<E>) -> Result<Vec<ScanResult>> {
+        let results = txn.scan_prefix(vec![])?;
+        txn.commit()?;
+        Ok(results)
+    }
+
+    #[test]
+    fn test_replication() -> Result<()> {
+        let mvcc = Mvcc::new(MemoryEngine::new())?.capture_changes(true);
+        let mut leader = Leader::new(mvcc.clone())?;
+        let (t1, t2) = ChannelTransport::pair();
+        leader.add_follower(t1);
+        let follower = Follower::new(MemoryEngine::new(), "f1", t2)?;
+
+        // interleaved transactions, committed out of version order
+        let tx1 = mvcc.begin()?;
+        tx1.set(b"key1".to_vec(), b"val1".to_vec())?;
+        let tx2 = mvcc.begin()?;
+        tx2.set(b"key2".to_vec(), b"val2".to_vec())?;
+        tx2.delete(b"key3".to_vec())?;
+        tx2.commit()?;
+        tx1.set(b"key4".to_vec(), b"val3".to_vec())?;
+        tx1.commit()?;
+        let tx3 = mvcc.begin()?;
+        tx3.set(b"key1".to_vec(), b"val4".to_vec())?;
+        tx3.delete(b"key2".to_vec())?;
+        tx3.commit()?;
+
+        assert_eq!(leader.replicate()?, 3);
+        assert_eq!(follower.poll(Duration::ZERO)?, 3);
+        assert_eq!(follower.applied()?, Some(3));
+        assert_eq!(scan(follower.begin()?)?, scan(mvcc.begin()?)?);
+        assert!(follower.begin()?.set(b"key1".to_vec(), vec![]).is_err());
+
+        // the applied records are truncated
+        assert_eq!(leader.replicate()?, 0);
+        assert_eq!(mvcc.committed_versions()?, vec![3]);
+
+        // a snapshot isn't changed by later transactions
+        let snapshot = follower.begin()?;
+        let tx = mvcc.begin()?;
+        tx.set(b"key2".to_vec(), b"val5".to_vec())?;
+        tx.commit()?;
+        leader.replicate()?;
+        assert_eq!(follower.poll(Duration::ZERO)?, 1);
+        assert_eq!(snapshot.get(b"key2".to_vec())?, None);
+        assert_eq!(
+            follower.begin()?.get(b"key2".to_vec())?,
+            Some(b"val5".to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_replication_restart() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        let mvcc = Mvcc::new(MemoryEngine::new())?.capture_changes(true);
+        let mut leader = Leader::new(mvcc.clone())?;
+        let set = |key: &[u8], value: &[u8]| -> Result<()> {
+            let tx = mvcc.begin()?;
+            tx.set(key.to_vec(), value.to_vec())?;
+            tx.commit()
+        };
+
+        let (t1, t2) = ChannelTransport::pair();
+        leader.add_follower(t1);
+        let (t3, t4) = ChannelTransport::pair();
+        leader.add_follower(t3);
+        let follower1 = Follower::new(DiskEngine::new(path.clone())?, "f1", t2)?;
+        let follower2 = Follower::new(MemoryEngine::new(), "f2", t4)?;
+        set(b"key1", b"val1")?;
+        set(b"key2", b"val2")?;
+        leader.replicate()?;
+        follower1.poll(Duration::ZERO)?;
+        follower2.poll(Duration::ZERO)?;
+
+        // follower1 is down while the leader commits and follower2 applies
+        drop(follower1);
+        set(b"key1", b"val3")?;
+        set(b"key3", b"val4")?;
+        leader.replicate()?;
+        assert_eq!(follower2.poll(Duration::ZERO)?, 2);
+        leader.replicate()?;
+        assert_eq!(mvcc.committed_versions()?, vec![2, 3, 4]);
+
+        // and catches up from where it stopped after the restart
+        let (t1, t2) = ChannelTransport::pair();
+        leader.add_follower(t1);
+        let follower1 = Follower::new(DiskEngine::new(path)?, "f1", t2)?;
+        assert_eq!(follower1.applied()?, Some(2));
+        assert_eq!(leader.replicate()?, 2);
+        assert_eq!(follower1.poll(Duration::ZERO)?, 2);
+        assert_eq!(scan(follower1.begin()?)?, scan(mvcc.begin()?)?);
+        assert_eq!(scan(follower2.begin()?)?, scan(mvcc.begin()?)?);
+        leader.replicate()?;
+        assert_eq!(mvcc.committed_versions()?, vec![4]);
+        Ok(())
+    }
+
+    // the leader's storage is reopened, it keeps what its followers acked
+    #[test]
+    fn test_leader_restart() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sqldb-log");
+        let leader_path = dir.path().join("sqldb-leader");
+        let open = || -> Result<Mvcc<DiskEngine>> {
+            Ok(Mvcc::new(DiskEngine::new(leader_path.clone())?)?.capture_changes(true))
+        };
+        let set = |mvcc: &Mvcc<DiskEngine>, key: &[u8], value: &[u8]| -> Result<()> {
+            let tx = mvcc.begin()?;
+            tx.set(key.to_vec(), value.to_vec())?;
+            tx.commit()
+        };
+        let mvcc = open()?;
+        let mut leader = Leader::new(mvcc.clone())?;
+        let (t1, t2) = ChannelTransport::pair();
+        leader.add_follower(t1);
+        let (t3, t4) = ChannelTransport::pair();
+        leader.add_follower(t3);
+        let follower1 = Follower::new(MemoryEngine::new(), "f1", t2)?;
+        let follower2 = Follower::new(DiskEngine::new(path.clone())?, "f2", t4)?;
+        set(&mvcc, b"key1", b"val1")?;
+        set(&mvcc, b"key2", b"val2")?;
+        leader.replicate()?;
+        follower1.poll(Duration::ZERO)?;
+        follower2.poll(Duration::ZERO)?;
+        leader.replicate()?;
+        assert_eq!(mvcc.committed_versions()?, vec![2]);
+
+        // follower1 is down while the leader restarts, the leader still waits for it
+        drop(follower1);
+        drop(follower2);
+        drop((leader, mvcc));
+        let mvcc = open()?;
+        assert_eq!(mvcc.committed_versions()?, vec![2]);
+        let mut leader = Leader::new(mvcc.clone())?;
+        let (t3, t4) = ChannelTransport::pair();
+        leader.add_follower(t3);
+        let follower2 = Follower::new(DiskEngine::new(path.clone())?, "f2", t4)?;
+        set(&mvcc, b"key3", b"val3")?;
+        leader.replicate()?;
+        follower2.poll(Duration::ZERO)?;
+        leader.replicate()?;
+        assert_eq!(mvcc.committed_versions()?, vec![2, 3]);
+        drop(follower2);
+        let follower2 = Mvcc::new(DiskEngine::new(path)?)?;
+        assert_eq!(
+            scan(follower2.begin()?)?
+                .into_iter()
+                .map(|r| r.key)
+                .collect::<Vec<_>>(),
+            vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()]
+        );
+
+        // a new follower would miss the truncated first transaction
+        let (t5, t6) = ChannelTransport::pair();
+        leader.add_follower(t5);
+        let follower3 = Follower::new(MemoryEngine::new(), "f3", t6)?;
+        leader.replicate()?;
+        assert!(follower3.poll(Duration::ZERO).is_err());
+
+        // once follower1 is removed the records are truncated
+        leader.remove_follower("f1")?;
+        leader.replicate()?;
+        assert_eq!(mvcc.committed_versions()?, vec![3]);
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::error::{Error, Result};
+
+use super::mvcc::Version;
+
+// What a serializable transaction read and wrote, and its rw-antidependencies
+struct Txn {
+    // the snapshot of the transaction
+    version: Version,
+    active_versions: HashSet<Version>,
+    // keys read by get and prefixes read by scans
+    reads: BTreeSet<Vec<u8>>,
+    scans: Vec<Vec<u8>>,
+    writes: BTreeSet<Vec<u8>>,
+    // concurrent transactions which read a key before this one overwrote it
+    in_conflicts: HashSet<u64>,
+    // concurrent transactions which overwrote a key this one read
+    out_conflicts: HashSet<u64>,
+    // the next version when it committed, all transactions from that version on see it
+    committed: Option<Version>,
+}
+
+impl Txn {
+    fn sees(&self, version: Version) -> bool {
+        !self.active_versions.contains(&version) && version <= self.version
+    }
+
+    fn has_read(&self, key: &[u8]) -> bool {
+        self.reads.contains(key) || self.scans.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    fn has_written(&self, prefix: &[u8]) -> bool {
+        self.writes
+            .range(prefix.to_vec()..)
+            .next()
+            .is_some_and(|key| key.starts_with(prefix))
+    }
+
+    // A pivot: a transaction with both an incoming and an outgoing
+    // rw-antidependency may close a cycle, so it can't be serialized
+    fn is_pivot(&self) -> bool {
+        !self.in_conflicts.is_empty() && !self.out_conflicts.is_empty()
+    }
+}
+
+// Tracks the serializable transactions to detect the dangerous structures of
+// serializable snapshot isolation: T1 -rw-> T2 -rw-> T3, where Tx -rw-> Ty means
+// Tx read a key Ty concurrently overwrote. Every cycle of a non-serializable
+// history contains such a pivot T2, so a transaction fails when it would commit
+// as a pivot, or add an edge to a committed pivot.
+// A committed transaction is kept as long as a transaction concurrent to it is active.
+// Transactions are identified by ids, since read-only ones share their version.
+#[derive(Default)]
+pub struct SsiTracker {
+    txns: HashMap<u64, Txn>,
+    next_id: u64,
+    // the prepared transactions by the id they're prepared as, they're
+    // committed here but may still be rolled back by that id
+    prepared: HashMap<String, u64>,
+}
+
+impl SsiTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Track a transaction with the snapshot, returns its id
+    pub fn begin(&mut self, version: Version, active_versions: HashSet<Version>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.txns.insert(
+            id,
+            Txn {
+                version,
+                active_versions,
+                reads: BTreeSet::new(),
+                scans: Vec::new(),
+                writes: BTreeSet::new(),
+                in_conflicts: HashSet::new(),
+                out_conflicts: HashSet::new(),
+                committed: None,
+            },
+        );
+        id
+    }
+
+    pub fn read(&mut self, id: u64, key: &[u8]) -> Result<()> {
+        let Some(txn) = self.txns.get_mut(&id) else {
+            return Ok(());
+        };
+        txn.reads.insert(key.to_vec());
+        let writers = self.concurrent_writers(id, |w| w.writes.contains(key));
+        for writer in writers {
+            self.add_conflict(id, writer)?;
+        }
+        Ok(())
+    }
+
+    pub fn scan(&mut self, id: u64, prefix: &[u8]) -> Result<()> {
+        let Some(txn) = self.txns.get_mut(&id) else {
+            return Ok(());
+        };
+        txn.scans.push(prefix.to_vec());
+        let writers = self.concurrent_writers(id, |w| w.has_written(prefix));
+        for writer in writers {
+            self.add_conflict(id, writer)?;
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, id: u64, key: &[u8]) -> Result<()> {
+        let Some(txn) = self.txns.get_mut(&id) else {
+            return Ok(());
+        };
+        txn.writes.insert(key.to_vec());
+        let version = txn.version;
+        let readers = self
+            .txns
+            .iter()
+            .filter(|(r_id, r)| **r_id != id && !r.sees(version) && r.has_read(key))
+            .map(|(r_id, _)| *r_id)
+            .collect::<Vec<_>>();
+        for reader in readers {
+            self.add_conflict(reader, id)?;
+        }
+        Ok(())
+    }
+
+    // Fails if the transaction would commit as a pivot, otherwise it's committed
+    // and visible to transactions from next_version on
+    pub fn commit(&mut self, id: u64, next_version: Version) -> Result<()> {
+        if let Some(txn) = self.txns.get_mut(&id) {
+            if txn.is_pivot() {
+                return Err(Error::Serialization);
+            }
+            txn.committed = Some(next_version);
+        }
+        self.prune();
+        Ok(())
+    }
+
+    // Remember the id a committed transaction is prepared as
+    pub fn prepare(&mut self, id: u64, prepared: &str) {
+        if self.txns.contains_key(&id) {
+            self.prepared.insert(prepared.to_string(), id);
+        }
+    }
+
+    // The transaction prepared as the id, None if it's forgotten
+    pub fn prepared(&self, prepared: &str) -> Option<u64> {
+        self.prepared.get(prepared).copied()
+    }
+
+    // The number of transactions tracked
+    #[cfg(test)]
+    pub(crate) fn tracked(&self) -> usize {
+        self.txns.len()
+    }
+
+    // Forget a rolled back transaction and its conflicts
+    pub fn abort(&mut self, id: u64) {
+        self.txns.remove(&id);
+        for txn in self.txns.values_mut() {
+            txn.in_conflicts.remove(&id);
+            txn.out_conflicts.remove(&id);
+        }
+        self.prune();
+    }
+
+    // The other transactions whose matching writes the transaction can't see
+    fn concurrent_writers(&self, id: u64, f: impl Fn(&Txn) -> bool) -> Vec<u64> {
+        match self.txns.get(&id) {
+            Some(reader) => self
+                .txns
+                .iter()
+                .filter(|(w_id, w)| **w_id != id && !reader.sees(w.version) && f(w))
+                .map(|(w_id, _)| *w_id)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Add reader -rw-> writer, fails if it makes a committed transaction a pivot
+    fn add_conflict(&mut self, reader: u64, writer: u64) -> Result<()> {
+        if let Some(txn) = self.txns.get_mut(&reader) {
+            txn.out_conflicts.insert(writer);
+        }
+        if let Some(txn) = self.txns.get_mut(&writer) {
+            txn.in_conflicts.insert(reader);
+        }
+        for id in [reader, writer] {
+            if let Some(txn) = self.txns.get(&id) {
+                if txn.committed.is_some() && txn.is_pivot() {
+                    return Err(Error::Serialization);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Drop the committed transactions every active transaction sees
+    fn prune(&mut self) {
+        let oldest_active = self
+            .txns
+            .values()
+            .filter(|txn| txn.committed.is_none())
+            .map(|txn| txn.version)
+            .min();
+        self.txns.retain(|_, txn| match (txn.committed, oldest_active) {
+            (None, _) => true,
+            (Some(next_version), Some(oldest)) => next_version > oldest,
+            (Some(_), None) => false,
+        });
+        let Self { txns, prepared, .. } = self;
+        prepared.retain(|_, id| txns.contains_key(id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::SsiTracker;
+    use crate::error::{Error, Result};
+
+    #[test]
+    fn test_pivot() -> Result<()> {
+        let mut ssi = SsiTracker::new();
+        let t1 = ssi.begin(1, HashSet::new());
+        let t2 = ssi.begin(2, HashSet::from([1]));
+        let t3 = ssi.begin(3, HashSet::from([1, 2]));
+
+        // t1 -rw-> t2 -rw-> t3, t3 commits first
+        ssi.read(t2, b"b")?;
+        ssi.read(t1, b"a")?;
+        ssi.write(t3, b"b")?;
+        ssi.commit(t3, 4)?;
+        ssi.write(t2, b"a")?;
+        assert_eq!(ssi.commit(t2, 4), Err(Error::Serialization));
+        ssi.abort(t2);
+        ssi.commit(t1, 4)?;
+        assert!(ssi.txns.is_empty());
+
+        // a committed pivot fails the transaction adding the edge
+        let t4 = ssi.begin(4, HashSet::new());
+        let t5 = ssi.begin(5, HashSet::from([4]));
+        let t6 = ssi.begin(6, HashSet::from([4, 5]));
+        ssi.scan(t5, b"k")?;
+        ssi.write(t6, b"k1")?;
+        ssi.commit(t6, 7)?;
+        ssi.write(t5, b"x")?;
+        ssi.commit(t5, 7)?;
+        assert_eq!(ssi.read(t4, b"x"), Err(Error::Serialization));
+        Ok(())
+    }
+}